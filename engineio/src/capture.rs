@@ -0,0 +1,176 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::Path,
+    str::from_utf8,
+    sync::Mutex,
+    time::Duration,
+};
+
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+use tracing::warn;
+
+use crate::{error::Result, Packet, Sid, Socket};
+
+/// Whether a captured packet was sent or received by the recording side.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Incoming,
+    Outgoing,
+}
+
+/// A single packet of a capture file, stored as one JSON line.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CapturedPacket {
+    /// Milliseconds since the recorder was created.
+    pub timestamp: u64,
+    pub direction: Direction,
+    pub sid: Sid,
+    /// The packet in its engine.io text encoding.
+    pub packet: String,
+}
+
+/// Serializes every packet passing a socket to a writer, one JSON line each.
+pub struct Recorder {
+    start: Instant,
+    writer: Mutex<Box<dyn Write + Send>>,
+}
+
+impl Recorder {
+    /// Creates a recorder which writes to the file at `path`.
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = File::create(path)?;
+        Ok(Self::from_writer(BufWriter::new(file)))
+    }
+
+    pub fn from_writer<W: Write + Send + 'static>(writer: W) -> Self {
+        Self {
+            start: Instant::now(),
+            writer: Mutex::new(Box::new(writer)),
+        }
+    }
+
+    pub(crate) fn record(&self, direction: Direction, sid: &Sid, packet: &Packet) {
+        let bytes = Bytes::from(packet.clone());
+        let captured = CapturedPacket {
+            timestamp: self.start.elapsed().as_millis() as u64,
+            direction,
+            sid: sid.clone(),
+            packet: from_utf8(&bytes).unwrap_or_default().to_owned(),
+        };
+
+        if let Err(e) = self.write(&captured) {
+            warn!("record packet failed: {}", e);
+        }
+    }
+
+    fn write(&self, captured: &CapturedPacket) -> Result<()> {
+        let line = serde_json::to_string(captured)?;
+        let mut writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        writer.write_all(line.as_bytes())?;
+        writer.write_all(b"\n")?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for Recorder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Recorder")
+            .field("start", &self.start)
+            .finish()
+    }
+}
+
+/// Reads a capture written by a [`Recorder`] and feeds it back into a socket.
+#[derive(Debug, Clone)]
+pub struct Replayer {
+    packets: Vec<CapturedPacket>,
+}
+
+impl Replayer {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::from_reader(BufReader::new(File::open(path)?))
+    }
+
+    pub fn from_reader<R: BufRead>(reader: R) -> Result<Self> {
+        let mut packets = Vec::new();
+        for line in reader.lines() {
+            let line = line?;
+            if line.is_empty() {
+                continue;
+            }
+            packets.push(serde_json::from_str(&line)?);
+        }
+        Ok(Self { packets })
+    }
+
+    pub fn packets(&self) -> &[CapturedPacket] {
+        &self.packets
+    }
+
+    /// Emits every captured packet of the given direction through `socket`,
+    /// waiting between packets as recorded if `preserve_timing` is set.
+    pub async fn replay(
+        &self,
+        socket: &Socket,
+        direction: Direction,
+        preserve_timing: bool,
+    ) -> Result<()> {
+        let start = Instant::now();
+        for captured in self.packets.iter().filter(|p| p.direction == direction) {
+            if preserve_timing {
+                let at = start + Duration::from_millis(captured.timestamp);
+                tokio::time::sleep_until(at).await;
+            }
+            let packet = Packet::try_from(Bytes::from(captured.packet.clone()))?;
+            socket.emit(packet).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::PacketType;
+
+    #[test]
+    fn test_record_and_read() -> Result<()> {
+        let path = std::env::temp_dir().join("engineio_capture_test.jsonl");
+        let sid = Arc::new("sid".to_owned());
+
+        let recorder = Recorder::create(&path)?;
+        recorder.record(
+            Direction::Outgoing,
+            &sid,
+            &Packet::new(PacketType::Message, Bytes::from_static(b"hello")),
+        );
+        recorder.record(
+            Direction::Incoming,
+            &sid,
+            &Packet::new(PacketType::MessageBinary, Bytes::from_static(&[1, 2, 3])),
+        );
+        drop(recorder);
+
+        let replayer = Replayer::open(&path)?;
+        let packets = replayer.packets();
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[0].direction, Direction::Outgoing);
+        assert_eq!(packets[0].sid, sid);
+        assert_eq!(packets[0].packet, "4hello");
+        assert_eq!(packets[1].direction, Direction::Incoming);
+        assert_eq!(
+            Packet::try_from(Bytes::from(packets[1].packet.clone()))?,
+            Packet::new(PacketType::MessageBinary, Bytes::from_static(&[1, 2, 3]))
+        );
+
+        let _ = std::fs::remove_file(path);
+        Ok(())
+    }
+}
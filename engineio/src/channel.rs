@@ -0,0 +1,38 @@
+use tokio::sync::mpsc::Sender;
+use tracing::warn;
+
+/// Reports the usage of a bounded channel before sending to it: a warning
+/// whenever it fills up to 80% and, with the `metrics` feature, the gauge
+/// `engineio_channel_usage` labelled with `channel`, between 0 and 1.
+pub(crate) fn observe<T>(channel: &'static str, sender: &Sender<T>) {
+    let max = sender.max_capacity();
+    let used = max - sender.capacity();
+    #[cfg(feature = "metrics")]
+    ::metrics::gauge!("engineio_channel_usage", "channel" => channel).set(used as f64 / max as f64);
+    if crossed(used, max) {
+        warn!(
+            "{} channel near capacity, {} of {} used",
+            channel, used, max
+        );
+    }
+}
+
+/// Whether `used` is the usage at which a channel of `max` reaches 80%, so a
+/// channel staying full warns once.
+fn crossed(used: usize, max: usize) -> bool {
+    used == (max * 4).div_ceil(5)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_crossed() {
+        assert!(!crossed(7, 10));
+        assert!(crossed(8, 10));
+        assert!(!crossed(9, 10));
+        assert!(crossed(1, 1));
+        assert!(crossed(80, 100));
+    }
+}
@@ -0,0 +1,128 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+// added to the delays of the profile when holding back a packet
+const REORDER_HOLD: Duration = Duration::from_millis(50);
+
+/// Describes the network failures injected into outgoing packets of a socket.
+/// Rates are probabilities between `0.0` and `1.0`, evaluated per packet.
+#[derive(Debug, Clone, Default)]
+pub struct ChaosProfile {
+    /// Fixed delay added before every delivered packet.
+    pub latency: Duration,
+    /// Upper bound of a random delay added on top of `latency`.
+    pub jitter: Duration,
+    /// Probability a packet is silently dropped.
+    pub drop_rate: f64,
+    /// Probability a packet is held back and sent after the next one.
+    pub reorder_rate: f64,
+    /// Probability the socket is closed instead of sending the packet.
+    pub disconnect_rate: f64,
+    /// Seed of the random generator, a time based seed is used if `None`.
+    pub seed: Option<u64>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum ChaosAction {
+    Deliver(Duration),
+    Drop,
+    Reorder,
+    Disconnect,
+}
+
+#[derive(Debug)]
+pub(crate) struct Chaos {
+    profile: ChaosProfile,
+    state: Mutex<u64>,
+}
+
+impl Chaos {
+    pub(crate) fn new(profile: ChaosProfile) -> Self {
+        let seed = profile.seed.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_nanos() as u64)
+                .unwrap_or_default()
+        });
+        Self {
+            profile,
+            // xorshift must not start from zero
+            state: Mutex::new(seed | 1),
+        }
+    }
+
+    /// Decides what happens to the next outgoing packet.
+    pub(crate) fn next_action(&self) -> ChaosAction {
+        if self.roll() < self.profile.disconnect_rate {
+            return ChaosAction::Disconnect;
+        }
+        if self.roll() < self.profile.drop_rate {
+            return ChaosAction::Drop;
+        }
+        if self.roll() < self.profile.reorder_rate {
+            return ChaosAction::Reorder;
+        }
+
+        let jitter = self.profile.jitter.mul_f64(self.roll());
+        ChaosAction::Deliver(self.profile.latency + jitter)
+    }
+
+    /// How long a packet is held back at most, it is sent on its own once no
+    /// other packet overtook it in time.
+    pub(crate) fn reorder_hold(&self) -> Duration {
+        self.profile.latency + self.profile.jitter + REORDER_HOLD
+    }
+
+    /// Returns a pseudo random number in `[0, 1)`.
+    fn roll(&self) -> f64 {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let mut x = *state;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        *state = x;
+        (x >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_chaos_action() {
+        let chaos = Chaos::new(ChaosProfile::default());
+        assert_eq!(chaos.next_action(), ChaosAction::Deliver(Duration::ZERO));
+
+        let chaos = Chaos::new(ChaosProfile {
+            drop_rate: 1.0,
+            ..Default::default()
+        });
+        assert_eq!(chaos.next_action(), ChaosAction::Drop);
+
+        let chaos = Chaos::new(ChaosProfile {
+            disconnect_rate: 1.0,
+            drop_rate: 1.0,
+            ..Default::default()
+        });
+        assert_eq!(chaos.next_action(), ChaosAction::Disconnect);
+
+        let chaos = Chaos::new(ChaosProfile {
+            latency: Duration::from_millis(10),
+            jitter: Duration::from_millis(10),
+            seed: Some(42),
+            ..Default::default()
+        });
+        for _ in 0..100 {
+            match chaos.next_action() {
+                ChaosAction::Deliver(delay) => {
+                    assert!(delay >= Duration::from_millis(10));
+                    assert!(delay < Duration::from_millis(20));
+                }
+                action => panic!("unexpected action {:?}", action),
+            }
+        }
+    }
+}
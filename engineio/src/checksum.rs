@@ -0,0 +1,98 @@
+use adler32::RollingAdler32;
+use bytes::{BufMut, BytesMut};
+
+use crate::{error::Result, Error, Packet, PacketType};
+
+/// A separator, then the length and the Adler-32 of the data as 8 hex digits each.
+const TRAILER_LEN: usize = 17;
+const SEPARATOR: u8 = 0x1f;
+
+/// OPEN and NOOP packets are written by the server outside of a socket, so
+/// they never carry a trailer.
+fn exempt(ptype: PacketType) -> bool {
+    matches!(ptype, PacketType::Open | PacketType::Noop)
+}
+
+/// Appends the trailer to the data of an outgoing packet.
+pub(crate) fn seal(packet: Packet) -> Packet {
+    if exempt(packet.ptype) {
+        return packet;
+    }
+
+    let mut data = BytesMut::with_capacity(packet.data.len() + TRAILER_LEN);
+    data.put_slice(&packet.data);
+    data.put_u8(SEPARATOR);
+    data.put_slice(trailer(&packet.data).as_bytes());
+    Packet::new(packet.ptype, data.freeze())
+}
+
+/// Checks and strips the trailer of an incoming packet, so a packet which was
+/// cut, merged with its neighbour or sent by a peer without the `checksum`
+/// feature fails right where it is read.
+pub(crate) fn verify(packet: Packet) -> Result<Packet> {
+    if exempt(packet.ptype) {
+        return Ok(packet);
+    }
+
+    let invalid = |reason: &str| {
+        Error::InvalidTrailer(format!(
+            "{:?} packet of {} bytes: {}",
+            packet.ptype,
+            packet.data.len(),
+            reason
+        ))
+    };
+    if packet.data.len() < TRAILER_LEN {
+        return Err(invalid("too short"));
+    }
+    let split = packet.data.len() - TRAILER_LEN;
+    let (data, received) = (&packet.data[..split], &packet.data[split..]);
+    if received[0] != SEPARATOR {
+        return Err(invalid("no trailer"));
+    }
+    if received[1..] != *trailer(data).as_bytes() {
+        return Err(invalid("length or checksum mismatch"));
+    }
+
+    Ok(Packet::new(packet.ptype, packet.data.slice(..split)))
+}
+
+fn trailer(data: &[u8]) -> String {
+    format!(
+        "{:08x}{:08x}",
+        data.len(),
+        RollingAdler32::from_buffer(data).hash()
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::Bytes;
+
+    use super::*;
+
+    #[test]
+    fn test_seal_verify() -> Result<()> {
+        let packet = Packet::new(PacketType::Message, Bytes::from_static(b"hello"));
+        let sealed = seal(packet.clone());
+        assert_eq!(sealed.data.len(), 5 + TRAILER_LEN);
+        assert_eq!(verify(sealed.clone())?, packet);
+
+        // cut short
+        let cut = Packet::new(PacketType::Message, sealed.data.slice(1..));
+        assert!(verify(cut).is_err());
+
+        // corrupted
+        let mut data = BytesMut::from(&sealed.data[..]);
+        data[0] = b'j';
+        assert!(verify(Packet::new(PacketType::Message, data.freeze())).is_err());
+
+        // no trailer at all
+        assert!(verify(packet).is_err());
+
+        let noop = Packet::noop();
+        assert_eq!(verify(seal(noop.clone()))?, noop);
+
+        Ok(())
+    }
+}
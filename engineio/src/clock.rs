@@ -0,0 +1,20 @@
+use std::fmt::Debug;
+
+use tokio::time::Instant;
+
+/// The source of the instants heartbeats and queue ages are measured with,
+/// e.g. a virtual clock stepped by a simulation test. Timers still run on
+/// tokio's clock, which `tokio::time::pause` makes virtual as well.
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+/// Tokio's clock, the default one.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TokioClock;
+
+impl Clock for TokioClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
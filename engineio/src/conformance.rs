@@ -0,0 +1,100 @@
+// The test vectors of the JS engine.io-parser (protocol v4), so the framing of
+// both transports stays compatible with the reference implementation.
+
+#[cfg(feature = "server")]
+use std::collections::VecDeque;
+
+use bytes::Bytes;
+use tungstenite::Message;
+
+#[cfg(feature = "server")]
+use crate::packet::build_polling_payload;
+use crate::{packet::Payload, transports::Data, Error, Packet, PacketType, Result};
+
+/// A packet and its text encoding, as produced by `encodePacket`.
+const PACKETS: &[(PacketType, &[u8], &str)] = &[
+    (PacketType::Open, b"", "0"),
+    (PacketType::Close, b"", "1"),
+    (PacketType::Ping, b"", "2"),
+    (PacketType::Ping, b"probe", "2probe"),
+    (PacketType::Pong, b"probe", "3probe"),
+    (PacketType::Message, b"test", "4test"),
+    (PacketType::Message, "€".as_bytes(), "4€"),
+    (PacketType::Upgrade, b"", "5"),
+    (PacketType::Noop, b"", "6"),
+    // binary data is base64 encoded in text frames and polling payloads
+    (PacketType::MessageBinary, &[1, 2, 3, 4], "bAQIDBA=="),
+];
+
+#[test]
+fn test_packets() -> Result<()> {
+    for (ptype, data, encoded) in PACKETS {
+        let packet = Packet::new(*ptype, Bytes::from_static(data));
+        assert_eq!(
+            Bytes::from(packet.clone()),
+            encoded.as_bytes(),
+            "{:?}",
+            ptype
+        );
+        assert_eq!(
+            Packet::try_from(Bytes::from_static(encoded.as_bytes()))?,
+            packet
+        );
+    }
+
+    // an empty MESSAGE is encoded as `4` by the JS parser, but rejected here,
+    // see `test_packet_type_conversion_and_incompl_packet`
+    assert!(Packet::try_from(Bytes::from_static(b"4")).is_err());
+    assert!(Packet::try_from(Bytes::new()).is_err());
+    assert!(matches!(
+        Packet::try_from(Bytes::from_static(b"9")),
+        Err(Error::InvalidPacketType(b'9'))
+    ));
+    Ok(())
+}
+
+#[test]
+fn test_payloads() -> Result<()> {
+    for encoded in [
+        &b"4test1\x1e4test2"[..],
+        &b"4test\x1ebAQIDBA=="[..],
+        &b"2probe\x1e6\x1e4\xe2\x82\xac"[..],
+    ] {
+        let payload = Payload::try_from(Bytes::from_static(encoded))?;
+        assert_eq!(Bytes::try_from(payload)?, encoded);
+    }
+
+    let payload = Payload::try_from(Bytes::from_static(b"4test\x1ebAQIDBA=="))?;
+    assert_eq!(payload[0], Packet::new(PacketType::Message, "test"));
+    assert_eq!(
+        payload[1],
+        Packet::new(PacketType::MessageBinary, vec![1, 2, 3, 4])
+    );
+    Ok(())
+}
+
+#[cfg(feature = "server")]
+#[test]
+fn test_polling_response() {
+    // what the server polling transport buffers for a text and a binary packet
+    let mut buffered = VecDeque::new();
+    buffered.push_back(Data::Text(Bytes::from_static(b"4test")).into_polling());
+    buffered.push_back(Data::Binary(Bytes::from_static(&[1, 2, 3, 4])).into_polling());
+
+    let body = build_polling_payload(buffered).unwrap().concat();
+    assert_eq!(body, b"4test\x1ebAQIDBA==");
+}
+
+#[test]
+fn test_websocket_frames() -> Result<()> {
+    // text packets are sent as text frames, binary ones as raw binary frames
+    let text = Data::Text(Bytes::from(Packet::new(PacketType::Ping, "probe")));
+    assert_eq!(Message::try_from(text)?, Message::Text("2probe".to_owned()));
+
+    let binary = Data::Binary(Bytes::from_static(&[1, 2, 3, 4]));
+    assert_eq!(
+        Message::try_from(binary)?,
+        Message::Binary(vec![1, 2, 3, 4])
+    );
+    Ok(())
+}
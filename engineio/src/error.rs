@@ -7,6 +7,7 @@ use std::io::Error as IoError;
 use std::str::Utf8Error;
 use thiserror::Error;
 use tokio::sync::mpsc::error::SendError;
+use tungstenite::error::ProtocolError;
 use tungstenite::Error as WsError;
 
 use crate::Event;
@@ -30,26 +31,66 @@ pub enum Error {
     InvalidHeaderValueFromReqwest(#[from] InvalidHeaderValue),
     #[error("Invalid hand shake: {0}")]
     InvalidHandShake(String),
+    #[error("Invalid url: {0}")]
+    InvalidUrl(String),
     #[error("Invalid url scheme: {0}")]
     InvalidUrlScheme(String),
     #[error("Io Error: {0}")]
     IoError(#[from] IoError),
     #[error("Websocket Error: {0}")]
-    WsError(#[from] WsError),
+    // boxed, the websocket error is several times larger than the others
+    WsError(Box<WsError>),
     #[error("Utf8 error: {0}")]
     Utf8Error(#[from] Utf8Error),
     #[error("Http error: {0}")]
     HttpError(#[from] HttpError),
     #[error("Invalid http resposne status: {0}")]
     InvalidHttpResponseStatus(u16),
+    #[error("Session unknown to the server, which may have restarted")]
+    InvalidSession(),
     #[error("Send error: {0}")]
     SendError(#[from] SendError<Bytes>),
     #[error("Event Send error: {0}")]
     EventSendError(#[from] SendError<Event>),
     #[error("Server not allow upgrading to websocket")]
     IllegalWebsocketUpgrade(),
+    #[error("Polling buffer is full")]
+    PollingBufferFull(),
+    #[error("Invalid PROXY protocol header")]
+    InvalidProxyHeader(),
     #[error("Illegal action before open")]
     IllegalActionBeforeOpen(),
+    #[cfg(feature = "testing")]
+    #[error("Connection closed by chaos profile")]
+    ChaosDisconnect(),
+    #[cfg(feature = "checksum")]
+    #[error("Invalid packet trailer: {0}")]
+    InvalidTrailer(String),
+    #[cfg(feature = "tls")]
+    #[error("Invalid TLS config: {0}")]
+    InvalidTlsConfig(String),
+}
+
+impl Error {
+    /// Whether the peer closed the connection, with or without a websocket
+    /// closing handshake.
+    pub fn is_closed(&self) -> bool {
+        matches!(
+            self,
+            Error::WsError(e) if matches!(
+                **e,
+                WsError::ConnectionClosed
+                    | WsError::AlreadyClosed
+                    | WsError::Protocol(ProtocolError::ResetWithoutClosingHandshake)
+            )
+        )
+    }
+}
+
+impl From<WsError> for Error {
+    fn from(e: WsError) -> Self {
+        Error::WsError(Box::new(e))
+    }
 }
 
 pub(crate) type Result<T> = std::result::Result<T, Error>;
@@ -0,0 +1,16 @@
+//! Entry points for fuzzing the decoders with arbitrary network input, see the
+//! `fuzz` directory of `socketio-rs`. Not a stable API.
+
+use bytes::Bytes;
+
+use crate::packet::{Packet, Payload};
+
+/// Decodes `data` as a websocket frame and as a polling payload, returning the
+/// packets of the payload. Never panics, malformed input is an error.
+pub fn decode(data: &[u8]) -> Option<Vec<Packet>> {
+    let data = Bytes::copy_from_slice(data);
+    let _ = Packet::try_from(data.clone());
+    Payload::try_from(data)
+        .ok()
+        .map(|payload| payload.into_iter().collect())
+}
@@ -6,6 +6,7 @@ use http::{
 };
 use std::collections::HashMap;
 use std::convert::TryFrom;
+use std::fmt::Display;
 use std::str::FromStr;
 
 #[derive(Eq, PartialEq, Hash, Debug, Clone)]
@@ -27,9 +28,9 @@ pub struct IntoIter {
     inner: std::collections::hash_map::IntoIter<HeaderName, HeaderValue>,
 }
 
-impl ToString for HeaderName {
-    fn to_string(&self) -> std::string::String {
-        self.inner.clone()
+impl Display for HeaderName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.inner)
     }
 }
 
@@ -79,6 +80,12 @@ impl From<HttpHeaderValue> for HeaderValue {
     }
 }
 
+impl HeaderValue {
+    pub fn to_str(&self) -> Option<&str> {
+        std::str::from_utf8(&self.inner).ok()
+    }
+}
+
 impl From<&str> for HeaderValue {
     fn from(string: &str) -> Self {
         Self::from(string.to_owned())
@@ -119,6 +126,14 @@ impl HeaderMap {
         }
     }
 
+    /// Looks up a header, ignoring the case of its name.
+    pub fn get(&self, key: &str) -> Option<&HeaderValue> {
+        self.map
+            .iter()
+            .find(|(name, _)| name.inner.eq_ignore_ascii_case(key))
+            .map(|(_, value)| value)
+    }
+
     pub fn insert<T: Into<HeaderName>, U: Into<HeaderValue>>(
         &mut self,
         key: T,
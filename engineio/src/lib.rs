@@ -1,4 +1,16 @@
+pub(crate) mod capture;
+pub(crate) mod channel;
+#[cfg(feature = "testing")]
+pub(crate) mod chaos;
+#[cfg(feature = "checksum")]
+pub(crate) mod checksum;
+pub(crate) mod clock;
+#[cfg(test)]
+mod conformance;
 pub(crate) mod error;
+#[cfg(feature = "fuzz")]
+#[doc(hidden)]
+pub mod fuzz;
 pub(crate) mod generator;
 pub(crate) mod header;
 pub(crate) mod packet;
@@ -6,15 +18,27 @@ pub(crate) mod packet;
 pub(crate) mod server;
 pub(crate) mod socket;
 pub(crate) mod transports;
+pub(crate) mod wire;
 
+pub use capture::{CapturedPacket, Direction, Recorder, Replayer};
+#[cfg(feature = "testing")]
+pub use chaos::ChaosProfile;
+pub use clock::{Clock, TokioClock};
 pub use error::Error;
 pub(crate) use error::Result;
 pub use generator::{Generator, StreamGenerator};
 pub use header::{HeaderMap, HeaderName, HeaderValue};
 pub use packet::{Packet, PacketType};
 #[cfg(feature = "server")]
-pub use server::{Server, ServerBuilder, ServerOption};
+pub use server::{
+    IdGenerator, Incoming, PollingOverflow, QueueStats, Server, ServerBuilder, ServerOption,
+    SidGenerator, SlowConsumer, TransportKind, UpgradeStats,
+};
 pub use socket::{Event, Socket, SocketBuilder};
+#[cfg(feature = "tls")]
+pub use transports::tls::TlsConfig;
+pub use transports::{connector::AddressFamily, polling::PollingOption};
+pub use wire::WireLog;
 
 pub type Sid = std::sync::Arc<String>;
 
@@ -1,6 +1,7 @@
 use base64::{decode, encode};
 use bytes::{BufMut, Bytes, BytesMut};
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 use std::char;
 #[cfg(feature = "server")]
 use std::collections::VecDeque;
@@ -12,7 +13,7 @@ use std::str::from_utf8;
 
 use crate::{Error, Result, Sid};
 
-const SEPARATOR: char = '\x1e';
+pub(crate) const SEPARATOR: char = '\x1e';
 
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
 pub enum PacketType {
@@ -83,6 +84,9 @@ pub struct HandshakePacket {
     pub ping_interval: u64,
     pub ping_timeout: u64,
     pub max_payload: usize,
+    /// Additional fields advertised by the server, e.g. feature flags or version.
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
 impl TryFrom<Packet> for HandshakePacket {
@@ -124,7 +128,7 @@ impl TryFrom<Bytes> for Packet {
         let ptype = if is_base64 {
             PacketType::MessageBinary
         } else {
-            (*bytes.first().ok_or(Error::IncompletePacket())? as u8).try_into()?
+            (*bytes.first().ok_or(Error::IncompletePacket())?).try_into()?
         };
 
         if bytes.len() == 1 && ptype == PacketType::Message {
@@ -203,7 +207,7 @@ impl TryFrom<Payload> for Bytes {
         }
 
         // remove the last separator
-        let _ = buf.split_off(buf.len() - 1);
+        buf.truncate(buf.len().saturating_sub(1));
         Ok(buf.freeze())
     }
 }
@@ -237,21 +241,22 @@ impl Index<usize> for Payload {
     }
 }
 
+/// Chains the buffered packets into the chunks of a polling response body,
+/// the packets themselves are not copied. Binary packets are base64 encoded
+/// behind a `b` already, see `Data::into_polling`.
 #[cfg(feature = "server")]
-pub(crate) fn build_polling_payload(mut byte_vec: VecDeque<Bytes>) -> Option<String> {
-    let mut payload = String::new();
+pub(crate) fn build_polling_payload(mut byte_vec: VecDeque<Bytes>) -> Option<Vec<Bytes>> {
+    let mut payload = Vec::with_capacity(byte_vec.len() * 2);
     while let Some(bytes) = byte_vec.pop_front() {
-        if *bytes.first()? == b'b' {
-            payload.push_str(&encode(bytes));
-        } else if let Ok(s) = from_utf8(&bytes) {
-            payload.push_str(s);
+        if from_utf8(&bytes).is_ok() {
+            payload.push(bytes);
         }
 
         if !byte_vec.is_empty() {
-            payload.push(SEPARATOR);
+            payload.push(Bytes::from_static(b"\x1e"));
         }
     }
-    if payload.is_empty() {
+    if payload.iter().all(Bytes::is_empty) {
         None
     } else {
         Some(payload)
@@ -378,6 +383,7 @@ mod tests {
             max_payload: 1000,
             sid: Arc::new("Test".to_owned()),
             upgrades: vec!["websocket".to_owned(), "test".to_owned()],
+            extra: Map::new(),
         };
         let encoded: String = serde_json::to_string(&packet).unwrap();
 
@@ -386,6 +392,15 @@ mod tests {
             HandshakePacket::try_from(Packet::new(PacketType::Message, Bytes::from(encoded)))
                 .unwrap()
         );
+
+        let data = Bytes::from_static(
+            b"{\"sid\":\"Test\",\"upgrades\":[],\"pingInterval\":1,\"pingTimeout\":1,\"maxPayload\":1,\"version\":\"1.0\"}",
+        );
+        let packet = HandshakePacket::try_from(Packet::new(PacketType::Open, data)).unwrap();
+        assert_eq!(packet.extra.get("version"), Some(&Value::from("1.0")));
+        assert!(serde_json::to_string(&packet)
+            .unwrap()
+            .contains("\"version\":\"1.0\""));
     }
 
     #[test]
@@ -394,16 +409,17 @@ mod tests {
         let payload = build_polling_payload(byte_vec);
         assert!(payload.is_none());
 
-        let data = Bytes::from_static(b"Hello\x1eHelloWorld\x1eYkhlbGxv");
+        let data = Bytes::from_static(b"Hello\x1eHelloWorld\x1ebSGVsbG8=");
 
         let mut byte_vec = VecDeque::new();
         byte_vec.push_back(Bytes::from_static(b"Hello"));
         byte_vec.push_back(Bytes::from_static(b"HelloWorld"));
-        byte_vec.push_back(Bytes::from_static(b"bHello"));
+        byte_vec.push_back(Bytes::from_static(b"bSGVsbG8="));
         let payload = build_polling_payload(byte_vec);
 
         assert!(payload.is_some());
         let payload = payload.unwrap();
-        assert_eq!(payload, data);
+        assert_eq!(payload.len(), 5);
+        assert_eq!(payload.concat(), data);
     }
 }
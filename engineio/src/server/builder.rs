@@ -1,14 +1,43 @@
-use std::sync::Arc;
+use std::{
+    collections::HashSet,
+    net::IpAddr,
+    sync::{atomic::AtomicBool, Arc},
+    time::Duration,
+};
 
-use tokio::sync::{mpsc::channel, Mutex};
+use serde_json::{Map, Value};
+use tokio::{
+    runtime::Handle,
+    sync::{mpsc::channel, Mutex},
+};
 
-use crate::server::{server::ServerInner, Server, ServerOption};
+use crate::capture::Recorder;
+use crate::clock::{Clock, TokioClock};
+use crate::server::{
+    proxy::TrustedProxies, server::ServerInner, IdGenerator, PollingOverflow, Server, ServerOption,
+    SidGenerator, SlowConsumer, TransportKind,
+};
+use crate::wire::WireLog;
 
 pub struct ServerBuilder {
     port: u16,
     server_option: ServerOption,
     polling_buffer: usize,
+    polling_timeout: Option<Duration>,
+    polling_overflow: PollingOverflow,
+    polling_compression: Option<usize>,
+    slow_consumer: Option<SlowConsumer>,
+    trusted_proxies: TrustedProxies,
     event_size: usize,
+    handshake_extra: Map<String, Value>,
+    transports: HashSet<TransportKind>,
+    id_generator: Arc<dyn IdGenerator>,
+    clock: Arc<dyn Clock>,
+    recorder: Option<Arc<Recorder>>,
+    wire_log: Option<Arc<WireLog>>,
+    runtime: Option<Handle>,
+    #[cfg(feature = "testing")]
+    chaos: Option<crate::ChaosProfile>,
 }
 
 impl ServerBuilder {
@@ -17,7 +46,21 @@ impl ServerBuilder {
             port,
             server_option: Default::default(),
             polling_buffer: 100,
+            polling_timeout: None,
+            polling_overflow: Default::default(),
+            polling_compression: None,
+            slow_consumer: None,
+            trusted_proxies: Default::default(),
             event_size: 1000,
+            handshake_extra: Map::new(),
+            transports: HashSet::from([TransportKind::Polling, TransportKind::Websocket]),
+            id_generator: Arc::new(SidGenerator::default()),
+            clock: Arc::new(TokioClock),
+            recorder: None,
+            wire_log: None,
+            runtime: None,
+            #[cfg(feature = "testing")]
+            chaos: None,
         }
     }
 
@@ -26,28 +69,158 @@ impl ServerBuilder {
         self
     }
 
+    /// Capacity of the channels buffering the packets of a polling socket in
+    /// each direction, 100 by default.
     pub fn polling_buffer(mut self, polling_buffer: usize) -> Self {
         self.polling_buffer = polling_buffer;
         self
     }
 
+    /// Sets how long a polling GET request is held open while no packet is
+    /// buffered for the client. A NOOP packet is sent once it elapses.
+    /// Defaults to the ping interval.
+    pub fn polling_timeout(mut self, polling_timeout: Duration) -> Self {
+        self.polling_timeout = Some(polling_timeout);
+        self
+    }
+
+    /// Sets the policy applied when a polling client can not keep up and its
+    /// buffer of `polling_buffer` packets is full.
+    pub fn polling_overflow(mut self, polling_overflow: PollingOverflow) -> Self {
+        self.polling_overflow = polling_overflow;
+        self
+    }
+
+    /// Compresses polling responses of at least `threshold` bytes with brotli or
+    /// gzip, for clients advertising it in `Accept-Encoding`.
+    pub fn polling_compression(mut self, threshold: usize) -> Self {
+        self.polling_compression = Some(threshold);
+        self
+    }
+
+    /// Reports polling sockets whose buffer grows beyond `limits` with
+    /// `Event::OnSlowConsumer`.
+    pub fn slow_consumer(mut self, limits: SlowConsumer) -> Self {
+        self.slow_consumer = Some(limits);
+        self
+    }
+
+    /// Peers whose `X-Forwarded-For` header is believed when deriving the
+    /// client address, e.g. the load balancers in front of the server.
+    pub fn trusted_proxies<I: IntoIterator<Item = IpAddr>>(mut self, proxies: I) -> Self {
+        self.trusted_proxies.addrs = proxies.into_iter().collect();
+        self
+    }
+
+    /// Expects a PROXY protocol v1 header on connections from trusted proxies.
+    pub fn proxy_protocol(mut self, proxy_protocol: bool) -> Self {
+        self.trusted_proxies.proxy_protocol = proxy_protocol;
+        self
+    }
+
+    /// Capacity of the channel of events returned by `Server::event_rx`, 1000
+    /// by default.
     pub fn event_size(mut self, event_size: usize) -> Self {
         self.event_size = event_size;
         self
     }
 
+    /// Adds a field to the JSON payload of the OPEN packet, so clients can
+    /// read e.g. the server version or shard identity during the handshake.
+    pub fn handshake_extra<K: Into<String>, V: Into<Value>>(mut self, key: K, value: V) -> Self {
+        self.handshake_extra.insert(key.into(), value.into());
+        self
+    }
+
+    /// The transports clients may connect with, both by default. Requests of
+    /// other transports are refused with status 400, and clients are only
+    /// offered to upgrade from polling if websocket is allowed.
+    ///
+    /// # Panics
+    ///
+    /// If `transports` is empty, no client could connect.
+    pub fn transports<I: IntoIterator<Item = TransportKind>>(mut self, transports: I) -> Self {
+        self.transports = transports.into_iter().collect();
+        assert!(!self.transports.is_empty(), "no transport allowed");
+        self
+    }
+
+    /// Generates the sids of new sessions, sequential ones by default.
+    pub fn id_generator<G: IdGenerator + 'static>(mut self, id_generator: G) -> Self {
+        self.id_generator = Arc::new(id_generator);
+        self
+    }
+
+    /// Measures heartbeats and the age of buffered packets with `clock`,
+    /// tokio's clock by default.
+    pub fn clock<C: Clock + 'static>(mut self, clock: C) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Records every packet sent or received by any socket of the server.
+    pub fn recorder(mut self, recorder: Arc<Recorder>) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    /// Dumps every packet sent or received by any socket of the server to the
+    /// trace log.
+    pub fn wire_log(mut self, wire_log: WireLog) -> Self {
+        self.wire_log = Some(Arc::new(wire_log));
+        self
+    }
+
+    /// Runs the tasks of the server on `runtime` instead of the runtime calling
+    /// `serve`, e.g. a dedicated current-thread runtime.
+    pub fn runtime(mut self, runtime: Handle) -> Self {
+        self.runtime = Some(runtime);
+        self
+    }
+
+    /// Injects artificial network failures into packets sent to every client.
+    #[cfg(feature = "testing")]
+    pub fn chaos(mut self, profile: crate::ChaosProfile) -> Self {
+        self.chaos = Some(profile);
+        self
+    }
+
     pub fn build(self) -> Server {
         let (event_tx, event_rx) = channel(self.event_size);
+        let polling_timeout = self
+            .polling_timeout
+            .unwrap_or_else(|| Duration::from_millis(self.server_option.ping_interval));
         Server {
             inner: Arc::new(ServerInner {
                 port: self.port,
                 server_option: self.server_option,
-                id_generator: Default::default(),
+                id_generator: self.id_generator,
+                clock: self.clock,
                 sockets: Default::default(),
                 polling_handles: Default::default(),
+                closed_sessions: Default::default(),
+                closed_order: Default::default(),
                 polling_buffer: self.polling_buffer,
+                polling_timeout,
+                polling_overflow: self.polling_overflow,
+                polling_overflow_count: Default::default(),
+                polling_compression: self.polling_compression,
+                slow_consumer: self.slow_consumer,
+                emit_queues: Default::default(),
+                trusted_proxies: self.trusted_proxies,
+                peers: Default::default(),
                 event_tx: Arc::new(event_tx),
                 event_rx: Arc::new(Mutex::new(event_rx)),
+                handshake_extra: self.handshake_extra,
+                transports: self.transports,
+                upgrades: Default::default(),
+                upgrade_failures: Default::default(),
+                accepting: AtomicBool::new(true),
+                recorder: self.recorder,
+                wire_log: self.wire_log,
+                runtime: self.runtime,
+                #[cfg(feature = "testing")]
+                chaos: self.chaos,
             }),
         }
     }
@@ -0,0 +1,128 @@
+use std::io::Write;
+
+use bytes::Bytes;
+use flate2::{write::GzEncoder, Compression};
+
+/// The encoding of a polling response body, chosen from the `Accept-Encoding`
+/// header of the request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum ContentEncoding {
+    #[default]
+    Identity,
+    Gzip,
+    Brotli,
+}
+
+impl ContentEncoding {
+    /// Picks brotli over gzip if both are accepted, ignoring codings with `q=0`.
+    pub(crate) fn negotiate(accept_encoding: &str) -> Self {
+        let accepted = |name: &str| {
+            accept_encoding.split(',').any(|item| {
+                let mut parts = item.split(';');
+                let coding = parts.next().unwrap_or_default().trim();
+                let refused = parts.any(|param| {
+                    param
+                        .trim()
+                        .strip_prefix("q=")
+                        .and_then(|q| q.parse::<f32>().ok())
+                        == Some(0.0)
+                });
+                coding.eq_ignore_ascii_case(name) && !refused
+            })
+        };
+
+        if accepted("br") {
+            Self::Brotli
+        } else if accepted("gzip") {
+            Self::Gzip
+        } else {
+            Self::Identity
+        }
+    }
+
+    /// The `Content-Encoding` header value, `None` for uncompressed bodies.
+    pub(crate) fn header(&self) -> Option<&'static str> {
+        match self {
+            Self::Identity => None,
+            Self::Gzip => Some("gzip"),
+            Self::Brotli => Some("br"),
+        }
+    }
+
+    /// Encodes a body made of `body` chunks.
+    pub(crate) fn encode(&self, body: Vec<Bytes>) -> std::io::Result<Vec<Bytes>> {
+        let output = match self {
+            // passed through untouched, the chunks are written vectored
+            Self::Identity => return Ok(body),
+            Self::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                for chunk in &body {
+                    encoder.write_all(chunk)?;
+                }
+                encoder.finish()?
+            }
+            Self::Brotli => {
+                let mut output = Vec::new();
+                {
+                    // quality 5 and a 4MB window, a common choice for dynamic content
+                    let mut encoder = brotli::CompressorWriter::new(&mut output, 4096, 5, 22);
+                    for chunk in &body {
+                        encoder.write_all(chunk)?;
+                    }
+                }
+                output
+            }
+        };
+        Ok(vec![Bytes::from(output)])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+
+    use flate2::read::GzDecoder;
+
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(ContentEncoding::negotiate(""), ContentEncoding::Identity);
+        assert_eq!(
+            ContentEncoding::negotiate("gzip, deflate"),
+            ContentEncoding::Gzip
+        );
+        assert_eq!(
+            ContentEncoding::negotiate("gzip, deflate, br"),
+            ContentEncoding::Brotli
+        );
+        assert_eq!(
+            ContentEncoding::negotiate("br;q=0, GZIP;q=0.8"),
+            ContentEncoding::Gzip
+        );
+        assert_eq!(
+            ContentEncoding::negotiate("identity"),
+            ContentEncoding::Identity
+        );
+    }
+
+    #[test]
+    fn test_encode() -> std::io::Result<()> {
+        let body = "4[\"dashboard\",{\"value\":1}]\x1e".repeat(64);
+
+        let chunks = || vec![Bytes::from(body.clone())];
+
+        let gzip = ContentEncoding::Gzip.encode(chunks())?.concat();
+        let mut decoded = String::new();
+        GzDecoder::new(&gzip[..]).read_to_string(&mut decoded)?;
+        assert_eq!(decoded, body);
+        assert!(gzip.len() < body.len());
+
+        let br = ContentEncoding::Brotli.encode(chunks())?.concat();
+        let mut decoded = String::new();
+        brotli::Decompressor::new(&br[..], 4096).read_to_string(&mut decoded)?;
+        assert_eq!(decoded, body);
+        assert!(br.len() < body.len());
+        Ok(())
+    }
+}
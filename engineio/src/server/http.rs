@@ -1,49 +1,79 @@
-use std::{borrow::Cow, collections::VecDeque, net::SocketAddr};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, VecDeque},
+    io::{self, IoSlice},
+    net::{IpAddr, SocketAddr},
+};
 use std::{str::from_utf8, sync::Arc};
 
-use bytes::Bytes;
+use bytes::{Buf, Bytes};
 use futures_util::SinkExt;
 use futures_util::{future::poll_fn, StreamExt};
 use http::Response;
 use httparse::{Request, Status, EMPTY_HEADER};
 use reqwest::Url;
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt, ReadBuf},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
     sync::mpsc::{channel, Receiver, Sender},
+    time::timeout,
 };
 use tokio::{net::TcpStream, sync::Mutex};
-use tokio_tungstenite::{accept_async, MaybeTlsStream, WebSocketStream};
+use tokio_tungstenite::{accept_async, WebSocketStream};
 use tracing::trace;
 use tungstenite::Message;
 
 use crate::{
+    channel,
     error::Result,
     packet::build_polling_payload,
-    transports::{polling::ServerPollingTransport, websocket::WebsocketTransport, TransportType},
+    transports::{
+        polling::{ServerPollingTransport, UNKNOWN_SESSION},
+        websocket::WebsocketTransport,
+        TransportType,
+    },
     Error,
 };
-use crate::{Packet, PacketType, Sid};
+use crate::{HeaderMap, Packet, PacketType, Sid};
 
-use super::Server;
+use super::{
+    encoding::ContentEncoding,
+    incoming::{Incoming, Rewind},
+    proxy::TrustedProxies,
+    server::Peer,
+    Server, TransportKind,
+};
 
 /// Limit for the number of header lines.
 const MAX_HEADERS: usize = 124;
+/// Seconds a refused client is told to wait before retrying its handshake.
+const RETRY_AFTER: u64 = 5;
+const NOT_ACCEPTING: &str = "server is not accepting new connections";
+// the answer of the JS implementation to a transport it doesn't serve
+const UNKNOWN_TRANSPORT: &str = r#"{"code":0,"message":"Transport unknown"}"#;
 
 pub type PollingHandle = (Arc<Sender<Bytes>>, Arc<Mutex<Receiver<Bytes>>>);
 
 pub(crate) struct Polling {}
 
 impl Polling {
-    pub(crate) async fn handle(
+    pub(crate) async fn handle<S: AsyncRead + AsyncWrite + Unpin>(
         server: Server,
-        mut stream: TcpStream,
+        mut stream: S,
         peer_addr: &SocketAddr,
+        peer: Peer,
     ) -> Result<()> {
         match read_request_type(&mut stream, peer_addr, server.max_payload()).await {
+            Some(RequestType::PollingOpen) if !server.allows(TransportKind::Polling) => {
+                write_stream(&mut stream, 400, Some(UNKNOWN_TRANSPORT.to_owned())).await
+            }
+            Some(RequestType::PollingOpen) if !server.is_accepting() => {
+                write_stream(&mut stream, 503, Some(NOT_ACCEPTING.to_owned())).await
+            }
             Some(RequestType::PollingOpen) => {
                 let sid = server.generate_sid();
                 let transport = Self::polling_transport(&server, sid.clone()).await;
                 let transport = TransportType::ServerPolling(transport);
+                server.set_peer(sid.clone(), peer);
 
                 if server
                     .store_transport(sid.clone(), transport, false)
@@ -52,23 +82,39 @@ impl Polling {
                 {
                     write_stream(&mut stream, 200, Some(Self::handshake_body(&server, sid))).await
                 } else {
+                    server.close_socket(&sid).await;
                     write_stream(&mut stream, 500, None).await
                 }
             }
+            Some(RequestType::PollingPost(sid, _) | RequestType::PollingGet(sid, _))
+                if server.is_unknown_session(&sid) =>
+            {
+                // e.g. the server restarted, the client starts a new session
+                write_stream(&mut stream, 400, Some(UNKNOWN_SESSION.to_owned())).await
+            }
             Some(RequestType::PollingPost(sid, data)) => {
                 Self::polling_post(&server, &sid, data).await;
                 write_stream(&mut stream, 200, Some("ok".to_string())).await
             }
-            Some(RequestType::PollingGet(sid)) => {
+            Some(RequestType::PollingGet(sid, encoding)) => {
                 let data = Self::polling_get(&server, &sid).await;
-                write_stream(&mut stream, 200, data).await
+                let len = data.iter().flatten().map(Bytes::len).sum::<usize>();
+                let encoding = match server.polling_compression() {
+                    Some(threshold) if len >= threshold => encoding,
+                    _ => ContentEncoding::Identity,
+                };
+                write_encoded(&mut stream, 200, data, encoding).await
             }
             _ => write_stream(&mut stream, 400, None).await,
         }
     }
 
     fn handshake_body(server: &Server, sid: Sid) -> String {
-        let packet = server.handshake_packet(vec!["websocket".to_owned()], Some(sid));
+        let upgrades = match server.allows(TransportKind::Websocket) {
+            true => vec!["websocket".to_owned()],
+            false => vec![],
+        };
+        let packet = server.handshake_packet(upgrades, Some(sid));
         // SAFETY: all fields are safe to serialize
         let data = serde_json::to_string(&packet).unwrap();
         format!("{}{}", PacketType::Open as u8, data)
@@ -78,13 +124,15 @@ impl Polling {
         let (send_tx, send_rx) = channel(server.polling_buffer());
         let (recv_tx, recv_rx) = channel(server.polling_buffer());
 
+        let queue = server.new_emit_queue(sid.clone());
         let handles = &server.polling_handles();
         handles.insert(sid, (Arc::new(recv_tx), Arc::new(Mutex::new(send_rx))));
 
-        ServerPollingTransport::new(send_tx, recv_rx)
+        let (overflow, overflow_count) = server.polling_overflow();
+        ServerPollingTransport::new(send_tx, recv_rx, overflow, overflow_count, queue)
     }
 
-    async fn polling_get(server: &Server, sid: &Sid) -> Option<String> {
+    async fn polling_get(server: &Server, sid: &Sid) -> Option<Vec<Bytes>> {
         trace!("polling get {}", sid);
         let handle = match server.polling_handle(sid).await {
             None => return None,
@@ -92,13 +140,28 @@ impl Polling {
         };
 
         let rx = &mut handle.1.lock().await;
+        let queue = server.emit_queue(sid);
+        let popped = || {
+            if let Some(queue) = &queue {
+                queue.popped();
+            }
+        };
         let mut byte_vec = VecDeque::new();
 
-        if let Some(bytes) = rx.recv().await {
-            byte_vec.push_back(bytes);
+        // hold the request until a packet is buffered, answer with a NOOP
+        // packet if nothing arrives within the polling timeout
+        match timeout(server.polling_timeout(), rx.recv()).await {
+            Ok(Some(bytes)) => {
+                popped();
+                byte_vec.push_back(bytes)
+            }
+            Ok(None) => return None,
+            Err(_) => byte_vec.push_back(Bytes::from(Packet::noop())),
         }
 
+        // flush everything else already buffered in the same response
         while let Ok(bytes) = rx.try_recv() {
+            popped();
             byte_vec.push_back(bytes);
         }
 
@@ -112,6 +175,7 @@ impl Polling {
 
         if let Some(mut ref_mut) = server.polling_handles().get_mut(sid) {
             let (ref mut tx, _) = *ref_mut;
+            channel::observe("polling post", tx);
             let _ = tx.send(data).await;
         }
     }
@@ -120,18 +184,31 @@ impl Polling {
 pub(crate) struct Websocket {}
 
 impl Websocket {
-    pub(crate) async fn handle(
+    pub(crate) async fn handle<S>(
         server: Server,
         sid: Option<Sid>,
-        stream: MaybeTlsStream<TcpStream>,
-        _addr: &SocketAddr,
-    ) -> Result<()> {
-        let mut ws_stream = accept_async(stream).await?;
+        stream: S,
+        peer: Peer,
+    ) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
         let is_upgrade = sid.is_some();
-        let sid = match sid {
+        let (sid, ws_stream) = match sid {
             // websocket connecting directly, instead of upgrading from polling
-            None => handshake(server.clone(), &mut ws_stream).await?,
-            Some(sid) => handle_probe(server.clone(), sid, &mut ws_stream).await?,
+            None => {
+                let mut ws_stream = accept_async(stream).await?;
+                let sid = handshake(server.clone(), &mut ws_stream).await?;
+                server.set_peer(sid.clone(), peer);
+                (sid, ws_stream)
+            }
+            Some(sid) => match Self::probe(server.clone(), sid.clone(), stream).await {
+                Ok(upgraded) => upgraded,
+                Err(e) => {
+                    server.upgrade_failed(&sid, e.to_string()).await;
+                    return Err(e);
+                }
+            },
         };
 
         let (sender, receiver) = ws_stream.split();
@@ -142,26 +219,114 @@ impl Websocket {
 
         Ok(())
     }
+
+    async fn probe<S: AsyncRead + AsyncWrite + Unpin>(
+        server: Server,
+        sid: Sid,
+        stream: S,
+    ) -> Result<(Sid, WebSocketStream<S>)> {
+        let mut ws_stream = accept_async(stream).await?;
+        let sid = handle_probe(server, sid, &mut ws_stream).await?;
+        Ok((sid, ws_stream))
+    }
 }
 
 pub(crate) async fn handle_http(
     server: Server,
-    stream: TcpStream,
+    mut stream: TcpStream,
     peer_addr: SocketAddr,
 ) -> Result<()> {
+    let mut peer_ip = peer_addr.ip();
+    if server.trusted_proxies().expects_proxy_header(&peer_ip) {
+        peer_ip = TrustedProxies::read_proxy_header(&mut stream, peer_ip).await?;
+    }
+
     // TODO: tls
-    match peek_request_type(&stream, &peer_addr, server.max_payload()).await {
-        Some(RequestType::WsUpgrade(sid)) => {
-            Websocket::handle(server, sid, MaybeTlsStream::Plain(stream), &peer_addr).await
+    let (request_type, head) = peek_request_type(&stream, &peer_addr, server.max_payload())
+        .await
+        .unzip();
+    let (headers, query) = head.unwrap_or_default();
+
+    route(
+        server,
+        stream,
+        peer_addr,
+        peer_ip,
+        request_type,
+        headers,
+        query,
+    )
+    .await
+}
+
+/// Serves a connection accepted outside of the server. It can not be peeked
+/// like a `TcpStream`, so the request is read up front and replayed.
+pub(crate) async fn handle_incoming<S>(server: Server, incoming: Incoming<S>) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let peer_addr = incoming.peer_addr;
+    let mut stream = Rewind::new(incoming.head, incoming.stream);
+    let mut peer_ip = peer_addr.ip();
+    if server.trusted_proxies().expects_proxy_header(&peer_ip) {
+        peer_ip = TrustedProxies::read_proxy_header(&mut stream, peer_ip).await?;
+    }
+
+    let request = read_request(&mut stream, server.max_payload()).await?;
+    stream.unread(&request);
+    let request_type = parse_request_type(&request, &peer_addr, true);
+    let (headers, query) = request_head(&request, &peer_addr);
+
+    route(
+        server,
+        stream,
+        peer_addr,
+        peer_ip,
+        request_type,
+        headers,
+        query,
+    )
+    .await
+}
+
+async fn route<S>(
+    server: Server,
+    mut stream: S,
+    peer_addr: SocketAddr,
+    peer_ip: IpAddr,
+    request_type: Option<RequestType>,
+    headers: HeaderMap,
+    query: HashMap<String, String>,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let forwarded_for = headers.get("x-forwarded-for").and_then(|v| v.to_str());
+    let peer = Peer {
+        ip: server.trusted_proxies().client_ip(peer_ip, forwarded_for),
+        headers,
+        query,
+    };
+
+    match request_type {
+        Some(RequestType::WsUpgrade(_)) if !server.allows(TransportKind::Websocket) => {
+            let _ = read_request_type(&mut stream, &peer_addr, server.max_payload()).await;
+            write_stream(&mut stream, 400, Some(UNKNOWN_TRANSPORT.to_owned())).await
+        }
+        Some(RequestType::WsUpgrade(None)) if !server.is_accepting() => {
+            // consume the peeked request before answering
+            let _ = read_request_type(&mut stream, &peer_addr, server.max_payload()).await;
+            write_stream(&mut stream, 503, Some(NOT_ACCEPTING.to_owned())).await
         }
-        _ => Polling::handle(server.clone(), stream, &peer_addr).await,
+        Some(RequestType::WsUpgrade(sid)) => Websocket::handle(server, sid, stream, peer).await,
+        _ => Polling::handle(server.clone(), stream, &peer_addr, peer).await,
     }
 }
 
-async fn handle_probe(
+async fn handle_probe<S: AsyncRead + AsyncWrite + Unpin>(
     server: Server,
     sid: Sid,
-    ws_stream: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
+    ws_stream: &mut WebSocketStream<S>,
 ) -> Result<Sid> {
     if let Some(Ok(Message::Text(packet))) = ws_stream.next().await {
         if packet == "2probe" {
@@ -177,8 +342,8 @@ async fn handle_probe(
 
     if let Some(Ok(Message::Text(packet))) = ws_stream.next().await {
         // PacketType::Upgrade
+        // the polling transport is removed with the upgrade, see `store_transport`
         if packet == "5" {
-            close_polling(&server, &sid).await;
             return Ok(sid);
         }
     }
@@ -188,14 +353,9 @@ async fn handle_probe(
     ))
 }
 
-async fn close_polling(server: &Server, sid: &Sid) {
-    let handles = &server.polling_handles();
-    handles.remove(sid);
-}
-
-async fn handshake(
+async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
     server: Server,
-    ws_stream: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
+    ws_stream: &mut WebSocketStream<S>,
 ) -> Result<Sid> {
     let sid = server.generate_sid();
     let packet = server.handshake_packet(vec![], Some(sid.clone()));
@@ -212,24 +372,70 @@ async fn handshake(
 pub(crate) enum RequestType {
     WsUpgrade(Option<Sid>),
     PollingOpen,
-    PollingGet(Sid),
+    PollingGet(Sid, ContentEncoding),
     PollingPost(Sid, Bytes),
 }
 
+/// Peeks the request type and the request headers.
 pub(crate) async fn peek_request_type(
     stream: &TcpStream,
     addr: &SocketAddr,
     max_payload: usize,
-) -> Option<RequestType> {
+) -> Option<(RequestType, (HeaderMap, HashMap<String, String>))> {
     let mut buf = vec![0; max_payload];
     let mut buf = ReadBuf::new(&mut buf);
 
     poll_fn(|cx| stream.poll_peek(cx, &mut buf)).await.ok()?;
-    parse_request_type(buf.filled(), addr, true)
+    let request_type = parse_request_type(buf.filled(), addr, true)?;
+    Some((request_type, request_head(buf.filled(), addr)))
+}
+
+/// Collects the headers and the query of a request.
+fn request_head(buf: &[u8], addr: &SocketAddr) -> (HeaderMap, HashMap<String, String>) {
+    (request_headers(buf), request_query(buf, addr))
+}
+
+/// Collects the query pairs of a request, the last one wins for repeated keys.
+fn request_query(buf: &[u8], addr: &SocketAddr) -> HashMap<String, String> {
+    let mut header_buf = [EMPTY_HEADER; MAX_HEADERS];
+    let mut req = Request::new(&mut header_buf);
+    let _ = req.parse(buf);
+    req.path
+        .and_then(|path| Url::parse(&format!("http://{}{}", addr, path)).ok())
+        .map(|url| url.query_pairs().into_owned().collect())
+        .unwrap_or_default()
+}
+
+/// Collects the headers of a request with lowercase names, joining the values of
+/// repeated headers into one comma separated list.
+fn request_headers(buf: &[u8]) -> HeaderMap {
+    let mut header_buf = [EMPTY_HEADER; MAX_HEADERS];
+    let mut req = Request::new(&mut header_buf);
+    let mut headers: HashMap<String, String> = HashMap::new();
+    if req.parse(buf).is_ok() {
+        for header in req.headers.iter() {
+            let Ok(value) = from_utf8(header.value) else {
+                continue;
+            };
+            headers
+                .entry(header.name.to_lowercase())
+                .and_modify(|values| {
+                    values.push(',');
+                    values.push_str(value);
+                })
+                .or_insert_with(|| value.to_owned());
+        }
+    }
+
+    let mut map = HeaderMap::new();
+    for (name, value) in headers {
+        map.insert(name, value);
+    }
+    map
 }
 
-async fn read_request_type(
-    stream: &mut TcpStream,
+async fn read_request_type<S: AsyncRead + Unpin>(
+    stream: &mut S,
     addr: &SocketAddr,
     max_payload: usize,
 ) -> Option<RequestType> {
@@ -239,6 +445,40 @@ async fn read_request_type(
     parse_request_type(&buf[0..n], addr, false)
 }
 
+/// Reads until `stream` holds a whole request, or `max_payload` bytes.
+async fn read_request<S: AsyncRead + Unpin>(stream: &mut S, max_payload: usize) -> Result<Vec<u8>> {
+    let mut buf = vec![0; max_payload];
+    let mut len = 0;
+    while len < max_payload && !request_complete(&buf[..len]) {
+        let n = stream.read(&mut buf[len..]).await?;
+        if n == 0 {
+            break;
+        }
+        len += n;
+    }
+    buf.truncate(len);
+    Ok(buf)
+}
+
+/// Whether `buf` holds the head and the body of a request, or is no request.
+fn request_complete(buf: &[u8]) -> bool {
+    let mut header_buf = [EMPTY_HEADER; MAX_HEADERS];
+    let mut req = Request::new(&mut header_buf);
+    match req.parse(buf) {
+        Ok(Status::Complete(idx)) => {
+            let content_length = req
+                .headers
+                .iter()
+                .find(|header| header.name.eq_ignore_ascii_case("content-length"))
+                .and_then(|header| from_utf8(header.value).ok()?.trim().parse().ok())
+                .unwrap_or(0);
+            buf.len() >= idx + content_length
+        }
+        Ok(Status::Partial) => false,
+        Err(_) => true,
+    }
+}
+
 pub(crate) fn parse_request_type(
     buf: &[u8],
     addr: &SocketAddr,
@@ -256,6 +496,7 @@ pub(crate) fn parse_request_type(
     }
 
     let mut content_length = 0;
+    let mut encoding = ContentEncoding::Identity;
     let url = format!("http://{}{}", addr, req.path?);
     let url = Url::parse(&url).ok()?;
     let mut sid = None;
@@ -291,6 +532,10 @@ pub(crate) fn parse_request_type(
             let len_str = from_utf8(header.value).ok()?;
             content_length = len_str.parse().ok()?;
         }
+
+        if header.name.to_lowercase() == "accept-encoding" {
+            encoding = ContentEncoding::negotiate(from_utf8(header.value).ok()?);
+        }
     }
 
     if req.method?.to_uppercase() == "POST" {
@@ -309,47 +554,519 @@ pub(crate) fn parse_request_type(
     }
 
     match sid {
-        Some(sid) => Some(RequestType::PollingGet(sid)),
+        Some(sid) => Some(RequestType::PollingGet(sid, encoding)),
         _ => Some(RequestType::PollingOpen),
     }
 }
 
-async fn write_stream(stream: &mut TcpStream, status: u16, body: Option<String>) -> Result<()> {
-    let response = http_response(status, body); // not ok, will lost message
-    stream.write_all(&Bytes::from(response)).await?;
+async fn write_stream<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    status: u16,
+    body: Option<String>,
+) -> Result<()> {
+    let body = body.map(|body| vec![Bytes::from(body)]);
+    write_encoded(stream, status, body, ContentEncoding::Identity).await
+}
+
+async fn write_encoded<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    status: u16,
+    body: Option<Vec<Bytes>>,
+    encoding: ContentEncoding,
+) -> Result<()> {
+    let response = http_response(status, body, encoding)?; // not ok, will lost message
+    write_all_vectored(stream, response.into()).await?;
+    Ok(())
+}
+
+/// Writes `chunks` in as few vectored writes as the stream allows, so the
+/// packets of a response are not copied into one buffer behind the head.
+async fn write_all_vectored<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    mut chunks: VecDeque<Bytes>,
+) -> io::Result<()> {
+    chunks.retain(|chunk| !chunk.is_empty());
+    while !chunks.is_empty() {
+        let slices: Vec<IoSlice> = chunks.iter().map(|chunk| IoSlice::new(chunk)).collect();
+        let mut written = stream.write_vectored(&slices).await?;
+        if written == 0 {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        while written > 0 {
+            // SAFETY: the stream never writes more than the chunks
+            let chunk = chunks.front_mut().unwrap();
+            if written < chunk.len() {
+                chunk.advance(written);
+                break;
+            }
+            written -= chunk.len();
+            chunks.pop_front();
+        }
+    }
     Ok(())
 }
 
-fn http_response(status: u16, body: Option<String>) -> String {
-    let body_len = match body {
-        None => 0,
-        Some(ref b) => b.len(),
+/// The head of the response followed by the chunks of its body.
+fn http_response(
+    status: u16,
+    body: Option<Vec<Bytes>>,
+    encoding: ContentEncoding,
+) -> Result<Vec<Bytes>> {
+    let body = match body {
+        None => None,
+        Some(b) => Some(encoding.encode(b)?),
     };
-    let response = Response::builder()
+    let body_len = body
+        .as_ref()
+        .map_or(0, |b| b.iter().map(Bytes::len).sum::<usize>());
+    let mut builder = Response::builder()
         .status(status)
         .header("Content-Type", "text/plain; charset=UTF-8")
         .header("Connection", "Close")
-        .header("Content-Length", body_len)
-        .body(body);
+        .header("Content-Length", body_len);
+    if status == 503 {
+        builder = builder.header("Retry-After", RETRY_AFTER);
+    }
+    if let (Some(coding), Some(_)) = (encoding.header(), &body) {
+        builder = builder
+            .header("Content-Encoding", coding)
+            .header("Vary", "Accept-Encoding");
+    }
     // SAFETY: all response fields are valid to build
-    let response = response.unwrap();
+    let (parts, body) = builder.body(body).unwrap().into_parts();
 
-    let mut response_str = format!(
-        "{version:?} {status}\r\n",
-        version = response.version(),
-        status = response.status()
-    );
+    let mut head = format!("{:?} {}\r\n", parts.version, parts.status).into_bytes();
 
-    for (k, v) in response.headers() {
+    for (k, v) in &parts.headers {
         // SAFETY: all header value is valid
         let header = format!("{}: {}\r\n", k, v.to_str().unwrap());
-        response_str.push_str(&header);
+        head.extend_from_slice(header.as_bytes());
+    }
+
+    let mut response = vec![];
+    match body {
+        Some(body) => {
+            head.extend_from_slice(b"\r\n");
+            response.push(Bytes::from(head));
+            response.extend(body);
+        }
+        None => response.push(Bytes::from(head)),
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{
+        server::{ServerBuilder, ServerOption},
+        transports::{Data, Transport},
+    };
+
+    #[tokio::test]
+    async fn test_polling_get_flush() -> Result<()> {
+        let server = ServerBuilder::new(4207)
+            .polling_timeout(Duration::from_millis(50))
+            .build();
+        let sid = server.generate_sid();
+        let transport = Polling::polling_transport(&server, sid.clone()).await;
+
+        // nothing buffered, a NOOP is sent once the polling timeout elapsed
+        let data = Polling::polling_get(&server, &sid).await;
+        assert_eq!(data.map(|d| d.concat()), Some(b"6".to_vec()));
+
+        // everything buffered is flushed in a single response
+        transport
+            .emit(Data::Text(Bytes::from_static(b"4hello")))
+            .await?;
+        transport
+            .emit(Data::Text(Bytes::from_static(b"4world")))
+            .await?;
+        let data = Polling::polling_get(&server, &sid).await;
+        assert_eq!(data.map(|d| d.concat()), Some(b"4hello\x1e4world".to_vec()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_polling_get_encoding() {
+        let addr = "127.0.0.1:4207".parse().unwrap();
+        let request = b"GET /engine.io/?EIO=4&transport=polling&sid=abc HTTP/1.1\r\n\
+            Host: localhost\r\nAccept-Encoding: gzip, deflate\r\n\r\n";
+
+        match parse_request_type(request, &addr, false) {
+            Some(RequestType::PollingGet(sid, encoding)) => {
+                assert_eq!(*sid, "abc");
+                assert_eq!(encoding, ContentEncoding::Gzip);
+            }
+            _ => panic!("expected a polling GET"),
+        }
+    }
+
+    #[test]
+    fn test_http_response_encoding() -> Result<()> {
+        let body = "4hello".repeat(10);
+        let chunks = || Some(vec![Bytes::from(body.clone())]);
+        let response = http_response(200, chunks(), ContentEncoding::Gzip)?.concat();
+        let split = response
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .expect("header end");
+        let head = from_utf8(&response[..split])?.to_lowercase();
+        assert!(head.contains("content-encoding: gzip"));
+        assert!(head.contains("vary: accept-encoding"));
+
+        let compressed = &response[split + 4..];
+        assert!(head.contains(&format!("content-length: {}", compressed.len())));
+        let mut decoded = String::new();
+        std::io::Read::read_to_string(&mut flate2::read::GzDecoder::new(compressed), &mut decoded)?;
+        assert_eq!(decoded, body);
+
+        let plain = http_response(200, chunks(), ContentEncoding::Identity)?;
+        // the body is passed through as is
+        assert_eq!(plain.len(), 2);
+        let plain = plain.concat();
+        assert!(plain.ends_with(body.as_bytes()));
+        assert!(!from_utf8(&plain)?.contains("Content-Encoding"));
+        Ok(())
+    }
+
+    async fn open_polling(prefix: &str, forwarded_for: &str) -> Result<Sid> {
+        let mut stream = TcpStream::connect("127.0.0.1:4219").await?;
+        let request = format!(
+            "{}GET /engine.io/?EIO=4&transport=polling&lang=rust HTTP/1.1\r\n\
+             Host: localhost\r\nX-Forwarded-For: {}\r\n\r\n",
+            prefix, forwarded_for
+        );
+        stream.write_all(request.as_bytes()).await?;
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        let body = &response[response.find("\r\n\r\n").expect("header end") + 5..];
+        let handshake: serde_json::Value = serde_json::from_str(body)?;
+        Ok(Arc::new(
+            handshake["sid"].as_str().unwrap_or_default().to_owned(),
+        ))
+    }
+
+    #[tokio::test]
+    async fn test_remote_addr() -> Result<()> {
+        let server = ServerBuilder::new(4219)
+            .trusted_proxies(["127.0.0.1".parse().unwrap()])
+            .proxy_protocol(true)
+            .build();
+        let server_clone = server.clone();
+        tokio::spawn(async move { server_clone.serve().await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let sid = open_polling(
+            "PROXY TCP4 10.1.1.1 127.0.0.1 5000 4219\r\n",
+            "198.51.100.7",
+        )
+        .await?;
+        // the proxy reported 10.1.1.1, which is not trusted to forward
+        assert_eq!(server.remote_addr(&sid), Some("10.1.1.1".parse().unwrap()));
+
+        let sid = open_polling("PROXY UNKNOWN\r\n", "198.51.100.7").await?;
+        assert_eq!(
+            server.remote_addr(&sid),
+            Some("198.51.100.7".parse().unwrap())
+        );
+
+        let headers = server.handshake_headers(&sid).expect("headers");
+        assert_eq!(
+            headers.get("X-Forwarded-For").and_then(|v| v.to_str()),
+            Some("198.51.100.7")
+        );
+        let query = server.handshake_query(&sid).expect("query");
+        assert_eq!(query.get("lang").map(String::as_str), Some("rust"));
+
+        server.close_socket(&sid).await;
+        assert_eq!(server.remote_addr(&sid), None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_not_accepting() -> Result<()> {
+        let server = ServerBuilder::new(4234).build();
+        let server_clone = server.clone();
+        tokio::spawn(async move { server_clone.serve().await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let open = || async {
+            let mut stream = TcpStream::connect("127.0.0.1:4234").await?;
+            stream
+                .write_all(b"GET /engine.io/?EIO=4&transport=polling HTTP/1.1\r\n\r\n")
+                .await?;
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await?;
+            Ok::<_, Error>(response.to_lowercase())
+        };
+        assert!(open().await?.starts_with("http/1.1 200"));
+
+        server.set_accepting(false);
+        let response = open().await?;
+        assert!(response.starts_with("http/1.1 503"));
+        assert!(response.contains("retry-after: 5"));
+        // the session opened before is kept
+        assert_eq!(server.inner.sockets.len(), 1);
+
+        server.set_accepting(true);
+        assert!(open().await?.starts_with("http/1.1 200"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_polling_overflow_disconnect() -> Result<()> {
+        use crate::{server::PollingOverflow, Event};
+
+        let server = ServerBuilder::new(4265)
+            .polling_buffer(1)
+            .polling_overflow(PollingOverflow::Disconnect)
+            .build();
+        let sid = server.generate_sid();
+        let transport = Polling::polling_transport(&server, sid.clone()).await;
+        server
+            .store_transport(sid.clone(), TransportType::ServerPolling(transport), false)
+            .await?;
+
+        // nobody polls, the buffer overflows at the latest on the second packet
+        let message = || Packet::new(PacketType::Message, Bytes::from_static(b"hello"));
+        let _ = server.emit(&sid, message()).await;
+        let overflowed = server.emit(&sid, message()).await;
+        assert!(matches!(overflowed, Err(Error::PollingBufferFull())));
+        assert_eq!(server.polling_overflows(), 1);
+
+        let socket = server.socket(&sid).await.unwrap();
+        assert!(!socket.is_connected());
+        let event_rx = server.event_rx();
+        let mut event_rx = event_rx.lock().await;
+        loop {
+            match event_rx.recv().await {
+                Some(Event::OnClose(closed)) => break assert_eq!(closed, sid),
+                Some(_) => continue,
+                None => panic!("closed without an OnClose event"),
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "testing")]
+    #[tokio::test]
+    async fn test_chaos_polling() -> Result<()> {
+        use crate::ChaosProfile;
+
+        let store = |server: Server| async move {
+            let sid = server.generate_sid();
+            let transport = Polling::polling_transport(&server, sid.clone()).await;
+            let transport = TransportType::ServerPolling(transport);
+            server
+                .store_transport(sid.clone(), transport, false)
+                .await?;
+            Ok::<_, Error>(sid)
+        };
+        let message = || Packet::new(PacketType::Message, Bytes::from_static(b"hello"));
+
+        // a held back packet is sent even if no other packet follows
+        let server = ServerBuilder::new(4266)
+            .chaos(ChaosProfile {
+                reorder_rate: 1.0,
+                ..Default::default()
+            })
+            .build();
+        let sid = store(server.clone()).await?;
+        server.emit(&sid, message()).await?;
+        assert_eq!(server.queue_stats(&sid).unwrap().depth, 0);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        // the pings are held back and flushed the same way
+        assert!(server.queue_stats(&sid).unwrap().depth >= 1);
+
+        // batches go through the chaos too
+        let server = ServerBuilder::new(4266)
+            .chaos(ChaosProfile {
+                drop_rate: 1.0,
+                ..Default::default()
+            })
+            .build();
+        let sid = store(server.clone()).await?;
+        let socket = server.socket(&sid).await.unwrap();
+        socket.emit_multi(vec![message(), message()]).await?;
+        assert_eq!(server.queue_stats(&sid).unwrap().depth, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unknown_session() -> Result<()> {
+        use crate::transports::polling::ClientPollingTransport;
+
+        let server = ServerBuilder::new(4257).build();
+        let server_clone = server.clone();
+        tokio::spawn(async move { server_clone.serve().await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let transport = |sid: &Sid| {
+            let url = format!("http://localhost:4257/engine.io/?EIO=4&sid={}", sid);
+            let url = Url::parse(&url).unwrap();
+            ClientPollingTransport::new(
+                url,
+                None,
+                &[],
+                &Default::default(),
+                reqwest::ClientBuilder::new(),
+            )
+        };
+
+        // a session closed lately still takes the requests in flight
+        let closed = server.generate_sid();
+        let _transport = Polling::polling_transport(&server, closed.clone()).await;
+        server.close_socket(&closed).await;
+        let sent = transport(&closed)?
+            .emit(Data::Text(Bytes::from_static(b"1")))
+            .await;
+        assert!(sent.is_ok());
+
+        // a session the server never opened, as after a restart of the server
+        let mut transport = transport(&server.generate_sid())?;
+        let sent = transport
+            .emit(Data::Text(Bytes::from_static(b"4hello")))
+            .await;
+        assert!(matches!(sent, Err(Error::InvalidSession())));
+        let polled = transport.next().await;
+        assert!(matches!(polled, Some(Err(Error::InvalidSession()))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_closed_sessions_expire() {
+        // sessions closed 40ms ago expire
+        let server = ServerBuilder::new(4268)
+            .server_option(ServerOption {
+                ping_interval: 10,
+                ping_timeout: 10,
+                ..Default::default()
+            })
+            .build();
+        let first = server.generate_sid();
+        let _transport = Polling::polling_transport(&server, first.clone()).await;
+        server.close_socket(&first).await;
+        assert!(!server.is_unknown_session(&first));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(server.is_unknown_session(&first));
+
+        // dropped once a later close finds them expired
+        let second = server.generate_sid();
+        let _transport = Polling::polling_transport(&server, second.clone()).await;
+        server.close_socket(&second).await;
+        let closed: Vec<_> = server
+            .inner
+            .closed_sessions
+            .iter()
+            .map(|entry| entry.key().clone())
+            .collect();
+        assert_eq!(closed, vec![second]);
+    }
+
+    #[tokio::test]
+    async fn test_transports() -> Result<()> {
+        let request = |port: u16, transport: &'static str| async move {
+            let mut stream = TcpStream::connect(("127.0.0.1", port)).await?;
+            let upgrade = match transport {
+                "websocket" => "Connection: Upgrade\r\nUpgrade: websocket\r\n",
+                _ => "",
+            };
+            let request = format!(
+                "GET /engine.io/?EIO=4&transport={} HTTP/1.1\r\nHost: localhost\r\n{}\r\n",
+                transport, upgrade
+            );
+            stream.write_all(request.as_bytes()).await?;
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await?;
+            Ok::<_, Error>(response.to_lowercase())
+        };
+
+        let polling = ServerBuilder::new(4260)
+            .transports([TransportKind::Polling])
+            .build();
+        let websocket = ServerBuilder::new(4261)
+            .transports([TransportKind::Websocket])
+            .build();
+        for server in [polling, websocket] {
+            tokio::spawn(async move { server.serve().await });
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let response = request(4260, "polling").await?;
+        assert!(response.starts_with("http/1.1 200"));
+        // websocket is not offered as upgrade
+        assert!(response.contains(r#""upgrades":[]"#));
+        let response = request(4260, "websocket").await?;
+        assert!(response.starts_with("http/1.1 400"));
+        assert!(response.contains("transport unknown"));
+
+        let response = request(4261, "polling").await?;
+        assert!(response.starts_with("http/1.1 400"));
+        Ok(())
     }
 
-    if let Some(body) = response.body() {
-        response_str.push_str("\r\n");
-        response_str.push_str(body);
+    #[tokio::test]
+    async fn test_upgrade_flushes_polling() -> Result<()> {
+        let server = ServerBuilder::new(4269).build();
+        let serving = server.clone();
+        tokio::spawn(async move { serving.serve().await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let mut stream = TcpStream::connect(("127.0.0.1", 4269)).await?;
+        stream
+            .write_all(
+                b"GET /engine.io/?EIO=4&transport=polling HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            )
+            .await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        let open = &response[response.find("0{").unwrap() + 1..];
+        let open: serde_json::Value = serde_json::from_str(open)?;
+        let sid = open["sid"].as_str().unwrap().to_owned();
+
+        let url = format!(
+            "ws://localhost:4269/engine.io/?EIO=4&transport=websocket&sid={}",
+            sid
+        );
+        let (mut ws, _) = tokio_tungstenite::connect_async(url).await?;
+        ws.send(Message::text("2probe")).await?;
+        assert_eq!(ws.next().await.unwrap()?, Message::text("3probe"));
+
+        // emitted before the upgrade completes, buffered for polling
+        let sid: Sid = sid.into();
+        for message in ["a", "b"] {
+            server
+                .emit(&sid, Packet::new(PacketType::Message, Bytes::from(message)))
+                .await?;
+        }
+        ws.send(Message::text("5")).await?;
+
+        let mut received = Vec::new();
+        while received.len() < 2 {
+            let message = tokio::time::timeout(Duration::from_secs(1), ws.next())
+                .await
+                .unwrap()
+                .unwrap()?;
+            if let Message::Text(text) = message {
+                // without the trailer of the `checksum` feature
+                if text.starts_with('4') {
+                    received.push(text.split('\x1f').next().unwrap().to_owned());
+                }
+            }
+        }
+        assert_eq!(received, vec!["4a", "4b"]);
+        assert_eq!(server.upgrade_stats().succeeded, 1);
+        Ok(())
     }
 
-    response_str
+    #[test]
+    #[should_panic(expected = "no transport allowed")]
+    fn test_no_transports() {
+        ServerBuilder::new(4270).transports([]);
+    }
 }
@@ -0,0 +1,109 @@
+use std::{
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// A connection accepted outside of the server, e.g. by a custom TLS
+/// terminator, a QUIC bridge or a test harness, see `Server::serve_incoming`.
+pub struct Incoming<S> {
+    pub(crate) stream: S,
+    pub(crate) peer_addr: SocketAddr,
+    pub(crate) head: Bytes,
+}
+
+impl<S> Incoming<S> {
+    /// `peer_addr` is the client address the server reports, streams without
+    /// one can pass any placeholder such as `127.0.0.1:0`.
+    pub fn new(stream: S, peer_addr: SocketAddr) -> Self {
+        Self {
+            stream,
+            peer_addr,
+            head: Bytes::new(),
+        }
+    }
+
+    /// Bytes the acceptor already read from the stream, e.g. the request head
+    /// it routed on. They are handled as if they were read from the stream.
+    pub fn head(mut self, head: Bytes) -> Self {
+        self.head = head;
+        self
+    }
+}
+
+/// A stream replaying `prefix` before reading from `inner`, so a request read
+/// up front can still be read by the handlers.
+pub(crate) struct Rewind<S> {
+    prefix: Bytes,
+    inner: S,
+}
+
+impl<S> Rewind<S> {
+    pub(crate) fn new(prefix: Bytes, inner: S) -> Self {
+        Self { prefix, inner }
+    }
+
+    /// Replays `data` before the rest of the prefix.
+    pub(crate) fn unread(&mut self, data: &[u8]) {
+        let mut prefix = BytesMut::with_capacity(data.len() + self.prefix.len());
+        prefix.put_slice(data);
+        prefix.put_slice(&self.prefix);
+        self.prefix = prefix.freeze();
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Rewind<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if !self.prefix.is_empty() {
+            let n = self.prefix.len().min(buf.remaining());
+            let data = self.prefix.split_to(n);
+            buf.put_slice(&data);
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Rewind<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_rewind() -> io::Result<()> {
+        let mut stream = Rewind::new(Bytes::from_static(b"lo "), &b"world"[..]);
+        stream.unread(b"hel");
+
+        let mut read = String::new();
+        stream.read_to_string(&mut read).await?;
+        assert_eq!(read, "hello world");
+        Ok(())
+    }
+}
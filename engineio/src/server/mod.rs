@@ -1,7 +1,16 @@
 mod builder;
+mod encoding;
 mod http;
+mod incoming;
+mod proxy;
+mod queue;
 #[allow(clippy::module_inception)]
 mod server;
 
 pub use builder::ServerBuilder;
-pub use server::{Server, ServerOption};
+pub use incoming::Incoming;
+pub(crate) use queue::EmitQueue;
+pub use queue::{QueueStats, SlowConsumer};
+pub use server::{
+    IdGenerator, PollingOverflow, Server, ServerOption, SidGenerator, TransportKind, UpgradeStats,
+};
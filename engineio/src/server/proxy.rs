@@ -0,0 +1,132 @@
+use std::net::IpAddr;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::{error::Result, Error};
+
+/// Longest PROXY protocol v1 header, including the trailing CRLF.
+const MAX_PROXY_HEADER: usize = 107;
+
+/// Peers allowed to tell the server the address of the client behind them,
+/// through `X-Forwarded-For` or a PROXY protocol header.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct TrustedProxies {
+    pub(crate) addrs: Vec<IpAddr>,
+    pub(crate) proxy_protocol: bool,
+}
+
+impl TrustedProxies {
+    fn is_trusted(&self, ip: &IpAddr) -> bool {
+        self.addrs.contains(ip)
+    }
+
+    /// Whether a PROXY protocol header is expected from `peer`.
+    pub(crate) fn expects_proxy_header(&self, peer: &IpAddr) -> bool {
+        self.proxy_protocol && self.is_trusted(peer)
+    }
+
+    /// The client address of a request from `peer`. Walks `X-Forwarded-For` from
+    /// the right while the hops are trusted proxies, so entries prepended by the
+    /// client itself are never believed.
+    pub(crate) fn client_ip(&self, peer: IpAddr, forwarded_for: Option<&str>) -> IpAddr {
+        let mut client = peer;
+        let Some(forwarded_for) = forwarded_for else {
+            return client;
+        };
+
+        for hop in forwarded_for.rsplit(',') {
+            if !self.is_trusted(&client) {
+                break;
+            }
+            match hop.trim().parse() {
+                Ok(ip) => client = ip,
+                Err(_) => break,
+            }
+        }
+        client
+    }
+
+    /// Reads the PROXY protocol v1 header a trusted proxy sends ahead of the
+    /// request, returning the source address it reports.
+    pub(crate) async fn read_proxy_header<S: AsyncRead + Unpin>(
+        stream: &mut S,
+        peer: IpAddr,
+    ) -> Result<IpAddr> {
+        let mut line = Vec::with_capacity(MAX_PROXY_HEADER);
+        while !line.ends_with(b"\r\n") {
+            if line.len() == MAX_PROXY_HEADER {
+                return Err(Error::InvalidProxyHeader());
+            }
+            line.push(stream.read_u8().await?);
+        }
+
+        let line = std::str::from_utf8(&line)?;
+        parse_proxy_header(line, peer).ok_or(Error::InvalidProxyHeader())
+    }
+}
+
+/// Parses e.g. `PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n`. Connections
+/// the proxy could not describe are reported as `UNKNOWN` and keep `peer`.
+fn parse_proxy_header(line: &str, peer: IpAddr) -> Option<IpAddr> {
+    let mut parts = line.trim_end().split(' ');
+    if parts.next()? != "PROXY" {
+        return None;
+    }
+    match parts.next()? {
+        "UNKNOWN" => Some(peer),
+        "TCP4" | "TCP6" => parts.next()?.parse().ok(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let proxies = TrustedProxies {
+            addrs: vec![ip("10.0.0.1"), ip("10.0.0.2")],
+            proxy_protocol: false,
+        };
+
+        // untrusted peers can not spoof their address
+        assert_eq!(
+            proxies.client_ip(ip("203.0.113.9"), Some("198.51.100.7")),
+            ip("203.0.113.9")
+        );
+        assert_eq!(proxies.client_ip(ip("10.0.0.1"), None), ip("10.0.0.1"));
+        assert_eq!(
+            proxies.client_ip(ip("10.0.0.1"), Some("198.51.100.7")),
+            ip("198.51.100.7")
+        );
+        // a chain of trusted proxies, the client prepended a fake hop
+        assert_eq!(
+            proxies.client_ip(ip("10.0.0.1"), Some("1.1.1.1, 198.51.100.7, 10.0.0.2")),
+            ip("198.51.100.7")
+        );
+        assert_eq!(
+            proxies.client_ip(ip("10.0.0.1"), Some("garbage")),
+            ip("10.0.0.1")
+        );
+    }
+
+    #[test]
+    fn test_parse_proxy_header() {
+        let peer = ip("10.0.0.1");
+        assert_eq!(
+            parse_proxy_header("PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n", peer),
+            Some(ip("192.0.2.1"))
+        );
+        assert_eq!(
+            parse_proxy_header("PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n", peer),
+            Some(ip("2001:db8::1"))
+        );
+        assert_eq!(parse_proxy_header("PROXY UNKNOWN\r\n", peer), Some(peer));
+        assert_eq!(parse_proxy_header("GET / HTTP/1.1\r\n", peer), None);
+    }
+}
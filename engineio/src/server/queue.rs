@@ -0,0 +1,171 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+use crate::clock::{Clock, TokioClock};
+
+/// Packets buffered for a polling socket which its client did not fetch yet.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueueStats {
+    pub depth: usize,
+    /// The size of the buffered packets as sent.
+    pub bytes: usize,
+    /// How long the oldest buffered packet has been waiting.
+    pub oldest: Option<Duration>,
+}
+
+/// Thresholds above which a socket is reported with `Event::OnSlowConsumer`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlowConsumer {
+    pub max_depth: usize,
+    pub max_age: Duration,
+}
+
+impl SlowConsumer {
+    fn exceeded_by(&self, stats: &QueueStats) -> bool {
+        stats.depth > self.max_depth || stats.oldest.is_some_and(|age| age > self.max_age)
+    }
+}
+
+/// Tracks when the buffered packets of a polling socket were queued, and their
+/// sizes.
+#[derive(Debug)]
+pub(crate) struct EmitQueue {
+    enqueued: Mutex<VecDeque<(Instant, usize)>>,
+    limits: Option<SlowConsumer>,
+    slow: AtomicBool,
+    clock: Arc<dyn Clock>,
+}
+
+impl Default for EmitQueue {
+    fn default() -> Self {
+        Self {
+            enqueued: Default::default(),
+            limits: None,
+            slow: Default::default(),
+            clock: Arc::new(TokioClock),
+        }
+    }
+}
+
+impl EmitQueue {
+    pub(crate) fn new(limits: Option<SlowConsumer>) -> Self {
+        Self {
+            limits,
+            ..Default::default()
+        }
+    }
+
+    /// Measures the age of the packets with `clock`.
+    pub(crate) fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub(crate) fn pushed(&self, bytes: usize) {
+        self.lock().push_back((self.clock.now(), bytes));
+    }
+
+    /// Forgets the packet last pushed, which did not make it into the buffer.
+    pub(crate) fn unpushed(&self) {
+        self.lock().pop_back();
+    }
+
+    pub(crate) fn popped(&self) {
+        self.lock().pop_front();
+    }
+
+    pub(crate) fn stats(&self) -> QueueStats {
+        let now = self.clock.now();
+        let enqueued = self.lock();
+        QueueStats {
+            depth: enqueued.len(),
+            bytes: enqueued.iter().map(|(_, bytes)| bytes).sum(),
+            oldest: enqueued
+                .front()
+                .map(|(at, _)| now.saturating_duration_since(*at)),
+        }
+    }
+
+    /// Returns the stats once the limits are exceeded, and not again until the
+    /// queue recovered below them.
+    pub(crate) fn check_slow(&self) -> Option<QueueStats> {
+        let limits = self.limits?;
+        let stats = self.stats();
+        if !limits.exceeded_by(&stats) {
+            self.slow.store(false, Ordering::Relaxed);
+            return None;
+        }
+        match self.slow.swap(true, Ordering::Relaxed) {
+            true => None,
+            false => Some(stats),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, VecDeque<(Instant, usize)>> {
+        // the queue only holds numbers, a poisoned one is still consistent
+        self.enqueued.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_emit_queue() {
+        let queue = EmitQueue::new(Some(SlowConsumer {
+            max_depth: 2,
+            max_age: Duration::from_secs(10),
+        }));
+        assert_eq!(queue.stats(), QueueStats::default());
+
+        queue.pushed(5);
+        tokio::time::advance(Duration::from_secs(3)).await;
+        queue.pushed(7);
+        let stats = queue.stats();
+        assert_eq!(stats.depth, 2);
+        assert_eq!(stats.bytes, 12);
+        assert_eq!(stats.oldest, Some(Duration::from_secs(3)));
+        assert_eq!(queue.check_slow(), None);
+
+        queue.pushed(1);
+        assert_eq!(queue.check_slow().map(|s| s.depth), Some(3));
+        // reported once per episode
+        assert_eq!(queue.check_slow(), None);
+
+        queue.popped();
+        queue.popped();
+        assert_eq!(queue.check_slow(), None);
+        tokio::time::advance(Duration::from_secs(11)).await;
+        assert!(queue.check_slow().is_some());
+
+        queue.unpushed();
+        assert_eq!(queue.stats(), QueueStats::default());
+    }
+
+    #[derive(Debug)]
+    struct ManualClock(Mutex<Instant>);
+
+    impl Clock for ManualClock {
+        fn now(&self) -> Instant {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    #[test]
+    fn test_emit_queue_clock() {
+        let clock = Arc::new(ManualClock(Mutex::new(Instant::now())));
+        let queue = EmitQueue::new(None).with_clock(clock.clone());
+        queue.pushed(5);
+        *clock.0.lock().unwrap() += Duration::from_secs(42);
+        assert_eq!(queue.stats().oldest, Some(Duration::from_secs(42)));
+    }
+}
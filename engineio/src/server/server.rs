@@ -1,29 +1,57 @@
 use std::{
-    sync::{atomic::AtomicUsize, Arc},
+    collections::{HashMap, HashSet, VecDeque},
+    future::Future,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
     time::Duration,
 };
 
 use bytes::Bytes;
 use dashmap::DashMap;
+use futures_util::{Stream, StreamExt};
+use serde_json::{Map, Value};
 use tokio::{
+    io::{AsyncRead, AsyncWrite},
     net::TcpListener,
+    runtime::Handle,
     sync::{
         mpsc::{Receiver, Sender},
         Mutex,
     },
+    task::JoinHandle,
     time::{interval, Instant},
 };
 use tracing::{trace, warn};
 
 use crate::{
+    capture::Recorder,
+    channel,
+    clock::Clock,
     error::Result,
+    header::HeaderMap,
     packet::HandshakePacket,
-    server::http::{handle_http, PollingHandle},
+    server::{
+        http::{handle_http, handle_incoming, PollingHandle},
+        proxy::TrustedProxies,
+        EmitQueue, Incoming, QueueStats, SlowConsumer,
+    },
     socket::Socket,
-    transports::TransportType,
+    transports::{Data, TransportType},
+    wire::WireLog,
     Event, Packet, PacketType, Sid,
 };
 
+/// The client of a session, as seen in its handshake request.
+#[derive(Debug, Clone)]
+pub(crate) struct Peer {
+    pub(crate) ip: IpAddr,
+    pub(crate) headers: HeaderMap,
+    pub(crate) query: HashMap<String, String>,
+}
+
 #[derive(Clone)]
 pub struct Server {
     pub(super) inner: Arc<ServerInner>,
@@ -32,12 +60,68 @@ pub struct Server {
 pub(super) struct ServerInner {
     pub(super) port: u16,
     pub(super) server_option: ServerOption,
-    pub(super) id_generator: SidGenerator,
+    pub(super) id_generator: Arc<dyn IdGenerator>,
+    pub(super) clock: Arc<dyn Clock>,
     pub(super) polling_handles: Arc<DashMap<Sid, PollingHandle>>,
+    // when polling sessions were closed, their late requests aren't answered
+    // as unknown sessions until they expire
+    pub(super) closed_sessions: DashMap<Sid, Instant>,
+    // the closed sessions oldest first, expired ones are dropped from the front
+    pub(super) closed_order: std::sync::Mutex<VecDeque<(Sid, Instant)>>,
     pub(super) polling_buffer: usize,
+    pub(super) polling_timeout: Duration,
+    pub(super) polling_overflow: PollingOverflow,
+    pub(super) polling_overflow_count: Arc<AtomicUsize>,
+    pub(super) polling_compression: Option<usize>,
+    pub(super) slow_consumer: Option<SlowConsumer>,
+    pub(super) emit_queues: DashMap<Sid, Arc<EmitQueue>>,
+    pub(super) trusted_proxies: TrustedProxies,
+    pub(super) peers: DashMap<Sid, Peer>,
     pub(super) event_tx: Arc<Sender<Event>>,
     pub(super) event_rx: Arc<Mutex<Receiver<Event>>>,
     pub(super) sockets: Arc<DashMap<Sid, Socket>>,
+    pub(super) handshake_extra: Map<String, Value>,
+    pub(super) transports: HashSet<TransportKind>,
+    pub(super) upgrades: AtomicUsize,
+    pub(super) upgrade_failures: AtomicUsize,
+    // whether new sessions are opened, see `Server::set_accepting`
+    pub(super) accepting: AtomicBool,
+    pub(super) recorder: Option<Arc<Recorder>>,
+    pub(super) wire_log: Option<Arc<WireLog>>,
+    pub(super) runtime: Option<Handle>,
+    #[cfg(feature = "testing")]
+    pub(super) chaos: Option<crate::ChaosProfile>,
+}
+
+/// What a polling transport does when the client does not drain its buffer
+/// fast enough and the channel is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PollingOverflow {
+    /// Waits until the client fetched buffered packets.
+    #[default]
+    Wait,
+    /// Drops the packet silently.
+    Drop,
+    /// Returns `Error::PollingBufferFull` to the emitter.
+    Error,
+    /// Returns `Error::PollingBufferFull` and closes the socket.
+    Disconnect,
+}
+
+/// A transport clients may connect with, see `ServerBuilder::transports`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TransportKind {
+    /// HTTP long-polling, which clients may upgrade to websocket.
+    Polling,
+    /// Websocket, connected directly or upgraded from polling.
+    Websocket,
+}
+
+/// Counters of websocket upgrade attempts from polling.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UpgradeStats {
+    pub succeeded: usize,
+    pub failed: usize,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -47,8 +131,15 @@ pub struct ServerOption {
     pub max_payload: usize,
 }
 
-#[derive(Default)]
-pub(super) struct SidGenerator {
+/// Generates the sid of every new session, e.g. from a seeded generator so a
+/// simulation test gets the same sids on every run.
+pub trait IdGenerator: Send + Sync {
+    fn generate(&self) -> Sid;
+}
+
+/// Numbers the sessions in the order they are opened, the default generator.
+#[derive(Debug, Default)]
+pub struct SidGenerator {
     seq: AtomicUsize,
 }
 
@@ -61,7 +152,21 @@ impl Server {
 
         while let Ok((stream, peer_addr)) = listener.accept().await {
             let server = self.clone();
-            tokio::spawn(async move { handle_http(server, stream, peer_addr).await });
+            self.spawn(async move { handle_http(server, stream, peer_addr).await });
+        }
+    }
+
+    /// Serves the connections of `incoming` instead of listening on the port,
+    /// e.g. streams from a custom TLS terminator, a QUIC bridge or a test
+    /// harness. Returns once `incoming` ends.
+    pub async fn serve_incoming<I, S>(&self, mut incoming: I)
+    where
+        I: Stream<Item = Incoming<S>> + Unpin,
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        while let Some(connection) = incoming.next().await {
+            let server = self.clone();
+            self.spawn(async move { handle_incoming(server, connection).await });
         }
     }
 
@@ -75,6 +180,29 @@ impl Server {
         Ok(())
     }
 
+    /// The runtime set with `ServerBuilder::runtime`.
+    pub fn runtime(&self) -> Option<&Handle> {
+        self.inner.runtime.as_ref()
+    }
+
+    /// The clock set with `ServerBuilder::clock`.
+    pub fn clock(&self) -> Arc<dyn Clock> {
+        self.inner.clock.clone()
+    }
+
+    /// Spawns `future` on the runtime the server was built with, or on the
+    /// current one.
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        match &self.inner.runtime {
+            Some(handle) => handle.spawn(future),
+            None => tokio::spawn(future),
+        }
+    }
+
     pub fn event_rx(&self) -> Arc<Mutex<Receiver<Event>>> {
         self.inner.event_rx.clone()
     }
@@ -89,6 +217,113 @@ impl Server {
         if let Some((_, socket)) = sockets.remove(sid) {
             let _ = socket.disconnect().await;
         }
+        self.inner.peers.remove(sid);
+        self.inner.emit_queues.remove(sid);
+        self.remove_polling(sid);
+    }
+
+    /// Stops serving `sid` over polling, e.g. once it upgraded to websocket.
+    pub(crate) fn remove_polling(&self, sid: &Sid) {
+        if self.inner.polling_handles.remove(sid).is_some() {
+            let expiry = self.session_expiry();
+            let now = self.inner.clock.now();
+            // the order only holds sids, a poisoned one is still consistent
+            let mut order = self
+                .inner
+                .closed_order
+                .lock()
+                .unwrap_or_else(|e| e.into_inner());
+            while let Some((expired, _)) = order
+                .front()
+                .filter(|(_, at)| now.duration_since(*at) >= expiry)
+            {
+                self.inner.closed_sessions.remove(expired);
+                order.pop_front();
+            }
+            self.inner.closed_sessions.insert(sid.to_owned(), now);
+            order.push_back((sid.to_owned(), now));
+        }
+    }
+
+    /// Stops serving `sid` over polling once it upgraded, returning the packets
+    /// still buffered for it except NOOPs.
+    async fn take_polling(&self, sid: &Sid) -> Vec<Data> {
+        let handle = self.polling_handle(sid).await;
+        self.remove_polling(sid);
+        let mut buffered = Vec::new();
+        if let Some((_, receiver)) = handle {
+            let mut receiver = receiver.lock().await;
+            while let Ok(bytes) = receiver.try_recv() {
+                if bytes == Bytes::from(Packet::noop()) {
+                    continue;
+                }
+                match Data::from_polling(bytes) {
+                    Ok(data) => buffered.push(data),
+                    Err(e) => warn!("buffered packet of {} not decoded: {}", sid, e),
+                }
+            }
+        }
+        buffered
+    }
+
+    /// Whether `sid` is no polling session, neither open nor closed lately, e.g.
+    /// a session of the server before it restarted.
+    pub(crate) fn is_unknown_session(&self, sid: &Sid) -> bool {
+        if self.inner.polling_handles.contains_key(sid) {
+            return false;
+        }
+        let expiry = self.session_expiry();
+        let now = self.inner.clock.now();
+        !matches!(self.inner.closed_sessions.get(sid), Some(at) if now.duration_since(*at) < expiry)
+    }
+
+    // how long a client may keep using a closed session: it gives up once a
+    // ping interval and timeout passed without a ping, counted from a last ping
+    // which may be as old again when the server closed the session
+    fn session_expiry(&self) -> Duration {
+        let option = &self.inner.server_option;
+        Duration::from_millis(2 * (option.ping_interval + option.ping_timeout))
+    }
+
+    /// The packets buffered for the polling socket `sid`, `None` for unknown
+    /// and websocket sockets.
+    pub fn queue_stats(&self, sid: &Sid) -> Option<QueueStats> {
+        self.inner.emit_queues.get(sid).map(|queue| queue.stats())
+    }
+
+    pub(crate) fn new_emit_queue(&self, sid: Sid) -> Arc<EmitQueue> {
+        let queue = EmitQueue::new(self.inner.slow_consumer).with_clock(self.inner.clock.clone());
+        let queue = Arc::new(queue);
+        self.inner.emit_queues.insert(sid, queue.clone());
+        queue
+    }
+
+    pub(crate) fn emit_queue(&self, sid: &Sid) -> Option<Arc<EmitQueue>> {
+        self.inner.emit_queues.get(sid).map(|queue| queue.clone())
+    }
+
+    /// The address of the client behind `sid`. This is the peer of the handshake
+    /// request, or the client reported by a trusted proxy.
+    pub fn remote_addr(&self, sid: &Sid) -> Option<IpAddr> {
+        self.inner.peers.get(sid).map(|peer| peer.ip)
+    }
+
+    /// The headers of the request which opened the session `sid`.
+    pub fn handshake_headers(&self, sid: &Sid) -> Option<HeaderMap> {
+        self.inner.peers.get(sid).map(|peer| peer.headers.clone())
+    }
+
+    /// The query of the request which opened the session `sid`.
+    pub fn handshake_query(&self, sid: &Sid) -> Option<HashMap<String, String>> {
+        self.inner.peers.get(sid).map(|peer| peer.query.clone())
+    }
+
+    pub(crate) fn set_peer(&self, sid: Sid, peer: Peer) {
+        self.inner.peers.insert(sid, peer);
+    }
+
+    pub(crate) fn trusted_proxies(&self) -> &TrustedProxies {
+        &self.inner.trusted_proxies
     }
 
     pub(crate) fn polling_handles(&self) -> Arc<DashMap<Sid, PollingHandle>> {
@@ -111,10 +346,66 @@ impl Server {
         self.inner.polling_buffer
     }
 
+    /// Stops or resumes opening new sessions, e.g. to drain the server behind a
+    /// load balancer. Handshakes are refused with `503 Service Unavailable` and
+    /// a `Retry-After` header while existing sessions are served as usual.
+    pub fn set_accepting(&self, accepting: bool) {
+        self.inner.accepting.store(accepting, Ordering::Release);
+    }
+
+    /// Whether new sessions are opened, see `set_accepting`.
+    pub fn is_accepting(&self) -> bool {
+        self.inner.accepting.load(Ordering::Acquire)
+    }
+
+    /// Returns how many polling sockets were upgraded to websocket, or failed to.
+    pub fn upgrade_stats(&self) -> UpgradeStats {
+        UpgradeStats {
+            succeeded: self.inner.upgrades.load(Ordering::Relaxed),
+            failed: self.inner.upgrade_failures.load(Ordering::Relaxed),
+        }
+    }
+
+    pub(crate) async fn upgrade_failed(&self, sid: &Sid, reason: String) {
+        trace!("upgrade failed {} {}", sid, reason);
+        self.inner.upgrade_failures.fetch_add(1, Ordering::Relaxed);
+        channel::observe("event", &self.inner.event_tx);
+        let _ = self
+            .inner
+            .event_tx
+            .send(Event::OnUpgradeFailed(sid.clone(), reason))
+            .await;
+    }
+
+    /// Number of packets which hit a full polling buffer since the server started.
+    pub fn polling_overflows(&self) -> usize {
+        self.inner.polling_overflow_count.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn polling_overflow(&self) -> (PollingOverflow, Arc<AtomicUsize>) {
+        (
+            self.inner.polling_overflow,
+            self.inner.polling_overflow_count.clone(),
+        )
+    }
+
+    pub(crate) fn polling_timeout(&self) -> Duration {
+        self.inner.polling_timeout
+    }
+
+    pub(crate) fn polling_compression(&self) -> Option<usize> {
+        self.inner.polling_compression
+    }
+
     pub(crate) fn generate_sid(&self) -> Sid {
         self.inner.id_generator.generate()
     }
 
+    /// Whether clients may connect with `transport`.
+    pub(crate) fn allows(&self, transport: TransportKind) -> bool {
+        self.inner.transports.contains(&transport)
+    }
+
     pub(crate) fn handshake_packet(
         &self,
         upgrades: Vec<String>,
@@ -131,6 +422,7 @@ impl Server {
             ping_interval: self.inner.server_option.ping_interval,
             ping_timeout: self.inner.server_option.ping_timeout,
             max_payload: self.inner.server_option.max_payload,
+            extra: self.inner.handshake_extra.clone(),
         }
     }
 
@@ -145,7 +437,13 @@ impl Server {
         if is_upgrade {
             let sockets = &self.inner.sockets;
             match sockets.get_mut(&sid) {
-                Some(socket) => socket.upgrade(transport).await,
+                Some(socket) => {
+                    socket.upgrade(transport, self.take_polling(&sid)).await;
+                    self.inner.emit_queues.remove(&sid);
+                    self.inner.upgrades.fetch_add(1, Ordering::Relaxed);
+                    channel::observe("event", &self.inner.event_tx);
+                    let _ = self.inner.event_tx.send(Event::OnUpgrade(sid)).await;
+                }
                 None => warn!("upgrade polling not exist {:?}", sid),
             };
         } else {
@@ -155,7 +453,12 @@ impl Server {
                 Some(self.inner.event_tx.clone()),
                 false, // server no need to pong
                 true,
-            );
+            )
+            .with_recorder(self.inner.recorder.clone())
+            .with_wire_log(self.inner.wire_log.clone())
+            .with_clock(self.inner.clock.clone());
+            #[cfg(feature = "testing")]
+            let socket = socket.with_chaos(self.inner.chaos.clone());
 
             socket.connect().await?;
 
@@ -176,7 +479,7 @@ impl Server {
         trace!("start_ping_pong {} interval {:?}", sid, duration);
         let mut interval = interval(duration);
 
-        tokio::spawn(async move {
+        self.spawn(async move {
             loop {
                 interval.tick().await;
                 let ping_packet = Packet {
@@ -188,9 +491,15 @@ impl Server {
                     break;
                 };
                 let last_pong = server.last_pong(&sid).await;
+                let now = server.inner.clock.now();
                 match last_pong {
-                    Some(instant) if instant.elapsed() < timeout => {}
-                    _ => break,
+                    Some(instant) if now.saturating_duration_since(instant) < timeout => {}
+                    _ => {
+                        // tells why the socket closes, ahead of its `OnClose`
+                        let timed_out = Event::OnPingTimeout(sid.clone());
+                        let _ = server.inner.event_tx.send(timed_out).await;
+                        break;
+                    }
                 }
             }
             trace!("pong_timeout close {}", sid);
@@ -218,7 +527,7 @@ impl Default for ServerOption {
     }
 }
 
-impl SidGenerator {
+impl IdGenerator for SidGenerator {
     fn generate(&self) -> Sid {
         let seq = self.seq.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
         Arc::new(base64::encode(seq.to_string()))
@@ -233,6 +542,7 @@ mod test {
 
     use futures_util::{Stream, StreamExt};
     use reqwest::Url;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
     use crate::{server::builder::ServerBuilder, socket::SocketBuilder, Packet};
 
@@ -242,22 +552,42 @@ mod test {
         //     .with_env_filter("engineio=trace")
         //     .init();
         let url = crate::test::rust_engine_io_server();
-        let (mut rx, _server) = start_server(url.clone()).await;
+        let (mut rx, server) = start_server(url.clone()).await;
 
         let socket = SocketBuilder::new(url.clone()).build_polling().await?;
+        assert_eq!(
+            socket.handshake_extra().get("server"),
+            Some(&serde_json::Value::from("engineio-rs"))
+        );
         test_data_transport(socket, &mut rx).await?;
 
         let socket = SocketBuilder::new(url.clone()).build().await?;
         test_data_transport(socket, &mut rx).await?;
 
+        // handshakes over http, as copied from JS examples
+        let mut ws_url = url.clone();
+        ws_url.set_scheme("ws").unwrap();
+        let socket = SocketBuilder::new(ws_url).build_polling().await?;
+        test_data_transport(socket, &mut rx).await?;
+
         let socket = SocketBuilder::new(url.clone()).build_websocket().await?;
         test_data_transport(socket, &mut rx).await?;
 
+        let stream = tokio::net::TcpStream::connect(("127.0.0.1", url.port().unwrap())).await?;
+        let socket = SocketBuilder::new(url.clone())
+            .build_websocket_over(stream)
+            .await?;
+        test_data_transport(socket, &mut rx).await?;
+
         let socket = SocketBuilder::new(url)
             .build_websocket_with_upgrade()
             .await?;
         test_data_transport(socket, &mut rx).await?;
 
+        let stats = server.upgrade_stats();
+        assert_eq!(stats.succeeded, 2);
+        assert_eq!(stats.failed, 0);
+
         Ok(())
     }
 
@@ -294,6 +624,43 @@ mod test {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_serve_incoming() -> Result<()> {
+        let server_option = ServerOption {
+            ping_timeout: 20,
+            ping_interval: 20,
+            max_payload: 102400,
+        };
+        let (server, mut rx) = setup(4238, server_option);
+        let peer_addr = "127.0.0.1:0".parse().unwrap();
+        let (websocket, websocket_server) = tokio::io::duplex(4096);
+        let (mut polling, polling_server) = tokio::io::duplex(4096);
+        let incoming = futures_util::stream::iter(vec![
+            Incoming::new(websocket_server, peer_addr),
+            // the acceptor already read the start of the request
+            Incoming::new(polling_server, peer_addr)
+                .head(Bytes::from_static(b"GET /engine.io/?EIO=4&tra")),
+        ]);
+        let server_clone = server.clone();
+        tokio::spawn(async move { server_clone.serve_incoming(incoming).await });
+
+        let url = Url::parse("http://localhost:4238/engine.io/").unwrap();
+        let socket = SocketBuilder::new(url)
+            .build_websocket_over(websocket)
+            .await?;
+        test_data_transport(socket, &mut rx).await?;
+
+        polling
+            .write_all(b"nsport=polling HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await?;
+        let mut response = String::new();
+        polling.read_to_string(&mut response).await?;
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert!(response.contains("\"sid\""));
+
+        Ok(())
+    }
+
     async fn test_transport_timeout(mut client: Socket) -> Result<()> {
         client.connect().await?;
 
@@ -341,6 +708,7 @@ mod test {
             .polling_buffer(100)
             .event_size(100)
             .server_option(server_option)
+            .handshake_extra("server", "engineio-rs")
             .build();
 
         let event_rx = server.event_rx();
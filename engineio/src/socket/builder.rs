@@ -1,15 +1,28 @@
+use std::sync::Arc;
+
 use futures_util::StreamExt;
 use reqwest::Url;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_tungstenite::Connector;
 use tracing::trace;
 
+#[cfg(feature = "testing")]
+use crate::ChaosProfile;
+#[cfg(feature = "tls")]
+use crate::TlsConfig;
 use crate::{
+    capture::Recorder,
     error::Result,
     header::HeaderMap,
     packet::HandshakePacket,
     socket::Socket,
     transports::{
-        polling::ClientPollingTransport, websocket::WebsocketTransport, Transport, TransportType,
+        connector::{self, AddressFamily},
+        polling::{ClientPollingTransport, PollingOption},
+        websocket::WebsocketTransport,
+        Transport, TransportType,
     },
+    wire::WireLog,
     Error, Packet, ENGINE_IO_VERSION,
 };
 
@@ -18,13 +31,35 @@ pub struct SocketBuilder {
     url: Url,
     should_pong: bool,
     headers: Option<HeaderMap>,
+    websocket_headers: Option<HeaderMap>,
+    websocket_protocols: Vec<String>,
     handshake: Option<HandshakePacket>,
     channel_size: usize,
+    recorder: Option<Arc<Recorder>>,
+    wire_log: Option<Arc<WireLog>>,
+    address_family: AddressFamily,
+    polling_option: PollingOption,
+    #[cfg(feature = "tls")]
+    tls_config: Option<TlsConfig>,
+    #[cfg(feature = "testing")]
+    chaos: Option<ChaosProfile>,
 }
 
 impl SocketBuilder {
+    /// `ws` and `wss` URLs are handshaked over `http` and `https`, the transport
+    /// decides on the scheme of each request.
     pub fn new(url: Url) -> Self {
         let mut url = url;
+        // the schemes are all special, so setting them can't fail
+        match url.scheme() {
+            "ws" => {
+                let _ = url.set_scheme("http");
+            }
+            "wss" => {
+                let _ = url.set_scheme("https");
+            }
+            _ => {}
+        }
         url.query_pairs_mut()
             .append_pair("EIO", &ENGINE_IO_VERSION.to_string());
 
@@ -35,9 +70,19 @@ impl SocketBuilder {
         SocketBuilder {
             url,
             headers: None,
+            websocket_headers: None,
+            websocket_protocols: Vec::new(),
             should_pong: true,
             handshake: None,
             channel_size: 100,
+            recorder: None,
+            wire_log: None,
+            address_family: AddressFamily::default(),
+            polling_option: PollingOption::default(),
+            #[cfg(feature = "tls")]
+            tls_config: None,
+            #[cfg(feature = "testing")]
+            chaos: None,
         }
     }
 
@@ -46,11 +91,90 @@ impl SocketBuilder {
         self
     }
 
+    /// Headers sent only with the websocket handshake, in addition to `headers`,
+    /// e.g. for gateways which route or authenticate websocket upgrades.
+    pub fn websocket_headers(mut self, headers: HeaderMap) -> Self {
+        self.websocket_headers = Some(headers);
+        self
+    }
+
+    /// Subprotocols offered in the `Sec-WebSocket-Protocol` header of the
+    /// websocket handshake.
+    pub fn websocket_protocols<I, T>(mut self, protocols: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<String>,
+    {
+        self.websocket_protocols = protocols.into_iter().map(Into::into).collect();
+        self
+    }
+
     pub fn channel_buf(mut self, size: usize) -> Self {
         self.channel_size = size;
         self
     }
 
+    /// Records every packet sent or received by the socket.
+    pub fn recorder(mut self, recorder: Arc<Recorder>) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    /// Dumps every packet sent or received by the socket to the trace log.
+    pub fn wire_log(mut self, wire_log: WireLog) -> Self {
+        self.wire_log = Some(Arc::new(wire_log));
+        self
+    }
+
+    /// The address family tried first for hosts with both IPv6 and IPv4
+    /// addresses, the other family is raced after a short delay.
+    pub fn address_family(mut self, address_family: AddressFamily) -> Self {
+        self.address_family = address_family;
+        self
+    }
+
+    /// Tunes the HTTP connection pool of the polling transport.
+    pub fn polling_option(mut self, polling_option: PollingOption) -> Self {
+        self.polling_option = polling_option;
+        self
+    }
+
+    /// Negotiates TLS of `https` and `wss` urls with `tls_config` instead of
+    /// the default roots, for both transports, e.g. to trust a private CA or to
+    /// present a client certificate.
+    #[cfg(feature = "tls")]
+    pub fn tls_config(mut self, tls_config: TlsConfig) -> Self {
+        self.tls_config = Some(tls_config);
+        self
+    }
+
+    /// The HTTP client of the polling transport, before its connection settings.
+    fn http_client(&self) -> Result<reqwest::ClientBuilder> {
+        let builder = reqwest::ClientBuilder::new();
+        #[cfg(feature = "tls")]
+        if let Some(tls_config) = &self.tls_config {
+            return tls_config.apply(builder);
+        }
+        Ok(builder)
+    }
+
+    /// The TLS connector of the websocket transport, the native roots if `None`.
+    fn websocket_connector(&self) -> Result<Option<Connector>> {
+        #[cfg(feature = "tls")]
+        if let Some(tls_config) = &self.tls_config {
+            return tls_config.connector().map(Some);
+        }
+        Ok(None)
+    }
+
+    /// Injects artificial latency, drops, reordering and disconnects into the
+    /// packets sent by the socket.
+    #[cfg(feature = "testing")]
+    pub fn chaos(mut self, profile: ChaosProfile) -> Self {
+        self.chaos = Some(profile);
+        self
+    }
+
     async fn handshake_with_transport<T: Transport>(&mut self, transport: &mut T) -> Result<()> {
         trace!("client handshake_with_transport {:?}", self.handshake);
         // No need to handshake twice
@@ -88,7 +212,14 @@ impl SocketBuilder {
         };
 
         // Start with polling transport
-        let mut transport = ClientPollingTransport::new(self.url.clone(), headers)?;
+        let addrs = connector::resolve_fastest(&self.url, self.address_family).await?;
+        let mut transport = ClientPollingTransport::new(
+            self.url.clone(),
+            headers,
+            &addrs,
+            &self.polling_option,
+            self.http_client()?,
+        )?;
 
         self.handshake_with_transport(&mut transport).await
     }
@@ -143,16 +274,52 @@ impl SocketBuilder {
     }
 
     /// Build socket with only a websocket transport
-    pub async fn build_websocket(mut self) -> Result<Socket> {
-        let headers = if let Some(map) = self.headers.clone() {
-            Some(map.try_into()?)
-        } else {
-            None
-        };
+    pub async fn build_websocket(self) -> Result<Socket> {
+        let headers = Some(self.websocket_request_headers()?);
+        let transport = WebsocketTransport::connect(
+            self.url.clone(),
+            headers,
+            self.address_family,
+            self.websocket_connector()?,
+        )
+        .await?;
+        self.finish_websocket(transport).await
+    }
 
-        let (sender, receiver) = WebsocketTransport::connect(self.url.clone(), headers).await?;
-        let mut transport = WebsocketTransport::new(sender, receiver);
+    /// Build socket with only a websocket transport over an already established
+    /// `stream`, e.g. a TLS tunnel, a unix socket or an in-memory duplex. The url
+    /// is not resolved, it only provides the path, query and `Host` of the
+    /// handshake. Polling is not available over such a stream.
+    pub async fn build_websocket_over<S>(self, stream: S) -> Result<Socket>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let headers = Some(self.websocket_request_headers()?);
+        let transport = WebsocketTransport::connect_over(
+            self.url.clone(),
+            headers,
+            stream,
+            self.websocket_connector()?,
+        )
+        .await?;
+        self.finish_websocket(transport).await
+    }
 
+    fn websocket_request_headers(&self) -> Result<http::HeaderMap> {
+        let mut headers = self.headers.clone().unwrap_or_default();
+        for (key, value) in self.websocket_headers.clone().unwrap_or_default() {
+            headers.insert(key, value);
+        }
+        if !self.websocket_protocols.is_empty() {
+            headers.insert(
+                "Sec-WebSocket-Protocol".to_owned(),
+                self.websocket_protocols.join(", "),
+            );
+        }
+        headers.try_into()
+    }
+
+    async fn finish_websocket(mut self, mut transport: WebsocketTransport) -> Result<Socket> {
         if self.handshake.is_some() {
             transport.upgrade().await?;
         } else {
@@ -163,13 +330,19 @@ impl SocketBuilder {
 
         // NOTE: Although self.url contains the sid, it does not propagate to the transport
         // SAFETY: handshake function called previously.
-        Ok(Socket::new(
+        let socket = Socket::new(
             TransportType::Websocket(transport),
             self.handshake.unwrap(),
             None,
             self.should_pong,
             false,
-        ))
+        )
+        .with_recorder(self.recorder)
+        .with_wire_log(self.wire_log);
+        #[cfg(feature = "testing")]
+        let socket = socket.with_chaos(self.chaos);
+
+        Ok(socket)
     }
 
     pub async fn build_polling(mut self) -> Result<Socket> {
@@ -177,18 +350,30 @@ impl SocketBuilder {
         self.handshake().await?;
 
         // Make a polling transport with new sid
-        // TODO: tls
-        let transport =
-            ClientPollingTransport::new(self.url, self.headers.map(|v| v.try_into().unwrap()))?;
+        let addrs = connector::resolve_fastest(&self.url, self.address_family).await?;
+        let http_client = self.http_client()?;
+        let transport = ClientPollingTransport::new(
+            self.url,
+            self.headers.map(|v| v.try_into().unwrap()),
+            &addrs,
+            &self.polling_option,
+            http_client,
+        )?;
 
         // SAFETY: handshake function called previously.
-        Ok(Socket::new(
+        let socket = Socket::new(
             TransportType::ClientPolling(transport),
             self.handshake.unwrap(),
             None,
             self.should_pong,
             false,
-        ))
+        )
+        .with_recorder(self.recorder)
+        .with_wire_log(self.wire_log);
+        #[cfg(feature = "testing")]
+        let socket = socket.with_chaos(self.chaos);
+
+        Ok(socket)
     }
 
     #[cfg(test)]
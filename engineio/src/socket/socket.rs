@@ -10,31 +10,55 @@ use std::{
 
 use async_stream::try_stream;
 use bytes::Bytes;
-use futures_util::{FutureExt, Stream, StreamExt};
+use futures_util::{future::BoxFuture, FutureExt, Stream, StreamExt};
+use serde_json::{Map, Value};
 use tokio::{
     sync::{mpsc::Sender, Mutex},
     time::Instant,
 };
 use tracing::trace;
 
+#[cfg(feature = "testing")]
+use crate::chaos::{Chaos, ChaosAction, ChaosProfile};
 use crate::{
+    capture::{Direction, Recorder},
+    channel,
+    clock::{Clock, TokioClock},
     error::Result,
     packet::{HandshakePacket, Payload},
     transports::{Data, TransportType},
+    wire::WireLog,
     Error, Packet, PacketType, Sid, StreamGenerator,
 };
 
 #[derive(Clone)]
 pub struct Socket {
     transport: Arc<Mutex<TransportType>>,
+    // whether `transport` is a websocket, readable without locking it
+    websocket: Arc<AtomicBool>,
     event_tx: Option<Arc<Sender<Event>>>,
     connected: Arc<AtomicBool>,
     last_ping: Arc<Mutex<Instant>>,
     last_pong: Arc<Mutex<Instant>>,
+    clock: Arc<dyn Clock>,
     connection_data: Arc<HandshakePacket>,
-    generator: Arc<Mutex<StreamGenerator<Packet, Error>>>,
+    generator: Arc<Mutex<Incoming>>,
     server_end: bool,
     should_pong: bool,
+    recorder: Option<Arc<Recorder>>,
+    wire_log: Option<Arc<WireLog>>,
+    #[cfg(feature = "testing")]
+    chaos: Option<Arc<Chaos>>,
+    #[cfg(feature = "testing")]
+    held: Arc<Mutex<Option<Packet>>>,
+}
+
+/// The packets coming in over the transport, and the one taken off them which
+/// is still being handled. It is kept until handled, a poll of the socket
+/// which is cancelled meanwhile must not lose it.
+struct Incoming {
+    packets: StreamGenerator<Packet, Error>,
+    handling: Option<(Packet, BoxFuture<'static, ()>)>,
 }
 
 #[derive(Debug)]
@@ -44,6 +68,15 @@ pub enum Event {
     OnData(Sid, Bytes),
     OnPacket(Sid, Packet),
     OnError(Sid, String),
+    OnUpgrade(Sid),
+    OnUpgradeFailed(Sid, String),
+    /// The client stopped fetching its buffered packets fast enough.
+    #[cfg(feature = "server")]
+    OnSlowConsumer(Sid, crate::server::QueueStats),
+    /// The client missed its pongs, sent right before the `OnClose` of its
+    /// socket.
+    #[cfg(feature = "server")]
+    OnPingTimeout(Sid),
 }
 
 impl Socket {
@@ -56,16 +89,64 @@ impl Socket {
         server_end: bool,
     ) -> Self {
         Socket {
+            websocket: Arc::new(AtomicBool::new(transport.is_websocket())),
             transport: Arc::new(Mutex::new(transport.clone())),
             connected: Arc::new(AtomicBool::default()),
             last_ping: Arc::new(Mutex::new(Instant::now())),
             last_pong: Arc::new(Mutex::new(Instant::now())),
+            clock: Arc::new(TokioClock),
             connection_data: Arc::new(handshake),
-            generator: Arc::new(Mutex::new(StreamGenerator::new(Self::stream(transport)))),
+            generator: Arc::new(Mutex::new(Incoming {
+                packets: StreamGenerator::new(Self::stream(transport)),
+                handling: None,
+            })),
             event_tx,
             server_end,
             should_pong,
+            recorder: None,
+            wire_log: None,
+            #[cfg(feature = "testing")]
+            chaos: None,
+            #[cfg(feature = "testing")]
+            held: Default::default(),
+        }
+    }
+
+    /// Records every packet sent or received by this socket.
+    pub(crate) fn with_recorder(mut self, recorder: Option<Arc<Recorder>>) -> Self {
+        self.recorder = recorder;
+        self
+    }
+
+    /// Dumps every packet sent or received by this socket to the trace log.
+    pub(crate) fn with_wire_log(mut self, wire_log: Option<Arc<WireLog>>) -> Self {
+        self.wire_log = wire_log;
+        self
+    }
+
+    /// Measures the heartbeats of this socket with `clock`.
+    pub(crate) fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.last_ping = Arc::new(Mutex::new(clock.now()));
+        self.last_pong = Arc::new(Mutex::new(clock.now()));
+        self.clock = clock;
+        self
+    }
+
+    #[inline]
+    fn record(&self, direction: Direction, packet: &Packet) {
+        if let Some(ref recorder) = self.recorder {
+            recorder.record(direction, &self.connection_data.sid, packet);
         }
+        if let Some(ref wire_log) = self.wire_log {
+            wire_log.log(direction, &self.connection_data.sid, packet);
+        }
+    }
+
+    /// Injects the failures described by `profile` into outgoing packets.
+    #[cfg(feature = "testing")]
+    pub(crate) fn with_chaos(mut self, profile: Option<ChaosProfile>) -> Self {
+        self.chaos = profile.map(|p| Arc::new(Chaos::new(p)));
+        self
     }
 
     /// Opens the connection to a specified server. The first Pong packet is sent
@@ -73,12 +154,12 @@ impl Socket {
     pub async fn connect(&self) -> Result<()> {
         // SAFETY: Has valid handshake due to type
         self.connected.store(true, Ordering::Release);
-        if let Some(ref event_tx) = self.event_tx {
+        if let Some(event_tx) = self.event_tx() {
             event_tx.send(Event::OnOpen(self.sid())).await?;
         }
 
         // set the last ping to now and set the connected state
-        *self.last_ping.lock().await = Instant::now();
+        *self.last_ping.lock().await = self.clock.now();
 
         if !self.server_end {
             // emit a pong packet to keep trigger the ping cycle on the server
@@ -96,6 +177,7 @@ impl Socket {
 
     async fn handle_incoming_packet(&self, packet: Packet) {
         trace!("handle_incoming_packet {:?}", packet);
+        self.record(Direction::Incoming, &packet);
         // update last_pong on any packet, incoming data is a good sign of other side's liveness
         self.ponged().await;
         // check for the appropriate action or callback
@@ -121,6 +203,11 @@ impl Socket {
         }
     }
 
+    /// Additional fields the server sent in the OPEN packet.
+    pub fn handshake_extra(&self) -> &Map<String, Value> {
+        &self.connection_data.extra
+    }
+
     fn sid(&self) -> Sid {
         Arc::clone(&self.connection_data.sid)
     }
@@ -130,7 +217,7 @@ impl Socket {
             return Ok(());
         }
 
-        if let Some(ref event_tx) = self.event_tx {
+        if let Some(event_tx) = self.event_tx() {
             event_tx.send(Event::OnClose(self.sid())).await?;
         }
 
@@ -142,7 +229,8 @@ impl Socket {
         Ok(())
     }
 
-    /// Sends a packet to the server.
+    /// Sends packets to the server in one batch: one request over polling,
+    /// frames flushed together over websocket.
     pub async fn emit_multi(&self, packets: Vec<Packet>) -> Result<()> {
         if !self.connected.load(Ordering::Acquire) {
             let error = Error::IllegalActionBeforeOpen();
@@ -150,23 +238,34 @@ impl Socket {
             return Err(error);
         }
 
+        // packets meet the chaos one by one, the batch is not kept together
+        #[cfg(feature = "testing")]
+        if let Some(ref chaos) = self.chaos {
+            for packet in packets {
+                self.emit_with_chaos(chaos, packet).await?;
+            }
+            return Ok(());
+        }
+
         trace!("socket emit {:?}", packets);
-        let lock = self.transport.lock().await;
+        let mut batch = Vec::with_capacity(packets.len());
         for packet in packets {
-            // send a post request with the encoded payload as body
+            self.record(Direction::Outgoing, &packet);
+            #[cfg(feature = "checksum")]
+            let packet = crate::checksum::seal(packet);
             // if this is a binary attachment, then send the raw bytes
-            let data = match packet.ptype {
+            batch.push(match packet.ptype {
                 PacketType::MessageBinary => Data::Binary(packet.data),
                 _ => Data::Text(packet.into()),
-            };
-
-            let fut = lock.as_transport().emit(data);
+            });
+        }
 
-            if let Err(error) = fut.await {
-                self.on_error(error.to_string()).await;
-                return Err(error);
-            }
+        let lock = self.transport.lock().await;
+        if let Err(error) = lock.as_transport().emit_batch(batch).await {
+            self.handle_emit_error(&lock, &error).await;
+            return Err(error);
         }
+        self.check_slow_consumer(&lock).await;
 
         Ok(())
     }
@@ -179,6 +278,70 @@ impl Socket {
             return Err(error);
         }
 
+        #[cfg(feature = "testing")]
+        if let Some(ref chaos) = self.chaos {
+            return self.emit_with_chaos(chaos, packet).await;
+        }
+
+        self.send(packet).await
+    }
+
+    #[cfg(feature = "testing")]
+    async fn emit_with_chaos(&self, chaos: &Chaos, packet: Packet) -> Result<()> {
+        match chaos.next_action() {
+            ChaosAction::Deliver(delay) => {
+                if !delay.is_zero() {
+                    tokio::time::sleep(delay).await;
+                }
+                self.send(packet).await?;
+            }
+            ChaosAction::Drop => {
+                trace!("chaos drop {:?}", packet);
+                return Ok(());
+            }
+            ChaosAction::Reorder => {
+                trace!("chaos reorder {:?}", packet);
+                // at most one packet is held back
+                return match self.held.lock().await.replace(packet) {
+                    Some(held) => self.send(held).await,
+                    None => {
+                        self.flush_held(chaos.reorder_hold());
+                        Ok(())
+                    }
+                };
+            }
+            ChaosAction::Disconnect => {
+                trace!("chaos disconnect {:?}", packet);
+                self.handle_close().await;
+                return Err(Error::ChaosDisconnect());
+            }
+        }
+
+        let held = self.held.lock().await.take();
+        match held {
+            Some(held) => self.send(held).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Sends the held back packet after `hold`, unless another packet took it
+    /// along before.
+    #[cfg(feature = "testing")]
+    fn flush_held(&self, hold: std::time::Duration) {
+        let socket = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(hold).await;
+            let held = socket.held.lock().await.take();
+            if let Some(held) = held {
+                let _ = socket.send(held).await;
+            }
+        });
+    }
+
+    async fn send(&self, packet: Packet) -> Result<()> {
+        self.record(Direction::Outgoing, &packet);
+        #[cfg(feature = "checksum")]
+        let packet = crate::checksum::seal(packet);
         // send a post request with the encoded payload as body
         // if this is a binary attachment, then send the raw bytes
         let data = match packet.ptype {
@@ -191,43 +354,82 @@ impl Socket {
         let fut = lock.as_transport().emit(data);
 
         if let Err(error) = fut.await {
-            self.on_error(error.to_string()).await;
+            self.handle_emit_error(&lock, &error).await;
             return Err(error);
         }
+        self.check_slow_consumer(&lock).await;
 
         Ok(())
     }
 
+    async fn check_slow_consumer(&self, _transport: &TransportType) {
+        #[cfg(feature = "server")]
+        if let Some(stats) = _transport.slow_consumer() {
+            trace!("socket slow consumer {} {:?}", self.sid(), stats);
+            if let Some(event_tx) = self.event_tx() {
+                let _ = event_tx
+                    .send(Event::OnSlowConsumer(self.sid(), stats))
+                    .await;
+            }
+        }
+    }
+
+    async fn handle_emit_error(&self, transport: &TransportType, error: &Error) {
+        self.on_error(error.to_string()).await;
+        if matches!(error, Error::PollingBufferFull()) && transport.close_on_overflow() {
+            self.handle_close().await;
+        }
+    }
+
     /// Calls the error callback with a given message.
     #[inline]
     async fn on_error(&self, text: String) {
         trace!("socket on_error {}", text);
-        if let Some(ref event_tx) = self.event_tx {
+        if let Some(event_tx) = self.event_tx() {
             let _ = event_tx.send(Event::OnError(self.sid(), text)).await;
         }
     }
 
+    /// The packets buffered for the client of a polling socket, `None` over
+    /// websocket.
+    #[cfg(feature = "server")]
+    pub async fn queue_stats(&self) -> Option<crate::server::QueueStats> {
+        self.transport.lock().await.queue_stats()
+    }
+
     // Check if the underlying transport client is connected.
     pub fn is_connected(&self) -> bool {
         self.connected.load(Ordering::Acquire)
     }
 
+    /// Whether the socket currently uses the websocket transport, as opposed to polling.
+    pub fn is_websocket(&self) -> bool {
+        self.websocket.load(Ordering::Acquire)
+    }
+
     pub(crate) async fn pinged(&self) {
-        *self.last_ping.lock().await = Instant::now();
+        *self.last_ping.lock().await = self.clock.now();
     }
 
     pub(crate) async fn ponged(&self) {
-        *self.last_pong.lock().await = Instant::now();
+        *self.last_pong.lock().await = self.clock.now();
+    }
+
+    /// The channel of the server events, observed before every send.
+    fn event_tx(&self) -> Option<&Sender<Event>> {
+        let event_tx = self.event_tx.as_deref()?;
+        channel::observe("event", event_tx);
+        Some(event_tx)
     }
 
     pub(crate) async fn handle_packet(&self, packet: Packet) {
-        if let Some(ref event_tx) = self.event_tx {
+        if let Some(event_tx) = self.event_tx() {
             let _ = event_tx.send(Event::OnPacket(self.sid(), packet)).await;
         }
     }
 
     pub(crate) async fn handle_data(&self, data: Bytes) {
-        if let Some(ref event_tx) = self.event_tx {
+        if let Some(event_tx) = self.event_tx() {
             let _ = event_tx.send(Event::OnData(self.sid(), data)).await;
         }
     }
@@ -236,25 +438,46 @@ impl Socket {
         if !self.is_connected() {
             return;
         }
-        if let Some(ref event_tx) = self.event_tx {
+        if let Some(event_tx) = self.event_tx() {
             let _ = event_tx.send(Event::OnClose(self.sid())).await;
         }
 
         self.connected.store(false, Ordering::Release);
     }
 
-    pub(crate) async fn upgrade(&self, transport: TransportType) {
+    /// Switches to `transport`, sending the packets `buffered` resolves to
+    /// through it first. It is awaited once no emit can reach the previous
+    /// transport anymore, so none of them is lost.
+    #[cfg(feature = "server")]
+    pub(crate) async fn upgrade<F>(&self, transport: TransportType, buffered: F)
+    where
+        F: std::future::Future<Output = Vec<Data>>,
+    {
         trace!("socket upgrade from {:?}", transport);
         let mut lock = self.transport.lock().await;
+        for data in buffered.await {
+            if let Err(e) = transport.as_transport().emit(data).await {
+                tracing::warn!("packet buffered during the upgrade not sent: {}", e);
+            }
+        }
         *lock = transport.clone();
+        self.websocket
+            .store(transport.is_websocket(), Ordering::Release);
 
         let mut lock = self.generator.lock().await;
-        *lock = StreamGenerator::new(Self::stream(transport));
+        lock.packets = StreamGenerator::new(Self::stream(transport));
     }
 
     /// Helper method that parses bytes and returns an iterator over the elements.
-    fn parse_payload(bytes: Bytes) -> impl Stream<Item = Result<Packet>> {
+    /// A websocket frame always carries a single packet, which may contain the
+    /// payload separator if it is binary.
+    fn parse_payload(bytes: Bytes, is_websocket: bool) -> impl Stream<Item = Result<Packet>> {
         try_stream! {
+            if is_websocket {
+                yield Packet::try_from(bytes)?;
+                return;
+            }
+
             let payload = Payload::try_from(bytes);
 
             for elem in payload?.into_iter() {
@@ -271,9 +494,12 @@ impl Socket {
     ) -> Pin<Box<impl Stream<Item = Result<Packet>> + 'static + Send>> {
         // map the byte stream of the underlying transport
         // to a packet stream
+        let is_websocket = matches!(transport, TransportType::Websocket(_));
         Box::pin(try_stream! {
             for await payload in transport.as_pin_box() {
-                for await packet in Self::parse_payload(payload?) {
+                for await packet in Self::parse_payload(payload?, is_websocket) {
+                    #[cfg(feature = "checksum")]
+                    let packet = packet.and_then(crate::checksum::verify);
                     yield packet?;
                 }
             }
@@ -289,16 +515,25 @@ impl Stream for Socket {
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Option<Self::Item>> {
         let mut lock = ready!(Box::pin(self.generator.lock()).poll_unpin(cx));
-        let item = lock.poll_next_unpin(cx);
-        if let Poll::Ready(Some(Ok(packet))) = &item {
-            ready!(Box::pin(self.handle_incoming_packet(packet.clone())).poll_unpin(cx));
+        let incoming = &mut *lock;
+        if incoming.handling.is_none() {
+            match ready!(incoming.packets.poll_next_unpin(cx)) {
+                Some(Ok(packet)) => {
+                    let socket = self.clone();
+                    let handled = packet.clone();
+                    let handling = async move { socket.handle_incoming_packet(handled).await };
+                    incoming.handling = Some((packet, handling.boxed()));
+                }
+                item => return Poll::Ready(item),
+            }
+        }
+        if let Some((_, handling)) = &mut incoming.handling {
+            ready!(handling.poll_unpin(cx));
         }
-        item
+        Poll::Ready(incoming.handling.take().map(|(packet, _)| Ok(packet)))
     }
 }
 
-// impl Stre
-#[cfg_attr(tarpaulin, ignore)]
 impl Debug for Socket {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Socket")
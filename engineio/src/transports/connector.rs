@@ -0,0 +1,145 @@
+use std::{io, net::SocketAddr, time::Duration};
+
+use futures_util::{stream::FuturesUnordered, StreamExt};
+use reqwest::Url;
+use tokio::net::{lookup_host, TcpStream};
+
+use crate::{error::Result, Error};
+
+/// Address family tried first when a host resolves to both IPv6 and IPv4.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AddressFamily {
+    #[default]
+    Ipv6,
+    Ipv4,
+}
+
+/// Delay before the next connection attempt is started, as recommended by RFC 8305.
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Resolves the host of `url`, ordering the addresses for connection attempts.
+pub(crate) async fn resolve(url: &Url, preferred: AddressFamily) -> Result<Vec<SocketAddr>> {
+    let host = url
+        .host_str()
+        .ok_or_else(|| Error::InvalidUrl(url.to_string()))?;
+    let port = url
+        .port_or_known_default()
+        .ok_or_else(|| Error::InvalidUrl(url.to_string()))?;
+    // IPv6 literals are bracketed in urls
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+
+    let addrs = lookup_host((host, port)).await?.collect();
+    Ok(sort_addresses(addrs, preferred))
+}
+
+/// Interleaves both families starting with `preferred`, RFC 8305 section 4.
+fn sort_addresses(addrs: Vec<SocketAddr>, preferred: AddressFamily) -> Vec<SocketAddr> {
+    let (first, second): (Vec<_>, Vec<_>) = addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv6() == (preferred == AddressFamily::Ipv6));
+
+    let mut sorted = Vec::with_capacity(first.len() + second.len());
+    let (mut first, mut second) = (first.into_iter(), second.into_iter());
+    loop {
+        match (first.next(), second.next()) {
+            (None, None) => return sorted,
+            (a, b) => {
+                sorted.extend(a);
+                sorted.extend(b);
+            }
+        }
+    }
+}
+
+/// Connects to the first of `addrs` which accepts. The next attempt starts once
+/// the previous one failed or did not finish within the attempt delay, so a
+/// broken family only costs the delay instead of a full connect timeout.
+pub(crate) async fn connect(addrs: &[SocketAddr]) -> Result<TcpStream> {
+    let mut addrs = addrs.iter().copied();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_err = io::Error::new(io::ErrorKind::NotFound, "no address to connect to");
+
+    attempts.extend(addrs.next().map(TcpStream::connect));
+    while !attempts.is_empty() {
+        tokio::select! {
+            Some(result) = attempts.next() => match result {
+                Ok(stream) => return Ok(stream),
+                Err(e) => {
+                    last_err = e;
+                    attempts.extend(addrs.next().map(TcpStream::connect));
+                }
+            },
+            _ = tokio::time::sleep(CONNECTION_ATTEMPT_DELAY) => {
+                attempts.extend(addrs.next().map(TcpStream::connect));
+            }
+        }
+    }
+    Err(last_err.into())
+}
+
+/// Resolves the host of `url` and races connections to its addresses like
+/// `connect`, returning the address which accepted first. reqwest tries the
+/// addresses of the polling transport one after another, so it is pinned to
+/// the winner instead.
+pub(crate) async fn resolve_fastest(
+    url: &Url,
+    preferred: AddressFamily,
+) -> Result<Vec<SocketAddr>> {
+    let addrs = resolve(url, preferred).await?;
+    if addrs.len() < 2 {
+        return Ok(addrs);
+    }
+    let stream = connect(&addrs).await?;
+    Ok(vec![stream.peer_addr()?])
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[test]
+    fn test_sort_addresses() {
+        let v4 = |n| SocketAddr::from((Ipv4Addr::new(10, 0, 0, n), 80));
+        let v6 = |n| SocketAddr::from((Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, n), 80));
+        let addrs = vec![v4(1), v4(2), v4(3), v6(1), v6(2)];
+
+        assert_eq!(
+            sort_addresses(addrs.clone(), AddressFamily::Ipv6),
+            vec![v6(1), v4(1), v6(2), v4(2), v4(3)]
+        );
+        assert_eq!(
+            sort_addresses(addrs, AddressFamily::Ipv4),
+            vec![v4(1), v6(1), v4(2), v6(2), v4(3)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_connect_falls_back() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let reachable = listener.local_addr()?;
+        // nothing listens on port 1 of the loopback, the attempt fails fast
+        let refused = SocketAddr::from((Ipv4Addr::LOCALHOST, 1));
+
+        let stream = connect(&[refused, reachable]).await?;
+        assert_eq!(stream.peer_addr()?, reachable);
+
+        assert!(connect(&[refused]).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resolve_fastest() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let reachable = listener.local_addr()?;
+        let url = Url::parse(&format!("http://localhost:{}/", reachable.port())).unwrap();
+
+        // `::1` refuses, if localhost resolves to it at all
+        let addrs = resolve_fastest(&url, AddressFamily::Ipv6).await?;
+        assert_eq!(addrs, vec![reachable]);
+        Ok(())
+    }
+}
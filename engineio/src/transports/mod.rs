@@ -1,4 +1,4 @@
-use std::{borrow::Cow, fmt::Debug};
+use std::fmt::Debug;
 
 use async_trait::async_trait;
 use bytes::Bytes;
@@ -10,12 +10,23 @@ use crate::transports::polling::ClientPollingTransport;
 use crate::transports::polling::ServerPollingTransport;
 use crate::{error::Result, transports::websocket::WebsocketTransport};
 
+pub(crate) mod connector;
 pub(crate) mod polling;
+#[cfg(feature = "tls")]
+pub(crate) mod tls;
 pub(crate) mod websocket;
 
 #[async_trait]
-pub trait Transport: Send + Debug + Unpin + Stream<Item = Result<Bytes>> {
+pub trait Transport: Send + Sync + Debug + Unpin + Stream<Item = Result<Bytes>> {
     async fn emit(&self, payload: Data) -> Result<()>;
+
+    /// Emits `payloads` in order, in as few writes as the transport allows.
+    async fn emit_batch(&self, payloads: Vec<Data>) -> Result<()> {
+        for payload in payloads {
+            self.emit(payload).await?;
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
@@ -24,13 +35,42 @@ pub enum Data {
     Binary(Bytes),
 }
 
+impl Data {
+    /// The data as sent in a polling payload, binary data base64 encoded
+    /// behind a `b`.
+    pub(crate) fn into_polling(self) -> Bytes {
+        match self {
+            Data::Text(data) => data,
+            Data::Binary(data) => {
+                let mut buf = String::with_capacity(1 + data.len().div_ceil(3) * 4);
+                buf.push('b');
+                base64::encode_config_buf(data, base64::STANDARD, &mut buf);
+                Bytes::from(buf)
+            }
+        }
+    }
+
+    /// The data of a polling payload, see `into_polling`.
+    #[cfg(feature = "server")]
+    pub(crate) fn from_polling(data: Bytes) -> Result<Self> {
+        match data.first() {
+            Some(b'b') => Ok(Data::Binary(Bytes::from(base64::decode(&data[1..])?))),
+            _ => Ok(Data::Text(data)),
+        }
+    }
+}
+
 impl TryFrom<Data> for Message {
     type Error = crate::Error;
 
+    /// Takes over the buffer of `payload` if nothing else holds it, rather
+    /// than copying it into the message.
     fn try_from(payload: Data) -> std::result::Result<Self, Self::Error> {
         let message = match payload {
-            Data::Text(data) => Message::text(Cow::Borrowed(std::str::from_utf8(data.as_ref())?)),
-            Data::Binary(data) => Message::binary(Cow::Borrowed(data.as_ref())),
+            Data::Text(data) => {
+                Message::Text(String::from_utf8(Vec::from(data)).map_err(|e| e.utf8_error())?)
+            }
+            Data::Binary(data) => Message::Binary(Vec::from(data)),
         };
         Ok(message)
     }
@@ -73,6 +113,37 @@ impl TransportType {
         }
     }
 
+    pub(crate) fn is_websocket(&self) -> bool {
+        matches!(self, TransportType::Websocket(_))
+    }
+
+    /// Whether the socket should be closed after the transport buffer overflowed.
+    pub(crate) fn close_on_overflow(&self) -> bool {
+        match self {
+            #[cfg(feature = "server")]
+            TransportType::ServerPolling(transport) => transport.close_on_overflow(),
+            _ => false,
+        }
+    }
+
+    /// The buffer stats of a server polling transport.
+    #[cfg(feature = "server")]
+    pub(crate) fn queue_stats(&self) -> Option<crate::server::QueueStats> {
+        match self {
+            TransportType::ServerPolling(transport) => Some(transport.queue_stats()),
+            _ => None,
+        }
+    }
+
+    /// The buffer stats of a server polling transport which stopped keeping up.
+    #[cfg(feature = "server")]
+    pub(crate) fn slow_consumer(&self) -> Option<crate::server::QueueStats> {
+        match self {
+            TransportType::ServerPolling(transport) => transport.slow_consumer(),
+            _ => None,
+        }
+    }
+
     #[allow(clippy::redundant_allocation)]
     pub fn as_pin_box(&mut self) -> std::pin::Pin<Box<&mut (dyn Transport + Send)>> {
         match self {
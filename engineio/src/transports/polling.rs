@@ -1,9 +1,10 @@
 use std::{
     fmt::Debug,
+    net::SocketAddr,
     pin::Pin,
     sync::Arc,
     task::{Context, Poll},
-    time::SystemTime,
+    time::{Duration, SystemTime},
 };
 
 use async_stream::try_stream;
@@ -13,18 +14,46 @@ use futures_util::{ready, FutureExt, Stream, StreamExt};
 use http::HeaderMap;
 use reqwest::{Client, ClientBuilder, Response, Url};
 #[cfg(feature = "server")]
-use tokio::sync::mpsc::{Receiver, Sender};
+use std::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(feature = "server")]
+use tokio::sync::mpsc::{
+    error::{SendError, TrySendError},
+    Receiver, Sender,
+};
 use tokio::sync::Mutex;
-
 #[cfg(feature = "server")]
-use crate::Error;
+use tracing::warn;
+
 use crate::{
     error::Result,
+    packet::SEPARATOR,
     transports::{Data, Transport},
 };
+#[cfg(feature = "server")]
+use crate::{
+    server::{EmitQueue, PollingOverflow, QueueStats},
+    Error,
+};
 
 type ClientPollStream = Box<dyn Stream<Item = Result<Bytes>> + 'static + Send>;
 
+/// The answer of the server to polling requests of an unknown session, as sent
+/// by the JS implementation.
+pub(crate) const UNKNOWN_SESSION: &str = r#"{"code":1,"message":"Session ID unknown"}"#;
+
+/// Connection pool settings of the HTTP client used by the polling transport.
+/// `None` keeps the reqwest default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PollingOption {
+    /// How long an idle pooled connection is kept open.
+    pub pool_idle_timeout: Option<Duration>,
+    pub pool_max_idle_per_host: Option<usize>,
+    /// Interval of TCP keepalive probes on pooled connections.
+    pub tcp_keepalive: Option<Duration>,
+    /// Speaks HTTP/2 without negotiation, for servers known to support it.
+    pub http2_prior_knowledge: bool,
+}
+
 #[derive(Clone)]
 pub struct ClientPollingTransport {
     client: Client,
@@ -37,6 +66,9 @@ pub struct ClientPollingTransport {
 pub struct ServerPollingTransport {
     sender: Arc<Sender<Bytes>>,
     receiver: Arc<Mutex<Receiver<Bytes>>>,
+    overflow: PollingOverflow,
+    overflow_count: Arc<AtomicUsize>,
+    queue: Arc<EmitQueue>,
 }
 
 impl Debug for ClientPollingTransport {
@@ -49,11 +81,32 @@ impl Debug for ClientPollingTransport {
 }
 
 impl ClientPollingTransport {
-    pub(crate) fn new(mut url: Url, headers: Option<HeaderMap>) -> Result<Self> {
-        let mut builder = ClientBuilder::new();
+    /// Creates a transport connecting to `addrs` in the given order, or to the
+    /// addresses of the system resolver if `addrs` is empty. `builder` carries
+    /// the TLS settings, see `TlsConfig::apply`.
+    pub(crate) fn new(
+        mut url: Url,
+        headers: Option<HeaderMap>,
+        addrs: &[SocketAddr],
+        option: &PollingOption,
+        builder: ClientBuilder,
+    ) -> Result<Self> {
+        let mut builder = builder.tcp_keepalive(option.tcp_keepalive);
+        if let Some(timeout) = option.pool_idle_timeout {
+            builder = builder.pool_idle_timeout(timeout);
+        }
+        if let Some(max) = option.pool_max_idle_per_host {
+            builder = builder.pool_max_idle_per_host(max);
+        }
+        if option.http2_prior_knowledge {
+            builder = builder.http2_prior_knowledge();
+        }
         if let Some(headers) = headers {
             builder = builder.default_headers(headers);
         }
+        if let (Some(domain), false) = (url.domain(), addrs.is_empty()) {
+            builder = builder.resolve_to_addrs(domain, addrs);
+        }
         let client = builder.no_proxy().build()?;
         url.query_pairs_mut().append_pair("transport", "polling");
 
@@ -69,13 +122,31 @@ impl ClientPollingTransport {
         self.url.clone()
     }
 
+    async fn post(&self, body: Bytes) -> Result<()> {
+        let response = self
+            .client
+            .post(append_hash(&self.url))
+            .body(body)
+            .send()
+            .await?;
+
+        match response.status().as_u16() {
+            200 => Ok(()),
+            _ => Err(status_error(response).await),
+        }
+    }
+
     fn send_request(url: Url, client: Client) -> impl Stream<Item = Result<Response>> {
         try_stream! {
             let url = append_hash(&url);
 
-            yield client
-                .get(url)
-                .send().await?
+            let response = client.get(url).send().await?;
+            let response = if response.status().as_u16() != 200 {
+                Err(status_error(response).await)
+            } else {
+                Ok(response)
+            };
+            yield response?
         }
     }
 
@@ -95,32 +166,39 @@ impl ClientPollingTransport {
     }
 }
 
+/// The error of a failed polling request, telling a session the server no
+/// longer knows, e.g. after a restart, from other failures.
+async fn status_error(response: Response) -> crate::Error {
+    let status = response.status().as_u16();
+    let unknown_session = |body: &[u8]| {
+        let body: Option<serde_json::Value> = serde_json::from_slice(body).ok();
+        body.is_some_and(|body| body["code"] == 1)
+    };
+    match response.bytes().await {
+        Ok(body) if status == 400 && unknown_session(&body) => crate::Error::InvalidSession(),
+        _ => crate::Error::InvalidHttpResponseStatus(status),
+    }
+}
+
 #[async_trait]
 impl Transport for ClientPollingTransport {
     async fn emit(&self, payload: Data) -> Result<()> {
-        let body = match payload {
-            Data::Text(data) => data,
-            Data::Binary(data) => {
-                let mut buf = BytesMut::with_capacity(data.len() + 1);
-                buf.put_u8(b'b');
-                buf.put(base64::encode(data).as_bytes());
-                buf.freeze()
-            }
-        };
-
-        let status = self
-            .client
-            .post(append_hash(&self.url))
-            .body(body)
-            .send()
-            .await?
-            .status()
-            .as_u16();
+        self.post(payload.into_polling()).await
+    }
 
-        match status {
-            200 => Ok(()),
-            _ => Err(crate::Error::InvalidHttpResponseStatus(status)),
+    /// One request with a payload of all packets.
+    async fn emit_batch(&self, payloads: Vec<Data>) -> Result<()> {
+        if payloads.is_empty() {
+            return Ok(());
+        }
+        let mut body = BytesMut::new();
+        for payload in payloads {
+            if !body.is_empty() {
+                body.put_u8(SEPARATOR as u8);
+            }
+            body.put(payload.into_polling());
         }
+        self.post(body.freeze()).await
     }
 }
 
@@ -135,30 +213,69 @@ impl Stream for ClientPollingTransport {
 
 #[cfg(feature = "server")]
 impl ServerPollingTransport {
-    pub(crate) fn new(sender: Sender<Bytes>, receiver: Receiver<Bytes>) -> Self {
+    pub(crate) fn new(
+        sender: Sender<Bytes>,
+        receiver: Receiver<Bytes>,
+        overflow: PollingOverflow,
+        overflow_count: Arc<AtomicUsize>,
+        queue: Arc<EmitQueue>,
+    ) -> Self {
         Self {
             sender: Arc::new(sender),
             receiver: Arc::new(Mutex::new(receiver)),
+            overflow,
+            overflow_count,
+            queue,
         }
     }
+
+    /// Whether the socket should be closed after its buffer overflowed.
+    pub(crate) fn close_on_overflow(&self) -> bool {
+        self.overflow == PollingOverflow::Disconnect
+    }
+
+    pub(crate) fn queue_stats(&self) -> QueueStats {
+        self.queue.stats()
+    }
+
+    /// The buffer stats, once they exceed the slow consumer limits.
+    pub(crate) fn slow_consumer(&self) -> Option<QueueStats> {
+        self.queue.check_slow()
+    }
 }
 
 #[cfg(feature = "server")]
 #[async_trait]
 impl Transport for ServerPollingTransport {
     async fn emit(&self, payload: Data) -> Result<()> {
-        let data = match payload {
-            Data::Text(data) => data,
-            Data::Binary(data) => {
-                let mut buf = BytesMut::with_capacity(data.len() + 1);
-                buf.put_u8(b'b');
-                buf.put(base64::encode(data).as_bytes());
-                buf.freeze()
-            }
-        };
+        let data = payload.into_polling();
+
+        // queued ahead of sending, the client may fetch the packet right away
+        self.queue.pushed(data.len());
+        crate::channel::observe("polling", &self.sender);
+        if self.overflow == PollingOverflow::Wait {
+            return self.sender.send(data).await.map_err(|e| {
+                self.queue.unpushed();
+                Error::SendError(e)
+            });
+        }
 
-        self.sender.send(data).await.map_err(Error::SendError)?;
-        Ok(())
+        match self.sender.try_send(data) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => {
+                self.queue.unpushed();
+                self.overflow_count.fetch_add(1, Ordering::Relaxed);
+                warn!("polling buffer full, overflow policy {:?}", self.overflow);
+                match self.overflow {
+                    PollingOverflow::Drop => Ok(()),
+                    _ => Err(Error::PollingBufferFull()),
+                }
+            }
+            Err(TrySendError::Closed(data)) => {
+                self.queue.unpushed();
+                Err(Error::SendError(SendError(data)))
+            }
+        }
     }
 }
 
@@ -197,7 +314,14 @@ mod test {
     #[test]
     fn polling_transport_url() -> Result<()> {
         let url = Url::from_str("http://127.0.0.1").unwrap();
-        let transport = ClientPollingTransport::new(url.clone(), None).unwrap();
+        let transport = ClientPollingTransport::new(
+            url.clone(),
+            None,
+            &[],
+            &Default::default(),
+            ClientBuilder::new(),
+        )
+        .unwrap();
         assert_eq!(
             transport.url().to_string(),
             url.to_string() + "?transport=polling"
@@ -205,11 +329,29 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn polling_transport_option() {
+        let url = Url::from_str("http://127.0.0.1").unwrap();
+        let option = PollingOption {
+            pool_idle_timeout: Some(Duration::from_secs(30)),
+            pool_max_idle_per_host: Some(1),
+            tcp_keepalive: Some(Duration::from_secs(15)),
+            http2_prior_knowledge: true,
+        };
+        assert!(ClientPollingTransport::new(url, None, &[], &option, ClientBuilder::new()).is_ok());
+    }
+
     #[tokio::test]
     async fn test_server_polling_transport() -> Result<()> {
         let (send_tx, mut send_rx) = channel(100);
         let (recv_tx, recv_rx) = channel(100);
-        let mut transport = ServerPollingTransport::new(send_tx, recv_rx);
+        let mut transport = ServerPollingTransport::new(
+            send_tx,
+            recv_rx,
+            Default::default(),
+            Default::default(),
+            Default::default(),
+        );
 
         let data = Bytes::from_static(b"1Hello\x1e1HelloWorld");
 
@@ -232,4 +374,82 @@ mod test {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_server_polling_overflow() -> Result<()> {
+        let (send_tx, mut send_rx) = channel(1);
+        let (_recv_tx, recv_rx) = channel(1);
+        let overflow_count = Arc::new(AtomicUsize::default());
+        let queue = Arc::new(EmitQueue::default());
+        let transport = ServerPollingTransport::new(
+            send_tx,
+            recv_rx,
+            PollingOverflow::Drop,
+            overflow_count.clone(),
+            queue.clone(),
+        );
+
+        transport
+            .emit(Data::Text(Bytes::from_static(b"4a")))
+            .await?;
+        transport
+            .emit(Data::Text(Bytes::from_static(b"4b")))
+            .await?;
+        assert_eq!(overflow_count.load(Ordering::Relaxed), 1);
+        // the dropped packet is not counted as queued
+        assert_eq!(queue.stats().depth, 1);
+        assert_eq!(send_rx.recv().await, Some(Bytes::from_static(b"4a")));
+        assert!(send_rx.try_recv().is_err());
+
+        let (send_tx, _send_rx) = channel(1);
+        let (_recv_tx, recv_rx) = channel(1);
+        let transport = ServerPollingTransport::new(
+            send_tx,
+            recv_rx,
+            PollingOverflow::Error,
+            overflow_count.clone(),
+            Default::default(),
+        );
+
+        transport
+            .emit(Data::Text(Bytes::from_static(b"4a")))
+            .await?;
+        let result = transport.emit(Data::Text(Bytes::from_static(b"4b"))).await;
+        assert!(matches!(result, Err(Error::PollingBufferFull())));
+        assert_eq!(overflow_count.load(Ordering::Relaxed), 2);
+        assert!(!transport.close_on_overflow());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_server_polling_slow_consumer() -> Result<()> {
+        let (send_tx, mut send_rx) = channel(10);
+        let (_recv_tx, recv_rx) = channel(1);
+        let queue = Arc::new(EmitQueue::new(Some(crate::SlowConsumer {
+            max_depth: 1,
+            max_age: Duration::from_secs(60),
+        })));
+        let transport = ServerPollingTransport::new(
+            send_tx,
+            recv_rx,
+            PollingOverflow::Wait,
+            Default::default(),
+            queue.clone(),
+        );
+
+        transport
+            .emit(Data::Text(Bytes::from_static(b"4a")))
+            .await?;
+        assert_eq!(transport.slow_consumer(), None);
+        transport
+            .emit(Data::Text(Bytes::from_static(b"4b")))
+            .await?;
+        assert_eq!(transport.slow_consumer().map(|s| s.depth), Some(2));
+
+        send_rx.recv().await;
+        queue.popped();
+        assert_eq!(queue.stats().depth, 1);
+        Ok(())
+    }
 }
@@ -0,0 +1,200 @@
+use std::{sync::Arc, time::SystemTime};
+
+use rustls::{
+    client::{ServerCertVerified, ServerCertVerifier},
+    Certificate, ClientConfig, PrivateKey, RootCertStore, ServerName,
+};
+use tokio_tungstenite::Connector;
+
+use crate::{error::Result, Error};
+
+/// TLS settings of `https` and `wss` urls, applied to the polling requests and
+/// the websocket alike, see `SocketBuilder::tls_config`. Servers are checked
+/// against the default roots and those added with `add_root_certificate`.
+#[derive(Debug, Clone, Default)]
+pub struct TlsConfig {
+    roots: Vec<Certificate>,
+    // the PEM for reqwest and its parsed chain and key for the websocket
+    identity: Option<(Vec<u8>, Vec<Certificate>, PrivateKey)>,
+    accept_invalid_certs: bool,
+}
+
+impl TlsConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Trusts the certificates of `pem`, e.g. the CA of internal servers or a
+    /// self-signed certificate.
+    pub fn add_root_certificate(mut self, pem: &[u8]) -> Result<Self> {
+        let certs = rustls_pemfile::certs(&mut &*pem)?;
+        if certs.is_empty() {
+            return Err(invalid("no certificate in PEM"));
+        }
+        self.roots.extend(certs.into_iter().map(Certificate));
+        Ok(self)
+    }
+
+    /// Presents the certificate chain and the private key of `pem` to servers
+    /// asking for a client certificate.
+    pub fn identity(mut self, pem: &[u8]) -> Result<Self> {
+        let mut chain = Vec::new();
+        let mut key = None;
+        for item in rustls_pemfile::read_all(&mut &*pem)? {
+            match item {
+                rustls_pemfile::Item::X509Certificate(cert) => chain.push(Certificate(cert)),
+                rustls_pemfile::Item::PKCS8Key(der) | rustls_pemfile::Item::RSAKey(der) => {
+                    key = Some(PrivateKey(der))
+                }
+                _ => {}
+            }
+        }
+        match (chain.is_empty(), key) {
+            (false, Some(key)) => {
+                self.identity = Some((pem.to_vec(), chain, key));
+                Ok(self)
+            }
+            _ => Err(invalid("identity needs a certificate and a private key")),
+        }
+    }
+
+    /// Accepts any certificate, expired, self-signed or issued for another
+    /// host. Meant for tests and local development, the connection is open
+    /// to anyone in the middle.
+    pub fn accept_invalid_certs(mut self) -> Self {
+        self.accept_invalid_certs = true;
+        self
+    }
+
+    /// Configures the HTTP client of the polling transport.
+    pub(crate) fn apply(
+        &self,
+        mut builder: reqwest::ClientBuilder,
+    ) -> Result<reqwest::ClientBuilder> {
+        for root in &self.roots {
+            builder = builder.add_root_certificate(reqwest::Certificate::from_der(&root.0)?);
+        }
+        if let Some((pem, _, _)) = &self.identity {
+            builder = builder.identity(reqwest::Identity::from_pem(pem)?);
+        }
+        Ok(builder.danger_accept_invalid_certs(self.accept_invalid_certs))
+    }
+
+    /// The connector of the websocket transport.
+    pub(crate) fn connector(&self) -> Result<Connector> {
+        let mut roots = RootCertStore::empty();
+        // the roots tungstenite trusts without a config
+        let native = rustls_native_certs::load_native_certs()?;
+        roots.add_parsable_certificates(&native.into_iter().map(|cert| cert.0).collect::<Vec<_>>());
+        for root in &self.roots {
+            roots.add(root).map_err(|e| invalid(&e.to_string()))?;
+        }
+
+        let builder = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots);
+        let mut config = match &self.identity {
+            Some((_, chain, key)) => builder
+                .with_single_cert(chain.clone(), key.clone())
+                .map_err(|e| invalid(&e.to_string()))?,
+            None => builder.with_no_client_auth(),
+        };
+        if self.accept_invalid_certs {
+            config
+                .dangerous()
+                .set_certificate_verifier(Arc::new(AcceptAnyCert));
+        }
+        Ok(Connector::Rustls(Arc::new(config)))
+    }
+}
+
+fn invalid(reason: &str) -> Error {
+    Error::InvalidTlsConfig(reason.to_owned())
+}
+
+struct AcceptAnyCert;
+
+impl ServerCertVerifier for AcceptAnyCert {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use reqwest::Url;
+    use tokio::net::TcpListener;
+    use tokio_rustls::TlsAcceptor;
+
+    use super::*;
+    use crate::{
+        server::{Incoming, ServerBuilder},
+        SocketBuilder,
+    };
+
+    /// Serves on `port` behind TLS with a self-signed certificate for
+    /// localhost, returned as PEM.
+    async fn serve_tls(port: u16) -> String {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![Certificate(cert.serialize_der().unwrap())],
+                PrivateKey(cert.serialize_private_key_der()),
+            )
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let listener = TcpListener::bind(("127.0.0.1", port)).await.unwrap();
+        let incoming = Box::pin(async_stream::stream! {
+            while let Ok((stream, addr)) = listener.accept().await {
+                // clients refusing the certificate abort the handshake
+                if let Ok(stream) = acceptor.accept(stream).await {
+                    yield Incoming::new(stream, addr);
+                }
+            }
+        });
+
+        let server = ServerBuilder::new(port).build();
+        tokio::spawn(async move { server.serve_incoming(incoming).await });
+        cert.serialize_pem().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_tls_handshake() -> Result<()> {
+        let pem = serve_tls(4271).await;
+        let url = Url::parse("https://localhost:4271").unwrap();
+
+        // the certificate is not among the default roots
+        assert!(SocketBuilder::new(url.clone())
+            .build_polling()
+            .await
+            .is_err());
+        assert!(SocketBuilder::new(url.clone())
+            .build_websocket()
+            .await
+            .is_err());
+
+        let trusted = TlsConfig::new().add_root_certificate(pem.as_bytes())?;
+        for tls_config in [trusted, TlsConfig::new().accept_invalid_certs()] {
+            let builder = SocketBuilder::new(url.clone()).tls_config(tls_config);
+            builder.clone().build_polling().await?;
+            builder.build_websocket().await?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_pem() {
+        assert!(TlsConfig::new().add_root_certificate(b"").is_err());
+        assert!(TlsConfig::new().identity(b"").is_err());
+    }
+}
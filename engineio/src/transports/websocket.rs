@@ -1,5 +1,6 @@
 use std::{
     borrow::Cow,
+    pin::Pin,
     str::from_utf8,
     sync::Arc,
     task::{ready, Poll},
@@ -9,24 +10,30 @@ use async_trait::async_trait;
 use bytes::{BufMut, Bytes, BytesMut};
 use futures_util::{
     stream::{SplitSink, SplitStream},
-    FutureExt, SinkExt, Stream, StreamExt,
+    FutureExt, Sink, SinkExt, Stream, StreamExt,
 };
 use http::HeaderMap;
 use reqwest::Url;
-use tokio::{net::TcpStream, sync::Mutex};
-use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::Mutex,
+};
+use tokio_tungstenite::{client_async_tls_with_config, Connector, WebSocketStream};
 use tungstenite::{client::IntoClientRequest, Message};
 
 use crate::{
     error::Result,
-    transports::{Data, Transport},
+    transports::{
+        connector::{self, AddressFamily},
+        Data, Transport,
+    },
     Error, Packet, PacketType,
 };
 
-type WebsocketSender = SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>;
-type WebsocketReceiver = SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>;
+type WebsocketSender = Pin<Box<dyn Sink<Message, Error = tungstenite::Error> + Send>>;
+type WebsocketReceiver = Pin<Box<dyn Stream<Item = tungstenite::Result<Message>> + Send>>;
 
-#[derive(Clone, Debug)]
+#[derive(Clone)]
 pub struct WebsocketTransport {
     sender: Arc<Mutex<WebsocketSender>>,
     receiver: Arc<Mutex<WebsocketReceiver>>,
@@ -34,9 +41,28 @@ pub struct WebsocketTransport {
 
 impl WebsocketTransport {
     pub async fn connect(
+        url: Url,
+        headers: Option<HeaderMap>,
+        address_family: AddressFamily,
+        tls: Option<Connector>,
+    ) -> Result<Self> {
+        let addrs = connector::resolve(&url, address_family).await?;
+        let stream = connector::connect(&addrs).await?;
+        Self::connect_over(url, headers, stream, tls).await
+    }
+
+    /// Runs the websocket handshake of `url` over an already established
+    /// `stream`. TLS is still negotiated on top of it for `https` urls, with
+    /// `tls` or else the native roots.
+    pub async fn connect_over<S>(
         mut url: Url,
         headers: Option<HeaderMap>,
-    ) -> Result<(WebsocketSender, WebsocketReceiver)> {
+        stream: S,
+        tls: Option<Connector>,
+    ) -> Result<Self>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
         tracing::trace!("websocket_transport connect: {:?} with {:?}", url, headers);
         // SAFETY: ws is valid to parse scheme in `set_scheme`
         if url.scheme() == "https" {
@@ -46,21 +72,27 @@ impl WebsocketTransport {
         }
         url.query_pairs_mut().append_pair("transport", "websocket");
 
-        let mut req = url.clone().into_client_request()?;
+        let mut req = url.into_client_request()?;
         if let Some(map) = headers {
             req.headers_mut().extend(map)
         }
 
-        let (stream, _) = connect_async(req).await?;
+        let (stream, _) = client_async_tls_with_config(req, stream, None, tls).await?;
         let (sender, receiver) = stream.split();
 
-        Ok((sender, receiver))
+        Ok(Self::new(sender, receiver))
     }
 
-    pub fn new(sender: WebsocketSender, receiver: WebsocketReceiver) -> Self {
+    pub fn new<S>(
+        sender: SplitSink<WebSocketStream<S>, Message>,
+        receiver: SplitStream<WebSocketStream<S>>,
+    ) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
         WebsocketTransport {
-            sender: Arc::new(Mutex::new(sender)),
-            receiver: Arc::new(Mutex::new(receiver)),
+            sender: Arc::new(Mutex::new(Box::pin(sender))),
+            receiver: Arc::new(Mutex::new(Box::pin(receiver))),
         }
     }
 
@@ -96,6 +128,12 @@ impl WebsocketTransport {
     }
 }
 
+impl std::fmt::Debug for WebsocketTransport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WebsocketTransport").finish_non_exhaustive()
+    }
+}
+
 #[async_trait]
 impl Transport for WebsocketTransport {
     async fn emit(&self, payload: Data) -> Result<()> {
@@ -106,6 +144,18 @@ impl Transport for WebsocketTransport {
 
         Ok(())
     }
+
+    /// One frame per packet as the protocol requires, flushed together.
+    async fn emit_batch(&self, payloads: Vec<Data>) -> Result<()> {
+        let mut sender = self.sender.lock().await;
+        for payload in payloads {
+            let message: Message = payload.try_into()?;
+            sender.feed(message).await?;
+        }
+        sender.flush().await?;
+
+        Ok(())
+    }
 }
 
 impl Stream for WebsocketTransport {
@@ -0,0 +1,201 @@
+use std::{collections::HashSet, fmt::Write, str::from_utf8};
+
+use bytes::Bytes;
+use serde_json::Value;
+use tracing::trace;
+
+use crate::{capture::Direction, Packet, PacketType, Sid};
+
+const REDACTED: &str = "[redacted]";
+
+/// Dumps every frame of a socket at trace level with the target `engineio::wire`,
+/// text frames as text and binary frames as hex. Dumps are cut after `max_bytes`
+/// and the values of redacted JSON fields are replaced at any depth, so wire
+/// logging can be enabled in production without leaking secrets into logs.
+/// Once a field is redacted, bodies which aren't JSON, binary frames included,
+/// are left out as they can't be checked for it.
+#[derive(Debug, Clone)]
+pub struct WireLog {
+    max_bytes: usize,
+    redact: HashSet<String>,
+}
+
+impl WireLog {
+    pub fn new(max_bytes: usize) -> Self {
+        Self {
+            max_bytes,
+            redact: HashSet::new(),
+        }
+    }
+
+    /// Replaces the value of every JSON field named `field`.
+    pub fn redact<T: Into<String>>(mut self, field: T) -> Self {
+        self.redact.insert(field.into());
+        self
+    }
+
+    pub(crate) fn log(&self, direction: Direction, sid: &Sid, packet: &Packet) {
+        trace!(
+            target: "engineio::wire",
+            "{} {:?} {}",
+            sid,
+            direction,
+            self.dump(packet)
+        );
+    }
+
+    fn dump(&self, packet: &Packet) -> String {
+        let redacting = !self.redact.is_empty();
+        match packet.ptype {
+            PacketType::MessageBinary if redacting => omitted("", packet.data.len()),
+            PacketType::MessageBinary => self.hex(&packet.data),
+            _ => {
+                let bytes = Bytes::from(packet.clone());
+                match from_utf8(&bytes) {
+                    Ok(text) => self.text(text),
+                    Err(_) if redacting => omitted("", bytes.len()),
+                    Err(_) => self.hex(&bytes),
+                }
+            }
+        }
+    }
+
+    fn text(&self, text: &str) -> String {
+        let redacted = match self.redact.is_empty() {
+            true => None,
+            false => Some(self.redacted(text)),
+        };
+        let text = redacted.as_deref().unwrap_or(text);
+        if text.len() <= self.max_bytes {
+            return text.to_owned();
+        }
+
+        let mut end = self.max_bytes;
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        format!("{}... ({} bytes)", &text[..end], text.len())
+    }
+
+    fn hex(&self, data: &[u8]) -> String {
+        let mut hex = String::with_capacity(data.len().min(self.max_bytes) * 2);
+        for byte in data.iter().take(self.max_bytes) {
+            let _ = write!(hex, "{:02x}", byte);
+        }
+        if data.len() > self.max_bytes {
+            let _ = write!(hex, "... ({} bytes)", data.len());
+        }
+        hex
+    }
+
+    /// `text` with the values of redacted fields replaced, or without its body
+    /// if that isn't JSON.
+    fn redacted(&self, text: &str) -> String {
+        let (head, body) = text.split_at(body_start(text));
+        if body.is_empty() {
+            return text.to_owned();
+        }
+        match serde_json::from_str::<Value>(body) {
+            Ok(mut json) => {
+                self.redact_value(&mut json);
+                format!("{}{}", head, json)
+            }
+            Err(_) => omitted(head, body.len()),
+        }
+    }
+
+    fn redact_value(&self, value: &mut Value) {
+        match value {
+            Value::Object(map) => {
+                for (key, value) in map.iter_mut() {
+                    if self.redact.contains(key) {
+                        *value = Value::String(REDACTED.to_owned());
+                    } else {
+                        self.redact_value(value);
+                    }
+                }
+            }
+            Value::Array(values) => values.iter_mut().for_each(|v| self.redact_value(v)),
+            _ => {}
+        }
+    }
+}
+
+fn omitted(head: &str, len: usize) -> String {
+    format!("{}{} ({} bytes)", head, REDACTED, len)
+}
+
+/// Where the body of the encoded packet `text` starts. The body of a message
+/// follows the socket.io header of type, attachments, namespace and ack id,
+/// other packets may carry JSON like the handshake.
+fn body_start(text: &str) -> usize {
+    let bytes = text.as_bytes();
+    if bytes.first() != Some(&b'4') {
+        return text.find(['[', '{']).unwrap_or(text.len());
+    }
+    let digits = |from: usize| {
+        bytes[from..]
+            .iter()
+            .take_while(|byte| byte.is_ascii_digit())
+            .count()
+    };
+    // the engine.io type, then the socket.io type
+    let mut start = 1;
+    if bytes.get(start).is_some_and(u8::is_ascii_digit) {
+        start += 1;
+    }
+    let attachments = digits(start);
+    if bytes.get(start + attachments) == Some(&b'-') {
+        start += attachments + 1;
+    }
+    if bytes.get(start) == Some(&b'/') {
+        match text[start..].find(',') {
+            Some(comma) => start += comma + 1,
+            None => return text.len(),
+        }
+    }
+    start + digits(start)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_dump() {
+        let log = WireLog::new(64).redact("password");
+        let packet = Packet::new(
+            PacketType::Message,
+            Bytes::from_static(br#"2/chat,["login","bob",[{"password":"x"}]]"#),
+        );
+        assert_eq!(
+            log.dump(&packet),
+            r#"42/chat,["login","bob",[{"password":"[redacted]"}]]"#
+        );
+
+        // bodies which can't be redacted are left out
+        let packet = Packet::new(
+            PacketType::Message,
+            Bytes::from_static(br#"51-/chat,7["login",{"password":"x""#),
+        );
+        assert_eq!(log.dump(&packet), "451-/chat,7[redacted] (24 bytes)");
+        let packet = Packet::new(PacketType::Message, Bytes::from_static(b"hello"));
+        assert_eq!(log.dump(&packet), "4[redacted] (5 bytes)");
+        let packet = Packet::new(PacketType::MessageBinary, Bytes::from_static(&[1, 0xab]));
+        assert_eq!(log.dump(&packet), "[redacted] (2 bytes)");
+        let packet = Packet::new(PacketType::Message, Bytes::from_static(b"0/chat"));
+        assert_eq!(log.dump(&packet), "40/chat");
+        let packet = Packet::new(PacketType::Ping, Bytes::from_static(b"probe"));
+        assert_eq!(log.dump(&packet), "2probe");
+
+        let log = WireLog::new(64);
+        let packet = Packet::new(PacketType::MessageBinary, Bytes::from_static(&[1, 0xab]));
+        assert_eq!(log.dump(&packet), "01ab");
+
+        let log = WireLog::new(4);
+        let packet = Packet::new(PacketType::Message, Bytes::from_static(b"hello"));
+        assert_eq!(log.dump(&packet), "4hel... (6 bytes)");
+        let packet = Packet::new(PacketType::MessageBinary, Bytes::from_static(&[0; 5]));
+        assert_eq!(log.dump(&packet), "00000000... (5 bytes)");
+    }
+}
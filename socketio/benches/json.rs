@@ -0,0 +1,23 @@
+use bytes::Bytes;
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use serde_json::json;
+use socketio_rs::Packet;
+
+/// An event carrying a large payload, e.g. a dashboard snapshot.
+fn large_event() -> Bytes {
+    let rows: Vec<_> = (0..2000)
+        .map(|i| json!({"id": i, "name": format!("sensor-{}", i), "value": i as f64 * 0.5, "tags": ["a", "b"]}))
+        .collect();
+    let data = json!(["snapshot", { "rows": rows }]);
+    Bytes::from(format!("2{}", data))
+}
+
+fn decode(c: &mut Criterion) {
+    let payload = large_event();
+    c.bench_function("decode large event", |b| {
+        b.iter(|| Packet::try_from(black_box(&payload)).unwrap())
+    });
+}
+
+criterion_group!(benches, decode);
+criterion_main!(benches);
@@ -1,9 +1,16 @@
 use bytes::Bytes;
 use futures_util::FutureExt;
+use serde::Serialize;
 use serde_json::json;
 use socketio_rs::{ClientBuilder, Payload, Socket};
 use std::time::Duration;
 
+#[derive(Serialize)]
+struct Login<'a> {
+    token: u32,
+    device: &'a str,
+}
+
 #[tokio::main]
 async fn main() {
     tracing_subscriber::fmt()
@@ -40,10 +47,13 @@ async fn main() {
         .await
         .expect("Connection failed");
 
-    // emit to the "foo" event
-    let json_payload = json!({"token": 123});
+    // emit to the "foo" event, serializing the struct right into the packet
+    let login = Login {
+        token: 123,
+        device: "example",
+    };
     client
-        .emit("foo", json_payload)
+        .emit_ref("foo", &login)
         .await
         .expect("Server unreachable");
 
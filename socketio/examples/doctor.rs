@@ -0,0 +1,137 @@
+//! Checks that this crate can talk to a socket.io server, e.g. a Node server
+//! which a client fails to connect to, and prints a compatibility report.
+//!
+//! ```text
+//! cargo run --example socketio-doctor -- http://localhost:3000 /admin
+//! ```
+//!
+//! The namespaces after the URL are connected besides `/`. The ack and binary
+//! checks expect the server to ack `echo` with its argument, in Node:
+//! `socket.on("echo", (data, ack) => ack(data))`.
+
+use bytes::Bytes;
+use serde_json::json;
+use socketio_rs::{AckPolicy, Client, ClientBuilder, Payload, Result, TransportType};
+use std::{process::ExitCode, time::Duration};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+struct Report {
+    failed: usize,
+}
+
+impl Report {
+    fn check<T>(&mut self, name: &str, result: Result<T>) -> Option<T> {
+        match result {
+            Ok(value) => {
+                println!("[ ok ] {}", name);
+                Some(value)
+            }
+            Err(e) => {
+                println!("[fail] {}: {}", name, e);
+                self.failed += 1;
+                None
+            }
+        }
+    }
+
+    fn skip(&self, name: &str, reason: &str) {
+        println!("[skip] {}: {}", name, reason);
+    }
+}
+
+async fn connect(url: &str, namespace: &str, transport: TransportType) -> Result<Client> {
+    ClientBuilder::new(url)
+        .namespace(namespace)
+        .transport_type(transport)
+        .connect_timeout(TIMEOUT)
+        .reconnect(false)
+        .connect()
+        .await
+}
+
+async fn echo(client: &Client, data: Payload) -> Result<Payload> {
+    client
+        .request_with_policy("echo", data, TIMEOUT, AckPolicy::Fail)
+        .await
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    tracing_subscriber::fmt()
+        .with_env_filter("engineio=warn,socketio=warn")
+        .init();
+
+    let mut args = std::env::args().skip(1);
+    let url = match args.next() {
+        Some(url) => url,
+        None => {
+            eprintln!("usage: socketio-doctor <url> [namespace...]");
+            return ExitCode::FAILURE;
+        }
+    };
+    let namespaces: Vec<String> = args.collect();
+    let mut report = Report { failed: 0 };
+    println!("socketio-doctor {}", url);
+
+    let transports = [
+        ("handshake over polling", TransportType::Polling),
+        ("handshake over websocket", TransportType::Websocket),
+        ("upgrade from polling", TransportType::WebsocketUpgrade),
+    ];
+    let mut client = None;
+    for (name, transport) in transports {
+        if let Some(connected) = report.check(name, connect(&url, "/", transport).await) {
+            if let Some(previous) = client.replace(connected) {
+                let _ = previous.disconnect().await;
+            }
+        }
+    }
+
+    match &client {
+        Some(client) => {
+            let sent = json!({ "doctor": true, "list": [1, "two", null] });
+            let reply = echo(client, sent.clone().into()).await;
+            if let Some(reply) = report.check("ack", reply) {
+                if reply != Payload::from(sent) {
+                    println!("[warn] ack: echoed {:?}", reply);
+                }
+            }
+
+            let sent = Bytes::from_static(&[0, 1, 2, 254, 255]);
+            let reply = echo(client, sent.clone().into()).await;
+            if let Some(reply) = report.check("binary", reply) {
+                if reply != Payload::Binary(sent) {
+                    println!("[warn] binary: echoed {:?}", reply);
+                }
+            }
+        }
+        None => {
+            report.skip("ack", "not connected");
+            report.skip("binary", "not connected");
+        }
+    }
+
+    for namespace in &namespaces {
+        let name = format!("namespace {}", namespace);
+        if let Some(connected) =
+            report.check(&name, connect(&url, namespace, TransportType::Any).await)
+        {
+            let _ = connected.disconnect().await;
+        }
+    }
+    if let Some(client) = client {
+        let _ = client.disconnect().await;
+    }
+
+    match report.failed {
+        0 => {
+            println!("compatible");
+            ExitCode::SUCCESS
+        }
+        failed => {
+            println!("{} check(s) failed", failed);
+            ExitCode::FAILURE
+        }
+    }
+}
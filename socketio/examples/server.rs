@@ -7,8 +7,8 @@ async fn main() {
     tracing_subscriber::fmt::init();
     let callback = |_payload: Option<Payload>, socket: ServerSocket, _| {
         async move {
-            socket.join(vec!["room 1"]).await;
-            socket.emit_to(vec!["room 1"], "test", json!("foo")).await;
+            let _ = socket.join(vec!["room 1"]).await;
+            let _ = socket.emit_to(vec!["room 1"], "test", json!("foo")).await;
         }
         .boxed()
     };
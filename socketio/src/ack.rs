@@ -1,8 +1,17 @@
-use std::time::Duration;
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
 
-use tokio::time::Instant;
+use serde::Serialize;
+use thiserror::Error as ThisError;
+use tokio::{sync::oneshot, time::Instant};
+use tracing::warn;
 
-use crate::callback::Callback;
+use crate::{callback::Callback, error::Result, socket::RawSocket, Error, Event, Packet, Payload};
 
 /// Represents an `Ack` as given back to the caller. Holds the internal `id` as
 /// well as the current ack'ed state. Holds data which will be accessible as
@@ -14,6 +23,127 @@ pub(crate) struct Ack<C> {
     pub timeout: Duration,
     pub time_started: Instant,
     pub callback: Callback<C>,
+    // only a reconnecting client reads the fields below
+    #[cfg_attr(not(feature = "client"), allow(dead_code))]
+    pub policy: AckPolicy,
+    // the emitted event, kept to emit it again with `AckPolicy::Resend`
+    #[cfg_attr(not(feature = "client"), allow(dead_code))]
+    pub packet: Option<Packet>,
+    // notified instead of the `Error` handler if the ack fails
+    #[cfg_attr(not(feature = "client"), allow(dead_code))]
+    pub failed: Option<oneshot::Sender<AckError>>,
+    // the broadcast this ack answers, handed to the callback
+    pub context: Option<Arc<AckContext>>,
+    // approximate bytes held by the ack, see `Socket::memory_usage`
+    pub size: usize,
+}
+
+/// The broadcast answered by an ack, see `Socket::ack_context`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AckContext {
+    /// The event the broadcast emitted.
+    pub event: Event,
+    /// The rooms the broadcast was emitted to.
+    pub rooms: Vec<String>,
+    /// When the broadcast was emitted.
+    pub emitted_at: SystemTime,
 }
 
 pub type AckId = usize;
+
+/// What happens to an ack still outstanding when the client loses its
+/// connection and reconnects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AckPolicy {
+    /// The ack fails with `AckError::Disconnected` as soon as the connection is
+    /// lost, reported through the `Error` handler.
+    #[default]
+    Fail,
+    /// The event is emitted again once reconnected and the callback waits for
+    /// the ack on the new connection, keeping its timeout.
+    Resend,
+}
+
+/// Why an ack failed without an answer from the peer.
+#[derive(ThisError, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AckError {
+    #[error("connection lost before the ack arrived")]
+    Disconnected,
+}
+
+/// Holds the ack id of a received event until the handler responds. Dropping the
+/// guard without responding logs a warning, or acks with the fallback if one is
+/// set, instead of leaving the peer waiting for its timeout.
+#[derive(Debug)]
+pub struct AckGuard {
+    socket: RawSocket,
+    nsp: String,
+    is_connected: Arc<AtomicBool>,
+    id: AckId,
+    responded: bool,
+    fallback: Option<Payload>,
+}
+
+impl AckGuard {
+    pub(crate) fn new(
+        socket: RawSocket,
+        nsp: String,
+        is_connected: Arc<AtomicBool>,
+        id: AckId,
+    ) -> Self {
+        Self {
+            socket,
+            nsp,
+            is_connected,
+            id,
+            responded: false,
+            fallback: None,
+        }
+    }
+
+    /// Acks with `data` if the guard is dropped without a response.
+    pub fn fallback<D: Into<Payload>>(mut self, data: D) -> Self {
+        self.fallback = Some(data.into());
+        self
+    }
+
+    pub fn id(&self) -> AckId {
+        self.id
+    }
+
+    pub async fn ack<D: Into<Payload>>(mut self, data: D) -> Result<()> {
+        self.responded = true;
+        if !self.is_connected.load(Ordering::Acquire) {
+            return Err(Error::IllegalActionBeforeOpen());
+        }
+        self.socket.ack(&self.nsp, self.id, data.into()).await
+    }
+
+    /// Acks with `value` serialized to JSON.
+    pub async fn ack_with<T: Serialize>(self, value: &T) -> Result<()> {
+        let value = serde_json::to_value(value)?;
+        self.ack(value).await
+    }
+}
+
+impl Drop for AckGuard {
+    fn drop(&mut self) {
+        if self.responded {
+            return;
+        }
+        let id = self.id;
+        let handle = tokio::runtime::Handle::try_current();
+        match (self.fallback.take(), handle) {
+            (Some(data), Ok(handle)) if self.is_connected.load(Ordering::Acquire) => {
+                let socket = self.socket.clone();
+                let nsp = self.nsp.clone();
+                handle.spawn(async move {
+                    if let Err(e) = socket.ack(&nsp, id, data).await {
+                        warn!("fallback ack {} failed: {}", id, e);
+                    }
+                });
+            }
+            _ => warn!("ack {} of {} dropped without a response", id, self.nsp),
+        }
+    }
+}
@@ -2,6 +2,7 @@ use futures_util::future::BoxFuture;
 use std::{
     fmt::Debug,
     ops::{Deref, DerefMut},
+    time::Duration,
 };
 
 use crate::{ack::AckId, Payload};
@@ -14,6 +15,34 @@ type DynAsyncCallback<C> = Box<
         + Sync,
 >;
 
+/// What happens to a handler invocation exceeding its concurrency limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HandlerOverflow {
+    /// Wait until a running invocation finishes.
+    #[default]
+    Queue,
+    /// Skip the invocation.
+    Drop,
+}
+
+/// What happens to a socket after one of its handlers panicked. The `Error`
+/// handler is fired with the panic message in any case.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PanicPolicy {
+    /// Keep the connection and continue dispatching events.
+    #[default]
+    Keep,
+    /// Disconnect the socket.
+    Disconnect,
+}
+
+/// Execution options of a single handler.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct HandlerConfig {
+    pub(crate) limit: Option<(usize, HandlerOverflow)>,
+    pub(crate) timeout: Option<Duration>,
+}
+
 pub(crate) struct Callback<C> {
     inner: DynAsyncCallback<C>,
 }
@@ -0,0 +1,13 @@
+use bytes::Bytes;
+
+use crate::error::Result;
+
+/// A hook pair applied to every encoded `socket.io` packet and binary attachment
+/// right before it is handed to `engine.io`, and right after it is received.
+/// Both peers must use matching ciphers; encrypted packets are always sent as
+/// binary `engine.io` messages.
+pub trait PayloadCipher: Send + Sync {
+    fn encrypt(&self, data: Bytes) -> Result<Bytes>;
+
+    fn decrypt(&self, data: Bytes) -> Result<Bytes>;
+}
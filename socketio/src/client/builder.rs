@@ -1,18 +1,37 @@
-use std::sync::Arc;
+use std::{
+    collections::{HashMap, HashSet},
+    str::FromStr,
+    sync::{atomic::AtomicUsize, Arc},
+    time::Duration,
+};
 
 use super::client::{Client, Socket as ClientSocket};
+use super::health::HealthProbe;
+use super::metrics::Counters;
+use crate::callback::{HandlerConfig, HandlerOverflow, PanicPolicy};
+use crate::compression::{self, Compression};
+use crate::dispatcher::{DispatchMode, DispatchOverflow, WORKER_QUEUE_SIZE};
 use crate::socket::RawSocket;
 use crate::{ack::AckId, socket::Socket};
-use crate::{callback::Callback, error::Result, Event, Payload};
+use crate::{
+    callback::Callback, error::Result, Error, Event, MemoryLimits, Payload, PayloadCipher,
+};
 
+use backoff::{ExponentialBackoff, ExponentialBackoffBuilder};
 use dashmap::DashMap;
-use engineio_rs::{HeaderMap, HeaderValue, SocketBuilder as EngineSocketBuilder};
-use futures_util::future::BoxFuture;
-use tracing::trace;
+use engineio_rs::{
+    AddressFamily, HeaderMap, HeaderValue, PollingOption, ServerOption,
+    SocketBuilder as EngineSocketBuilder,
+};
+use futures_util::{future::BoxFuture, FutureExt};
+use rand::seq::SliceRandom;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use tracing::{trace, warn};
 use url::Url;
 
-/// Flavor of Engine.IO transport.
-#[derive(Clone, Eq, PartialEq)]
+/// Flavor of Engine.IO transport, like the `transports` option of the JS client.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum TransportType {
     /// Handshakes with polling, upgrades if possible
     Any,
@@ -24,22 +43,76 @@ pub enum TransportType {
     Polling,
 }
 
+/// When the delay between reconnect attempts falls back to its minimum.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BackoffReset {
+    /// As soon as a reconnect succeeds, like the JS client.
+    #[default]
+    OnConnect,
+    /// Once a connection stayed up for the given time, so a server that accepts
+    /// connections and drops them right away is not hammered.
+    AfterStable(Duration),
+}
+
+impl BackoffReset {
+    /// Whether the backoff starts over for a connection lost after it was up
+    /// for `connected_for`.
+    pub(crate) fn is_due(&self, connected_for: Duration) -> bool {
+        match self {
+            BackoffReset::OnConnect => true,
+            BackoffReset::AfterStable(stable) => connected_for >= *stable,
+        }
+    }
+}
+
 /// A builder class for a `socket.io` socket. This handles setting up the client and
 /// configuring the callback, the namespace and metadata of the socket. If no
 /// namespace is specified, the default namespace `/` is taken. The `connect` method
 /// acts the `build` method and returns a connected [`Client`].
 #[derive(Clone)]
 pub struct ClientBuilder {
-    address: String,
-    on: Arc<DashMap<Event, Callback<ClientSocket>>>,
-    namespace: String,
+    addresses: Vec<String>,
+    shuffle_addresses: bool,
+    pub(crate) on: Arc<DashMap<Event, Callback<ClientSocket>>>,
+    pub(crate) namespace: String,
     opening_headers: Option<HeaderMap>,
+    auth: Option<Value>,
+    websocket_headers: Option<HeaderMap>,
+    websocket_protocols: Vec<String>,
     transport_type: TransportType,
+    connect_timeout: Option<Duration>,
     pub(crate) reconnect: bool,
     // None reconnect attempts represent infinity.
     pub(crate) max_reconnect_attempts: Option<usize>,
-    pub(crate) reconnect_delay_min: u64,
-    pub(crate) reconnect_delay_max: u64,
+    reconnect_delay_min: Duration,
+    reconnect_delay_max: Duration,
+    reconnect_randomization: f64,
+    pub(crate) backoff_reset: BackoffReset,
+    recorder: Option<Arc<engineio_rs::Recorder>>,
+    wire_log: Option<engineio_rs::WireLog>,
+    cipher: Option<Arc<dyn PayloadCipher>>,
+    compression_threshold: Option<usize>,
+    handlers: HashMap<Event, HandlerConfig>,
+    coalesce: HashSet<Event>,
+    sticky: HashSet<Event>,
+    // shared by the sockets of a client, replaced for each new `Client`
+    pub(crate) sticky_payloads: Arc<DashMap<Event, Option<Payload>>>,
+    pub(crate) subscriptions: Vec<(Event, Payload)>,
+    // the encoded size of the subscriptions, replaced for each new `Client`
+    pub(crate) recovery_bytes: Arc<AtomicUsize>,
+    panic_policy: PanicPolicy,
+    dispatch_mode: DispatchMode,
+    dispatch_queue: (usize, DispatchOverflow),
+    address_family: AddressFamily,
+    polling_option: PollingOption,
+    #[cfg(feature = "tls")]
+    tls_config: Option<engineio_rs::TlsConfig>,
+    memory_limits: Option<MemoryLimits>,
+    max_payload: Option<usize>,
+    // shared by the sockets of a client, replaced for each new `Client`
+    pub(crate) counters: Arc<Counters>,
+    #[cfg(feature = "testing")]
+    chaos: Option<engineio_rs::ChaosProfile>,
 }
 
 impl ClientBuilder {
@@ -83,17 +156,53 @@ impl ClientBuilder {
     /// }
     /// ```
     pub fn new<T: Into<String>>(address: T) -> Self {
+        Self::new_multi(vec![address])
+    }
+
+    /// Creates a client builder from several URLs of the same server cluster. On
+    /// connect and on every reconnect the URLs are tried in order until one of
+    /// them accepts the connection.
+    pub fn new_multi<T: Into<String>>(addresses: Vec<T>) -> Self {
         Self {
-            address: address.into(),
+            addresses: addresses.into_iter().map(Into::into).collect(),
+            shuffle_addresses: false,
             on: Default::default(),
             namespace: "/".to_owned(),
             opening_headers: None,
+            auth: None,
+            websocket_headers: None,
+            websocket_protocols: Vec::new(),
             transport_type: TransportType::Any,
+            connect_timeout: None,
             reconnect: true,
             // None means infinity
             max_reconnect_attempts: None,
-            reconnect_delay_min: 1000,
-            reconnect_delay_max: 5000,
+            reconnect_delay_min: Duration::from_secs(1),
+            reconnect_delay_max: Duration::from_secs(5),
+            reconnect_randomization: 0.5,
+            backoff_reset: BackoffReset::default(),
+            recorder: None,
+            wire_log: None,
+            cipher: None,
+            compression_threshold: None,
+            handlers: HashMap::new(),
+            coalesce: HashSet::new(),
+            sticky: HashSet::new(),
+            sticky_payloads: Default::default(),
+            subscriptions: Vec::new(),
+            recovery_bytes: Default::default(),
+            panic_policy: Default::default(),
+            dispatch_mode: Default::default(),
+            dispatch_queue: (WORKER_QUEUE_SIZE, Default::default()),
+            address_family: AddressFamily::default(),
+            polling_option: PollingOption::default(),
+            #[cfg(feature = "tls")]
+            tls_config: None,
+            memory_limits: None,
+            max_payload: None,
+            counters: Default::default(),
+            #[cfg(feature = "testing")]
+            chaos: None,
         }
     }
 
@@ -114,6 +223,8 @@ impl ClientBuilder {
     /// Registers a new callback for a certain [`crate::event::Event`]. The event could either be
     /// one of the common events like `message`, `error`, `connect`, `close` or a custom
     /// event defined by a string, e.g. `onPayment` or `foo`.
+    /// Events are passed to the callbacks in the order they arrived with
+    /// [`DispatchMode::Ordered`] or [`DispatchMode::Pool`].
     ///
     /// # Example
     /// ```rust
@@ -201,6 +312,63 @@ impl ClientBuilder {
         self
     }
 
+    /// Registers `callback` for `event`, called with the payload deserialized
+    /// to `T`, see `Client::emit_ref` for the emitting side. A payload which
+    /// doesn't deserialize fires the `Error` handler instead.
+    pub fn on_typed<T, E, F>(self, event: E, mut callback: F) -> Self
+    where
+        T: DeserializeOwned,
+        E: Into<Event>,
+        F: FnMut(T, ClientSocket, Option<AckId>) -> BoxFuture<'static, ()> + 'static + Send + Sync,
+    {
+        let event = event.into();
+        let name = String::from(event.clone());
+        self.on(
+            event,
+            move |payload, socket: ClientSocket, ack| match Payload::deserialize(payload.as_ref()) {
+                Ok(data) => callback(data, socket, ack),
+                Err(e) => {
+                    socket.callback_error(format!("payload of {}: {}", name, e));
+                    async {}.boxed()
+                }
+            },
+        )
+    }
+
+    /// Bounds the memory of the client and reuses pre-allocated buffers, for
+    /// long-running clients on small devices.
+    pub fn memory_limits(mut self, limits: MemoryLimits) -> Self {
+        self.memory_limits = Some(limits);
+        self
+    }
+
+    /// Fails emits of more than `bytes` on the wire, attachments included, with
+    /// `Error::PayloadTooLarge` instead of sending them. Set it to the
+    /// `max_payload` of the server, which drops larger packets.
+    pub fn max_payload(mut self, bytes: usize) -> Self {
+        self.max_payload = Some(bytes);
+        self
+    }
+
+    /// Answers the health probe event of `probe` with the client metadata.
+    pub fn health_probe(self, probe: HealthProbe) -> Self {
+        let event = probe.event.clone();
+        self.on(event, move |_, socket: ClientSocket, need_ack| {
+            let answer = probe.answer(socket.rtt());
+            async move {
+                match need_ack {
+                    Some(id) => {
+                        if let Err(e) = socket.ack(id, answer).await {
+                            warn!("health probe answer failed {:?}", e);
+                        }
+                    }
+                    None => warn!("health probe without ack id"),
+                }
+            }
+            .boxed()
+        })
+    }
+
     /// Sets custom http headers for the opening request. The headers will be passed to the underlying
     /// transport type (either websockets or polling) and then get passed with every request thats made.
     /// via the transport layer.
@@ -233,6 +401,39 @@ impl ClientBuilder {
         self
     }
 
+    /// Sends `auth` in the CONNECT packet of the namespace, on every connect
+    /// and reconnect. The server hands it to its middlewares and `Connect`
+    /// handlers, e.g. `json!({ "token": token })`.
+    pub fn auth(mut self, auth: Value) -> Self {
+        self.auth = Some(auth);
+        self
+    }
+
+    /// Sets a header sent only with the websocket handshake, e.g. for gateways
+    /// which route or authenticate websocket upgrades. Headers set with
+    /// `opening_header` are sent as well.
+    pub fn websocket_header<T: Into<HeaderValue>, K: Into<String>>(
+        mut self,
+        key: K,
+        val: T,
+    ) -> Self {
+        self.websocket_headers
+            .get_or_insert_with(HeaderMap::default)
+            .insert(key.into(), val.into());
+        self
+    }
+
+    /// Subprotocols offered in the `Sec-WebSocket-Protocol` header of the
+    /// websocket handshake.
+    pub fn websocket_protocols<I, T>(mut self, protocols: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<String>,
+    {
+        self.websocket_protocols = protocols.into_iter().map(Into::into).collect();
+        self
+    }
+
     /// Specifies which EngineIO [`TransportType`] to use.
     ///
     /// # Example
@@ -290,40 +491,288 @@ impl ClientBuilder {
         client
     }
 
+    /// Fails a connection attempt to an address which is not connected to the
+    /// namespace within `timeout`, the next address is tried then.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
     pub fn reconnect(mut self, reconnect: bool) -> Self {
         self.reconnect = reconnect;
         self
     }
 
-    pub fn reconnect_delay(mut self, min: u64, max: u64) -> Self {
+    /// The delay before the first reconnect attempt, growing by half for every
+    /// further attempt up to `max`.
+    pub fn reconnect_delay(mut self, min: Duration, max: Duration) -> Self {
         self.reconnect_delay_min = min;
         self.reconnect_delay_max = max;
 
         self
     }
 
+    /// Spreads each reconnect delay randomly by up to `factor` of it in either
+    /// direction, like `randomizationFactor` of the JS client, so clients that
+    /// lost their connection at the same time don't reconnect in lockstep.
+    /// Clamped to `0.0..=1.0`, defaults to `0.5`, `0.0` disables the jitter.
+    pub fn reconnect_randomization(mut self, factor: f64) -> Self {
+        self.reconnect_randomization = factor.clamp(0.0, 1.0);
+        self
+    }
+
+    /// When the reconnect delay falls back to its minimum, see [`BackoffReset`].
+    pub fn backoff_reset(mut self, policy: BackoffReset) -> Self {
+        self.backoff_reset = policy;
+        self
+    }
+
+    /// The backoff between reconnect attempts.
+    pub(crate) fn backoff(&self) -> ExponentialBackoff {
+        ExponentialBackoffBuilder::new()
+            .with_initial_interval(self.reconnect_delay_min)
+            .with_max_interval(self.reconnect_delay_max)
+            .with_randomization_factor(self.reconnect_randomization)
+            // reconnects are bounded by `max_reconnect_attempts` only
+            .with_max_elapsed_time(None)
+            .build()
+    }
+
     pub fn max_reconnect_attempts(mut self, reconnect_attempts: usize) -> Self {
         self.max_reconnect_attempts = Some(reconnect_attempts);
         self
     }
 
+    /// Records every engine.io packet sent or received by the client.
+    pub fn recorder(mut self, recorder: Arc<engineio_rs::Recorder>) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    /// Dumps every engine.io packet sent or received by the client at trace
+    /// level, truncated and redacted as configured in `wire_log`.
+    pub fn wire_log(mut self, wire_log: engineio_rs::WireLog) -> Self {
+        self.wire_log = Some(wire_log);
+        self
+    }
+
+    /// Encrypts every packet sent and decrypts every packet received by the client.
+    /// The server must be configured with a matching cipher.
+    pub fn cipher(mut self, cipher: Arc<dyn PayloadCipher>) -> Self {
+        self.cipher = Some(cipher);
+        self
+    }
+
+    /// Runs at most `max` invocations of the handler registered for `event` at
+    /// once, excess invocations are queued or dropped according to `overflow`.
+    pub fn concurrency_limit<T: Into<Event>>(
+        mut self,
+        event: T,
+        max: usize,
+        overflow: HandlerOverflow,
+    ) -> Self {
+        self.handlers.entry(event.into()).or_default().limit = Some((max, overflow));
+        self
+    }
+
+    /// Cancels an invocation of the handler registered for `event` running longer
+    /// than `timeout`, and fires the `Error` handler.
+    pub fn handler_timeout<T: Into<Event>>(mut self, event: T, timeout: Duration) -> Self {
+        self.handlers.entry(event.into()).or_default().timeout = Some(timeout);
+        self
+    }
+
+    /// Emits `event` with `data` on connect and after every reconnect, see
+    /// `Client::subscribe`.
+    pub fn subscribe<E: Into<Event>, D: Into<Payload>>(mut self, event: E, data: D) -> Self {
+        let subscription = (event.into(), data.into());
+        if !self.subscriptions.contains(&subscription) {
+            self.subscriptions.push(subscription);
+        }
+        self
+    }
+
+    /// Collapses emits of `event` waiting behind one in flight to the latest
+    /// payload, for state like cursor positions where only the newest value
+    /// matters. An emit whose payload is replaced before it was sent fails with
+    /// `Error::SupersededEmit`.
+    pub fn coalesce<T: Into<Event>>(mut self, event: T) -> Self {
+        self.coalesce.insert(event.into());
+        self
+    }
+
+    /// Keeps the latest payload of `event` and replays it to handlers registered
+    /// with `Client::on` after it fired, e.g. `Event::Connect` for modules which
+    /// attach their handlers once the client is connected.
+    pub fn sticky<T: Into<Event>>(mut self, event: T) -> Self {
+        self.sticky.insert(event.into());
+        self
+    }
+
+    /// Whether to keep or disconnect the socket after a handler panicked.
+    pub fn panic_policy(mut self, panic_policy: PanicPolicy) -> Self {
+        self.panic_policy = panic_policy;
+        self
+    }
+
+    /// How handler invocations are scheduled, a task is spawned per event by
+    /// default.
+    pub fn dispatch_mode(mut self, dispatch_mode: DispatchMode) -> Self {
+        self.dispatch_mode = dispatch_mode;
+        self
+    }
+
+    /// Events the dispatcher buffers, 256 by default. Events beyond are handled
+    /// according to `overflow`, `DispatchOverflow::DropNew` by default.
+    pub fn dispatch_queue(mut self, size: usize, overflow: DispatchOverflow) -> Self {
+        self.dispatch_queue = (size, overflow);
+        self
+    }
+
+    /// Tries the URLs given to [`ClientBuilder::new_multi`] in random order, to
+    /// spread clients over the endpoints.
+    pub fn shuffle_addresses(mut self, shuffle: bool) -> Self {
+        self.shuffle_addresses = shuffle;
+        self
+    }
+
+    /// The address family tried first for hosts with both IPv6 and IPv4 addresses.
+    /// Connection attempts to the other family start after a short delay, so
+    /// broken IPv6 networks still connect quickly.
+    pub fn address_family(mut self, address_family: AddressFamily) -> Self {
+        self.address_family = address_family;
+        self
+    }
+
+    /// Tunes the HTTP connection pool of the polling transport, e.g. to keep
+    /// connections through proxies alive between polls.
+    pub fn polling_option(mut self, polling_option: PollingOption) -> Self {
+        self.polling_option = polling_option;
+        self
+    }
+
+    /// Negotiates TLS with `tls_config` instead of the default roots, for the
+    /// polling requests and the websocket alike, e.g. to trust the CA of
+    /// internal servers, to present a client certificate or, for local
+    /// development, to accept self-signed certificates.
+    #[cfg(feature = "tls")]
+    pub fn tls_config(mut self, tls_config: engineio_rs::TlsConfig) -> Self {
+        self.tls_config = Some(tls_config);
+        self
+    }
+
+    /// Gzips packets whose encoded size reaches `threshold` bytes, if the server
+    /// supports it. Servers which don't, like the JS implementation, are unaffected.
+    pub fn compression(mut self, threshold: usize) -> Self {
+        self.compression_threshold = Some(threshold);
+        self
+    }
+
+    /// Injects artificial network failures into the packets sent by the client,
+    /// useful to test reconnection and idempotency logic.
+    #[cfg(feature = "testing")]
+    pub fn chaos(mut self, profile: engineio_rs::ChaosProfile) -> Self {
+        self.chaos = Some(profile);
+        self
+    }
+
     #[cfg(test)]
     pub(crate) async fn connect_client(self) -> Result<Client> {
         Client::new(self.clone()).await
     }
 
-    pub(crate) async fn connect_socket(&self) -> Result<Socket<ClientSocket>> {
+    /// Connects to the first address which accepts, presenting the `session`
+    /// token, `(header, token)`, of a resumption or migration if given.
+    pub(crate) async fn connect_socket(
+        &self,
+        session: Option<(&str, &str)>,
+    ) -> Result<Socket<ClientSocket>> {
+        let mut addresses = self.addresses.clone();
+        if self.shuffle_addresses {
+            addresses.shuffle(&mut rand::thread_rng());
+        }
+
+        let mut last_err = Error::InvalidEmptyUrls();
+        for address in addresses {
+            let connect = self.connect_address(&address, session);
+            let result = match self.connect_timeout {
+                Some(timeout) => tokio::time::timeout(timeout, connect)
+                    .await
+                    .unwrap_or(Err(Error::TimedOutConnect(timeout))),
+                None => connect.await,
+            };
+            match result {
+                Ok(socket) => return Ok(socket),
+                Err(e) => {
+                    warn!("connect to {} failed: {}", address, e);
+                    last_err = e;
+                }
+            }
+        }
+        Err(last_err)
+    }
+
+    /// Connects to `address`, presenting the `session` token if given. It is
+    /// sent in its own header, apart from the auth of the opening packet.
+    pub(crate) async fn connect_address(
+        &self,
+        address: &str,
+        session: Option<(&str, &str)>,
+    ) -> Result<Socket<ClientSocket>> {
         // Parse url here rather than in new to keep new returning Self.
-        let mut url = Url::parse(&self.address)?;
+        let mut url = Url::parse(address)?;
+        check_scheme(&url)?;
 
         if url.path() == "/" {
             url.set_path("/socket.io/");
         }
+        if self.compression_threshold.is_some() {
+            url.query_pairs_mut()
+                .append_pair(compression::CAPABILITY, compression::GZIP);
+        }
+
+        let mut builder = EngineSocketBuilder::new(url)
+            .address_family(self.address_family)
+            .polling_option(self.polling_option);
+
+        let mut headers = self.opening_headers.clone();
+        if let Some((name, token)) = session {
+            headers
+                .get_or_insert_with(HeaderMap::new)
+                .insert(name.to_owned(), token);
+        }
+        if let Some(headers) = headers {
+            builder = builder.headers(headers);
+        }
+
+        #[cfg(feature = "tls")]
+        if let Some(tls_config) = &self.tls_config {
+            builder = builder.tls_config(tls_config.clone());
+        }
+
+        if let Some(headers) = &self.websocket_headers {
+            builder = builder.websocket_headers(headers.clone());
+        }
+
+        if !self.websocket_protocols.is_empty() {
+            builder = builder.websocket_protocols(self.websocket_protocols.clone());
+        }
+
+        if let Some(recorder) = &self.recorder {
+            builder = builder.recorder(recorder.clone());
+        }
 
-        let mut builder = EngineSocketBuilder::new(url);
+        if let Some(wire_log) = &self.wire_log {
+            builder = builder.wire_log(wire_log.clone());
+        }
 
-        if let Some(headers) = &self.opening_headers {
-            builder = builder.headers(headers.clone());
+        if let Some(limits) = &self.memory_limits {
+            builder = builder.channel_buf(limits.channel_size);
+        }
+
+        #[cfg(feature = "testing")]
+        if let Some(profile) = &self.chaos {
+            builder = builder.chaos(profile.clone());
         }
 
         let engine_client = match self.transport_type {
@@ -333,15 +782,192 @@ impl ClientBuilder {
             TransportType::WebsocketUpgrade => builder.build_websocket_with_upgrade().await?,
         };
 
-        let inner_socket = RawSocket::client_end(engine_client);
+        let inner_socket = RawSocket::client_end(
+            engine_client,
+            self.cipher.clone(),
+            self.compression_threshold.map(|threshold| {
+                let max_payload = self
+                    .max_payload
+                    .unwrap_or(ServerOption::default().max_payload);
+                Arc::new(Compression::new(threshold, max_payload))
+            }),
+        )
+        .with_memory_limits(self.memory_limits)
+        .with_counters(self.counters.clone())
+        .with_max_payload(self.max_payload);
         let socket = Socket::<ClientSocket>::new(
             inner_socket,
             self.namespace.clone(),
             self.on.clone(),
             Arc::new(|s| s.into()),
-        );
+            &self.handlers,
+        )
+        .with_coalesce(self.coalesce.clone())
+        .with_sticky(self.sticky.clone(), self.sticky_payloads.clone())
+        .with_recovery_bytes(self.recovery_bytes.clone())
+        .with_control_events()
+        .with_panic_policy(self.panic_policy)
+        .with_dispatcher(self.dispatch_mode.dispatcher(self.dispatch_queue, None));
 
-        socket.connect().await?;
+        socket.connect(self.auth.clone()).await?;
         Ok(socket)
     }
 }
+
+/// Parses a builder from a single URL like
+/// `wss://host:443/chat?transport=websocket&timeout=5s`, for clients configured
+/// from a file or the command line. The path of the URL is the namespace. These
+/// query options are read:
+/// - `transport`: `any`, `polling`, `websocket` or `websocket_upgrade`
+/// - `timeout`: the connect timeout, e.g. `500ms`, `5s` or `1m`
+/// - `reconnect`: `true` or `false`
+/// - `max_reconnect_attempts`: a number
+///
+/// Other query pairs are kept and sent with the handshake.
+impl FromStr for ClientBuilder {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut url = Url::parse(s)?;
+        check_scheme(&url)?;
+
+        let namespace = match url.path() {
+            "" | "/" => "/".to_owned(),
+            path => path.trim_end_matches('/').to_owned(),
+        };
+        url.set_path("/");
+
+        let mut options = Vec::new();
+        let mut query = Vec::new();
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                "transport" | "timeout" | "reconnect" | "max_reconnect_attempts" => {
+                    options.push((key.into_owned(), value.into_owned()))
+                }
+                _ => query.push((key.into_owned(), value.into_owned())),
+            }
+        }
+        if query.is_empty() {
+            url.set_query(None);
+        } else {
+            url.query_pairs_mut().clear().extend_pairs(query);
+        }
+
+        let mut builder = Self::new(url).namespace(namespace);
+        for (key, value) in options {
+            let invalid = || Error::InvalidUrlOption(format!("{}={}", key, value));
+            builder = match key.as_str() {
+                "transport" => builder.transport_type(match value.as_str() {
+                    "any" => TransportType::Any,
+                    "polling" => TransportType::Polling,
+                    "websocket" => TransportType::Websocket,
+                    "websocket_upgrade" => TransportType::WebsocketUpgrade,
+                    _ => return Err(invalid()),
+                }),
+                "timeout" => builder.connect_timeout(parse_duration(&value).ok_or_else(invalid)?),
+                "reconnect" => builder.reconnect(value.parse().map_err(|_| invalid())?),
+                _ => builder.max_reconnect_attempts(value.parse().map_err(|_| invalid())?),
+            };
+        }
+        Ok(builder)
+    }
+}
+
+/// Accepts the `ws` and `wss` URLs of JS examples besides `http` and `https`.
+fn check_scheme(url: &Url) -> Result<()> {
+    match url.scheme() {
+        "http" | "https" | "ws" | "wss" => Ok(()),
+        other => Err(Error::InvalidUrlScheme(other.to_owned())),
+    }
+}
+
+/// Parses `500ms`, `5s` or `1m`, a bare number is taken as milliseconds.
+fn parse_duration(value: &str) -> Option<Duration> {
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let amount: u64 = value[..split].parse().ok()?;
+    match &value[split..] {
+        "" | "ms" => Some(Duration::from_millis(amount)),
+        "s" => Some(Duration::from_secs(amount)),
+        "m" => Some(Duration::from_secs(amount.checked_mul(60)?)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use backoff::backoff::Backoff;
+
+    use super::*;
+
+    #[test]
+    fn test_from_str() -> Result<()> {
+        let builder: ClientBuilder =
+            "wss://example.com:443/chat?transport=websocket&timeout=5s&token=abc".parse()?;
+        assert_eq!(builder.addresses, ["wss://example.com/?token=abc"]);
+        assert_eq!(builder.namespace, "/chat");
+        assert!(builder.transport_type == TransportType::Websocket);
+        assert_eq!(builder.connect_timeout, Some(Duration::from_secs(5)));
+
+        let builder: ClientBuilder = "http://localhost:4200?reconnect=false".parse()?;
+        assert_eq!(builder.addresses, ["http://localhost:4200/"]);
+        assert_eq!(builder.namespace, "/");
+        assert!(!builder.reconnect);
+
+        assert!(matches!(
+            "ftp://localhost".parse::<ClientBuilder>(),
+            Err(Error::InvalidUrlScheme(_))
+        ));
+        assert!(matches!(
+            "http://localhost?timeout=soon".parse::<ClientBuilder>(),
+            Err(Error::InvalidUrlOption(_))
+        ));
+        let overflow = format!("http://localhost?timeout={}m", u64::MAX);
+        assert!(matches!(
+            overflow.parse::<ClientBuilder>(),
+            Err(Error::InvalidUrlOption(_))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_empty_urls() {
+        let result = ClientBuilder::new_multi(Vec::<String>::new())
+            .connect()
+            .await;
+        assert!(matches!(result, Err(Error::InvalidEmptyUrls())));
+    }
+
+    #[test]
+    fn test_reconnect_randomization() {
+        let second = Duration::from_secs(1);
+        let builder = ClientBuilder::new("http://localhost:4200").reconnect_delay(second, second);
+        let mut backoff = builder.clone().reconnect_randomization(0.0).backoff();
+        assert_eq!(backoff.next_backoff(), Some(Duration::from_millis(1000)));
+
+        let mut backoff = builder.reconnect_randomization(2.0).backoff();
+        for _ in 0..10 {
+            let delay = backoff.next_backoff().expect("no elapsed time limit hit");
+            assert!(delay <= Duration::from_millis(2000));
+        }
+    }
+
+    #[test]
+    fn test_backoff_reset() {
+        let mut backoff = ClientBuilder::new("http://localhost:4200")
+            .reconnect_delay(Duration::from_secs(1), Duration::from_secs(2))
+            .reconnect_randomization(0.0)
+            .backoff();
+        let mut next = || backoff.next_backoff().map(|delay| delay.as_millis());
+        assert_eq!(next(), Some(1000));
+        assert_eq!(next(), Some(1500));
+        assert_eq!(next(), Some(2000));
+        assert_eq!(next(), Some(2000));
+
+        assert!(BackoffReset::OnConnect.is_due(Duration::ZERO));
+        let stable = BackoffReset::AfterStable(Duration::from_secs(10));
+        assert!(!stable.is_due(Duration::from_secs(1)));
+        assert!(stable.is_due(Duration::from_secs(10)));
+    }
+}
@@ -1,16 +1,26 @@
 use std::{
     ops::{Deref, DerefMut},
-    sync::Arc,
-    time::Duration,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
 use crate::{
-    socket::Socket as InnerSocket, AckId, ClientBuilder, Error, Event, Packet, Payload, Result,
+    callback::Callback,
+    socket::{RawSocket, Socket as InnerSocket, MIGRATION_TOKEN, RESUME_TOKEN},
+    AckError, AckId, AckPolicy, ClientBuilder, ClientMetrics, DisconnectReason, Error, Event,
+    MemoryUsage, Packet, PacketType, Payload, Result,
 };
 
-use backoff::{backoff::Backoff, ExponentialBackoff, ExponentialBackoffBuilder};
+use backoff::{backoff::Backoff, ExponentialBackoff};
+use bytes::Bytes;
+use engineio_rs::Error as EngineError;
 use futures_util::future::BoxFuture;
-use tokio::sync::RwLock;
+use serde::Serialize;
+use serde_json::{json, Value};
+use tokio::sync::{watch, RwLock};
 use tracing::{trace, warn};
 
 #[derive(Clone)]
@@ -18,7 +28,15 @@ pub struct Client {
     builder: ClientBuilder,
     socket: Arc<RwLock<InnerSocket<Socket>>>,
     backoff: ExponentialBackoff,
+    // when the current connection was established, see `BackoffReset`
+    connected_at: Instant,
     connected: Arc<RwLock<bool>>,
+    // set once the poll loop stopped
+    closed: Arc<watch::Sender<bool>>,
+    // emitted again on every reconnect, see `subscribe`
+    subscriptions: Arc<RwLock<Vec<(Event, Payload)>>>,
+    // presented on the next reconnect, see `ServerBuilder::resume_sessions`
+    resume_token: Arc<RwLock<Option<String>>>,
 }
 
 #[derive(Clone)]
@@ -75,6 +93,43 @@ impl Client {
         socket.emit(event, data).await
     }
 
+    /// Emits `data` serialized straight into the outgoing packet, see
+    /// `Socket::emit_ref`.
+    #[inline]
+    pub async fn emit_ref<E, T>(&self, event: E, data: &T) -> Result<()>
+    where
+        E: Into<Event>,
+        T: Serialize + ?Sized,
+    {
+        let socket = self.socket.read().await;
+        socket.emit_ref(event, data).await
+    }
+
+    /// Emits `json` followed by `attachments` as one event, see
+    /// `Socket::emit_with_binary`.
+    #[inline]
+    pub async fn emit_with_binary<E, J>(
+        &self,
+        event: E,
+        json: J,
+        attachments: Vec<Bytes>,
+    ) -> Result<()>
+    where
+        E: Into<Event>,
+        J: Into<Value>,
+    {
+        let socket = self.socket.read().await;
+        socket.emit_with_binary(event, json, attachments).await
+    }
+
+    /// Emits `events` in order in one write of the transport, see
+    /// `Socket::emit_batch`.
+    #[inline]
+    pub async fn emit_batch(&self, events: Vec<(Event, Payload)>) -> Result<()> {
+        let socket = self.socket.read().await;
+        socket.emit_batch(events).await
+    }
+
     /// Sends a message to the server but `alloc`s an `ack` to check whether the
     /// server responded in a given time span. This message takes an event, which
     /// could either be one of the common events like "message" or "error" or a
@@ -144,11 +199,156 @@ impl Client {
         socket.emit_with_ack(event, data, timeout, callback).await
     }
 
+    /// Like `emit_with_ack`, `policy` decides what happens to the ack if the
+    /// client reconnects before it arrived.
+    pub async fn emit_with_ack_policy<F, E, D>(
+        &self,
+        event: E,
+        data: D,
+        timeout: Duration,
+        policy: AckPolicy,
+        callback: F,
+    ) -> Result<()>
+    where
+        F: for<'a> std::ops::FnMut(
+                Option<Payload>,
+                Socket,
+                Option<AckId>,
+            ) -> BoxFuture<'static, ()>
+            + 'static
+            + Send
+            + Sync,
+        E: Into<Event>,
+        D: Into<Payload>,
+    {
+        let socket = self.socket.read().await;
+        socket
+            .emit_with_ack_policy(event, data, timeout, policy, callback)
+            .await
+    }
+
+    /// Emits `event` and waits for the server to ack it, see `Socket::request`.
+    pub async fn request<E, D>(&self, event: E, data: D, timeout: Duration) -> Result<Payload>
+    where
+        E: Into<Event>,
+        D: Into<Payload>,
+    {
+        self.request_with_policy(event, data, timeout, AckPolicy::default())
+            .await
+    }
+
+    /// Like `request`, an ack failed through `policy` returns `Error::FailedAck`.
+    pub async fn request_with_policy<E, D>(
+        &self,
+        event: E,
+        data: D,
+        timeout: Duration,
+        policy: AckPolicy,
+    ) -> Result<Payload>
+    where
+        E: Into<Event>,
+        D: Into<Payload>,
+    {
+        // not locked while waiting, a reconnect replaces the socket meanwhile
+        let socket = self.socket.read().await.clone();
+        socket
+            .request_with_policy(event, data, timeout, policy)
+            .await
+    }
+
+    /// Emits `event` now and again after every reconnect, for interests like
+    /// channels the server forgets with the connection. Subscribing twice with
+    /// the same data emits it once per connection.
+    pub async fn subscribe<E, D>(&self, event: E, data: D) -> Result<()>
+    where
+        E: Into<Event>,
+        D: Into<Payload>,
+    {
+        let subscription = (event.into(), data.into());
+        {
+            let mut subscriptions = self.subscriptions.write().await;
+            if !subscriptions.contains(&subscription) {
+                let (event, data) = &subscription;
+                let size = subscription_size(event, data, &self.builder.namespace);
+                self.builder
+                    .recovery_bytes
+                    .fetch_add(size, Ordering::Relaxed);
+                subscriptions.push(subscription.clone());
+            }
+        }
+        let (event, data) = subscription;
+        self.emit(event, data).await
+    }
+
+    /// Stops emitting `event` with `data` after reconnects, returns whether it
+    /// was subscribed. Nothing is sent to the server.
+    pub async fn unsubscribe<E, D>(&self, event: E, data: D) -> bool
+    where
+        E: Into<Event>,
+        D: Into<Payload>,
+    {
+        let subscription = (event.into(), data.into());
+        let mut subscriptions = self.subscriptions.write().await;
+        let len = subscriptions.len();
+        subscriptions.retain(|s| *s != subscription);
+        let removed = subscriptions.len() != len;
+        if removed {
+            let (event, data) = &subscription;
+            let size = subscription_size(event, data, &self.builder.namespace);
+            self.builder
+                .recovery_bytes
+                .fetch_sub(size, Ordering::Relaxed);
+        }
+        removed
+    }
+
+    async fn resubscribe(&self, socket: &InnerSocket<Socket>) {
+        for (event, data) in self.subscriptions.read().await.iter() {
+            trace!("resubscribe {:?}", event);
+            if let Err(e) = socket.emit(event.clone(), data.clone()).await {
+                warn!("resubscribe to {:?} failed: {}", event, e);
+            }
+        }
+    }
+
     pub async fn ack(&self, id: usize, data: Payload) -> Result<()> {
         let socket = self.socket.read().await;
         socket.ack(id, data).await
     }
 
+    /// Registers the handler of `event` on the connected client, replacing the
+    /// previous one. If `event` was made sticky with `ClientBuilder::sticky` and
+    /// already fired, the handler is called right away with its latest payload.
+    pub async fn on<T: Into<Event>, F>(&self, event: T, callback: F)
+    where
+        F: for<'a> std::ops::FnMut(
+                Option<Payload>,
+                Socket,
+                Option<AckId>,
+            ) -> BoxFuture<'static, ()>
+            + 'static
+            + Send
+            + Sync,
+    {
+        let event = event.into();
+        self.builder
+            .on
+            .insert(event.clone(), Callback::new(callback));
+        let payload = self
+            .builder
+            .sticky_payloads
+            .get(&event)
+            .map(|payload| payload.clone());
+        if let Some(payload) = payload {
+            trace!("replay sticky {:?}", event);
+            self.socket
+                .read()
+                .await
+                .callback(&event, payload, None)
+                .await;
+        }
+    }
+
     /// Disconnects from the server by sending a socket.io `Disconnect` packet. This results
     /// in the underlying engine.io transport to get closed as well.
     pub async fn disconnect(&self) -> Result<()> {
@@ -158,7 +358,28 @@ impl Client {
             return Ok(());
         }
         *connected = false;
-        self.disconnect_socket().await
+        self.builder.counters.disconnected();
+        let socket = self.socket.read().await;
+        socket
+            .disconnect_callback(DisconnectReason::ClientDisconnect)
+            .await;
+        socket.disconnect().await
+    }
+
+    /// A snapshot of the activity of this client, e.g. to report telemetry
+    /// about its connectivity.
+    pub async fn metrics(&self) -> ClientMetrics {
+        let socket = self.socket.read().await;
+        self.builder.counters.snapshot(
+            socket.outstanding_ack_count().await,
+            socket.transport(),
+            socket.rtt(),
+        )
+    }
+
+    /// The approximate memory held for the connection, see `Socket::memory_usage`.
+    pub async fn memory_usage(&self) -> MemoryUsage {
+        self.socket.read().await.memory_usage().await
     }
 
     async fn disconnect_socket(&self) -> Result<()> {
@@ -166,26 +387,52 @@ impl Client {
         socket.disconnect().await
     }
 
-    pub(crate) async fn new(builder: ClientBuilder) -> Result<Self> {
+    pub(crate) async fn new(mut builder: ClientBuilder) -> Result<Self> {
+        // clients connected from the same builder are counted apart
+        builder.counters = Default::default();
+        builder.sticky_payloads = Default::default();
+        let recovery = builder
+            .subscriptions
+            .iter()
+            .map(|(event, data)| subscription_size(event, data, &builder.namespace))
+            .sum();
+        builder.recovery_bytes = Arc::new(AtomicUsize::new(recovery));
         let b = builder.clone();
-        let socket = b.connect_socket().await?;
+        let socket = b.connect_socket(None).await?;
+        builder.counters.connected();
         let connected = Arc::new(RwLock::new(true));
-        let backoff = ExponentialBackoffBuilder::new()
-            .with_initial_interval(Duration::from_millis(builder.reconnect_delay_min))
-            .with_max_interval(Duration::from_millis(builder.reconnect_delay_max))
-            .build();
+        let backoff = builder.backoff();
 
+        let subscriptions = builder.subscriptions.clone();
         let s = Self {
             builder,
             socket: Arc::new(RwLock::new(socket)),
             backoff,
+            connected_at: Instant::now(),
             connected,
+            closed: Arc::new(watch::channel(false).0),
+            subscriptions: Arc::new(RwLock::new(subscriptions)),
+            resume_token: Default::default(),
         };
+        s.resubscribe(&*s.socket.read().await).await;
 
         Ok(s)
     }
 
-    async fn reconnect(&mut self) {
+    /// Resolves once the connection is torn down, after `disconnect` or when it
+    /// was lost and could not be reconnected.
+    pub async fn join(&self) {
+        let mut closed = self.closed.subscribe();
+        while !*closed.borrow() {
+            if closed.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Returns whether a new connection was established. If `immediate`, the
+    /// first attempt is made without waiting for the backoff.
+    async fn reconnect(&mut self, immediate: bool) -> bool {
         let mut reconnect_attempts = 0;
         if self.builder.reconnect {
             loop {
@@ -196,23 +443,79 @@ impl Client {
                 }
                 reconnect_attempts += 1;
 
-                if let Some(backoff) = self.backoff.next_backoff() {
+                if immediate && reconnect_attempts == 1 {
+                    trace!("reconnect without backoff");
+                } else if let Some(backoff) = self.backoff.next_backoff() {
                     trace!("reconnect backoff {:?}", backoff);
                     tokio::time::sleep(backoff).await;
                 }
 
                 trace!("client reconnect {}", reconnect_attempts);
+                let attempt = json!(reconnect_attempts);
+                self.socket
+                    .read()
+                    .await
+                    .callback(&Event::Reconnecting, Some(attempt.into()), None)
+                    .await;
                 if self.do_reconnect().await.is_ok() {
-                    break;
+                    return true;
                 }
             }
         }
+        false
     }
 
     async fn do_reconnect(&self) -> Result<()> {
-        let new_socket = self.builder.clone().connect_socket().await?;
+        let token = self.resume_token.read().await.clone();
+        let session = token.as_deref().map(|token| (RESUME_TOKEN, token));
+        let new_socket = self.builder.connect_socket(session).await?;
+        // a token is single use, the server sends a new one
+        *self.resume_token.write().await = None;
+        let mut socket = self.socket.write().await;
+        *socket = new_socket;
+        self.builder.counters.reconnected();
+        Ok(())
+    }
+
+    /// Replaces the current socket, by one connected to the `(address, token)`
+    /// of a migration if given and otherwise by reconnecting, then re-sends the
+    /// unanswered emits. Returns whether a new connection was established.
+    /// With `immediate` the first reconnect attempt doesn't back off.
+    async fn recover(&mut self, migration: Option<(String, String)>, immediate: bool) -> bool {
+        if self
+            .builder
+            .backoff_reset
+            .is_due(self.connected_at.elapsed())
+        {
+            self.backoff.reset();
+        }
+        let acks = self.socket.read().await.take_acks().await;
+        let _ = self.disconnect_socket().await;
+        let migrated = match migration {
+            Some((address, token)) => self.do_migrate(&address, &token).await.is_ok(),
+            None => false,
+        };
+        let reconnected = migrated || self.reconnect(immediate).await;
+        let socket = self.socket.read().await;
+        if !reconnected {
+            for ack in acks {
+                socket.fail_ack(ack, AckError::Disconnected);
+            }
+            return false;
+        }
+        socket.resend_acks(acks).await;
+        self.resubscribe(&socket).await;
+        self.connected_at = Instant::now();
+        true
+    }
+
+    async fn do_migrate(&self, address: &str, token: &str) -> Result<()> {
+        trace!("client migrate to {}", address);
+        let session = Some((MIGRATION_TOKEN, token));
+        let new_socket = self.builder.connect_address(address, session).await?;
         let mut socket = self.socket.write().await;
         *socket = new_socket;
+        self.builder.counters.reconnected();
         Ok(())
     }
 
@@ -221,24 +524,86 @@ impl Client {
         // Use thread to consume items in iterator in order to call callbacks
         tokio::spawn(async move {
             trace!("start poll_callback ");
-            // tries to restart a poll cycle whenever a 'normal' error occurs,
-            // it just panics on network errors, in case the poll cycle returned
-            // `Result::Ok`, the server receives a close frame so it's safe to
-            // terminate
-            #[allow(clippy::for_loops_over_fallibles)]
-            loop {
+            // reconnects whenever the transport fails or closes, unless the
+            // connection was closed on purpose by either side before any
+            // announced maintenance
+            let mut maintenance = None;
+            let mut failure = DisconnectReason::TransportClose;
+            let lost = loop {
                 let packet = self_clone.poll_packet().await;
                 trace!("poll_callback packet {:?}", packet);
-                if let Some(Err(Error::IncompleteResponseFromEngineIo(_))) = packet {
-                    //TODO: logging error
-                    let _ = self_clone.disconnect_socket().await;
-                    self_clone.reconnect().await;
-                }
                 if !*self_clone.connected.read().await {
-                    break;
+                    break false;
                 }
+                match packet {
+                    None if maintenance.is_none()
+                        && !self_clone.socket.read().await.is_connected() =>
+                    {
+                        break false
+                    }
+                    // the server forgot the session, e.g. it restarted, so a
+                    // new one is opened right away instead of retrying it
+                    Some(Err(Error::IncompleteResponseFromEngineIo(
+                        EngineError::InvalidSession(),
+                    ))) => {
+                        trace!("session unknown to the server, renewing it");
+                        failure = DisconnectReason::TransportError;
+                        maintenance = None;
+                        if !self_clone.recover(None, true).await {
+                            break true;
+                        }
+                    }
+                    None | Some(Err(Error::IncompleteResponseFromEngineIo(_))) => {
+                        // a server closing the websocket is a close, not an error
+                        failure = match &packet {
+                            Some(Err(Error::IncompleteResponseFromEngineIo(e)))
+                                if !e.is_closed() =>
+                            {
+                                DisconnectReason::TransportError
+                            }
+                            _ => DisconnectReason::TransportClose,
+                        };
+                        if let Some(delay) = maintenance.take() {
+                            let delay = jitter(delay);
+                            trace!("reconnect after maintenance in {:?}", delay);
+                            tokio::time::sleep(delay).await;
+                        }
+                        if !self_clone.recover(None, false).await {
+                            break true;
+                        }
+                    }
+                    Some(Ok(packet)) => {
+                        let nsp = &self_clone.builder.namespace;
+                        if let Some(token) = resume_token(&packet, nsp) {
+                            *self_clone.resume_token.write().await = Some(token);
+                        }
+                        if let Some(delay) = maintenance_delay(&packet, nsp) {
+                            maintenance = Some(delay);
+                        }
+                        if let Some(target) = migration_target(&packet, nsp) {
+                            if !self_clone.recover(Some(target), false).await {
+                                break true;
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            };
+
+            *self_clone.connected.write().await = false;
+            self_clone.builder.counters.disconnected();
+            if lost {
+                warn!("poll_callback exit, connection lost");
+                let socket = self_clone.socket.read().await;
+                let reason = json!("connection lost");
+                socket
+                    .callback(&Event::Error, Some(reason.into()), None)
+                    .await;
+                socket.callback(&Event::Close, None, None).await;
+                socket.disconnect_callback(failure).await;
             }
-            warn!("poll_callback exist");
+            trace!("poll_callback exit");
+            self_clone.closed.send_replace(true);
         });
     }
 
@@ -248,6 +613,68 @@ impl Client {
     }
 }
 
+/// The resume token sent by the server with the CONNECT packet or a `Resume`
+/// event of the namespace `nsp` of the client.
+fn resume_token(packet: &Packet, nsp: &str) -> Option<String> {
+    if packet.nsp != nsp {
+        return None;
+    }
+    let token = match packet.ptype {
+        PacketType::Connect => packet.data.as_ref()?.get("pid")?,
+        PacketType::Event => {
+            let contents = packet.data.as_ref()?.as_array()?;
+            if Event::from(contents.first()?.as_str()?) != Event::Resume {
+                return None;
+            }
+            contents.get(1)?.get("pid")?
+        }
+        _ => return None,
+    };
+    token.as_str().map(str::to_owned)
+}
+
+/// The address and token of a `Migrate` event the server sent to the namespace
+/// `nsp` of the client.
+fn migration_target(packet: &Packet, nsp: &str) -> Option<(String, String)> {
+    if packet.ptype != PacketType::Event || packet.nsp != nsp {
+        return None;
+    }
+    let contents = packet.data.as_ref()?.as_array()?;
+    if Event::from(contents.first()?.as_str()?) != Event::Migrate {
+        return None;
+    }
+    let target = contents.get(1)?;
+    let address = target.get("url")?.as_str()?;
+    let token = target.get("token")?.as_str()?;
+    Some((address.to_owned(), token.to_owned()))
+}
+
+/// The reconnect delay suggested by a `Maintenance` event the server sent to
+/// the namespace `nsp` of the client.
+fn maintenance_delay(packet: &Packet, nsp: &str) -> Option<Duration> {
+    if packet.ptype != PacketType::Event || packet.nsp != nsp {
+        return None;
+    }
+    let contents = packet.data.as_ref()?.as_array()?;
+    if Event::from(contents.first()?.as_str()?) != Event::Maintenance {
+        return None;
+    }
+    let delay = contents.get(1)?.get("delay")?.as_u64()?;
+    Some(Duration::from_millis(delay))
+}
+
+/// The encoded size of a subscription, counted in `MemoryUsage::recovery`.
+fn subscription_size(event: &Event, data: &Payload, nsp: &str) -> usize {
+    RawSocket::build_packet_for_payload(data.clone(), Some(event.clone()), nsp, None, false)
+        .map_or(0, |packet| Bytes::from(&packet).len())
+}
+
+/// `delay` plus a random part of up to `delay`, so the clients of a restarting
+/// server come back spread over a window instead of all at once.
+fn jitter(delay: Duration) -> Duration {
+    delay + delay.mul_f64(rand::random::<f64>())
+}
+
 impl Deref for Socket {
     type Target = InnerSocket<Self>;
 
@@ -410,14 +837,55 @@ mod test {
         test_socketio_socket(socket, "/admin".to_owned()).await
     }
 
+    #[test]
+    fn test_control_events() {
+        let event = |nsp: &str, data: serde_json::Value| {
+            Packet::new(PacketType::Event, nsp.to_owned(), Some(data), None, 0, None)
+        };
+        let migrate = json!(["migrate", { "url": "http://peer", "token": "t" }]);
+
+        assert_eq!(
+            migration_target(&event("/", migrate.clone()), "/"),
+            Some(("http://peer".to_owned(), "t".to_owned()))
+        );
+        // only the server sends them, to the namespace of the client
+        assert_eq!(migration_target(&event("/chat", migrate), "/"), None);
+        let migrate = json!(["Migrate", { "url": "http://peer", "token": "t" }]);
+        assert_eq!(migration_target(&event("/", migrate), "/"), None);
+
+        let maintenance = json!(["maintenance", { "delay": 200 }]);
+        assert_eq!(
+            maintenance_delay(&event("/chat", maintenance.clone()), "/chat"),
+            Some(Duration::from_millis(200))
+        );
+        assert_eq!(maintenance_delay(&event("/", maintenance), "/chat"), None);
+
+        let resume = json!(["resume", { "pid": "p" }]);
+        assert_eq!(
+            resume_token(&event("/chat", resume.clone()), "/chat"),
+            Some("p".to_owned())
+        );
+        assert_eq!(resume_token(&event("/", resume), "/chat"), None);
+        let connect = |nsp: &str| {
+            let data = json!({ "sid": "s", "pid": "p" });
+            Packet::new(
+                PacketType::Connect,
+                nsp.to_owned(),
+                Some(data),
+                None,
+                0,
+                None,
+            )
+        };
+        assert_eq!(resume_token(&connect("/"), "/"), Some("p".to_owned()));
+        assert_eq!(resume_token(&connect("/admin"), "/"), None);
+    }
+
     async fn test_socketio_socket(socket: Client, nsp: String) -> Result<()> {
         // ignore connect packet
         let _: Option<Packet> = Some(socket.poll_packet().await.unwrap()?);
 
-        let packet: Option<Packet> = Some(socket.poll_packet().await.unwrap()?);
-        assert!(packet.is_some());
-
-        let packet = packet.unwrap();
+        let packet: Packet = socket.poll_packet().await.unwrap()?;
         assert_eq!(
             packet,
             Packet::new(
@@ -430,10 +898,7 @@ mod test {
             )
         );
 
-        let packet: Option<Packet> = Some(socket.poll_packet().await.unwrap()?);
-        assert!(packet.is_some());
-
-        let packet = packet.unwrap();
+        let packet: Packet = socket.poll_packet().await.unwrap()?;
         assert_eq!(
             packet,
             Packet::new(
@@ -446,10 +911,7 @@ mod test {
             )
         );
 
-        let packet: Option<Packet> = Some(socket.poll_packet().await.unwrap()?);
-        assert!(packet.is_some());
-
-        let packet = packet.unwrap();
+        let packet: Packet = socket.poll_packet().await.unwrap()?;
         match packet.data {
             Some(serde_json::Value::Array(array)) => assert_eq!(array.len(), 5),
             _ => panic!("invlaid emit multi payload"),
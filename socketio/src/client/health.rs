@@ -0,0 +1,75 @@
+use std::time::Duration;
+
+use serde_json::{json, Map, Value};
+
+/// Answers a health probe event of the server with metadata of the client, so
+/// device fleets can be checked without a handler in every firmware. The server
+/// sends the probe with an ack, e.g. through `Socket::request`.
+#[derive(Debug, Clone)]
+pub struct HealthProbe {
+    pub(crate) event: String,
+    version: Option<String>,
+    platform: String,
+    extra: Map<String, Value>,
+}
+
+impl HealthProbe {
+    /// Answers the event `event`, reporting the build target as platform.
+    pub fn new<T: Into<String>>(event: T) -> Self {
+        Self {
+            event: event.into(),
+            version: None,
+            platform: format!("{}-{}", std::env::consts::OS, std::env::consts::ARCH),
+            extra: Map::new(),
+        }
+    }
+
+    /// The version reported, e.g. of the firmware running the client.
+    pub fn version<T: Into<String>>(mut self, version: T) -> Self {
+        self.version = Some(version.into());
+        self
+    }
+
+    pub fn platform<T: Into<String>>(mut self, platform: T) -> Self {
+        self.platform = platform.into();
+        self
+    }
+
+    /// Adds a field to every answer.
+    pub fn extra<K: Into<String>, V: Into<Value>>(mut self, key: K, value: V) -> Self {
+        self.extra.insert(key.into(), value.into());
+        self
+    }
+
+    /// The answer, `rtt` is the round trip of the last acked emit to the server.
+    pub(crate) fn answer(&self, rtt: Option<Duration>) -> Value {
+        let mut answer = self.extra.clone();
+        answer.insert("version".to_owned(), json!(self.version));
+        answer.insert("platform".to_owned(), json!(self.platform));
+        let rtt = rtt.map(|rtt| rtt.as_millis() as u64);
+        answer.insert("rtt_ms".to_owned(), json!(rtt));
+        Value::Object(answer)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_answer() {
+        let probe = HealthProbe::new("health")
+            .version("2.1.0")
+            .platform("gateway-arm")
+            .extra("site", "berlin");
+
+        assert_eq!(
+            probe.answer(Some(Duration::from_millis(42))),
+            json!({"version": "2.1.0", "platform": "gateway-arm", "rtt_ms": 42, "site": "berlin"})
+        );
+        assert_eq!(
+            HealthProbe::new("health").answer(None)["rtt_ms"],
+            Value::Null
+        );
+    }
+}
@@ -0,0 +1,83 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+
+/// A snapshot of the activity of a [`crate::Client`] since it was connected,
+/// see `Client::metrics`. With the `metrics` feature the counters are also
+/// reported to the `metrics` facade as `socketio_client_*`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientMetrics {
+    /// Events and acks sent, resent ones included.
+    pub emits_sent: u64,
+    /// Events received from the server.
+    pub events_received: u64,
+    /// Emits waiting for their ack.
+    pub acks_outstanding: usize,
+    /// Connections re-established after the previous one was lost, or moved
+    /// to another server.
+    pub reconnects: u64,
+    /// `"websocket"` or `"polling"`, `None` while disconnected.
+    pub transport: Option<&'static str>,
+    /// How long the current connection has been up.
+    pub connected_for: Option<Duration>,
+    /// How long the server took to answer the last acked emit.
+    pub rtt: Option<Duration>,
+}
+
+/// The counters of a client, shared by the sockets of its connections.
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    emits_sent: AtomicU64,
+    events_received: AtomicU64,
+    reconnects: AtomicU64,
+    connected_at: Mutex<Option<Instant>>,
+}
+
+impl Counters {
+    pub(crate) fn emitted(&self) {
+        self.emits_sent.fetch_add(1, Ordering::Relaxed);
+        #[cfg(feature = "metrics")]
+        ::metrics::counter!("socketio_client_emits_sent").increment(1);
+    }
+
+    pub(crate) fn received(&self) {
+        self.events_received.fetch_add(1, Ordering::Relaxed);
+        #[cfg(feature = "metrics")]
+        ::metrics::counter!("socketio_client_events_received").increment(1);
+    }
+
+    pub(crate) fn connected(&self) {
+        *self.connected_at.lock() = Some(Instant::now());
+    }
+
+    pub(crate) fn reconnected(&self) {
+        self.connected();
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+        #[cfg(feature = "metrics")]
+        ::metrics::counter!("socketio_client_reconnects").increment(1);
+    }
+
+    pub(crate) fn disconnected(&self) {
+        *self.connected_at.lock() = None;
+    }
+
+    pub(crate) fn snapshot(
+        &self,
+        acks_outstanding: usize,
+        transport: Option<&'static str>,
+        rtt: Option<Duration>,
+    ) -> ClientMetrics {
+        ClientMetrics {
+            emits_sent: self.emits_sent.load(Ordering::Relaxed),
+            events_received: self.events_received.load(Ordering::Relaxed),
+            acks_outstanding,
+            reconnects: self.reconnects.load(Ordering::Relaxed),
+            transport,
+            connected_for: self.connected_at.lock().map(|at| at.elapsed()),
+            rtt,
+        }
+    }
+}
@@ -1,6 +1,10 @@
 pub(crate) mod builder;
 #[allow(clippy::module_inception)]
 pub(crate) mod client;
+pub(crate) mod health;
+pub(crate) mod metrics;
 
-pub use builder::{ClientBuilder, TransportType};
+pub use self::metrics::ClientMetrics;
+pub use builder::{BackoffReset, ClientBuilder, TransportType};
 pub use client::{Client, Socket};
+pub use health::HealthProbe;
@@ -0,0 +1,118 @@
+use std::{
+    io::{Read, Write},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::error::{Error, Result};
+use bytes::Bytes;
+use flate2::{read::GzDecoder, write::GzEncoder};
+
+/// Key of the capability flag exchanged in the engine.io handshake: in the query
+/// of the client request and in the OPEN packet of the server.
+pub(crate) const CAPABILITY: &str = "compression";
+/// The only supported algorithm.
+pub(crate) const GZIP: &str = "gzip";
+
+// a `socket.io` text packet always starts with an ascii digit, so a gzip stream
+// is never mistaken for a plain packet
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Compresses encoded packets above a size threshold once the peer is known to
+/// support it.
+#[derive(Debug)]
+pub(crate) struct Compression {
+    threshold: usize,
+    // bound of a decompressed packet
+    max_payload: usize,
+    enabled: AtomicBool,
+}
+
+impl Compression {
+    pub(crate) fn new(threshold: usize, max_payload: usize) -> Self {
+        Self {
+            threshold,
+            max_payload,
+            enabled: AtomicBool::new(false),
+        }
+    }
+
+    /// Enables compression if the peer advertised the capability.
+    pub(crate) fn negotiate(&self, capability: Option<&str>) -> bool {
+        let supported = capability == Some(GZIP);
+        if supported {
+            self.enabled.store(true, Ordering::Release);
+        }
+        supported
+    }
+
+    /// Returns the compressed `data`, or `None` if it should be sent as is.
+    pub(crate) fn compress(&self, data: &[u8]) -> Result<Option<Bytes>> {
+        if !self.enabled.load(Ordering::Acquire) || data.len() < self.threshold {
+            return Ok(None);
+        }
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data)?;
+        Ok(Some(Bytes::from(encoder.finish()?)))
+    }
+
+    /// Decompresses `data` if compression was negotiated and it is a gzip stream,
+    /// otherwise returns it unchanged. Fails once more than `max_payload` bytes
+    /// were decompressed.
+    pub(crate) fn decompress(&self, data: Bytes) -> Result<Bytes> {
+        if !self.enabled.load(Ordering::Acquire) || !data.starts_with(&GZIP_MAGIC) {
+            return Ok(data);
+        }
+        let mut decoded = Vec::new();
+        GzDecoder::new(&data[..])
+            .take(self.max_payload as u64 + 1)
+            .read_to_end(&mut decoded)?;
+        if decoded.len() > self.max_payload {
+            return Err(Error::DecompressedTooLarge(self.max_payload));
+        }
+        Ok(Bytes::from(decoded))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn gzip(data: &[u8]) -> Result<Bytes> {
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data)?;
+        Ok(Bytes::from(encoder.finish()?))
+    }
+
+    #[test]
+    fn test_compression() -> Result<()> {
+        let compression = Compression::new(16, 1024);
+        let data = Bytes::from(format!("2[\"message\",\"{}\"]", "a".repeat(64)));
+
+        // not negotiated yet, a gzip stream is passed on as is
+        assert_eq!(compression.compress(&data)?, None);
+        let gzipped = gzip(&data)?;
+        assert_eq!(compression.decompress(gzipped.clone())?, gzipped);
+
+        assert!(!compression.negotiate(None));
+        assert!(!compression.negotiate(Some("zstd")));
+        assert!(compression.negotiate(Some(GZIP)));
+
+        let compressed = compression.compress(&data)?.unwrap();
+        assert!(compressed.len() < data.len());
+        assert_eq!(compression.decompress(compressed)?, data);
+
+        // below threshold
+        let small = Bytes::from_static(b"2[\"message\"]");
+        assert_eq!(compression.compress(&small)?, None);
+        assert_eq!(compression.decompress(small.clone())?, small);
+
+        // a bomb stops at the limit
+        let bomb = gzip(&vec![b'a'; 1 << 20])?;
+        assert!(matches!(
+            compression.decompress(bomb),
+            Err(Error::DecompressedTooLarge(1024))
+        ));
+        assert_eq!(compression.decompress(gzip(&[b'a'; 1024])?)?.len(), 1024);
+        Ok(())
+    }
+}
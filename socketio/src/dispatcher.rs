@@ -0,0 +1,386 @@
+use std::{
+    collections::VecDeque,
+    fmt::Debug,
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, AtomicU8, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+};
+
+use futures_util::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use tokio::{runtime::Handle, sync::Notify, task::JoinHandle};
+use tracing::warn;
+
+// jobs a worker buffers before the `DispatchOverflow` of the dispatcher applies
+pub(crate) const WORKER_QUEUE_SIZE: usize = 256;
+
+/// How handler invocations are scheduled. Except for `Parallel`, the events of
+/// one socket are handled one after another, in the order they arrived.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DispatchMode {
+    /// Every socket handles its events in order on its own task.
+    Ordered,
+    /// Spawn a task for every event, handlers of one socket may run concurrently
+    /// and complete in any order.
+    #[default]
+    Parallel,
+    /// Run handlers on a fixed number of workers. All events of a socket go to
+    /// the same worker, so a slow handler delays other sockets on that worker.
+    Pool(usize),
+}
+
+impl DispatchMode {
+    /// Creates and starts the dispatcher of a socket, a pool is created per call.
+    pub(crate) fn dispatcher(
+        self,
+        (queue_size, overflow): (usize, DispatchOverflow),
+        runtime: Option<&Handle>,
+    ) -> Option<Arc<Dispatcher>> {
+        let size = match self {
+            DispatchMode::Ordered => 1,
+            DispatchMode::Parallel => return None,
+            DispatchMode::Pool(size) => size,
+        };
+        let dispatcher = Dispatcher::new(size, queue_size, overflow);
+        dispatcher.start(runtime);
+        Some(Arc::new(dispatcher))
+    }
+}
+
+/// What happens to an event of a namespace whose handler invocations reached
+/// the limit, see `ServerBuilder::dispatch_limit`, or to an event whose
+/// dispatcher worker has a full queue, see `ServerBuilder::dispatch_queue`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DispatchOverflow {
+    /// Skip the new event.
+    #[default]
+    DropNew,
+    /// Skip the oldest event whose handler did not start yet in favour of the
+    /// new one, or the new one if all handlers are running.
+    DropOldest,
+    /// Stop reading from the transport of the socket until a handler finished.
+    Pushback,
+}
+
+type Job = BoxFuture<'static, ()>;
+
+const WAITING: u8 = 0;
+const STARTED: u8 = 1;
+const CANCELLED: u8 = 2;
+
+/// Bounds the handler invocations in flight, queued or running, of all sockets
+/// of a namespace.
+#[derive(Debug)]
+#[cfg_attr(not(feature = "server"), allow(dead_code))]
+pub(crate) struct DispatchLimit {
+    max: usize,
+    overflow: DispatchOverflow,
+    admitted: Mutex<VecDeque<Arc<AtomicU8>>>,
+    released: Notify,
+}
+
+#[cfg_attr(not(feature = "server"), allow(dead_code))]
+impl DispatchLimit {
+    pub(crate) fn new(max: usize, overflow: DispatchOverflow) -> Self {
+        Self {
+            max: max.max(1),
+            overflow,
+            admitted: Default::default(),
+            released: Notify::new(),
+        }
+    }
+
+    /// Admits an invocation, waiting for a slot with `DispatchOverflow::Pushback`.
+    /// Returns `None` if the invocation is dropped.
+    pub(crate) async fn admit(self: &Arc<Self>) -> Option<Admission> {
+        loop {
+            // registered before checking, so no release is missed
+            let released = self.released.notified();
+            {
+                let mut admitted = self.lock();
+                if admitted.len() >= self.max {
+                    match self.overflow {
+                        DispatchOverflow::DropNew => return None,
+                        DispatchOverflow::DropOldest => {
+                            let oldest = admitted.iter().position(|state| {
+                                state
+                                    .compare_exchange(
+                                        WAITING,
+                                        CANCELLED,
+                                        Ordering::AcqRel,
+                                        Ordering::Acquire,
+                                    )
+                                    .is_ok()
+                            })?;
+                            admitted.remove(oldest);
+                            warn!("dispatch limit reached, oldest waiting event dropped");
+                        }
+                        DispatchOverflow::Pushback => {}
+                    }
+                }
+                if admitted.len() < self.max {
+                    let state = Arc::new(AtomicU8::new(WAITING));
+                    admitted.push_back(state.clone());
+                    return Some(Admission {
+                        limit: self.clone(),
+                        state,
+                    });
+                }
+            }
+            released.await;
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, VecDeque<Arc<AtomicU8>>> {
+        self.admitted.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// A slot of a `DispatchLimit`, released when dropped.
+#[cfg_attr(not(feature = "server"), allow(dead_code))]
+pub(crate) struct Admission {
+    limit: Arc<DispatchLimit>,
+    state: Arc<AtomicU8>,
+}
+
+impl Admission {
+    /// Marks the invocation as running, returns `false` if it was dropped
+    /// in favour of a newer one.
+    pub(crate) fn start(&self) -> bool {
+        self.state
+            .compare_exchange(WAITING, STARTED, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+    }
+}
+
+impl Drop for Admission {
+    fn drop(&mut self) {
+        let mut admitted = self.limit.lock();
+        if let Some(index) = admitted.iter().position(|s| Arc::ptr_eq(s, &self.state)) {
+            admitted.remove(index);
+        }
+        drop(admitted);
+        self.limit.released.notify_waiters();
+    }
+}
+
+/// Spawns `future` on `runtime`, or on the current runtime if there is none.
+pub(crate) fn spawn<F>(runtime: Option<&Handle>, future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    match runtime {
+        Some(handle) => handle.spawn(future),
+        None => tokio::spawn(future),
+    }
+}
+
+/// The jobs of a worker waiting to run.
+#[derive(Default)]
+struct Queue {
+    jobs: Mutex<VecDeque<Job>>,
+    // set once the dispatcher is dropped, the worker stops when it ran the rest
+    closed: AtomicBool,
+    pushed: Notify,
+    popped: Notify,
+}
+
+impl Queue {
+    fn lock(&self) -> MutexGuard<'_, VecDeque<Job>> {
+        self.jobs.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Debug for Queue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Queue")
+            .field("jobs", &self.lock().len())
+            .field("closed", &self.closed)
+            .finish()
+    }
+}
+
+/// A fixed-size pool of worker tasks.
+#[derive(Debug)]
+pub(crate) struct Dispatcher {
+    queues: Vec<Arc<Queue>>,
+    queue_size: usize,
+    overflow: DispatchOverflow,
+    started: AtomicBool,
+}
+
+impl Dispatcher {
+    pub(crate) fn new(size: usize, queue_size: usize, overflow: DispatchOverflow) -> Self {
+        Self {
+            queues: (0..size.max(1)).map(|_| Default::default()).collect(),
+            queue_size: queue_size.max(1),
+            overflow,
+            started: AtomicBool::new(false),
+        }
+    }
+
+    /// Spawns the worker tasks, does nothing if they are running already.
+    pub(crate) fn start(&self, runtime: Option<&Handle>) {
+        if self.started.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        for queue in &self.queues {
+            let queue = queue.clone();
+            spawn(runtime, async move {
+                loop {
+                    let pushed = queue.pushed.notified();
+                    let job = queue.lock().pop_front();
+                    match job {
+                        Some(job) => {
+                            queue.popped.notify_waiters();
+                            job.await;
+                        }
+                        None if queue.closed.load(Ordering::Acquire) => break,
+                        None => pushed.await,
+                    }
+                }
+            });
+        }
+    }
+
+    /// Queues `job` on the worker owning `key`. Only waits for room in a full
+    /// queue with `DispatchOverflow::Pushback`.
+    pub(crate) async fn dispatch(&self, key: usize, job: Job) {
+        let queue = &self.queues[key % self.queues.len()];
+        loop {
+            // registered before checking, so no pop is missed
+            let popped = queue.popped.notified();
+            {
+                let mut jobs = queue.lock();
+                // reported once each time the queue fills up to 80%
+                if jobs.len() == (self.queue_size * 4).div_ceil(5) {
+                    warn!(
+                        "dispatcher worker queue near capacity, {} jobs",
+                        self.queue_size
+                    );
+                    #[cfg(feature = "metrics")]
+                    ::metrics::counter!("socketio_dispatch_queue_near_capacity").increment(1);
+                }
+                if jobs.len() >= self.queue_size {
+                    match self.overflow {
+                        DispatchOverflow::DropNew => {
+                            warn!("dispatcher worker queue full, new event dropped");
+                            return;
+                        }
+                        DispatchOverflow::DropOldest => {
+                            jobs.pop_front();
+                            warn!("dispatcher worker queue full, oldest event dropped");
+                        }
+                        DispatchOverflow::Pushback => {}
+                    }
+                }
+                if jobs.len() < self.queue_size {
+                    jobs.push_back(job);
+                    drop(jobs);
+                    queue.pushed.notify_one();
+                    return;
+                }
+            }
+            popped.await;
+        }
+    }
+}
+
+impl Drop for Dispatcher {
+    fn drop(&mut self) {
+        for queue in &self.queues {
+            queue.closed.store(true, Ordering::Release);
+            queue.pushed.notify_one();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures_util::FutureExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_dispatch_order() {
+        let dispatcher = Dispatcher::new(2, WORKER_QUEUE_SIZE, DispatchOverflow::Pushback);
+        dispatcher.start(None);
+        dispatcher.start(None);
+
+        let order = Arc::new(Mutex::new(vec![]));
+        for i in 0..10u64 {
+            let order = order.clone();
+            let job = async move {
+                tokio::time::sleep(std::time::Duration::from_millis(10 - i)).await;
+                order.lock().unwrap().push(i);
+            };
+            dispatcher.dispatch(7, job.boxed()).await;
+        }
+
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        assert_eq!(*order.lock().unwrap(), (0..10).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_overflow() {
+        let run = |overflow| async move {
+            // not started, so the queue fills up
+            let dispatcher = Dispatcher::new(1, 2, overflow);
+            let ran = Arc::new(Mutex::new(vec![]));
+            for i in 0..3 {
+                let ran = ran.clone();
+                let job = async move { ran.lock().unwrap().push(i) };
+                dispatcher.dispatch(0, job.boxed()).await;
+            }
+            dispatcher.start(None);
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            let ran = ran.lock().unwrap().clone();
+            ran
+        };
+        assert_eq!(run(DispatchOverflow::DropNew).await, vec![0, 1]);
+        assert_eq!(run(DispatchOverflow::DropOldest).await, vec![1, 2]);
+
+        let dispatcher = Arc::new(Dispatcher::new(1, 1, DispatchOverflow::Pushback));
+        dispatcher.dispatch(0, async {}.boxed()).await;
+        let dispatcher_clone = dispatcher.clone();
+        let pushed_back =
+            tokio::spawn(async move { dispatcher_clone.dispatch(0, async {}.boxed()).await });
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(!pushed_back.is_finished());
+        dispatcher.start(None);
+        pushed_back.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_limit() {
+        let limit = Arc::new(DispatchLimit::new(2, DispatchOverflow::DropNew));
+        let first = limit.admit().await.unwrap();
+        let _second = limit.admit().await.unwrap();
+        assert!(limit.admit().await.is_none());
+        drop(first);
+        assert!(limit.admit().await.is_some());
+
+        let limit = Arc::new(DispatchLimit::new(2, DispatchOverflow::DropOldest));
+        let running = limit.admit().await.unwrap();
+        assert!(running.start());
+        let waiting = limit.admit().await.unwrap();
+        let newest = limit.admit().await.unwrap();
+        // the running invocation is kept, the waiting one dropped
+        assert!(!waiting.start());
+        assert!(newest.start());
+        assert!(limit.admit().await.is_none());
+
+        let limit = Arc::new(DispatchLimit::new(1, DispatchOverflow::Pushback));
+        let first = limit.admit().await.unwrap();
+        let limit_clone = limit.clone();
+        let pushed_back = tokio::spawn(async move { limit_clone.admit().await.is_some() });
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(!pushed_back.is_finished());
+        drop(first);
+        assert!(pushed_back.await.unwrap());
+    }
+}
@@ -1,8 +1,11 @@
+use crate::ack::AckError;
 use base64::DecodeError;
+use bytes::Bytes;
 use serde_json::Error as JsonError;
 use std::io::Error as IoError;
 use std::num::ParseIntError;
 use std::str::Utf8Error;
+use std::time::Duration;
 use thiserror::Error;
 use url::ParseError as UrlParseError;
 
@@ -18,14 +21,21 @@ pub enum Error {
     IncompletePacket(),
     #[error("Got an invalid packet which did not follow the protocol format")]
     InvalidPacket(),
+    /// The reason and the raw packet which violated the protocol.
+    #[error("Peer violated the protocol: {0}")]
+    ProtocolViolation(String, Bytes),
     #[error("An error occurred while decoding the utf-8 text: {0}")]
     InvalidUtf8(#[from] Utf8Error),
     #[error("An error occurred while encoding/decoding base64: {0}")]
     InvalidBase64(#[from] DecodeError),
     #[error("Invalid Url during parsing")]
     InvalidUrl(#[from] UrlParseError),
+    #[error("No Url given to connect to")]
+    InvalidEmptyUrls(),
     #[error("Invalid Url Scheme: {0}")]
     InvalidUrlScheme(String),
+    #[error("Invalid Url option: {0}")]
+    InvalidUrlOption(String),
     #[error("Got illegal handshake response: {0}")]
     InvalidHandshake(String),
     #[error("Called an action before the connection was established")]
@@ -42,10 +52,38 @@ pub enum Error {
     IncompleteResponseFromEngineIo(#[from] engineio_rs::Error),
     #[error("Invalid packet type while reading attachments")]
     InvalidAttachmentPacketType(u8),
+    #[error("Payload with {0} binary parts, a packet holds at most 255")]
+    InvalidAttachmentCount(usize),
+    #[error("Invalid encrypted payload: {0}")]
+    InvalidCiphertext(String),
+    #[error("Invalid handler argument: {0}")]
+    InvalidHandlerArgument(String),
     #[error("Invalid reconnect: {0}")]
     InvalidReconnect(String),
+    #[error("Packet of {0} bytes exceeds the memory limit")]
+    InvalidPacketSize(usize),
+    #[error("Payload of {0} bytes exceeds the limit of {1} bytes")]
+    PayloadTooLarge(usize, usize),
+    #[error("Decompressed payload exceeds the limit of {0} bytes")]
+    DecompressedTooLarge(usize),
+    #[error("Socket holds {0} bytes, above its memory cap")]
+    ExceededMemoryCap(usize),
+    #[error("No ack received within {0:?}")]
+    TimedOutAck(Duration),
+    #[error("No connection established within {0:?}")]
+    TimedOutConnect(Duration),
+    #[error("Ack failed: {0}")]
+    FailedAck(#[from] AckError),
+    #[error("Session store error: {0}")]
+    IncompleteSessionStore(String),
     #[error("Underlying Engine.IO connection has closed")]
     StoppedEngineIoSocket,
+    #[error("Room {0} is full with {1} sockets")]
+    RoomFull(String, usize),
+    #[error("Event {0} is reserved and can't be emitted")]
+    InvalidReservedEvent(String),
+    #[error("Coalesced emit was replaced by a newer payload before it was sent")]
+    SupersededEmit(),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -58,7 +96,7 @@ impl<T> From<std::sync::PoisonError<T>> for Error {
 
 impl From<Error> for std::io::Error {
     fn from(err: Error) -> std::io::Error {
-        std::io::Error::new(std::io::ErrorKind::Other, err)
+        std::io::Error::other(err)
     }
 }
 
@@ -76,7 +114,7 @@ mod tests {
         assert!(matches!(Error::InvalidPoisonedLock(), _error));
 
         let _io_error = std::io::Error::from(Error::IncompletePacket());
-        let _error = std::io::Error::new(std::io::ErrorKind::Other, Error::IncompletePacket());
+        let _error = std::io::Error::other(Error::IncompletePacket());
         assert!(matches!(_io_error, _error));
     }
 }
@@ -1,3 +1,7 @@
+use serde_json::Value;
+
+use crate::{Error, Payload, Result};
+
 /// An `Event` in `socket.io` could either (`Message`, `Error`) or custom.
 #[derive(Debug, PartialEq, PartialOrd, Clone, Eq, Hash)]
 pub enum Event {
@@ -6,15 +10,185 @@ pub enum Event {
     Custom(String),
     Connect,
     Close,
+    /// Emitted on the server when a client stops fetching its packets fast
+    /// enough, see `ServerBuilder::slow_consumer`.
+    SlowConsumer,
+    /// Emitted on the client before each reconnect attempt, with the attempt
+    /// number as payload.
+    Reconnecting,
+    /// Emitted on the client when a heartbeat of the server arrives.
+    Ping,
+    /// Emitted on the server when a client answers a heartbeat.
+    Pong,
+    /// Emitted on the client when the server hands it over to another instance,
+    /// see `Server::migrate`. The client moves on its own.
+    Migrate,
+    /// Emitted on the client when the server announces a restart, see
+    /// `Server::announce_maintenance`. Once the connection drops, the client
+    /// reconnects on its own after the suggested delay plus a random jitter.
+    Maintenance,
+    /// Emitted on the client when the server rotates its resume token, see
+    /// `ServerBuilder::resume_sessions`. The client keeps the token on its own.
+    Resume,
+    /// Emitted on both sides once a socket is disconnected, with the
+    /// [`DisconnectReason`] as payload.
+    Disconnect,
+}
+
+/// Why a socket was disconnected, the payload of `Event::Disconnect`. Named
+/// like the reasons of the JS implementation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The server is closing, see `Server::close`.
+    ServerShutdown,
+    /// The server disconnected the socket, e.g. with `Server::disconnect_sid`.
+    ServerDisconnect,
+    /// The client disconnected the socket, e.g. with `Client::disconnect`.
+    ClientDisconnect,
+    /// The peer stopped answering heartbeats.
+    PingTimeout,
+    /// The connection was closed.
+    TransportClose,
+    /// The connection failed.
+    TransportError,
+    /// The peer sent a packet which could not be decoded.
+    ParseError,
+}
+
+impl DisconnectReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::ServerShutdown => "server shutting down",
+            Self::ServerDisconnect => "io server disconnect",
+            Self::ClientDisconnect => "io client disconnect",
+            Self::PingTimeout => "ping timeout",
+            Self::TransportClose => "transport close",
+            Self::TransportError => "transport error",
+            Self::ParseError => "parse error",
+        }
+    }
+}
+
+impl TryFrom<&Payload> for DisconnectReason {
+    type Error = Error;
+
+    fn try_from(payload: &Payload) -> Result<Self> {
+        let reason = match payload {
+            Payload::Json(Value::String(reason)) => reason.as_str(),
+            _ => return Err(Error::InvalidPacket()),
+        };
+        [
+            Self::ServerShutdown,
+            Self::ServerDisconnect,
+            Self::ClientDisconnect,
+            Self::PingTimeout,
+            Self::TransportClose,
+            Self::TransportError,
+            Self::ParseError,
+        ]
+        .into_iter()
+        .find(|known| known.as_str() == reason)
+        .ok_or(Error::InvalidPacket())
+    }
+}
+
+impl From<DisconnectReason> for Payload {
+    fn from(reason: DisconnectReason) -> Self {
+        Payload::Json(Value::from(reason.as_str()))
+    }
+}
+
+impl Event {
+    /// The name of the event on the wire.
+    pub(crate) fn as_str(&self) -> &str {
+        match self {
+            Event::Message => "message",
+            Event::Connect => "open",
+            Event::Close => "close",
+            Event::SlowConsumer => "slow_consumer",
+            Event::Reconnecting => "reconnecting",
+            Event::Ping => "ping",
+            Event::Pong => "pong",
+            Event::Migrate => "migrate",
+            Event::Maintenance => "maintenance",
+            Event::Resume => "resume",
+            Event::Disconnect => "disconnect",
+            Event::Error => "error",
+            Event::Custom(string) => string,
+        }
+    }
+
+    /// Whether the library sends or fires this event itself, like `Migrate`.
+    /// User code can't emit it, so the peer can trust where it came from.
+    pub(crate) fn is_reserved(&self) -> bool {
+        !matches!(
+            Event::from(self.as_str()),
+            Event::Message | Event::Custom(_)
+        )
+    }
+
+    /// Fails with `Error::InvalidReservedEvent` if this event is reserved.
+    pub(crate) fn check_emit(&self) -> Result<()> {
+        match self.is_reserved() {
+            true => Err(Error::InvalidReservedEvent(self.as_str().to_owned())),
+            false => Ok(()),
+        }
+    }
+
+    /// Whether this is a wildcard pattern like `chat:*`.
+    pub(crate) fn is_pattern(&self) -> bool {
+        matches!(self, Event::Custom(pattern) if pattern.contains('*'))
+    }
+
+    /// Whether the custom `event` matches this pattern, where `*` matches any
+    /// sequence of characters.
+    pub(crate) fn matches(&self, event: &Event) -> bool {
+        match (self, event) {
+            (Event::Custom(pattern), Event::Custom(name)) => glob(pattern, name),
+            _ => false,
+        }
+    }
+}
+
+fn glob(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    // SAFETY: split always yields at least one element
+    let first = parts.next().unwrap();
+    let mut rest = match name.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+
+    let parts: Vec<&str> = parts.collect();
+    match parts.split_last() {
+        None => rest.is_empty(),
+        Some((last, middle)) => {
+            for part in middle {
+                match rest.find(part) {
+                    Some(index) => rest = &rest[index + part.len()..],
+                    None => return false,
+                }
+            }
+            rest.ends_with(last)
+        }
+    }
 }
 
 impl From<String> for Event {
     fn from(string: String) -> Self {
-        match &string.to_lowercase()[..] {
+        match &string[..] {
             "message" => Event::Message,
             "error" => Event::Error,
             "open" => Event::Connect,
             "close" => Event::Close,
+            "slow_consumer" => Event::SlowConsumer,
+            "reconnecting" => Event::Reconnecting,
+            "ping" => Event::Ping,
+            "pong" => Event::Pong,
+            "migrate" => Event::Migrate,
+            "maintenance" => Event::Maintenance,
+            "resume" => Event::Resume,
+            "disconnect" => Event::Disconnect,
             _ => Event::Custom(string),
         }
     }
@@ -29,11 +203,71 @@ impl From<&str> for Event {
 impl From<Event> for String {
     fn from(event: Event) -> Self {
         match event {
-            Event::Message => Self::from("message"),
-            Event::Connect => Self::from("open"),
-            Event::Close => Self::from("close"),
-            Event::Error => Self::from("error"),
             Event::Custom(string) => string,
+            event => event.as_str().to_owned(),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pattern() {
+        let pattern = Event::from("chat:*");
+        assert!(pattern.is_pattern());
+        assert!(!Event::from("chat").is_pattern());
+
+        assert!(pattern.matches(&Event::from("chat:message")));
+        assert!(pattern.matches(&Event::from("chat:")));
+        assert!(!pattern.matches(&Event::from("chat")));
+        assert!(!pattern.matches(&Event::from("room:chat:message")));
+
+        let pattern = Event::from("*:update:*");
+        assert!(pattern.matches(&Event::from("user:update:name")));
+        assert!(!pattern.matches(&Event::from("user:delete:name")));
+
+        // reserved events are never dispatched to patterns
+        assert!(!Event::from("*").matches(&Event::Message));
+        assert!(Event::from("*").matches(&Event::from("anything")));
+    }
+
+    #[test]
+    fn test_reserved() {
+        for event in [
+            Event::Close,
+            Event::Error,
+            Event::Reconnecting,
+            Event::Ping,
+            Event::Pong,
+            Event::Migrate,
+            Event::Maintenance,
+            Event::Resume,
+            Event::Disconnect,
+        ] {
+            assert_eq!(Event::from(String::from(event.clone())), event);
+            assert!(event.is_reserved());
+        }
+        // names are matched exactly, a custom event may differ in case only
+        assert_eq!(Event::from("Ping"), Event::Custom("Ping".to_owned()));
+        assert!(!Event::from("Migrate").is_reserved());
+        assert!(Event::Custom("migrate".to_owned()).is_reserved());
+        assert!(!Event::Message.is_reserved());
+        assert!(matches!(
+            Event::from("resume").check_emit(),
+            Err(Error::InvalidReservedEvent(name)) if name == "resume"
+        ));
+    }
+
+    #[test]
+    fn test_disconnect_reason() {
+        let payload = Payload::from(DisconnectReason::PingTimeout);
+        assert_eq!(payload, Payload::Json(Value::from("ping timeout")));
+        assert_eq!(
+            DisconnectReason::try_from(&payload).unwrap(),
+            DisconnectReason::PingTimeout
+        );
+        assert!(DisconnectReason::try_from(&Payload::from(Value::from("bye"))).is_err());
+    }
+}
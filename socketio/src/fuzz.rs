@@ -0,0 +1,53 @@
+//! Entry points for fuzzing the decoders with arbitrary network input, see the
+//! `fuzz` directory. Not a stable API.
+
+use bytes::Bytes;
+use engineio_rs::{PacketType as EnginePacketType, ServerOption};
+
+use crate::{
+    compression::{self, Compression},
+    packet::{Packet, PacketType},
+    socket::Socket,
+    Error, Payload, Result,
+};
+
+/// Decodes `data` the way a socket reads it: as an engine.io payload whose
+/// first message is a socket.io packet, followed by its binary attachments.
+/// Compression is taken as negotiated, within the default `max_payload`.
+/// Never panics, malformed input is an error.
+pub fn decode(data: &[u8]) -> Result<Option<Payload>> {
+    let packets = match engineio_rs::fuzz::decode(data) {
+        Some(packets) => packets,
+        None => return Err(Error::InvalidPacket()),
+    };
+    let mut packets = packets.into_iter().filter(|packet| {
+        matches!(
+            packet.ptype,
+            EnginePacketType::Message | EnginePacketType::MessageBinary
+        )
+    });
+    let first = match packets.next() {
+        Some(packet) => packet,
+        None => return Ok(None),
+    };
+
+    let compression = Compression::new(0, ServerOption::default().max_payload);
+    compression.negotiate(Some(compression::GZIP));
+    let mut packet = Packet::try_from(&compression.decompress(first.data)?)?;
+    if packet.attachment_count > 0 {
+        let attachments: Vec<Bytes> = packets
+            .take(packet.attachment_count as usize)
+            .map(|packet| packet.data)
+            .collect();
+        packet.attachments = Some(attachments);
+    }
+
+    Ok(match packet.ptype {
+        PacketType::BinaryEvent | PacketType::BinaryAck => Socket::<()>::decode_binary_payload(
+            &packet.data,
+            &packet.attachments,
+            packet.ptype == PacketType::BinaryEvent,
+        ),
+        _ => Socket::<()>::decode_event_payload(&packet, packet.ptype == PacketType::Event),
+    })
+}
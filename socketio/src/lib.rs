@@ -1,9 +1,16 @@
 pub(crate) mod ack;
 pub(crate) mod callback;
+pub(crate) mod cipher;
 #[cfg(feature = "client")]
 pub(crate) mod client;
+pub(crate) mod compression;
+pub(crate) mod dispatcher;
 pub(crate) mod error;
 pub(crate) mod event;
+#[cfg(feature = "fuzz")]
+#[doc(hidden)]
+pub mod fuzz;
+pub(crate) mod memory;
 pub(crate) mod packet;
 pub(crate) mod payload;
 #[cfg(feature = "server")]
@@ -11,15 +18,41 @@ pub(crate) mod server;
 
 mod socket;
 
-pub use ack::AckId;
+pub use ack::{AckContext, AckError, AckGuard, AckId, AckPolicy};
+pub use callback::{HandlerOverflow, PanicPolicy};
+pub use cipher::PayloadCipher;
 #[cfg(feature = "client")]
-pub use client::{Client, ClientBuilder, Socket, TransportType};
+pub use client::{
+    BackoffReset, Client, ClientBuilder, ClientMetrics, HealthProbe, Socket, TransportType,
+};
+pub use dispatcher::{DispatchMode, DispatchOverflow};
+#[cfg(feature = "testing")]
+pub use engineio_rs::ChaosProfile;
+#[cfg(feature = "tls")]
+pub use engineio_rs::TlsConfig;
+pub use engineio_rs::{AddressFamily, PollingOption, Recorder, Replayer, WireLog};
+#[cfg(feature = "server")]
+pub use engineio_rs::{
+    Clock, IdGenerator, Incoming, QueueStats, SlowConsumer, TokioClock, TransportKind,
+};
 pub use error::{Error, Result};
-pub use event::Event;
+pub use event::{DisconnectReason, Event};
+#[cfg(feature = "auth-jwt")]
+pub use jsonwebtoken;
+pub use memory::{MemoryLimits, MemoryUsage};
 pub use packet::{Packet, PacketType};
-pub use payload::Payload;
+pub use payload::{Nested, Payload, RawPayload};
+#[cfg(feature = "session-redis")]
+pub use server::RedisStore;
 #[cfg(feature = "server")]
-pub use server::{Client as ServerSocket, Server, ServerBuilder};
+pub use server::{
+    AckSender, AuditStats, BroadcastOperator, Client as ServerSocket, Data, EventParts, FromEvent,
+    Handler, Handshake, Json, MemoryStore, MiddlewareResult, NamespaceConfig, NamespaceInfo,
+    NamespaceMatcher, Next, ProtocolError, ProtocolErrorKind, RoomChange, RoomsSnapshot, Sample,
+    Server, ServerBuilder, ServerConfig, SessionKey, SessionStore, SocketRef,
+};
+#[cfg(feature = "auth-jwt")]
+pub use server::{JoinTokens, JwtAuth};
 
 #[cfg(feature = "server")]
 pub(crate) type NameSpace = String;
@@ -0,0 +1,121 @@
+use bytes::{Bytes, BytesMut};
+use parking_lot::Mutex;
+
+use crate::{error::Result, packet::Packet, Error};
+
+/// Memory ceilings for long-running clients on small devices, e.g. IoT
+/// gateways, where allocator fragmentation over weeks matters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryLimits {
+    /// Bytes pre-allocated for encoding outgoing packets, reused between packets.
+    pub encode_buffer: usize,
+    /// Largest encoded packet sent, larger ones fail with `Error::InvalidPacketSize`.
+    pub max_packet_size: usize,
+    /// Capacity of the engine.io packet channel.
+    pub channel_size: usize,
+}
+
+impl Default for MemoryLimits {
+    fn default() -> Self {
+        Self {
+            encode_buffer: 4 * 1024,
+            max_packet_size: 64 * 1024,
+            channel_size: 16,
+        }
+    }
+}
+
+/// The approximate memory held for a socket, see `Socket::memory_usage`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryUsage {
+    /// Packets buffered for a polling client.
+    pub queued: usize,
+    /// Emits awaiting their ack, including the packets kept to resend them.
+    pub acks: usize,
+    /// Payloads kept to restore the connection, e.g. the subscriptions emitted
+    /// again after a reconnect.
+    pub recovery: usize,
+}
+
+impl MemoryUsage {
+    pub fn total(&self) -> usize {
+        self.queued + self.acks + self.recovery
+    }
+}
+
+/// Encodes packets into one pre-allocated buffer. The buffer is split off per
+/// packet and its allocation reclaimed once the sent packets are dropped.
+pub(crate) struct Encoder {
+    buffer: Mutex<BytesMut>,
+    limits: MemoryLimits,
+}
+
+impl Encoder {
+    // only clients are configured with memory limits
+    #[cfg_attr(not(feature = "client"), allow(dead_code))]
+    pub(crate) fn new(limits: MemoryLimits) -> Self {
+        Self {
+            buffer: Mutex::new(BytesMut::with_capacity(limits.encode_buffer)),
+            limits,
+        }
+    }
+
+    pub(crate) fn encode(&self, packet: &Packet) -> Result<Bytes> {
+        self.encode_with(|buffer| {
+            packet.encode_into(buffer);
+            Ok(())
+        })
+    }
+
+    /// Encodes a packet written by `encode` into the shared buffer.
+    pub(crate) fn encode_with<F>(&self, encode: F) -> Result<Bytes>
+    where
+        F: FnOnce(&mut BytesMut) -> Result<()>,
+    {
+        let mut buffer = self.buffer.lock();
+        // reclaims the allocation if every previous packet was dropped
+        buffer.reserve(self.limits.encode_buffer);
+        if let Err(e) = encode(&mut buffer) {
+            // drops a partly written packet
+            buffer.clear();
+            return Err(e);
+        }
+        let data = buffer.split().freeze();
+        if data.len() > self.limits.max_packet_size {
+            return Err(Error::InvalidPacketSize(data.len()));
+        }
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+    use crate::packet::PacketType;
+
+    #[test]
+    fn test_encoder() -> Result<()> {
+        let encoder = Encoder::new(MemoryLimits {
+            encode_buffer: 64,
+            max_packet_size: 32,
+            channel_size: 1,
+        });
+        let packet =
+            |data| Packet::new(PacketType::Event, "/".to_owned(), Some(data), None, 0, None);
+
+        let small = packet(json!(["ev", 1]));
+        assert_eq!(encoder.encode(&small)?, Bytes::from(&small));
+        // the same allocation is reused for every packet
+        let first = encoder.encode(&small)?.as_ptr();
+        assert_eq!(encoder.encode(&small)?.as_ptr(), first);
+
+        let large = packet(json!(["ev", "x".repeat(64)]));
+        assert!(matches!(
+            encoder.encode(&large),
+            Err(Error::InvalidPacketSize(_))
+        ));
+        Ok(())
+    }
+}
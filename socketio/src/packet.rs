@@ -1,9 +1,13 @@
 use crate::error::Error::{InvalidJson, InvalidUtf8};
 use crate::error::{Error, Result};
+use crate::Event;
 use bytes::{BufMut, Bytes, BytesMut};
+use serde::Serialize;
 use serde_json::Value;
 use std::{
+    borrow::Cow,
     convert::TryFrom,
+    fmt::Write as _,
     sync::atomic::{AtomicUsize, Ordering},
 };
 
@@ -87,47 +91,68 @@ impl Packet {
     }
 }
 
-impl From<Packet> for Bytes {
-    fn from(packet: Packet) -> Self {
-        Bytes::from(&packet)
-    }
-}
-
-impl From<&Packet> for Bytes {
-    /// Method for encoding from a `Packet` to a `u8` byte stream.
-    /// The binary payload of a packet is not put at the end of the
-    /// stream as it gets handled and send by it's own logic via the socket.
-    fn from(packet: &Packet) -> Bytes {
+impl Packet {
+    /// Appends the encoded packet to `buffer`, without intermediate strings.
+    pub(crate) fn encode_into(&self, buffer: &mut BytesMut) {
         // first the packet type
-        let mut string = (packet.ptype as u8).to_string();
+        buffer.put_u8(b'0' + self.ptype as u8);
 
         // eventually a number of attachments, followed by '-'
-        if let PacketType::BinaryAck | PacketType::BinaryEvent = packet.ptype {
-            string.push_str(&packet.attachment_count.to_string());
-            string.push('-');
+        // SAFETY: writing to a `BytesMut` never fails
+        if let PacketType::BinaryAck | PacketType::BinaryEvent = self.ptype {
+            write!(buffer, "{}-", self.attachment_count).unwrap();
         }
 
         // if the namespace is different from the default one append it as well,
         // followed by ','
-        if packet.nsp != "/" {
-            string.push_str(packet.nsp.as_ref());
-            string.push(',');
+        if self.nsp != "/" {
+            buffer.put(self.nsp.as_bytes());
+            buffer.put_u8(b',');
         }
 
         // if an id is present append it...
-        if let Some(id) = packet.id.as_ref() {
-            string.push_str(&id.to_string());
+        if let Some(id) = self.id {
+            write!(buffer, "{}", id).unwrap();
         }
 
-        let mut buffer = BytesMut::new();
-        buffer.put(string.as_ref());
-
-        if let Some(data) = &packet.data {
+        if let Some(data) = &self.data {
             // SAFETY: data is valid to serialize
-            let data = serde_json::to_string(data).unwrap();
-            buffer.put(data.as_ref());
+            serde_json::to_writer(buffer.writer(), data).unwrap();
         }
+    }
+}
+
+/// Appends an `Event` packet with the arguments `event` and `data` to `buffer`,
+/// serializing `data` in place rather than through a `Value`.
+pub(crate) fn encode_event_into<T: Serialize + ?Sized>(
+    buffer: &mut BytesMut,
+    nsp: &str,
+    event: &Event,
+    data: &T,
+) -> Result<()> {
+    buffer.put_u8(b'0' + PacketType::Event as u8);
+    if nsp != "/" {
+        buffer.put(nsp.as_bytes());
+        buffer.put_u8(b',');
+    }
+    // a tuple serializes as a JSON array
+    serde_json::to_writer(buffer.writer(), &(event.as_str(), data))?;
+    Ok(())
+}
+
+impl From<Packet> for Bytes {
+    fn from(packet: Packet) -> Self {
+        Bytes::from(&packet)
+    }
+}
 
+impl From<&Packet> for Bytes {
+    /// Method for encoding from a `Packet` to a `u8` byte stream.
+    /// The binary payload of a packet is not put at the end of the
+    /// stream as it gets handled and send by it's own logic via the socket.
+    fn from(packet: &Packet) -> Bytes {
+        let mut buffer = BytesMut::new();
+        packet.encode_into(&mut buffer);
         buffer.freeze()
     }
 }
@@ -152,8 +177,7 @@ impl TryFrom<&Bytes> for Packet {
         let mut packet: Packet = Default::default();
         let payload_utf8 =
             String::from_utf8(payload.to_vec()).map_err(|e| InvalidUtf8(e.utf8_error()))?;
-        let mut utf8_iter = payload_utf8.chars().into_iter().peekable();
-        let mut next_utf8;
+        let mut utf8_iter = payload_utf8.chars().peekable();
         let mut char_buf: Vec<char> = vec![];
 
         // packet_type
@@ -162,12 +186,10 @@ impl TryFrom<&Bytes> for Packet {
         // attachment_count
         if let PacketType::BinaryAck | PacketType::BinaryEvent = packet.ptype {
             loop {
-                next_utf8 = utf8_iter.peek().ok_or(Error::IncompletePacket())?;
-                if *next_utf8 == '-' {
-                    let _ = utf8_iter.next(); // consume '-' char
-                    break;
+                match utf8_iter.next().ok_or(Error::IncompletePacket())? {
+                    '-' => break,
+                    c => char_buf.push(c),
                 }
-                char_buf.push(utf8_iter.next().unwrap()); // SAFETY: already peeked
             }
         }
         let count_str: String = char_buf.iter().collect();
@@ -177,20 +199,13 @@ impl TryFrom<&Bytes> for Packet {
 
         // namespace
         char_buf.clear();
-        next_utf8 = match utf8_iter.peek() {
-            Some(c) => c,
-            None => return Ok(packet),
-        };
-
-        if *next_utf8 == '/' {
-            char_buf.push(utf8_iter.next().unwrap()); // SAFETY: already peeked
+        if let Some(slash) = utf8_iter.next_if_eq(&'/') {
+            char_buf.push(slash);
             loop {
-                next_utf8 = utf8_iter.peek().ok_or(Error::IncompletePacket())?;
-                if *next_utf8 == ',' {
-                    let _ = utf8_iter.next(); // consume ','
-                    break;
+                match utf8_iter.next().ok_or(Error::IncompletePacket())? {
+                    ',' => break,
+                    c => char_buf.push(c),
                 }
-                char_buf.push(utf8_iter.next().unwrap()); // SAFETY: already peeked
             }
         }
         if !char_buf.is_empty() {
@@ -199,20 +214,11 @@ impl TryFrom<&Bytes> for Packet {
 
         // id
         char_buf.clear();
-        next_utf8 = match utf8_iter.peek() {
-            None => return Ok(packet),
-            Some(c) => c,
-        };
-
-        loop {
-            if !next_utf8.is_ascii_digit() {
-                break;
-            }
-            char_buf.push(utf8_iter.next().unwrap()); // SAFETY: already peeked
-            next_utf8 = match utf8_iter.peek() {
-                None => return Ok(packet),
-                Some(c) => c,
-            };
+        while let Some(c) = utf8_iter.next_if(char::is_ascii_digit) {
+            char_buf.push(c);
+        }
+        if utf8_iter.peek().is_none() {
+            return Ok(packet);
         }
 
         let count_str: String = char_buf.iter().collect();
@@ -221,8 +227,8 @@ impl TryFrom<&Bytes> for Packet {
         }
 
         // data
-        let json_str: String = utf8_iter.into_iter().collect();
-        let json_data: Value = serde_json::from_str(&json_str).map_err(InvalidJson)?;
+        let json_str: String = utf8_iter.collect();
+        let json_data = decode_json(replace_lone_surrogates(&json_str))?;
 
         packet.data = match json_data {
             Value::Array(vec) if vec.is_empty() => None,
@@ -233,15 +239,101 @@ impl TryFrom<&Bytes> for Packet {
     }
 }
 
+/// Decodes the data of a packet.
+#[cfg(not(feature = "simd-json"))]
+fn decode_json(json: Cow<'_, str>) -> Result<Value> {
+    serde_json::from_str(&json).map_err(InvalidJson)
+}
+
+/// Decodes the data of a packet with simd-json, which parses in place.
+#[cfg(feature = "simd-json")]
+fn decode_json(json: Cow<'_, str>) -> Result<Value> {
+    let mut json = json.into_owned().into_bytes();
+    simd_json::serde::from_slice(&mut json).map_err(|e| InvalidJson(serde::de::Error::custom(e)))
+}
+
+/// `JSON.stringify` escapes lone UTF-16 surrogates (`"\ud800"`), which can't be
+/// represented in a rust string, so they are replaced by U+FFFD like a browser
+/// `TextDecoder` does. Valid surrogate pairs are left untouched.
+fn replace_lone_surrogates(json: &str) -> Cow<'_, str> {
+    if !json.contains("\\u") {
+        return Cow::Borrowed(json);
+    }
+
+    let bytes = json.as_bytes();
+    let mut replaced = String::new();
+    let mut last = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'\\' {
+            i += 1;
+            continue;
+        }
+        // skip any other escape, including an escaped backslash
+        if bytes.get(i + 1) != Some(&b'u') {
+            i += 2;
+            continue;
+        }
+        match escaped_unit(bytes, i) {
+            Some(0xD800..=0xDBFF)
+                if matches!(escaped_unit(bytes, i + 6), Some(0xDC00..=0xDFFF)) =>
+            {
+                i += 12;
+                continue;
+            }
+            Some(0xD800..=0xDFFF) => {
+                replaced.push_str(&json[last..i]);
+                replaced.push_str("\\ufffd");
+                i += 6;
+                last = i;
+            }
+            _ => i += 2,
+        }
+    }
+
+    if last == 0 {
+        return Cow::Borrowed(json);
+    }
+    replaced.push_str(&json[last..]);
+    Cow::Owned(replaced)
+}
+
+/// Parses the `\uXXXX` escape starting at `at`.
+fn escaped_unit(bytes: &[u8], at: usize) -> Option<u16> {
+    if bytes.get(at..at + 2)? != b"\\u" {
+        return None;
+    }
+    let hex = bytes.get(at + 2..at + 6)?;
+    if !hex.iter().all(u8::is_ascii_hexdigit) {
+        return None;
+    }
+    u16::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()
+}
+
+/// Ack ids wrap after this one, the largest id every client implementation parses.
+const MAX_ACK_ID: usize = u32::MAX as usize;
+
+/// Allocates the ack ids of one socket. A reconnect builds a new socket, so ids
+/// restart at 0 on every connection.
 #[derive(Default)]
 pub(crate) struct AckIdGenerator {
     seq: AtomicUsize,
 }
 
 impl AckIdGenerator {
+    #[cfg(test)]
+    fn starting_at(id: usize) -> Self {
+        Self {
+            seq: AtomicUsize::new(id),
+        }
+    }
+
     pub fn generate(&self) -> usize {
-        let seq = self.seq.fetch_add(1, Ordering::SeqCst);
-        seq as usize
+        let next = |id: usize| Some(if id >= MAX_ACK_ID { 0 } else { id + 1 });
+        // SAFETY: the update closure never returns `None`
+        self.seq
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, next)
+            .unwrap()
     }
 }
 
@@ -250,6 +342,33 @@ mod test {
     use super::*;
     use serde_json::json;
 
+    #[test]
+    fn test_encode_event() -> Result<()> {
+        #[derive(Serialize)]
+        struct Tick<'a> {
+            frame: u32,
+            players: &'a [&'a str],
+        }
+
+        let tick = Tick {
+            frame: 7,
+            players: &["ann", "bob"],
+        };
+        let mut buffer = BytesMut::new();
+        encode_event_into(&mut buffer, "/game", &Event::from("tick"), &tick)?;
+
+        let packet = Packet::new(
+            PacketType::Event,
+            "/game".to_owned(),
+            Some(json!(["tick", {"frame": 7, "players": ["ann", "bob"]}])),
+            None,
+            0,
+            None,
+        );
+        assert_eq!(buffer.freeze(), Bytes::from(&packet));
+        Ok(())
+    }
+
     #[test]
     /// This test suite is taken from the explanation section here:
     /// https://github.com/socketio/socket.io-protocol
@@ -597,4 +716,74 @@ mod test {
         let _sut = PacketType::try_from(42).expect_err("error!");
         assert!(matches!(Error::InvalidPacketType(42 as char), _sut))
     }
+
+    #[test]
+    fn test_decode_malformed() {
+        for payload in [
+            &b""[..],
+            b"5",
+            b"51",
+            b"2/chat",
+            b"2/chat,1[",
+            b"2\xff",
+            b"9[]",
+        ] {
+            assert!(Packet::try_from(&Bytes::from_static(payload)).is_err());
+        }
+    }
+
+    #[test]
+    /// Payloads as produced by the JS parser in a browser.
+    fn test_text_codec_conformance() {
+        let fixtures: Vec<(&[u8], Value)> = vec![
+            ("2[\"ev\",\"😀\"]".as_bytes(), json!(["ev", "😀"])),
+            (b"2[\"ev\",\"\\ud83d\\ude00\"]", json!(["ev", "😀"])),
+            (b"2[\"ev\",\"a\\ud800b\"]", json!(["ev", "a\u{fffd}b"])),
+            (
+                b"2[\"ev\",\"\\udc00\\ud800\"]",
+                json!(["ev", "\u{fffd}\u{fffd}"]),
+            ),
+            (b"2[\"ev\",\"\\ud83d\"]", json!(["ev", "\u{fffd}"])),
+            (b"2[\"ev\",\"\\\\ud800\"]", json!(["ev", "\\ud800"])),
+            (b"2[\"ev\",\"\\u001e\"]", json!(["ev", "\u{1e}"])),
+        ];
+
+        for (payload, data) in fixtures {
+            let packet = Packet::try_from(&Bytes::from_static(payload)).unwrap();
+            assert_eq!(packet.data, Some(data));
+        }
+
+        // the record separator of polling payloads is never written raw
+        let packet = Packet::new(
+            PacketType::Event,
+            "/".to_owned(),
+            Some(json!(["ev", "a\u{1e}b😀"])),
+            None,
+            0,
+            None,
+        );
+        let bytes = Bytes::from(&packet);
+        assert!(!bytes.contains(&0x1e));
+        assert_eq!(Packet::try_from(&bytes).unwrap(), packet);
+    }
+
+    #[test]
+    fn test_ack_id_wraparound() {
+        let generator = AckIdGenerator::starting_at(MAX_ACK_ID - 1);
+        assert_eq!(generator.generate(), MAX_ACK_ID - 1);
+        assert_eq!(generator.generate(), MAX_ACK_ID);
+        assert_eq!(generator.generate(), 0);
+        assert_eq!(generator.generate(), 1);
+
+        let packet = Packet::new(
+            PacketType::Event,
+            "/".to_owned(),
+            Some(json!(["ev"])),
+            Some(MAX_ACK_ID),
+            0,
+            None,
+        );
+        let decoded = Packet::try_from(&Bytes::from(&packet)).unwrap();
+        assert_eq!(decoded.id, Some(MAX_ACK_ID));
+    }
 }
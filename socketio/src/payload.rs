@@ -1,6 +1,11 @@
+use std::collections::BTreeMap;
+
 use bytes::Bytes;
+use serde::{de::DeserializeOwned, Serialize};
 use serde_json::Value;
 
+use crate::{Error, Result};
+
 /// A type which represents a `payload` in the `socket.io` context.
 /// The enum is used for both representing data that's send and
 /// data that's received.
@@ -16,6 +21,75 @@ pub enum Payload {
 pub enum RawPayload {
     Binary(Bytes),
     Json(Value),
+    Nested(Nested),
+}
+
+/// A JSON value with binary data inside, like an object holding a buffer in the
+/// JS implementation. On the wire each binary is replaced in place by a
+/// placeholder referencing its attachment.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Nested {
+    Json(Value),
+    Binary(Bytes),
+    Array(Vec<Nested>),
+    Object(BTreeMap<String, Nested>),
+}
+
+impl Nested {
+    /// The number of binaries inside, each one an attachment of the packet.
+    pub(crate) fn binary_count(&self) -> usize {
+        match self {
+            Self::Json(_) => 0,
+            Self::Binary(_) => 1,
+            Self::Array(values) => values.iter().map(Self::binary_count).sum(),
+            Self::Object(map) => map.values().map(Self::binary_count).sum(),
+        }
+    }
+}
+
+impl Payload {
+    /// Serializes `value` into a JSON payload, for the emits taking a `Payload`
+    /// such as `Socket::emit_with_ack`. A plain emit can skip the intermediate
+    /// value with `Socket::emit_ref`.
+    pub fn json<T: Serialize + ?Sized>(value: &T) -> Result<Self> {
+        Ok(Self::Json(serde_json::to_value(value)?))
+    }
+
+    /// Deserializes the JSON of a received payload, from `null` if there is
+    /// none, so `Option<T>` accepts it.
+    pub(crate) fn deserialize<T: DeserializeOwned>(payload: Option<&Payload>) -> Result<T> {
+        let value = match payload {
+            Some(Payload::Json(value)) => value,
+            None => &Value::Null,
+            Some(_) => {
+                return Err(Error::InvalidHandlerArgument(
+                    "binary payload where JSON was expected".to_owned(),
+                ))
+            }
+        };
+        T::deserialize(value).map_err(|e| Error::InvalidHandlerArgument(e.to_string()))
+    }
+
+    /// Fails like an emit of the payload would, so a broadcast reports it once
+    /// rather than per socket. A packet counts its attachments in a byte.
+    pub(crate) fn check(&self) -> Result<()> {
+        let attachments = match self {
+            Self::Binary(_) => 1,
+            Self::Json(_) => 0,
+            Self::Multi(payloads) => payloads
+                .iter()
+                .map(|payload| match payload {
+                    RawPayload::Binary(_) => 1,
+                    RawPayload::Json(_) => 0,
+                    RawPayload::Nested(nested) => nested.binary_count(),
+                })
+                .sum(),
+        };
+        if attachments > u8::MAX as usize {
+            return Err(Error::InvalidAttachmentCount(attachments));
+        }
+        Ok(())
+    }
 }
 
 impl From<serde_json::Value> for Payload {
@@ -35,11 +109,9 @@ impl From<Option<serde_json::Value>> for Payload {
 
 impl From<Vec<serde_json::Value>> for Payload {
     fn from(value: Vec<serde_json::Value>) -> Self {
-        if value.len() == 1 {
-            // SAFETY: first element must exist
-            Self::Json(value.first().unwrap().to_owned())
-        } else {
-            Self::Multi(value.into_iter().map(|v| v.into()).collect())
+        match <[serde_json::Value; 1]>::try_from(value) {
+            Ok([value]) => Self::Json(value),
+            Err(value) => Self::Multi(value.into_iter().map(|v| v.into()).collect()),
         }
     }
 }
@@ -86,11 +158,24 @@ impl From<Bytes> for RawPayload {
     }
 }
 
+impl From<Nested> for RawPayload {
+    fn from(nested: Nested) -> Self {
+        Self::Nested(nested)
+    }
+}
+
+impl From<Nested> for Payload {
+    fn from(nested: Nested) -> Self {
+        Self::Multi(vec![RawPayload::Nested(nested)])
+    }
+}
+
 impl From<RawPayload> for Payload {
     fn from(val: RawPayload) -> Self {
         match val {
             RawPayload::Json(data) => Payload::Json(data),
             RawPayload::Binary(bin) => Payload::Binary(bin),
+            nested @ RawPayload::Nested(_) => Payload::Multi(vec![nested]),
         }
     }
 }
@@ -101,6 +186,25 @@ mod tests {
 
     use super::*;
 
+    #[test]
+    fn test_deserialize() {
+        let payload = Payload::from(json!({ "name": "foo", "age": 3 }));
+        let value: Value = Payload::deserialize(Some(&payload)).unwrap();
+        assert_eq!(value["age"], 3);
+        let none: Option<u8> = Payload::deserialize(None).unwrap();
+        assert_eq!(none, None);
+
+        assert!(matches!(
+            Payload::deserialize::<u8>(Some(&payload)),
+            Err(Error::InvalidHandlerArgument(_))
+        ));
+        let binary = Payload::from(vec![1, 2, 3]);
+        assert!(matches!(
+            Payload::deserialize::<Value>(Some(&binary)),
+            Err(Error::InvalidHandlerArgument(_))
+        ));
+    }
+
     #[test]
     fn test_from() {
         let sut = Payload::from(json!("foo ™"));
@@ -121,4 +225,30 @@ mod tests {
         let sut = Payload::from(json!("5"));
         assert_eq!(Payload::Json(json!("5")), sut);
     }
+
+    #[test]
+    fn test_json() -> Result<()> {
+        #[derive(Serialize)]
+        struct Order<'a> {
+            id: u32,
+            items: &'a [&'a str],
+        }
+
+        let order = Order {
+            id: 7,
+            items: &["tea"],
+        };
+        assert_eq!(
+            Payload::json(&order)?,
+            Payload::Json(json!({ "id": 7, "items": ["tea"] }))
+        );
+
+        // JSON objects only have string keys
+        let invalid = std::collections::HashMap::from([((1, 2), "pair")]);
+        assert!(matches!(
+            Payload::json(&invalid),
+            Err(Error::InvalidJson(_))
+        ));
+        Ok(())
+    }
 }
@@ -0,0 +1,59 @@
+use std::sync::Arc;
+
+use crate::{error::Result, server::server::Server, Event, Payload};
+
+/// Selects the sockets of a namespace to emit to, like `io.of(nsp).to(room)`
+/// of the JS server, see `Server::of` and `ServerSocket::broadcast`.
+#[derive(Clone)]
+pub struct BroadcastOperator {
+    server: Arc<Server>,
+    nsp: String,
+    rooms: Vec<String>,
+    except: Vec<String>,
+}
+
+impl BroadcastOperator {
+    pub(crate) fn new(server: Arc<Server>, nsp: String) -> Self {
+        Self {
+            server,
+            nsp,
+            rooms: Vec::new(),
+            except: Vec::new(),
+        }
+    }
+
+    /// Narrows the emit to the sockets in `room`, or to the socket `room` if no
+    /// such room exists. Sockets in several of the chosen rooms get the event
+    /// once. Without any room, every socket of the namespace is picked.
+    pub fn to<T: Into<String>>(mut self, room: T) -> Self {
+        self.rooms.push(room.into());
+        self
+    }
+
+    /// Leaves out the sockets in `room`, or the socket `room` if no such room
+    /// exists.
+    pub fn except<T: Into<String>>(mut self, room: T) -> Self {
+        self.except.push(room.into());
+        self
+    }
+
+    /// Emits to the chosen sockets, see `Server::emit_to`.
+    pub async fn emit<E, D>(&self, event: E, data: D) -> Result<()>
+    where
+        E: Into<Event>,
+        D: Into<Payload>,
+    {
+        let rooms = self.rooms.iter().map(String::as_str).collect();
+        let mut sids = match self.rooms.is_empty() {
+            true => self.server.namespace_sids(&self.nsp),
+            false => self.server.sids_to_emit(&self.nsp, rooms).await,
+        };
+        let except = self.except.iter().map(String::as_str).collect();
+        for sid in self.server.sids_to_emit(&self.nsp, except).await {
+            sids.remove(&sid);
+        }
+        self.server
+            .emit_to_sids(&self.nsp, sids, event.into(), data.into())
+            .await
+    }
+}
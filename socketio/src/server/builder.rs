@@ -1,17 +1,64 @@
-use crate::server::server::Server;
+use crate::callback::{HandlerConfig, HandlerOverflow, PanicPolicy};
+use crate::dispatcher::{
+    DispatchLimit, DispatchMode, DispatchOverflow, Dispatcher, WORKER_QUEUE_SIZE,
+};
+use crate::server::{
+    config::ServerConfig,
+    diagnostic::{ProtocolError, ProtocolErrorHook},
+    extract::{EventParts, Handler},
+    middleware::{Handshake, Middleware, MiddlewareResult, Next},
+    namespace::{DynamicHandler, NamespaceMatcher},
+    server::Server,
+};
 use crate::{callback::Callback, server::client::Client};
-use crate::{AckId, NameSpace};
-use crate::{Event, Payload};
+use crate::{compression, AckId, NameSpace};
+use crate::{Event, MemoryStore, Payload, PayloadCipher, SessionStore};
 use dashmap::DashMap;
-use engineio_rs::{ServerBuilder as EngineServerBuilder, ServerOption};
+use engineio_rs::{
+    Clock, IdGenerator, ServerBuilder as EngineServerBuilder, ServerOption, SlowConsumer,
+    TransportKind,
+};
 use futures_util::future::BoxFuture;
-use std::{collections::HashMap, sync::Arc};
+use futures_util::FutureExt;
+use serde::de::DeserializeOwned;
+use std::{
+    any::{Any, TypeId},
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::runtime::Handle;
 
 #[allow(dead_code)]
 pub struct ServerBuilder {
     server_option: ServerOption,
     on: HashMap<NameSpace, DashMap<Event, Callback<Client>>>,
+    dynamic: Vec<DynamicHandler>,
     builder: EngineServerBuilder,
+    cipher: Option<Arc<dyn PayloadCipher>>,
+    compression_threshold: Option<usize>,
+    handlers: HashMap<NameSpace, HashMap<Event, HandlerConfig>>,
+    coalesce: HashMap<NameSpace, HashSet<Event>>,
+    panic_policy: PanicPolicy,
+    dispatch_mode: DispatchMode,
+    dispatch_queue: (usize, DispatchOverflow),
+    dispatch_limits: HashMap<NameSpace, (usize, DispatchOverflow)>,
+    room_capacities: HashMap<NameSpace, usize>,
+    max_payloads: HashMap<NameSpace, usize>,
+    rate_limits: HashMap<NameSpace, (u32, Duration)>,
+    namespace_timeouts: HashMap<NameSpace, Duration>,
+    #[cfg(feature = "auth-jwt")]
+    jwt_auth: HashMap<NameSpace, Arc<crate::JwtAuth>>,
+    #[cfg(feature = "auth-jwt")]
+    join_tokens: HashMap<NameSpace, Arc<crate::JoinTokens>>,
+    session_store: Arc<dyn SessionStore>,
+    resume: Option<(Duration, Duration)>,
+    audit_interval: Option<Duration>,
+    memory_cap: Option<usize>,
+    states: HashMap<TypeId, Arc<dyn Any + Send + Sync>>,
+    middlewares: HashMap<NameSpace, Vec<Middleware>>,
+    protocol_error_hook: Option<ProtocolErrorHook>,
 }
 
 #[allow(dead_code)]
@@ -21,14 +68,416 @@ impl ServerBuilder {
             builder: EngineServerBuilder::new(port),
             server_option: Default::default(),
             on: Default::default(),
+            dynamic: Vec::new(),
+            cipher: None,
+            compression_threshold: None,
+            handlers: Default::default(),
+            coalesce: Default::default(),
+            panic_policy: Default::default(),
+            dispatch_mode: Default::default(),
+            dispatch_queue: (WORKER_QUEUE_SIZE, Default::default()),
+            dispatch_limits: Default::default(),
+            room_capacities: Default::default(),
+            max_payloads: Default::default(),
+            rate_limits: Default::default(),
+            namespace_timeouts: Default::default(),
+            #[cfg(feature = "auth-jwt")]
+            jwt_auth: Default::default(),
+            #[cfg(feature = "auth-jwt")]
+            join_tokens: Default::default(),
+            session_store: Arc::new(MemoryStore::default()),
+            resume: None,
+            audit_interval: None,
+            memory_cap: None,
+            states: HashMap::new(),
+            middlewares: Default::default(),
+            protocol_error_hook: None,
         }
     }
 
+    /// A builder set up from `config`, to be completed with the handlers.
+    pub fn from_config(config: ServerConfig) -> Self {
+        let mut builder = Self::new(config.port)
+            .server_option(config.server_option())
+            .trusted_proxies(config.trusted_proxies)
+            .proxy_protocol(config.proxy_protocol);
+        if let Some(threshold) = config.compression {
+            builder = builder.compression(threshold);
+        }
+        if let Some(threshold) = config.polling_compression {
+            builder = builder.polling_compression(threshold);
+        }
+        if let Some(secs) = config.audit_interval {
+            builder = builder.audit_interval(Duration::from_secs(secs));
+        }
+        if let Some(bytes) = config.memory_cap {
+            builder = builder.memory_cap(bytes);
+        }
+        for (namespace, limits) in config.namespaces {
+            if let Some(max) = limits.room_capacity {
+                builder = builder.room_capacity(namespace.clone(), max);
+            }
+            if let Some(bytes) = limits.max_payload {
+                builder = builder.max_payload(namespace.clone(), bytes);
+            }
+            if let Some(events) = limits.rate_limit {
+                builder = builder.rate_limit(namespace.clone(), events, Duration::from_secs(1));
+            }
+            if let Some(millis) = limits.namespace_timeout {
+                builder =
+                    builder.namespace_timeout(namespace.clone(), Duration::from_millis(millis));
+            }
+            if let Some(max) = limits.dispatch_limit {
+                builder = builder.dispatch_limit(namespace, max, limits.dispatch_overflow);
+            }
+        }
+        builder
+    }
+
     pub fn server_option(mut self, server_option: ServerOption) -> Self {
+        self.server_option = server_option;
         self.builder = self.builder.server_option(server_option);
         self
     }
 
+    /// Adds a field to the engine.io OPEN packet payload.
+    pub fn handshake_extra<K: Into<String>, V: Into<serde_json::Value>>(
+        mut self,
+        key: K,
+        value: V,
+    ) -> Self {
+        self.builder = self.builder.handshake_extra(key, value);
+        self
+    }
+
+    /// Records every engine.io packet sent or received by the server.
+    pub fn recorder(mut self, recorder: Arc<engineio_rs::Recorder>) -> Self {
+        self.builder = self.builder.recorder(recorder);
+        self
+    }
+
+    /// Dumps every engine.io packet sent or received by the server at trace
+    /// level, truncated and redacted as configured in `wire_log`.
+    pub fn wire_log(mut self, wire_log: engineio_rs::WireLog) -> Self {
+        self.builder = self.builder.wire_log(wire_log);
+        self
+    }
+
+    /// Encrypts every packet sent and decrypts every packet received by the server.
+    /// Clients must be configured with a matching cipher.
+    pub fn cipher(mut self, cipher: Arc<dyn PayloadCipher>) -> Self {
+        self.cipher = Some(cipher);
+        self
+    }
+
+    /// Runs at most `max` invocations per socket of the handler registered for
+    /// `event`, excess invocations are queued or dropped according to `overflow`.
+    pub fn concurrency_limit<S: Into<String>, T: Into<Event>>(
+        mut self,
+        namespace: S,
+        event: T,
+        max: usize,
+        overflow: HandlerOverflow,
+    ) -> Self {
+        self.handler_config(namespace, event).limit = Some((max, overflow));
+        self
+    }
+
+    /// Cancels an invocation of the handler registered for `event` running longer
+    /// than `timeout`, and fires the `Error` handler of the socket.
+    pub fn handler_timeout<S: Into<String>, T: Into<Event>>(
+        mut self,
+        namespace: S,
+        event: T,
+        timeout: Duration,
+    ) -> Self {
+        self.handler_config(namespace, event).timeout = Some(timeout);
+        self
+    }
+
+    /// Collapses emits of `event` to a socket waiting behind one in flight to
+    /// the latest payload, for state like cursor positions where only the newest
+    /// value matters. An emit whose payload is replaced before it was sent fails
+    /// with `Error::SupersededEmit`.
+    pub fn coalesce<S: Into<String>, T: Into<Event>>(mut self, namespace: S, event: T) -> Self {
+        self.coalesce
+            .entry(namespace.into())
+            .or_default()
+            .insert(event.into());
+        self
+    }
+
+    fn handler_config<S: Into<String>, T: Into<Event>>(
+        &mut self,
+        namespace: S,
+        event: T,
+    ) -> &mut HandlerConfig {
+        self.handlers
+            .entry(namespace.into())
+            .or_default()
+            .entry(event.into())
+            .or_default()
+    }
+
+    /// Refuses connections to `namespace` without a valid JWT, and stores the
+    /// claims of accepted ones in the socket data.
+    #[cfg(feature = "auth-jwt")]
+    pub fn jwt_auth<S: Into<String>>(mut self, namespace: S, auth: crate::JwtAuth) -> Self {
+        self.jwt_auth.insert(namespace.into(), Arc::new(auth));
+        self
+    }
+
+    /// Runs `middleware` on each connection to `namespace`, like `io.use()` of
+    /// the JS server. Middlewares run in the order they are added, each calls
+    /// `next.run(handshake)` to hand over to the following one, or returns an
+    /// error to refuse the connection with a CONNECT_ERROR packet. A chain
+    /// which panics, or runs longer than the `namespace_timeout` or 5 seconds
+    /// without one, refuses the connection as well.
+    pub fn use_middleware<S, F>(mut self, namespace: S, middleware: F) -> Self
+    where
+        S: Into<String>,
+        F: Fn(Handshake, Next) -> BoxFuture<'static, MiddlewareResult> + Send + Sync + 'static,
+    {
+        self.middlewares
+            .entry(namespace.into())
+            .or_default()
+            .push(Arc::new(middleware));
+        self
+    }
+
+    /// Calls `hook` with the diagnostics of each packet the server could not
+    /// handle: undecodable packets, packets for unknown namespaces and events
+    /// without handler. The `Error` handlers of the namespace are fired with
+    /// the same diagnostics as JSON.
+    pub fn on_protocol_error<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(ProtocolError) + Send + Sync + 'static,
+    {
+        self.protocol_error_hook = Some(Arc::new(hook));
+        self
+    }
+
+    /// Joins sockets of `namespace` presenting a token of `tokens` to the rooms
+    /// it grants, see `Server::join_token`.
+    #[cfg(feature = "auth-jwt")]
+    pub fn join_tokens<S: Into<String>>(mut self, namespace: S, tokens: crate::JoinTokens) -> Self {
+        let namespace = namespace.into();
+        let tokens = Arc::new(tokens);
+        self.join_tokens.insert(namespace.clone(), tokens.clone());
+        let event = tokens.event.clone();
+        self.on(
+            namespace,
+            event,
+            move |payload, socket: Client, need_ack| {
+                let tokens = tokens.clone();
+                async move {
+                    let token = match payload {
+                        Some(Payload::Json(token)) => Some(token),
+                        _ => None,
+                    };
+                    let answer = match tokens.verify(&socket.nsp, token.as_ref()) {
+                        Ok(rooms) => match socket.join(rooms.clone()).await {
+                            Ok(_) => serde_json::json!({ "rooms": rooms }),
+                            Err(e) => serde_json::json!({ "error": e.to_string() }),
+                        },
+                        Err(message) => {
+                            tracing::trace!("refused join of {}: {}", socket.sid(), message);
+                            serde_json::json!({ "error": message })
+                        }
+                    };
+                    if let Some(id) = need_ack {
+                        let _ = socket.ack(id, answer).await;
+                    }
+                }
+                .boxed()
+            },
+        )
+    }
+
+    /// Where sessions outliving a connection are kept, in memory by default.
+    pub fn session_store(mut self, store: Arc<dyn SessionStore>) -> Self {
+        self.session_store = store;
+        self
+    }
+
+    /// Lets a client which lost its connection get its rooms, data and user
+    /// back by reconnecting within `ttl`. The state is kept in the session
+    /// store under a resume token the client got when it connected, which
+    /// unlike the sid is never shown to other clients. Tokens are single use,
+    /// and a new one is issued every `rotation`. The client presents its token
+    /// in the `x-socketio-resume` header, its auth is left untouched.
+    pub fn resume_sessions(mut self, ttl: Duration, rotation: Duration) -> Self {
+        self.resume = Some((ttl, rotation));
+        self
+    }
+
+    /// Runs `Server::audit` every `interval` while serving, repairing room
+    /// memberships and clients left behind by missed cleanups.
+    pub fn audit_interval(mut self, interval: Duration) -> Self {
+        self.audit_interval = Some(interval);
+        self
+    }
+
+    /// Disconnects a socket whose approximate memory, see `Socket::memory_usage`,
+    /// exceeds `bytes` after an emit, firing its `Close` handlers with
+    /// `"memory cap exceeded"`. The emit fails with `Error::ExceededMemoryCap`.
+    pub fn memory_cap(mut self, bytes: usize) -> Self {
+        self.memory_cap = Some(bytes);
+        self
+    }
+
+    /// Whether to keep or disconnect a socket after one of its handlers panicked.
+    pub fn panic_policy(mut self, panic_policy: PanicPolicy) -> Self {
+        self.panic_policy = panic_policy;
+        self
+    }
+
+    /// How handler invocations are scheduled, a task is spawned per event by
+    /// default. A pool is shared by all sockets of the server.
+    pub fn dispatch_mode(mut self, dispatch_mode: DispatchMode) -> Self {
+        self.dispatch_mode = dispatch_mode;
+        self
+    }
+
+    /// Events a dispatcher worker buffers, 256 by default. Events beyond are
+    /// handled according to `overflow`, `DispatchOverflow::DropNew` by default.
+    pub fn dispatch_queue(mut self, size: usize, overflow: DispatchOverflow) -> Self {
+        self.dispatch_queue = (size, overflow);
+        self
+    }
+
+    /// Runs at most `max` handler invocations of all sockets of `namespace` at
+    /// once, queued ones included. Events beyond are handled according to
+    /// `overflow`, lifecycle events like `Connect` and `Close` are never limited.
+    pub fn dispatch_limit<S: Into<String>>(
+        mut self,
+        namespace: S,
+        max: usize,
+        overflow: DispatchOverflow,
+    ) -> Self {
+        self.dispatch_limits
+            .insert(namespace.into(), (max, overflow));
+        self
+    }
+
+    /// Lets at most `max` sockets join any room of `namespace`, joining a full
+    /// room fails with `Error::RoomFull`.
+    pub fn room_capacity<S: Into<String>>(mut self, namespace: S, max: usize) -> Self {
+        self.room_capacities.insert(namespace.into(), max);
+        self
+    }
+
+    /// Drops the events sent to `namespace` whose encoded size, binary
+    /// attachments included, exceeds `bytes`, and reports them as
+    /// `ProtocolErrorKind::PayloadTooLarge`. The `max_payload` of the
+    /// `ServerOption` still bounds every packet of the connection.
+    pub fn max_payload<S: Into<String>>(mut self, namespace: S, bytes: usize) -> Self {
+        self.max_payloads.insert(namespace.into(), bytes);
+        self
+    }
+
+    /// Lets each socket of `namespace` send `events` events per `per`, in
+    /// bursts of up to `events`. Events beyond are dropped and reported as
+    /// `ProtocolErrorKind::RateLimited`.
+    pub fn rate_limit<S: Into<String>>(mut self, namespace: S, events: u32, per: Duration) -> Self {
+        self.rate_limits.insert(namespace.into(), (events, per));
+        self
+    }
+
+    /// Applies `timeout` to the handlers of `namespace` without a
+    /// `handler_timeout` of their own.
+    pub fn namespace_timeout<S: Into<String>>(mut self, namespace: S, timeout: Duration) -> Self {
+        self.namespace_timeouts.insert(namespace.into(), timeout);
+        self
+    }
+
+    /// Gzips packets whose encoded size reaches `threshold` bytes for clients
+    /// which support it. Other clients, like the JS implementation, are unaffected.
+    pub fn compression(mut self, threshold: usize) -> Self {
+        self.compression_threshold = Some(threshold);
+        self.builder = self
+            .builder
+            .handshake_extra(compression::CAPABILITY, compression::GZIP);
+        self
+    }
+
+    /// Compresses long-polling responses of at least `threshold` bytes for
+    /// clients sending `Accept-Encoding: br` or `gzip`.
+    pub fn polling_compression(mut self, threshold: usize) -> Self {
+        self.builder = self.builder.polling_compression(threshold);
+        self
+    }
+
+    /// Capacity of the channels buffering the packets of a polling socket in
+    /// each direction, 100 by default.
+    pub fn polling_buffer(mut self, size: usize) -> Self {
+        self.builder = self.builder.polling_buffer(size);
+        self
+    }
+
+    /// Capacity of the channel of engine.io events the server reads sockets
+    /// from, 1000 by default.
+    pub fn event_size(mut self, size: usize) -> Self {
+        self.builder = self.builder.event_size(size);
+        self
+    }
+
+    /// Calls the `Event::SlowConsumer` handlers of a polling socket once its
+    /// buffer grows beyond `limits`.
+    pub fn slow_consumer(mut self, limits: SlowConsumer) -> Self {
+        self.builder = self.builder.slow_consumer(limits);
+        self
+    }
+
+    /// Peers whose `X-Forwarded-For` header is believed for
+    /// [`Client::remote_addr`], e.g. the load balancers in front of the server.
+    pub fn trusted_proxies<I: IntoIterator<Item = IpAddr>>(mut self, proxies: I) -> Self {
+        self.builder = self.builder.trusted_proxies(proxies);
+        self
+    }
+
+    /// The transports clients may connect with, polling and websocket by
+    /// default, e.g. only `TransportKind::Websocket` behind a load balancer
+    /// without sticky sessions. Panics if `transports` is empty.
+    pub fn transports<I: IntoIterator<Item = TransportKind>>(mut self, transports: I) -> Self {
+        self.builder = self.builder.transports(transports);
+        self
+    }
+
+    /// Generates the engine.io sids of new connections, which the sids of their
+    /// sockets derive from, e.g. deterministic ones in simulation tests.
+    pub fn id_generator<G: IdGenerator + 'static>(mut self, id_generator: G) -> Self {
+        self.builder = self.builder.id_generator(id_generator);
+        self
+    }
+
+    /// Measures heartbeats, the age of buffered packets and rate limits with
+    /// `clock` instead of tokio's clock.
+    pub fn clock<C: Clock + 'static>(mut self, clock: C) -> Self {
+        self.builder = self.builder.clock(clock);
+        self
+    }
+
+    /// Expects a PROXY protocol v1 header on connections from trusted proxies.
+    pub fn proxy_protocol(mut self, proxy_protocol: bool) -> Self {
+        self.builder = self.builder.proxy_protocol(proxy_protocol);
+        self
+    }
+
+    /// Runs the tasks of the server, including the handlers, on `runtime`
+    /// instead of the runtime calling `serve`.
+    pub fn runtime(mut self, runtime: Handle) -> Self {
+        self.builder = self.builder.runtime(runtime);
+        self
+    }
+
+    /// Injects artificial network failures into packets sent to every client.
+    #[cfg(feature = "testing")]
+    pub fn chaos(mut self, profile: engineio_rs::ChaosProfile) -> Self {
+        self.builder = self.builder.chaos(profile);
+        self
+    }
+
     pub fn on<S: Into<String>, T: Into<Event>, F>(
         mut self,
         namespace: S,
@@ -56,6 +505,109 @@ impl ServerBuilder {
         self
     }
 
+    /// Registers `callback` for `event` in `namespace`, called with the payload
+    /// deserialized to `T`, see `Socket::emit_ref` for the emitting side. A
+    /// payload which doesn't deserialize fires the `Error` handler of the
+    /// socket instead.
+    /// The ack can be answered with `socket.ack_sender(ack)`.
+    pub fn on_typed<T, S, E, F>(self, namespace: S, event: E, mut callback: F) -> Self
+    where
+        T: DeserializeOwned,
+        S: Into<String>,
+        E: Into<Event>,
+        F: FnMut(T, Client, Option<AckId>) -> BoxFuture<'static, ()> + 'static + Send + Sync,
+    {
+        let event = event.into();
+        let name = String::from(event.clone());
+        self.on(
+            namespace,
+            event,
+            move |payload, socket: Client, ack| match Payload::deserialize(payload.as_ref()) {
+                Ok(data) => callback(data, socket, ack),
+                Err(e) => {
+                    socket.callback_error(format!("payload of {}: {}", name, e));
+                    async {}.boxed()
+                }
+            },
+        )
+    }
+
+    /// Registers `callback` for `event` in every namespace matched by `matcher`,
+    /// e.g. `Regex::new("^/tenant-\\d+$")`, so clients can connect to
+    /// namespaces not known when the server is built. The handlers of a
+    /// matching namespace are set up when a client first connects to it and
+    /// removed once its last socket left. Namespaces registered with `on` are
+    /// not matched.
+    ///
+    /// The limits set per namespace, `rate_limit`, `room_capacity`,
+    /// `max_payload`, `dispatch_limit`, `namespace_timeout` and the middlewares,
+    /// are looked up by the exact name and never apply to a dynamic namespace.
+    pub fn on_dynamic<M: Into<NamespaceMatcher>, T: Into<Event>, F>(
+        mut self,
+        matcher: M,
+        event: T,
+        callback: F,
+    ) -> Self
+    where
+        F: for<'a> std::ops::FnMut(
+                Option<Payload>,
+                Client,
+                Option<AckId>,
+            ) -> BoxFuture<'static, ()>
+            + 'static
+            + Send
+            + Sync
+            + Clone,
+    {
+        self.dynamic.push(DynamicHandler {
+            matcher: matcher.into(),
+            event: event.into(),
+            callback: Box::new(move || Callback::new(callback.clone())),
+        });
+        self
+    }
+
+    /// Registers `handler` for `event` in `namespace`. Its arguments are
+    /// extracted from the event, e.g.
+    /// `|Json(login): Json<Login>, ack: AckSender, Data(db): Data<Db>| async move { .. }`.
+    /// The ack is answered with an [`AckSender`](crate::AckSender), which
+    /// sends it at most once and warns if it is dropped unsent, like the one
+    /// `Client::ack_sender` gives other callbacks.
+    /// If an argument can't be extracted, the handler is not run and an ack
+    /// the client asked for is answered with `{ "error": reason }`.
+    pub fn handle<S, T, H, Args>(self, namespace: S, event: T, handler: H) -> Self
+    where
+        S: Into<String>,
+        T: Into<Event>,
+        H: Handler<Args>,
+    {
+        let event = event.into();
+        let name = String::from(event.clone());
+        self.on(namespace, event, move |payload, socket: Client, ack| {
+            let handler = handler.clone();
+            let name = name.clone();
+            async move {
+                let parts = EventParts::new(payload, socket.clone(), ack);
+                if let Err(e) = handler.call(parts).await {
+                    tracing::warn!("handler of {} not run: {}", name, e);
+                    if let Some(id) = ack {
+                        let _ = socket
+                            .ack(id, serde_json::json!({ "error": e.to_string() }))
+                            .await;
+                    }
+                }
+            }
+            .boxed()
+        })
+    }
+
+    /// Shares `state` with the handlers, which extract it as `Data<S>`. A
+    /// later state of the same type replaces the earlier one.
+    pub fn state<S: Send + Sync + 'static>(mut self, state: S) -> Self {
+        self.states.insert(TypeId::of::<S>(), Arc::new(state));
+        self
+    }
+
     pub fn build(self) -> Arc<Server> {
         let engine_server = self.builder.build();
         let on = DashMap::new();
@@ -66,10 +618,56 @@ impl ServerBuilder {
 
         Arc::new(Server {
             on,
+            dynamic: self.dynamic,
+            dynamic_sockets: Default::default(),
             engine_server,
             rooms: Default::default(),
+            users: Default::default(),
+            imported_rooms: Default::default(),
             clients: Default::default(),
+            poll_tasks: Default::default(),
             sid_generator: Default::default(),
+            cipher: self.cipher,
+            compression_threshold: self.compression_threshold,
+            max_payload: self.server_option.max_payload,
+            handlers: self.handlers,
+            coalesce: self.coalesce,
+            panic_policy: self.panic_policy,
+            dispatcher: match self.dispatch_mode {
+                DispatchMode::Pool(size) => {
+                    let (queue_size, overflow) = self.dispatch_queue;
+                    Some(Arc::new(Dispatcher::new(size, queue_size, overflow)))
+                }
+                _ => None,
+            },
+            dispatch_mode: self.dispatch_mode,
+            dispatch_queue: self.dispatch_queue,
+            dispatch_limits: self
+                .dispatch_limits
+                .into_iter()
+                .map(|(nsp, (max, overflow))| (nsp, Arc::new(DispatchLimit::new(max, overflow))))
+                .collect(),
+            room_capacities: self.room_capacities,
+            max_payloads: self.max_payloads,
+            rate_limits: self.rate_limits,
+            namespace_timeouts: self.namespace_timeouts,
+            #[cfg(feature = "auth-jwt")]
+            jwt_auth: self.jwt_auth,
+            middlewares: self
+                .middlewares
+                .into_iter()
+                .map(|(nsp, chain)| (nsp, Arc::new(chain)))
+                .collect(),
+            protocol_error_hook: self.protocol_error_hook,
+            #[cfg(feature = "auth-jwt")]
+            join_tokens: self.join_tokens,
+            session_store: self.session_store,
+            resume: self.resume,
+            resume_tokens: Default::default(),
+            audit_interval: self.audit_interval,
+            audit_stats: Default::default(),
+            memory_cap: self.memory_cap,
+            states: self.states,
         })
     }
 }
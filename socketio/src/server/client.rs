@@ -1,25 +1,38 @@
-use std::{fmt::Debug, ops::Deref, sync::Arc, time::Duration};
+use std::{collections::HashMap, fmt::Debug, net::IpAddr, ops::Deref, sync::Arc, time::Duration};
 
 use dashmap::DashMap;
-use engineio_rs::Sid;
+use engineio_rs::{QueueStats, Sid};
 use futures_util::future::BoxFuture;
+use serde_json::{json, Value};
 use tracing::trace;
 
 use crate::{
     ack::AckId,
     callback::Callback,
-    server::server::Server,
+    error::Result,
+    server::{
+        broadcast::BroadcastOperator,
+        extract::AckSender,
+        rate::RateLimiter,
+        server::{RoomChange, Sample, Server, SidGenerator},
+    },
     socket::{RawSocket, Socket},
-    Event, Payload,
+    DisconnectReason, Event, Packet, Payload,
 };
 
 #[derive(Clone)]
 pub struct Client {
     socket: Socket<Self>,
-    server: Arc<Server>,
+    pub(crate) server: Arc<Server>,
     sid: Sid,
+    data: SocketData,
+    // shared by the clones of the socket, see `ServerBuilder::rate_limit`
+    pub(crate) rate_limiter: Option<Arc<RateLimiter>>,
 }
 
+/// Values attached to a socket for its lifetime, shared by all its callbacks.
+pub(crate) type SocketData = Arc<DashMap<String, Value>>;
+
 impl Debug for Client {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_tuple("sid").field(&self.sid).finish()
@@ -33,9 +46,20 @@ impl Client {
         sid: Sid,
         on: Arc<DashMap<Event, Callback<Self>>>,
         server: Arc<Server>,
+        data: SocketData,
     ) -> Self {
+        let namespace = namespace.into();
+        let handlers = server.handlers.get(&namespace);
+        let coalesce = server.coalesce.get(&namespace).cloned();
+        let dispatch_limit = server.dispatch_limits.get(&namespace).cloned();
+        let namespace_timeout = server.namespace_timeouts.get(&namespace).copied();
+        let rate_limiter = server.rate_limits.get(&namespace).map(|&(events, per)| {
+            Arc::new(RateLimiter::new(events, per, server.engine_server.clock()))
+        });
         let server_clone = server.clone();
         let sid_clone = sid.clone();
+        let data_clone = data.clone();
+        let rate_limiter_clone = rate_limiter.clone();
         let client = Socket::new(
             socket,
             namespace,
@@ -44,40 +68,139 @@ impl Client {
                 sid: sid_clone.clone(),
                 socket: c,
                 server: server_clone.clone(),
+                data: data_clone.clone(),
+                rate_limiter: rate_limiter_clone.clone(),
             }),
-        );
+            handlers.unwrap_or(&HashMap::new()),
+        )
+        .with_coalesce(coalesce.unwrap_or_default())
+        .with_panic_policy(server.panic_policy)
+        .with_dispatcher(server.dispatcher())
+        .with_dispatch_limit(dispatch_limit)
+        .with_namespace_timeout(namespace_timeout)
+        .with_memory_cap(server.memory_cap)
+        .with_runtime(server.engine_server.runtime().cloned());
 
         Self {
             sid,
             socket: client,
             server,
+            data,
+            rate_limiter,
         }
     }
 
-    pub(crate) async fn connect_callback(&self) {
+    /// Fires the `Connect` handlers with the auth payload of `packet`.
+    pub(crate) async fn connect_callback(&self, packet: &Packet) {
         trace!("server handle_connect");
-        let _ = self.socket.handle_connect(None).await;
+        let _ = self.socket.handle_connect(Some(packet)).await;
+    }
+
+    /// Fires the `Close` handlers with `reason`, the `Disconnect` handlers with
+    /// `disconnect`, and tells the client.
+    pub(crate) async fn close(&self, reason: &str, disconnect: DisconnectReason) {
+        self.socket
+            .callback(&Event::Close, Some(json!(reason).into()), None)
+            .await;
+        self.socket.disconnect_callback(disconnect).await;
+        let _ = self.socket.disconnect().await;
+    }
+
+    /// Disconnects this socket from the server side, see `Server::disconnect_sid`.
+    pub async fn disconnect(&self) -> Result<()> {
+        self.server
+            .disconnect_sid(&self.socket.nsp, &self.sid)
+            .await;
+        Ok(())
+    }
+
+    /// Fires the `Close` and `Disconnect` handlers of a socket whose connection
+    /// was lost.
+    pub(crate) async fn lost(&self, reason: DisconnectReason) {
+        self.socket.callback(&Event::Close, None, None).await;
+        self.socket.disconnect_callback(reason).await;
     }
 
     pub fn sid(&self) -> Sid {
         self.sid.clone()
     }
 
+    /// Values attached to this socket, e.g. the claims of its authentication.
+    pub fn data(&self) -> &DashMap<String, Value> {
+        &self.data
+    }
+
     pub fn namespace(&self) -> String {
         self.socket.nsp.clone()
     }
 
-    pub async fn join<T: Into<String>>(&self, rooms: Vec<T>) {
+    /// An [`AckSender`](crate::AckSender) answering `ack`, for callbacks
+    /// registered with `on`, `on_typed` or `on_dynamic`. Senders of separate
+    /// calls don't know of each other, so take one per event.
+    pub fn ack_sender(&self, ack: Option<AckId>) -> AckSender {
+        AckSender::new(self.clone(), ack)
+    }
+
+    /// The packets buffered for a polling client, `None` over websocket.
+    pub fn queue_stats(&self) -> Option<QueueStats> {
+        let esid = SidGenerator::decode(&self.sid)?;
+        self.server.engine_server.queue_stats(&esid)
+    }
+
+    pub(crate) async fn slow_consumer_callback(&self, stats: QueueStats) {
+        let oldest = stats.oldest.map(|age| age.as_millis() as u64);
+        let payload = json!({ "depth": stats.depth, "oldest_ms": oldest });
+        self.socket
+            .callback(&Event::SlowConsumer, Some(payload.into()), None)
+            .await;
+    }
+
+    /// The address of the client, as reported by a trusted proxy if the
+    /// connection came through one.
+    pub fn remote_addr(&self) -> Option<IpAddr> {
+        let esid = SidGenerator::decode(&self.sid)?;
+        self.server.engine_server.remote_addr(&esid)
+    }
+
+    /// Joins all of `rooms` at once, or none of them with `Error::RoomFull` if
+    /// one reached `ServerBuilder::room_capacity`.
+    pub async fn join<T: Into<String>>(&self, rooms: Vec<T>) -> Result<Vec<RoomChange>> {
         self.server
             .join(&self.socket.nsp, rooms, self.sid.clone())
-            .await;
+            .await
     }
 
-    pub async fn leave(&self, rooms: Vec<&str>) {
-        self.server.leave(&self.socket.nsp, rooms, &self.sid).await;
+    /// Binds the socket to the authenticated `user`, to be reached with
+    /// `Server::emit_to_user` alongside the other sockets of the user.
+    pub fn bind_user<T: Into<String>>(&self, user: T) {
+        self.server
+            .bind_user(&self.socket.nsp, user.into(), self.sid.clone());
+    }
+
+    pub async fn leave(&self, rooms: Vec<&str>) -> Vec<RoomChange> {
+        self.server.leave(&self.socket.nsp, rooms, &self.sid).await
+    }
+
+    /// Leaves every room of the namespace, returning the rooms left.
+    pub async fn leave_all(&self) -> Vec<RoomChange> {
+        self.server.leave_all(&self.socket.nsp, &self.sid).await
+    }
+
+    /// Picks the other sockets of this namespace to emit to, like
+    /// `socket.broadcast` of the JS server.
+    pub fn broadcast(&self) -> BroadcastOperator {
+        self.server
+            .of(self.socket.nsp.clone())
+            .except(self.sid.as_str())
+    }
+
+    /// Picks the other sockets in `room` to emit to, like `socket.to(room)` of
+    /// the JS server.
+    pub fn to<T: Into<String>>(&self, room: T) -> BroadcastOperator {
+        self.broadcast().to(room)
     }
 
-    pub async fn emit_to<E, D>(&self, rooms: Vec<&str>, event: E, data: D)
+    pub async fn emit_to<E, D>(&self, rooms: Vec<&str>, event: E, data: D) -> Result<()>
     where
         E: Into<Event>,
         D: Into<Payload>,
@@ -87,6 +210,24 @@ impl Client {
             .await
     }
 
+    /// Emits to a random sample of the sockets in any of `rooms`, see
+    /// `Server::emit_to_sample`.
+    pub async fn emit_to_sample<E, D>(
+        &self,
+        rooms: Vec<&str>,
+        sample: Sample,
+        event: E,
+        data: D,
+    ) -> Result<usize>
+    where
+        E: Into<Event>,
+        D: Into<Payload>,
+    {
+        self.server
+            .emit_to_sample(&self.socket.nsp, rooms, sample, event, data)
+            .await
+    }
+
     pub async fn emit_to_with_ack<F, E, D>(
         &self,
         rooms: Vec<&str>,
@@ -94,7 +235,8 @@ impl Client {
         data: D,
         timeout: Duration,
         callback: F,
-    ) where
+    ) -> Result<()>
+    where
         F: for<'a> std::ops::FnMut(Option<Payload>, Self, Option<AckId>) -> BoxFuture<'static, ()>
             + 'static
             + Send
@@ -0,0 +1,135 @@
+use std::{collections::HashMap, net::IpAddr};
+
+use engineio_rs::ServerOption;
+use serde::{Deserialize, Serialize};
+
+use crate::DispatchOverflow;
+
+/// The settings of a server which can be loaded with serde, e.g. from a YAML
+/// file or the environment, see `ServerBuilder::from_config`. Handlers, stores
+/// and other code are still registered on the builder. Unknown fields are
+/// rejected, so a misspelled setting is not silently ignored.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServerConfig {
+    pub port: u16,
+    /// Milliseconds between two pings.
+    #[serde(default = "default_ping_interval")]
+    pub ping_interval: u64,
+    /// Milliseconds the client may take to answer a ping.
+    #[serde(default = "default_ping_timeout")]
+    pub ping_timeout: u64,
+    #[serde(default = "default_max_payload")]
+    pub max_payload: usize,
+    /// See `ServerBuilder::compression`.
+    #[serde(default)]
+    pub compression: Option<usize>,
+    /// See `ServerBuilder::polling_compression`.
+    #[serde(default)]
+    pub polling_compression: Option<usize>,
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpAddr>,
+    #[serde(default)]
+    pub proxy_protocol: bool,
+    /// Seconds between two membership audits, see `ServerBuilder::audit_interval`.
+    #[serde(default)]
+    pub audit_interval: Option<u64>,
+    /// See `ServerBuilder::memory_cap`.
+    #[serde(default)]
+    pub memory_cap: Option<usize>,
+    /// Limits of the namespaces by name.
+    #[serde(default)]
+    pub namespaces: HashMap<String, NamespaceConfig>,
+}
+
+/// The limits of a namespace in a [`ServerConfig`]. Heartbeats are not among
+/// them, the namespaces of a client share its connection and its pings.
+/// Namespaces are matched by name, so the limits never apply to the dynamic
+/// namespaces of `ServerBuilder::on_dynamic`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NamespaceConfig {
+    /// See `ServerBuilder::room_capacity`.
+    pub room_capacity: Option<usize>,
+    /// See `ServerBuilder::max_payload`.
+    pub max_payload: Option<usize>,
+    /// Events per second of each socket, see `ServerBuilder::rate_limit`.
+    pub rate_limit: Option<u32>,
+    /// Milliseconds, see `ServerBuilder::namespace_timeout`.
+    pub namespace_timeout: Option<u64>,
+    /// See `ServerBuilder::dispatch_limit`.
+    pub dispatch_limit: Option<usize>,
+    pub dispatch_overflow: DispatchOverflow,
+}
+
+fn default_ping_interval() -> u64 {
+    ServerOption::default().ping_interval
+}
+
+fn default_ping_timeout() -> u64 {
+    ServerOption::default().ping_timeout
+}
+
+fn default_max_payload() -> usize {
+    ServerOption::default().max_payload
+}
+
+impl ServerConfig {
+    pub(crate) fn server_option(&self) -> ServerOption {
+        ServerOption {
+            ping_interval: self.ping_interval,
+            ping_timeout: self.ping_timeout,
+            max_payload: self.max_payload,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::ServerBuilder;
+
+    #[tokio::test]
+    async fn test_from_config() {
+        let config: ServerConfig = serde_json::from_value(json!({
+            "port": 4221,
+            "ping_interval": 5000,
+            "trusted_proxies": ["10.0.0.1"],
+            "namespaces": {
+                "/chat": {
+                    "room_capacity": 50,
+                    "max_payload": 4096,
+                    "rate_limit": 20,
+                    "namespace_timeout": 500,
+                    "dispatch_limit": 8,
+                    "dispatch_overflow": "drop_oldest"
+                }
+            }
+        }))
+        .unwrap();
+        assert_eq!(config.ping_interval, 5000);
+        assert_eq!(config.ping_timeout, ServerOption::default().ping_timeout);
+        assert_eq!(
+            config.namespaces["/chat"].dispatch_overflow,
+            DispatchOverflow::DropOldest
+        );
+
+        // never served, only the configuration is checked
+        let server = ServerBuilder::from_config(config).build();
+        assert_eq!(server.room_capacities["/chat"], 50);
+        assert!(server.dispatch_limits.contains_key("/chat"));
+        assert_eq!(server.max_payloads["/chat"], 4096);
+        assert_eq!(server.rate_limits["/chat"], (20, Duration::from_secs(1)));
+        assert_eq!(
+            server.namespace_timeouts["/chat"],
+            Duration::from_millis(500)
+        );
+
+        let misspelled = json!({ "port": 4221, "ping_intervall": 5000 });
+        assert!(serde_json::from_value::<ServerConfig>(misspelled).is_err());
+    }
+}
@@ -0,0 +1,62 @@
+use std::sync::Arc;
+
+use bytes::Bytes;
+use engineio_rs::Sid as EngineSid;
+use serde_json::{json, Value};
+
+/// What a client sent which the server could not handle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolErrorKind {
+    /// A packet which could not be decoded, the connection is closed.
+    Undecodable,
+    /// A packet for a namespace the server does not serve or the client did
+    /// not connect to.
+    UnknownNamespace,
+    /// An event without handler in its namespace.
+    UnknownEvent,
+    /// An event larger than the `max_payload` of its namespace, dropped.
+    PayloadTooLarge,
+    /// An event beyond the `rate_limit` of its namespace, dropped.
+    RateLimited,
+}
+
+impl ProtocolErrorKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Undecodable => "undecodable",
+            Self::UnknownNamespace => "unknown_namespace",
+            Self::UnknownEvent => "unknown_event",
+            Self::PayloadTooLarge => "payload_too_large",
+            Self::RateLimited => "rate_limited",
+        }
+    }
+}
+
+/// The diagnostics of a packet the server could not handle, handed to the hook
+/// of `ServerBuilder::on_protocol_error`, e.g. to count and alert on clients
+/// speaking another version of the protocol.
+#[derive(Debug, Clone)]
+pub struct ProtocolError {
+    pub kind: ProtocolErrorKind,
+    /// The engine.io session of the client.
+    pub esid: EngineSid,
+    /// The namespace of the packet, unknown if it could not be decoded.
+    pub nsp: Option<String>,
+    /// The packet as received.
+    pub raw: Bytes,
+    pub reason: String,
+}
+
+impl ProtocolError {
+    /// The payload of the `Error` event fired on the sockets of the namespace.
+    pub(crate) fn to_json(&self) -> Value {
+        json!({
+            "kind": self.kind.as_str(),
+            "nsp": self.nsp,
+            "raw": String::from_utf8_lossy(&self.raw),
+            "reason": self.reason,
+        })
+    }
+}
+
+pub(crate) type ProtocolErrorHook = Arc<dyn Fn(ProtocolError) + Send + Sync>;
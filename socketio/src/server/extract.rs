@@ -0,0 +1,180 @@
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use futures_util::future::BoxFuture;
+use serde::de::DeserializeOwned;
+
+use crate::{error::Result, server::Client, AckId, Error, Payload};
+
+/// An incoming event, what the arguments of a [`Handler`] are extracted from.
+pub struct EventParts {
+    pub payload: Option<Payload>,
+    pub socket: Client,
+    pub ack: Option<AckId>,
+    // set once the ack is sent, shared by the `AckSender`s of the event
+    pub(crate) acked: Arc<AtomicBool>,
+}
+
+impl EventParts {
+    pub(crate) fn new(payload: Option<Payload>, socket: Client, ack: Option<AckId>) -> Self {
+        Self {
+            payload,
+            socket,
+            ack,
+            acked: Default::default(),
+        }
+    }
+}
+
+/// An argument of a [`Handler`], extracted from the event it handles.
+pub trait FromEvent: Sized {
+    fn from_event(parts: &EventParts) -> Result<Self>;
+}
+
+/// The JSON payload of the event deserialized to `T`. An event without payload
+/// is deserialized from `null`, so `Json<Option<T>>` accepts it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Json<T>(pub T);
+
+impl<T: DeserializeOwned> FromEvent for Json<T> {
+    fn from_event(parts: &EventParts) -> Result<Self> {
+        Payload::deserialize(parts.payload.as_ref()).map(Json)
+    }
+}
+
+/// The socket which received the event.
+#[derive(Debug, Clone)]
+pub struct SocketRef(pub Client);
+
+impl FromEvent for SocketRef {
+    fn from_event(parts: &EventParts) -> Result<Self> {
+        Ok(SocketRef(parts.socket.clone()))
+    }
+}
+
+/// Answers the ack the client asked for with the event, if any. It is sent at
+/// most once: `send` consumes the sender, and once an ack is sent any other
+/// sender of the same event does nothing. Dropping the sender of a requested
+/// ack without sending it logs a warning.
+#[derive(Debug)]
+pub struct AckSender {
+    socket: Client,
+    id: Option<AckId>,
+    acked: Arc<AtomicBool>,
+}
+
+impl AckSender {
+    pub(crate) fn new(socket: Client, id: Option<AckId>) -> Self {
+        Self {
+            socket,
+            id,
+            acked: Default::default(),
+        }
+    }
+
+    /// Whether the client awaits an ack which is not sent yet.
+    pub fn requested(&self) -> bool {
+        self.id.is_some() && !self.acked.load(Ordering::SeqCst)
+    }
+
+    /// Acks with `data`, JSON or binary. Does nothing if the client asked for
+    /// no ack, or if it was already sent.
+    pub async fn send<D: Into<Payload>>(mut self, data: D) -> Result<()> {
+        match self.id.take() {
+            Some(id) if !self.acked.swap(true, Ordering::SeqCst) => self.socket.ack(id, data).await,
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Drop for AckSender {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            if !self.acked.load(Ordering::SeqCst) {
+                tracing::warn!(
+                    "ack {} of {} dropped without being sent",
+                    id,
+                    self.socket.sid()
+                );
+            }
+        }
+    }
+}
+
+impl FromEvent for AckSender {
+    fn from_event(parts: &EventParts) -> Result<Self> {
+        Ok(AckSender {
+            socket: parts.socket.clone(),
+            id: parts.ack,
+            acked: parts.acked.clone(),
+        })
+    }
+}
+
+/// State of type `S` shared by the handlers, see `ServerBuilder::state`.
+#[derive(Debug)]
+pub struct Data<S>(pub Arc<S>);
+
+impl<S: Send + Sync + 'static> FromEvent for Data<S> {
+    fn from_event(parts: &EventParts) -> Result<Self> {
+        parts.socket.server.state::<S>().map(Data).ok_or_else(|| {
+            Error::InvalidHandlerArgument(format!(
+                "no state of type {} registered",
+                std::any::type_name::<S>()
+            ))
+        })
+    }
+}
+
+/// An async function handling an event, whose arguments are [`FromEvent`]
+/// extractors, see `ServerBuilder::handle`.
+pub trait Handler<Args>: Clone + Send + Sync + 'static {
+    /// Extracts the arguments and runs the handler, fails if an argument can't
+    /// be extracted.
+    fn call(&self, parts: EventParts) -> BoxFuture<'static, Result<()>>;
+}
+
+macro_rules! impl_handler {
+    ($($ty:ident $var:ident),*) => {
+        impl<F, Fut, $($ty,)*> Handler<($($ty,)*)> for F
+        where
+            F: Fn($($ty),*) -> Fut + Clone + Send + Sync + 'static,
+            Fut: Future<Output = ()> + Send + 'static,
+            $($ty: FromEvent + Send + 'static,)*
+        {
+            #[allow(unused_variables)]
+            fn call(&self, parts: EventParts) -> BoxFuture<'static, Result<()>> {
+                fn extract<$($ty: FromEvent,)*>(parts: &EventParts) -> Result<($($ty,)*)> {
+                    Ok(($($ty::from_event(parts)?,)*))
+                }
+                // the handler won't run, the ack is answered with the error
+                let extracted = extract::<$($ty,)*>(&parts).map_err(|e| {
+                    parts.acked.store(true, Ordering::SeqCst);
+                    e
+                });
+                match extracted {
+                    Ok(($($var,)*)) => {
+                        let future = self($($var),*);
+                        Box::pin(async move {
+                            future.await;
+                            Ok(())
+                        })
+                    }
+                    Err(e) => Box::pin(async move { Err(e) }),
+                }
+            }
+        }
+    };
+}
+
+impl_handler!();
+impl_handler!(A a);
+impl_handler!(A a, B b);
+impl_handler!(A a, B b, C c);
+impl_handler!(A a, B b, C c, D d);
+impl_handler!(A a, B b, C c, D d, E e);
@@ -0,0 +1,154 @@
+use std::time::Duration;
+
+use engineio_rs::HeaderMap;
+use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
+use serde_json::Value;
+
+/// Authenticates connecting sockets with a JWT, sent either as `token` of the
+/// CONNECT auth payload or as bearer token of the `Authorization` header. The
+/// claims of a valid token are stored in the socket data, connections without
+/// one are refused with a CONNECT_ERROR.
+#[derive(Clone)]
+pub struct JwtAuth {
+    key: DecodingKey,
+    validation: Validation,
+    claims_key: String,
+}
+
+impl JwtAuth {
+    pub fn new(key: DecodingKey, algorithm: Algorithm) -> Self {
+        let mut validation = Validation::new(algorithm);
+        // only checked once an audience is configured
+        validation.validate_aud = false;
+        Self {
+            key,
+            validation,
+            claims_key: "claims".to_owned(),
+        }
+    }
+
+    /// Validates HS256 tokens signed with `secret`.
+    pub fn hs256(secret: &[u8]) -> Self {
+        Self::new(DecodingKey::from_secret(secret), Algorithm::HS256)
+    }
+
+    /// Requires the `aud` claim to contain one of `audience`.
+    pub fn audience<T: ToString>(mut self, audience: &[T]) -> Self {
+        self.validation.set_audience(audience);
+        self.validation.validate_aud = true;
+        self
+    }
+
+    /// Requires the `iss` claim to be one of `issuer`.
+    pub fn issuer<T: ToString>(mut self, issuer: &[T]) -> Self {
+        self.validation.set_issuer(issuer);
+        self
+    }
+
+    /// Clock skew tolerated when checking `exp` and `nbf`, one minute by default.
+    pub fn leeway(mut self, leeway: Duration) -> Self {
+        self.validation.leeway = leeway.as_secs();
+        self
+    }
+
+    /// The socket data key the claims are stored under, `"claims"` by default.
+    pub fn claims_key<T: Into<String>>(mut self, claims_key: T) -> Self {
+        self.claims_key = claims_key.into();
+        self
+    }
+
+    pub(crate) fn key(&self) -> &str {
+        &self.claims_key
+    }
+
+    /// Returns the claims of the token, or why the connection is refused.
+    pub(crate) fn authenticate(
+        &self,
+        auth: Option<&Value>,
+        headers: Option<&HeaderMap>,
+    ) -> Result<Value, String> {
+        let from_auth = auth
+            .and_then(|auth| auth.get("token"))
+            .and_then(Value::as_str);
+        let from_header = || {
+            headers?
+                .get("authorization")?
+                .to_str()?
+                .strip_prefix("Bearer ")
+        };
+        let token = from_auth
+            .or_else(from_header)
+            .ok_or_else(|| "missing token".to_owned())?;
+
+        decode::<Value>(token.trim(), &self.key, &self.validation)
+            .map(|data| data.claims)
+            .map_err(|e| format!("invalid token: {}", e))
+    }
+}
+
+impl std::fmt::Debug for JwtAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("JwtAuth")
+            .field("validation", &self.validation)
+            .field("claims_key", &self.claims_key)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::json;
+
+    use super::*;
+
+    const SECRET: &[u8] = b"secret";
+
+    fn token(claims: Value) -> String {
+        encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(SECRET),
+        )
+        .unwrap()
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    #[test]
+    fn test_authenticate() {
+        let auth = JwtAuth::hs256(SECRET).audience(&["chat"]);
+        let claims = json!({"sub": "alice", "aud": "chat", "exp": now() + 60});
+        let valid = token(claims.clone());
+
+        let payload = json!({ "token": valid });
+        assert_eq!(auth.authenticate(Some(&payload), None), Ok(claims.clone()));
+
+        let mut headers = HeaderMap::new();
+        headers.insert("authorization".to_owned(), format!("Bearer {}", valid));
+        assert_eq!(auth.authenticate(None, Some(&headers)), Ok(claims));
+
+        assert!(auth.authenticate(None, None).is_err());
+        let other_audience = token(json!({"aud": "admin", "exp": now() + 60}));
+        let payload = json!({ "token": other_audience });
+        assert!(auth.authenticate(Some(&payload), None).is_err());
+    }
+
+    #[test]
+    fn test_leeway() {
+        let expired = json!({ "token": token(json!({"exp": now() - 30})) });
+
+        let auth = JwtAuth::hs256(SECRET).leeway(Duration::from_secs(60));
+        assert!(auth.authenticate(Some(&expired), None).is_ok());
+
+        let auth = auth.leeway(Duration::from_secs(5));
+        assert!(auth.authenticate(Some(&expired), None).is_err());
+    }
+}
@@ -0,0 +1,155 @@
+use std::{net::IpAddr, panic::AssertUnwindSafe, sync::Arc, time::Duration};
+
+use engineio_rs::HeaderMap;
+use futures_util::{future::BoxFuture, FutureExt};
+use serde_json::Value;
+use tracing::{error, warn};
+
+/// What a client presents when it connects to a namespace, inspected by the
+/// middlewares of `ServerBuilder::use_middleware`.
+#[derive(Debug, Clone)]
+pub struct Handshake {
+    pub nsp: String,
+    /// The payload of the CONNECT packet, e.g. `{ "token": .. }`.
+    pub auth: Option<Value>,
+    /// The headers of the request which opened the engine.io session.
+    pub headers: HeaderMap,
+    pub address: Option<IpAddr>,
+}
+
+/// The outcome of a middleware, the error message is sent to the client in a
+/// CONNECT_ERROR packet.
+pub type MiddlewareResult = std::result::Result<(), String>;
+
+pub(crate) type Middleware =
+    Arc<dyn Fn(Handshake, Next) -> BoxFuture<'static, MiddlewareResult> + Send + Sync>;
+
+/// The rest of the middleware chain of a namespace.
+pub struct Next {
+    chain: Arc<Vec<Middleware>>,
+    index: usize,
+}
+
+impl Next {
+    pub(crate) fn new(chain: Arc<Vec<Middleware>>) -> Self {
+        Self { chain, index: 0 }
+    }
+
+    /// Runs the next middleware, accepts the connection once all have passed.
+    pub async fn run(self, handshake: Handshake) -> MiddlewareResult {
+        match self.chain.get(self.index).cloned() {
+            Some(middleware) => {
+                let next = Next {
+                    chain: self.chain,
+                    index: self.index + 1,
+                };
+                middleware(handshake, next).await
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// Runs the middleware `chain` like a handler: a panic or a chain still
+/// running after `timeout` refuses the connection.
+pub(crate) async fn run_chain(
+    chain: Arc<Vec<Middleware>>,
+    handshake: Handshake,
+    timeout: Duration,
+) -> MiddlewareResult {
+    let nsp = handshake.nsp.clone();
+    let run = AssertUnwindSafe(Next::new(chain).run(handshake)).catch_unwind();
+    match tokio::time::timeout(timeout, run).await {
+        Ok(Ok(result)) => result,
+        Ok(Err(panic)) => {
+            let reason = panic
+                .downcast_ref::<&str>()
+                .map(|s| s.to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_default();
+            error!("middleware of {} panicked: {}", nsp, reason);
+            Err("middleware failed".to_owned())
+        }
+        Err(_) => {
+            warn!("middleware of {} timed out after {:?}", nsp, timeout);
+            Err("middleware timed out".to_owned())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures_util::FutureExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_chain() {
+        let handshake = Handshake {
+            nsp: "/admin".to_owned(),
+            auth: Some(serde_json::json!({ "token": "secret" })),
+            headers: HeaderMap::new(),
+            address: None,
+        };
+        let check: Middleware = Arc::new(|handshake: Handshake, next: Next| {
+            async move {
+                match handshake.auth.as_ref().and_then(|auth| auth.get("token")) {
+                    Some(token) if token == "secret" => next.run(handshake).await,
+                    _ => Err("unauthorized".to_owned()),
+                }
+            }
+            .boxed()
+        });
+        let refuse: Middleware =
+            Arc::new(|_: Handshake, _: Next| async { Err("closed".to_owned()) }.boxed());
+
+        assert_eq!(
+            Next::new(Arc::new(vec![])).run(handshake.clone()).await,
+            Ok(())
+        );
+        let chain = Arc::new(vec![check.clone()]);
+        assert_eq!(Next::new(chain).run(handshake.clone()).await, Ok(()));
+        let chain = Arc::new(vec![check.clone(), refuse]);
+        let refused = Next::new(chain).run(handshake.clone()).await;
+        assert_eq!(refused, Err("closed".to_owned()));
+
+        let anonymous = Handshake {
+            auth: None,
+            ..handshake
+        };
+        let chain = Arc::new(vec![check]);
+        assert_eq!(
+            Next::new(chain).run(anonymous).await,
+            Err("unauthorized".to_owned())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run_chain() {
+        let handshake = Handshake {
+            nsp: "/".to_owned(),
+            auth: None,
+            headers: HeaderMap::new(),
+            address: None,
+        };
+        let timeout = Duration::from_millis(50);
+        let pass: Middleware =
+            Arc::new(|handshake: Handshake, next: Next| next.run(handshake).boxed());
+        let panics: Middleware = Arc::new(|_: Handshake, _: Next| panic!("broken"));
+        let hangs: Middleware =
+            Arc::new(|_: Handshake, _: Next| futures_util::future::pending().boxed());
+
+        let chain = Arc::new(vec![pass.clone()]);
+        assert_eq!(run_chain(chain, handshake.clone(), timeout).await, Ok(()));
+        let chain = Arc::new(vec![pass.clone(), panics]);
+        assert_eq!(
+            run_chain(chain, handshake.clone(), timeout).await,
+            Err("middleware failed".to_owned())
+        );
+        let chain = Arc::new(vec![pass, hangs]);
+        assert_eq!(
+            run_chain(chain, handshake, timeout).await,
+            Err("middleware timed out".to_owned())
+        );
+    }
+}
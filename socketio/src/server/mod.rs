@@ -1,8 +1,33 @@
+pub(crate) mod broadcast;
 pub(crate) mod builder;
 pub(crate) mod client;
+pub(crate) mod config;
+pub(crate) mod diagnostic;
+pub(crate) mod extract;
+#[cfg(feature = "auth-jwt")]
+pub(crate) mod join_token;
+#[cfg(feature = "auth-jwt")]
+pub(crate) mod jwt;
+pub(crate) mod middleware;
+pub(crate) mod namespace;
+pub(crate) mod rate;
 #[allow(clippy::module_inception)]
 pub(crate) mod server;
+pub(crate) mod store;
 
+pub use broadcast::BroadcastOperator;
 pub use builder::ServerBuilder;
 pub use client::Client;
-pub use server::Server;
+pub use config::{NamespaceConfig, ServerConfig};
+pub use diagnostic::{ProtocolError, ProtocolErrorKind};
+pub use extract::{AckSender, Data, EventParts, FromEvent, Handler, Json, SocketRef};
+#[cfg(feature = "auth-jwt")]
+pub use join_token::JoinTokens;
+#[cfg(feature = "auth-jwt")]
+pub use jwt::JwtAuth;
+pub use middleware::{Handshake, MiddlewareResult, Next};
+pub use namespace::NamespaceMatcher;
+pub use server::{AuditStats, NamespaceInfo, RoomChange, RoomsSnapshot, Sample, Server};
+#[cfg(feature = "session-redis")]
+pub use store::RedisStore;
+pub use store::{MemoryStore, SessionKey, SessionStore};
@@ -0,0 +1,64 @@
+use std::{fmt::Debug, sync::Arc};
+
+use regex::Regex;
+
+use crate::{callback::Callback, server::Client, Event};
+
+/// Matches the names of namespaces not known when the server is built, see
+/// `ServerBuilder::on_dynamic`. Built from a [`Regex`] or with [`Self::new`].
+#[derive(Clone)]
+pub struct NamespaceMatcher {
+    matches: Arc<dyn Fn(&str) -> bool + Send + Sync>,
+}
+
+impl NamespaceMatcher {
+    pub fn new<F>(matches: F) -> Self
+    where
+        F: Fn(&str) -> bool + Send + Sync + 'static,
+    {
+        Self {
+            matches: Arc::new(matches),
+        }
+    }
+
+    pub fn matches(&self, nsp: &str) -> bool {
+        (self.matches)(nsp)
+    }
+}
+
+impl From<Regex> for NamespaceMatcher {
+    fn from(regex: Regex) -> Self {
+        Self::new(move |nsp| regex.is_match(nsp))
+    }
+}
+
+impl Debug for NamespaceMatcher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NamespaceMatcher").finish_non_exhaustive()
+    }
+}
+
+/// A handler registered for every namespace of a matcher, instantiated for
+/// each of them once a client connects to it.
+pub(crate) struct DynamicHandler {
+    pub(crate) matcher: NamespaceMatcher,
+    pub(crate) event: Event,
+    pub(crate) callback: Box<dyn Fn() -> Callback<Client> + Send + Sync>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_matcher() {
+        let tenants = NamespaceMatcher::from(Regex::new(r"^/tenant-\d+$").unwrap());
+        assert!(tenants.matches("/tenant-7"));
+        assert!(!tenants.matches("/tenant-x"));
+        assert!(!tenants.matches("/tenant-7/admin"));
+
+        let orgs = NamespaceMatcher::new(|nsp| nsp.starts_with("/org/"));
+        assert!(orgs.matches("/org/acme"));
+        assert!(!orgs.matches("/"));
+    }
+}
@@ -0,0 +1,87 @@
+use std::{sync::Arc, time::Duration};
+
+use engineio_rs::Clock;
+use parking_lot::Mutex;
+use tokio::time::Instant;
+
+/// A token bucket admitting `events` per `per` on average, and bursts of up to
+/// `events`, see `ServerBuilder::rate_limit`.
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    events: u32,
+    per: Duration,
+    // the tokens left and when they were counted
+    bucket: Mutex<(f64, Instant)>,
+    clock: Arc<dyn Clock>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(events: u32, per: Duration, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            events,
+            per,
+            bucket: Mutex::new((events as f64, clock.now())),
+            clock,
+        }
+    }
+
+    /// Takes a token, false if none is left.
+    pub(crate) fn admit(&self) -> bool {
+        let mut bucket = self.bucket.lock();
+        let (tokens, counted) = &mut *bucket;
+        let now = self.clock.now();
+        let refill = match self.per.is_zero() {
+            true => self.events as f64,
+            false => {
+                now.duration_since(*counted).as_secs_f64() / self.per.as_secs_f64()
+                    * self.events as f64
+            }
+        };
+        *tokens = (*tokens + refill).min(self.events as f64);
+        *counted = now;
+        if *tokens < 1.0 {
+            return false;
+        }
+        *tokens -= 1.0;
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Debug)]
+    struct ManualClock(Mutex<Instant>);
+
+    impl ManualClock {
+        fn advance(&self, duration: Duration) {
+            *self.0.lock() += duration;
+        }
+    }
+
+    impl Clock for ManualClock {
+        fn now(&self) -> Instant {
+            *self.0.lock()
+        }
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let clock = Arc::new(ManualClock(Mutex::new(Instant::now())));
+        let limiter = RateLimiter::new(2, Duration::from_millis(200), clock.clone());
+        assert!(limiter.admit());
+        assert!(limiter.admit());
+        assert!(!limiter.admit());
+
+        clock.advance(Duration::from_millis(120));
+        assert!(limiter.admit());
+        assert!(!limiter.admit());
+
+        // the bucket holds no more than a burst
+        clock.advance(Duration::from_millis(600));
+        assert!(limiter.admit());
+        assert!(limiter.admit());
+        assert!(!limiter.admit());
+    }
+}
@@ -1,60 +1,340 @@
+#[cfg(feature = "auth-jwt")]
+use crate::server::{JoinTokens, JwtAuth};
 use crate::{
-    ack::AckId, callback::Callback, packet::PacketType, server::Client as ServerSocket,
-    socket::RawSocket, Error, Event, NameSpace, Payload,
+    ack::{AckContext, AckId},
+    callback::{Callback, HandlerConfig, PanicPolicy},
+    compression::Compression,
+    dispatcher::{DispatchLimit, DispatchMode, DispatchOverflow, Dispatcher},
+    error::Result,
+    packet::PacketType,
+    server::{
+        broadcast::BroadcastOperator,
+        client::SocketData,
+        diagnostic::{ProtocolError, ProtocolErrorHook, ProtocolErrorKind},
+        middleware::{run_chain, Handshake, Middleware},
+        namespace::DynamicHandler,
+        Client as ServerSocket,
+    },
+    socket::{RawSocket, Received, MIGRATION_TOKEN, RESUME_TOKEN},
+    DisconnectReason, Error, Event, NameSpace, Packet, Payload, PayloadCipher, SessionKey,
+    SessionStore,
 };
+use async_stream::stream;
+use bytes::Bytes;
 use dashmap::DashMap;
-use engineio_rs::{Event as EngineEvent, Server as EngineServer, Sid as EngineSid};
-use futures_util::future::BoxFuture;
-use serde_json::json;
+use engineio_rs::{
+    Event as EngineEvent, Incoming, QueueStats, Server as EngineServer, Sid as EngineSid,
+};
+use futures_util::{future::BoxFuture, Stream};
+use parking_lot::Mutex;
+use rand::{distributions::Alphanumeric, seq::IteratorRandom, Rng};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
 use std::{
-    collections::{HashMap, HashSet},
+    any::{Any, TypeId},
+    collections::{hash_map::Entry, BTreeMap, BTreeSet, HashMap, HashSet},
     sync::{
         atomic::{AtomicUsize, Ordering},
         Arc,
     },
-    time::Duration,
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    task::JoinHandle,
 };
 use tracing::{error, trace, warn};
 
 // TODO: read from config
 const CONNECT_TIMEOUT: u64 = 5;
+// how long the state of a migrated socket waits for its client
+const MIGRATION_TTL: Duration = Duration::from_secs(60);
+// how long the middlewares of a namespace without a timeout may take
+const MIDDLEWARE_TIMEOUT: Duration = Duration::from_secs(CONNECT_TIMEOUT);
 
 type Sid = Arc<String>;
 type Room = String;
 type Rooms = DashMap<NameSpace, HashMap<Room, HashSet<Sid>>>;
+type User = String;
+type Users = DashMap<NameSpace, HashMap<User, HashSet<Sid>>>;
+type UserRooms = HashMap<NameSpace, HashMap<User, Vec<Room>>>;
 type On = DashMap<Event, Callback<ServerSocket>>;
+type Clients<T> = DashMap<EngineSid, DashMap<Sid, HashMap<NameSpace, T>>>;
+
+/// A registered namespace, as reported by [`Server::namespace_info`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NamespaceInfo {
+    pub name: String,
+    /// Number of sockets connected to the namespace.
+    pub sockets: usize,
+    /// Names of the events with a registered handler.
+    pub events: Vec<String>,
+}
+
+/// The membership of a socket in a room after `Client::join` or `Client::leave`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RoomChange {
+    pub room: String,
+    /// Whether the socket was added or removed, `false` if it already was or
+    /// was not a member.
+    pub changed: bool,
+    /// Number of sockets in the room afterwards.
+    pub size: usize,
+}
+
+/// How many sockets `Server::emit_to_sample` picks at random.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Sample {
+    /// This share of the sockets, between `0.0` and `1.0`, rounded.
+    Fraction(f64),
+    /// At most this many sockets.
+    Count(usize),
+}
+
+impl Sample {
+    /// Picks the sockets out of `sids`.
+    fn pick(self, sids: HashSet<Sid>) -> HashSet<Sid> {
+        let amount = match self {
+            Sample::Fraction(fraction) => {
+                (sids.len() as f64 * fraction.clamp(0.0, 1.0)).round() as usize
+            }
+            Sample::Count(count) => count,
+        };
+        sids.into_iter()
+            .choose_multiple(&mut rand::thread_rng(), amount)
+            .into_iter()
+            .collect()
+    }
+}
+
+/// Inconsistencies repaired by the membership audit since the server started,
+/// see `ServerBuilder::audit_interval`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AuditStats {
+    pub runs: usize,
+    /// Sids removed from rooms without a connected socket.
+    pub stale_room_sids: usize,
+    /// Sessions dropped whose engine.io socket was already gone.
+    pub orphan_clients: usize,
+}
+
+/// The users in the rooms of every namespace, taken with `Server::export_rooms`
+/// before a restart and handed to `Server::import_rooms` after it. Sockets not
+/// bound to a user are left out, their sids don't survive the restart.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoomsSnapshot {
+    /// The user ids in each room, by namespace and room.
+    pub namespaces: BTreeMap<NameSpace, BTreeMap<Room, BTreeSet<User>>>,
+}
 
 pub struct Server {
     pub(crate) on: DashMap<NameSpace, Arc<On>>,
+    // instantiated into `on` for each matching namespace, see `namespace_on`
+    pub(crate) dynamic: Vec<DynamicHandler>,
+    // the sockets of each namespace set up from `dynamic`, evicted with the last
+    pub(crate) dynamic_sockets: DashMap<NameSpace, usize>,
     pub(crate) rooms: Rooms,
-    pub(crate) clients: DashMap<EngineSid, DashMap<Sid, HashMap<NameSpace, ServerSocket>>>,
+    // the sockets bound to a user id, see `Client::bind_user`
+    pub(crate) users: Users,
+    // the rooms users rejoin when they bind, until the deadline, see `import_rooms`
+    pub(crate) imported_rooms: Mutex<Option<(Instant, UserRooms)>>,
+    pub(crate) clients: Clients<ServerSocket>,
+    // the poll loops of the sockets of an engine session, stopped with it
+    pub(crate) poll_tasks: DashMap<EngineSid, JoinHandle<()>>,
     pub(crate) engine_server: EngineServer,
     pub(crate) sid_generator: SidGenerator,
+    pub(crate) cipher: Option<Arc<dyn PayloadCipher>>,
+    pub(crate) compression_threshold: Option<usize>,
+    // the `max_payload` of the `ServerOption`, bounds decompressed packets
+    pub(crate) max_payload: usize,
+    pub(crate) handlers: HashMap<NameSpace, HashMap<Event, HandlerConfig>>,
+    pub(crate) coalesce: HashMap<NameSpace, HashSet<Event>>,
+    pub(crate) panic_policy: PanicPolicy,
+    // the pool shared by all sockets if dispatching to one
+    pub(crate) dispatcher: Option<Arc<Dispatcher>>,
+    pub(crate) dispatch_mode: DispatchMode,
+    pub(crate) dispatch_queue: (usize, DispatchOverflow),
+    pub(crate) dispatch_limits: HashMap<NameSpace, Arc<DispatchLimit>>,
+    pub(crate) room_capacities: HashMap<NameSpace, usize>,
+    pub(crate) max_payloads: HashMap<NameSpace, usize>,
+    pub(crate) rate_limits: HashMap<NameSpace, (u32, Duration)>,
+    pub(crate) namespace_timeouts: HashMap<NameSpace, Duration>,
+    #[cfg(feature = "auth-jwt")]
+    pub(crate) jwt_auth: HashMap<NameSpace, Arc<JwtAuth>>,
+    pub(crate) middlewares: HashMap<NameSpace, Arc<Vec<Middleware>>>,
+    pub(crate) protocol_error_hook: Option<ProtocolErrorHook>,
+    #[cfg(feature = "auth-jwt")]
+    pub(crate) join_tokens: HashMap<NameSpace, Arc<JoinTokens>>,
+    pub(crate) session_store: Arc<dyn SessionStore>,
+    // the ttl and rotation of resume tokens, see `ServerBuilder::resume_sessions`
+    pub(crate) resume: Option<(Duration, Duration)>,
+    // the current resume token of each socket
+    pub(crate) resume_tokens: DashMap<Sid, String>,
+    pub(crate) audit_interval: Option<Duration>,
+    pub(crate) audit_stats: Mutex<AuditStats>,
+    pub(crate) memory_cap: Option<usize>,
+    // the state extracted by `Data`, by type
+    pub(crate) states: HashMap<TypeId, Arc<dyn Any + Send + Sync>>,
 }
 
 impl Server {
     #[allow(dead_code)]
     pub async fn serve(self: Arc<Self>) {
-        self.recv_event();
+        self.start();
         self.engine_server.serve().await
     }
 
-    pub async fn emit_to<E, D>(self: &Arc<Self>, nsp: &str, rooms: Vec<&str>, event: E, data: D)
+    /// Serves the connections of `incoming` instead of listening on the port,
+    /// see `engineio_rs::Server::serve_incoming`.
+    pub async fn serve_incoming<I, S>(self: Arc<Self>, incoming: I)
+    where
+        I: Stream<Item = Incoming<S>> + Unpin,
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        self.start();
+        self.engine_server.serve_incoming(incoming).await
+    }
+
+    fn start(self: &Arc<Self>) {
+        if let Some(dispatcher) = &self.dispatcher {
+            dispatcher.start(self.engine_server.runtime());
+        }
+        self.recv_event();
+        if let Some(interval) = self.audit_interval {
+            self.start_audit(interval);
+        }
+    }
+
+    /// Emits to the sockets of `nsp` in any of `rooms`, once per socket. Fails
+    /// if `data` can't be sent, the emits to single sockets run in the
+    /// background and their errors are logged.
+    pub async fn emit_to<E, D>(
+        self: &Arc<Self>,
+        nsp: &str,
+        rooms: Vec<&str>,
+        event: E,
+        data: D,
+    ) -> Result<()>
+    where
+        E: Into<Event>,
+        D: Into<Payload>,
+    {
+        let sids_to_emit = self.sids_to_emit(nsp, rooms).await;
+        self.emit_to_sids(nsp, sids_to_emit, event.into(), data.into())
+            .await
+    }
+
+    /// Emits to a random sample of the sockets of `nsp` in any of `rooms`, e.g.
+    /// for A/B prompts or to spread refreshes of a very large room over time.
+    /// Returns the number of sockets picked.
+    pub async fn emit_to_sample<E, D>(
+        self: &Arc<Self>,
+        nsp: &str,
+        rooms: Vec<&str>,
+        sample: Sample,
+        event: E,
+        data: D,
+    ) -> Result<usize>
     where
         E: Into<Event>,
         D: Into<Payload>,
     {
-        let event = event.into();
-        let payload = data.into();
+        let sids = sample.pick(self.sids_to_emit(nsp, rooms).await);
+        let picked = sids.len();
+        self.emit_to_sids(nsp, sids, event.into(), data.into())
+            .await?;
+        Ok(picked)
+    }
 
-        let sids_to_emit = self.sids_to_emit(nsp, rooms).await;
+    /// Emits to every socket of `nsp` bound to `user` with `Client::bind_user`,
+    /// e.g. to all tabs and devices the user has open.
+    pub async fn emit_to_user<E, D>(
+        self: &Arc<Self>,
+        nsp: &str,
+        user: &str,
+        event: E,
+        data: D,
+    ) -> Result<()>
+    where
+        E: Into<Event>,
+        D: Into<Payload>,
+    {
+        let sids = self.user_sids(nsp, user);
+        self.emit_to_sids(nsp, sids, event.into(), data.into())
+            .await
+    }
+
+    /// Disconnects every socket of `nsp` bound to `user`, e.g. to log the user
+    /// out everywhere, firing their `Close` handlers with `reason`. Returns the
+    /// number of sockets disconnected.
+    pub async fn disconnect_user(self: &Arc<Self>, nsp: &str, user: &str, reason: &str) -> usize {
+        let mut disconnected = 0;
+        for sid in self.user_sids(nsp, user) {
+            if let Some(client) = self.client(&sid, nsp).await {
+                client
+                    .close(reason, DisconnectReason::ServerDisconnect)
+                    .await;
+                disconnected += 1;
+            }
+            // closing a socket closes its connection, the rooms of every
+            // namespace on it are left with it. The caller may be a handler run
+            // by the poll task of the connection, which dropping the client aborts
+            if let Some(esid) = SidGenerator::decode(&sid) {
+                let server = self.clone();
+                self.engine_server.spawn(async move {
+                    server
+                        .drop_client(&esid, DisconnectReason::ServerDisconnect)
+                        .await
+                });
+            }
+        }
+        disconnected
+    }
+
+    /// Disconnects the socket `sid` of `nsp`, e.g. to kick or ban a client. Its
+    /// `Close` handlers fire with "server namespace disconnect", the client is
+    /// sent a DISCONNECT packet and its engine.io connection is closed, leaving
+    /// the rooms and users of every namespace on it. Returns whether the socket
+    /// was connected.
+    pub async fn disconnect_sid(self: &Arc<Self>, nsp: &str, sid: &Sid) -> bool {
+        let client = match self.client(sid, nsp).await {
+            Some(client) => client,
+            None => return false,
+        };
+        client
+            .close(
+                "server namespace disconnect",
+                DisconnectReason::ServerDisconnect,
+            )
+            .await;
+        if let Some(esid) = SidGenerator::decode(sid) {
+            // the caller may be a handler run by the poll task of the connection,
+            // which dropping the client aborts
+            let server = self.clone();
+            self.engine_server.spawn(async move {
+                server
+                    .drop_client(&esid, DisconnectReason::ServerDisconnect)
+                    .await
+            });
+        }
+        true
+    }
 
-        for sid in sids_to_emit {
+    pub(crate) async fn emit_to_sids(
+        self: &Arc<Self>,
+        nsp: &str,
+        sids: HashSet<Sid>,
+        event: Event,
+        payload: Payload,
+    ) -> Result<()> {
+        event.check_emit()?;
+        payload.check()?;
+        for sid in sids {
             if let Some(client) = self.client(&sid, nsp).await {
                 let event = event.clone();
                 let payload = payload.clone();
 
-                tokio::spawn(async move {
+                self.engine_server.spawn(async move {
                     let r = client.emit(event, payload).await;
                     trace!("server emit_to: {}, status: {:?}", sid, r);
                     if r.is_err() {
@@ -63,6 +343,166 @@ impl Server {
                 });
             }
         }
+        Ok(())
+    }
+
+    /// Hands the socket `sid` of `nsp` over to the server at `peer`, e.g. to drain
+    /// this instance during a rolling deploy. Its rooms, data and user are put
+    /// in the session store under a one-time token, so the store must be shared
+    /// with the peer like `RedisStore`. The client is told to reconnect to `peer`
+    /// presenting the token and re-sends its emits still awaiting an ack, acks
+    /// awaited by this server are not carried over. Returns `false` if the socket
+    /// is not connected.
+    pub async fn migrate(self: &Arc<Self>, nsp: &str, sid: &Sid, peer: &str) -> Result<bool> {
+        let client = match self.client(sid, nsp).await {
+            Some(client) => client,
+            None => return Ok(false),
+        };
+        let token = random_token();
+        let state = self.session_state(nsp, sid, &client);
+        self.session_store
+            .set(
+                &SessionKey::Migration(token.clone()),
+                state,
+                Some(MIGRATION_TTL),
+            )
+            .await?;
+
+        client
+            .emit_reserved(
+                Event::Migrate,
+                json!({ "url": peer, "token": token }).into(),
+            )
+            .await?;
+        client
+            .close("migrated", DisconnectReason::ServerDisconnect)
+            .await;
+        if let Some(esid) = SidGenerator::decode(sid) {
+            // the caller may be a handler run by the poll task of the connection,
+            // which dropping the client aborts
+            let server = self.clone();
+            self.engine_server.spawn(async move {
+                server
+                    .drop_client(&esid, DisconnectReason::ServerDisconnect)
+                    .await
+            });
+        }
+        Ok(true)
+    }
+
+    /// The rooms, data and user of the socket `sid`, as put back by `restore`.
+    fn session_state(&self, nsp: &str, sid: &Sid, client: &ServerSocket) -> Value {
+        let rooms: Vec<Room> = self
+            .rooms
+            .get(nsp)
+            .map(|nsp_rooms| {
+                nsp_rooms
+                    .iter()
+                    .filter(|(_, room_sids)| room_sids.contains(sid))
+                    .map(|(room, _)| room.clone())
+                    .collect()
+            })
+            .unwrap_or_default();
+        let user = self.users.get(nsp).and_then(|nsp_users| {
+            nsp_users
+                .iter()
+                .find(|(_, user_sids)| user_sids.contains(sid))
+                .map(|(user, _)| user.clone())
+        });
+        let data: Map<String, Value> = client
+            .data()
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect();
+        json!({ "nsp": nsp, "rooms": rooms, "data": data, "user": user })
+    }
+
+    /// Tells every connected client that this server is about to restart. Once
+    /// their connection drops, clients reconnect on their own after `delay`
+    /// plus a random jitter of up to `delay`, instead of all at once right
+    /// after the restart.
+    pub async fn announce_maintenance(self: &Arc<Self>, delay: Duration) -> Result<()> {
+        let payload = Payload::from(json!({ "delay": delay.as_millis() as u64 }));
+        for client in self.all_sockets() {
+            let payload = payload.clone();
+            self.engine_server.spawn(async move {
+                let r = client.emit_reserved(Event::Maintenance, payload).await;
+                if r.is_err() {
+                    error!("announce_maintenance to {} failed {:?}", client.sid(), r);
+                }
+            });
+        }
+        Ok(())
+    }
+
+    /// Emits to every connected socket of `nsp`, whatever rooms they are in.
+    pub async fn broadcast<E, D>(self: &Arc<Self>, nsp: &str, event: E, data: D) -> Result<()>
+    where
+        E: Into<Event>,
+        D: Into<Payload>,
+    {
+        let sids = self.namespace_sids(nsp);
+        self.emit_to_sids(nsp, sids, event.into(), data.into())
+            .await
+    }
+
+    /// Picks sockets of `nsp` to emit to by rooms, e.g.
+    /// `server.of("/chat").to("lobby").except("muted").emit("msg", data)`.
+    pub fn of<T: Into<String>>(self: &Arc<Self>, nsp: T) -> BroadcastOperator {
+        BroadcastOperator::new(self.clone(), nsp.into())
+    }
+
+    /// The sids of the connected sockets of `nsp`.
+    pub(crate) fn namespace_sids(&self, nsp: &str) -> HashSet<Sid> {
+        self.clients
+            .iter()
+            .flat_map(|sid_map| {
+                sid_map
+                    .iter()
+                    .filter(|nsp_map| nsp_map.contains_key(nsp))
+                    .map(|nsp_map| nsp_map.key().clone())
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    /// Emits to every connected socket of every namespace, e.g. for maintenance
+    /// notices.
+    pub async fn emit_all<E, D>(self: &Arc<Self>, event: E, data: D) -> Result<()>
+    where
+        E: Into<Event>,
+        D: Into<Payload>,
+    {
+        let event = event.into();
+        event.check_emit()?;
+        let payload = data.into();
+        payload.check()?;
+
+        for client in self.all_sockets() {
+            let event = event.clone();
+            let payload = payload.clone();
+
+            self.engine_server.spawn(async move {
+                let r = client.emit(event, payload).await;
+                if r.is_err() {
+                    error!("emit_all to {} failed {:?}", client.sid(), r);
+                }
+            });
+        }
+        Ok(())
+    }
+
+    /// The connected sockets of every namespace.
+    fn all_sockets(&self) -> Vec<ServerSocket> {
+        self.clients
+            .iter()
+            .flat_map(|sid_map| {
+                sid_map
+                    .iter()
+                    .flat_map(|nsp_map| nsp_map.values().cloned().collect::<Vec<_>>())
+                    .collect::<Vec<_>>()
+            })
+            .collect()
     }
 
     pub async fn emit_to_with_ack<F, E, D>(
@@ -73,7 +513,8 @@ impl Server {
         data: D,
         timeout: Duration,
         callback: F,
-    ) where
+    ) -> Result<()>
+    where
         F: for<'a> std::ops::FnMut(
                 Option<Payload>,
                 ServerSocket,
@@ -87,23 +528,24 @@ impl Server {
         D: Into<Payload>,
     {
         let event = event.into();
+        event.check_emit()?;
         let payload = data.into();
+        payload.check()?;
+        let context = Arc::new(AckContext {
+            event,
+            rooms: rooms.iter().map(|room| room.to_string()).collect(),
+            emitted_at: SystemTime::now(),
+        });
 
         for sid in self.sids_to_emit(nsp, rooms).await {
             if let Some(client) = self.client(&sid, nsp).await {
-                let event = event.clone();
                 let payload = payload.clone();
-
+                let context = context.clone();
                 let callback_clone = callback.clone();
 
-                tokio::spawn(async move {
+                self.engine_server.spawn(async move {
                     let r = client
-                        .emit_with_ack(
-                            event.clone(),
-                            payload.clone(),
-                            timeout,
-                            callback_clone.clone(),
-                        )
+                        .emit_with_ack_context(payload, timeout, context, callback_clone)
                         .await;
                     if r.is_err() {
                         error!("emit_with_ack to {} {:?}", sid, r);
@@ -111,25 +553,55 @@ impl Server {
                 });
             }
         }
+        Ok(())
     }
 
-    async fn sids_to_emit(&self, nsp: &str, rooms: Vec<&str>) -> HashSet<Sid> {
-        let clients = &self.rooms;
+    /// The sockets of `room` in `nsp`, resolved one at a time as the stream is
+    /// polled, so large rooms can be processed incrementally. Only the sids are
+    /// copied up front, sockets which disconnect meanwhile are skipped.
+    pub fn sockets_in_room(
+        self: &Arc<Self>,
+        nsp: &str,
+        room: &str,
+    ) -> impl Stream<Item = ServerSocket> {
+        let sids: Vec<Sid> = self
+            .rooms
+            .get(nsp)
+            .and_then(|nsp_rooms| {
+                nsp_rooms
+                    .get(room)
+                    .map(|sids| sids.iter().cloned().collect())
+            })
+            .unwrap_or_default();
+        let server = self.clone();
+        let nsp = nsp.to_owned();
+        stream! {
+            for sid in sids {
+                if let Some(socket) = server.client(&sid, &nsp).await {
+                    yield socket;
+                }
+            }
+        }
+    }
+
+    /// The recipients of a broadcast to `rooms`. Every broadcast resolves its
+    /// recipients here, so a socket reached through several rooms (or a room and
+    /// its own sid) is emitted to exactly once.
+    pub(crate) async fn sids_to_emit(&self, nsp: &str, rooms: Vec<&str>) -> HashSet<Sid> {
+        let room_clients = self.rooms.get(nsp);
         let mut sids_to_emit = HashSet::new();
-        if let Some(room_clients) = clients.get(nsp) {
-            for room_name in rooms {
-                match room_clients.get(room_name) {
-                    Some(room) => {
-                        for sid in room {
-                            sids_to_emit.insert(sid.clone());
-                        }
-                    }
-                    // room may be sid
-                    None => {
-                        let _ = sids_to_emit.insert(Arc::new(room_name.to_owned()));
+        for room_name in rooms {
+            match room_clients.as_ref().and_then(|r| r.get(room_name)) {
+                Some(room) => {
+                    for sid in room {
+                        sids_to_emit.insert(sid.clone());
                     }
-                };
-            }
+                }
+                // room may be sid
+                None => {
+                    let _ = sids_to_emit.insert(Arc::new(room_name.to_owned()));
+                }
+            };
         }
         sids_to_emit
     }
@@ -137,14 +609,29 @@ impl Server {
     pub(crate) fn recv_event(self: &Arc<Self>) {
         let event_rx = self.engine_server.event_rx();
         let server = self.to_owned();
-        tokio::spawn(async move {
+        self.engine_server.spawn(async move {
             let mut event_rx = event_rx.lock().await;
+            // the sockets closed by the engine for missing their pongs
+            let mut timed_out = HashSet::new();
 
             while let Some(event) = event_rx.recv().await {
                 trace!("server recv_event: {:?}", event);
                 match event {
                     EngineEvent::OnOpen(esid) => server.create_client(esid).await,
-                    EngineEvent::OnClose(esid) => server.drop_client(&esid).await,
+                    EngineEvent::OnPingTimeout(esid) => {
+                        timed_out.insert(esid);
+                    }
+                    EngineEvent::OnClose(esid) => {
+                        let reason = match timed_out.remove(&esid) {
+                            true => DisconnectReason::PingTimeout,
+                            false => DisconnectReason::TransportClose,
+                        };
+                        server.drop_client(&esid, reason).await
+                    }
+                    EngineEvent::OnSlowConsumer(esid, stats) => {
+                        warn!("slow consumer {}: {:?}", esid, stats);
+                        server.slow_consumer(&esid, stats)
+                    }
                     EngineEvent::OnPacket(_esid, _packet) => {
                         // TODO: watch new namespace packet
                     }
@@ -154,210 +641,959 @@ impl Server {
         });
     }
 
-    pub(crate) async fn client(&self, sid: &Sid, nsp: &str) -> Option<ServerSocket> {
-        let esid = &SidGenerator::decode(sid)?;
-        self.clients.get(esid)?.get(sid)?.get(nsp).cloned()
+    /// Stops or resumes accepting new connections while the connected sockets
+    /// are served as usual, e.g. to drain a blue/green deployment behind a load
+    /// balancer. Refused handshakes are answered with `503 Service Unavailable`
+    /// and a `Retry-After` hint, which clients treat as a failed connect.
+    pub fn set_accepting(&self, accepting: bool) {
+        self.engine_server.set_accepting(accepting)
     }
 
-    pub(crate) async fn join<T: Into<String>>(
-        self: &Arc<Self>,
-        nsp: &str,
-        rooms: Vec<T>,
-        sid: Sid,
-    ) {
-        for room_name in rooms {
-            let room_name = room_name.into();
-            match self.rooms.get_mut(nsp) {
-                None => {
-                    let mut room_sids = HashSet::new();
-                    room_sids.insert(sid.clone());
-                    let mut rooms = HashMap::new();
-                    rooms.insert(room_name, room_sids);
-                    self.rooms.insert(nsp.to_owned(), rooms);
-                }
-                Some(mut rooms) => {
-                    if let Some(room_sids) = rooms.get_mut(&room_name) {
-                        let _ = room_sids.insert(sid.clone());
-                    } else {
-                        let mut room_sids = HashSet::new();
-                        room_sids.insert(sid.clone());
-                        rooms.insert(room_name, room_sids);
-                    }
-                }
-            };
-        }
+    /// Whether new connections are accepted, see `set_accepting`.
+    pub fn is_accepting(&self) -> bool {
+        self.engine_server.is_accepting()
     }
 
-    pub(crate) async fn leave(self: &Arc<Self>, nsp: &str, rooms: Vec<&str>, sid: &Sid) {
-        for room_name in rooms {
-            if let Some(mut nsp_rooms) = self.rooms.get_mut(nsp) {
-                if let Some(room_sids) = nsp_rooms.get_mut(room_name) {
-                    room_sids.remove(sid);
-                }
-            };
+    /// Disconnects every socket and waits for their poll loops to stop. The
+    /// server keeps accepting new connections while `serve` runs.
+    pub async fn close(self: &Arc<Self>) {
+        let esids: Vec<EngineSid> = self.clients.iter().map(|c| c.key().to_owned()).collect();
+        for esid in esids {
+            self.drop_client(&esid, DisconnectReason::ServerShutdown)
+                .await;
         }
     }
 
-    async fn create_client(self: &Arc<Self>, esid: EngineSid) {
-        if let Some(engine_socket) = self.engine_server.socket(&esid).await {
-            let socket = RawSocket::server_end(engine_socket);
+    /// Cross-checks rooms, clients and engine.io sockets once, dropping clients
+    /// whose engine.io socket is gone and removing sids without a client from
+    /// rooms. Returns what was repaired by this run.
+    pub async fn audit(self: &Arc<Self>) -> AuditStats {
+        let mut repaired = AuditStats {
+            runs: 1,
+            ..Default::default()
+        };
 
-            // TODO: support multiple namespace
-            match self.client_info(&esid).await {
-                Some((sid, nsp)) => self.insert_clients(socket, nsp, esid, sid, false).await,
-                None => self.handle_connect(socket, esid).await,
-            };
+        let esids: Vec<EngineSid> = self.clients.iter().map(|c| c.key().to_owned()).collect();
+        for esid in esids {
+            if self.engine_server.socket(&esid).await.is_none() {
+                warn!("audit: client {} without engine socket", esid);
+                self.drop_client(&esid, DisconnectReason::TransportClose)
+                    .await;
+                repaired.orphan_clients += 1;
+            }
         }
-    }
 
-    // TODO: support multiple nsp
-    // currently one esid mapping to one sid,
-    // one sid mapping one nsp
-    async fn client_info(&self, esid: &EngineSid) -> Option<(Sid, String)> {
-        let sid_map = self.clients.get(esid)?;
-        let entry = sid_map.iter().next()?;
-        let (sid, nsp_map) = entry.pair();
-        let (nsp, _) = nsp_map.iter().next()?;
+        let mut stale = Vec::new();
+        for nsp_rooms in self.rooms.iter() {
+            for (room, room_sids) in nsp_rooms.value() {
+                for sid in room_sids {
+                    if !self.connected(sid, nsp_rooms.key()) {
+                        stale.push((nsp_rooms.key().to_owned(), room.to_owned(), sid.clone()));
+                    }
+                }
+            }
+        }
+        for (nsp, room, sid) in stale {
+            // a socket joins rooms in its connect handler before it is a client
+            if let Some(esid) = SidGenerator::decode(&sid) {
+                if self.engine_server.socket(&esid).await.is_some() {
+                    continue;
+                }
+            }
+            warn!("audit: stale sid {} in room {} of {}", sid, room, nsp);
+            self.leave(&nsp, vec![&room], &sid).await;
+            repaired.stale_room_sids += 1;
+        }
+        for mut nsp_rooms in self.rooms.iter_mut() {
+            nsp_rooms.retain(|_, room_sids| !room_sids.is_empty());
+        }
 
-        Some((sid.to_owned(), nsp.to_owned()))
+        let mut stats = self.audit_stats.lock();
+        stats.runs += repaired.runs;
+        stats.stale_room_sids += repaired.stale_room_sids;
+        stats.orphan_clients += repaired.orphan_clients;
+        repaired
     }
 
-    async fn handle_connect(self: &Arc<Self>, socket: RawSocket, esid: EngineSid) {
-        trace!("handle_connect: {:?}", esid);
-        let slf = self.clone();
-        tokio::spawn(async move {
-            if tokio::time::timeout(
-                Duration::from_secs(CONNECT_TIMEOUT),
-                slf.do_handle_connect(socket, esid.clone()),
-            )
-            .await
-            .is_err()
-            {
-                warn!("handle_connect timeout, {:?} dropped", esid);
-                slf.drop_client(&esid).await;
+    /// Totals of the audits run so far.
+    pub fn audit_stats(&self) -> AuditStats {
+        *self.audit_stats.lock()
+    }
+
+    fn start_audit(self: &Arc<Self>, interval: Duration) {
+        let server = Arc::downgrade(self);
+        self.engine_server.spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            // the first tick completes immediately
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                match server.upgrade() {
+                    Some(server) => {
+                        let repaired = server.audit().await;
+                        trace!("audit repaired {:?}", repaired);
+                    }
+                    None => break,
+                }
             }
         });
     }
 
-    async fn do_handle_connect(self: &Arc<Self>, socket: RawSocket, esid: EngineSid) {
-        let sid = self.sid_generator.generate(&esid);
-        while let Some(Ok(packet)) = socket.poll_packet().await {
-            if packet.ptype == PacketType::Connect {
-                let nsp = packet.nsp.clone();
-                self.insert_clients(socket, nsp, esid, sid, true).await;
-                break;
-            } else {
-                continue;
+    /// The store sessions outliving a connection are kept in.
+    pub fn session_store(&self) -> &Arc<dyn SessionStore> {
+        &self.session_store
+    }
+
+    /// A token joining a socket of `nsp` presenting it to `rooms` within `ttl`,
+    /// `None` without `ServerBuilder::join_tokens` for the namespace.
+    #[cfg(feature = "auth-jwt")]
+    pub fn join_token<T: Into<String>>(
+        &self,
+        nsp: &str,
+        rooms: Vec<T>,
+        ttl: Duration,
+    ) -> Option<String> {
+        let tokens = self.join_tokens.get(nsp)?;
+        match tokens.mint(nsp, rooms, ttl) {
+            Ok(token) => Some(token),
+            Err(e) => {
+                error!("mint join token for {} failed {:?}", nsp, e);
+                None
             }
         }
     }
 
-    async fn insert_clients(
-        self: &Arc<Self>,
-        socket: RawSocket,
-        nsp: String,
-        esid: EngineSid,
-        sid: Sid,
-        handshake: bool,
-    ) {
-        if let Some(on) = self.on.get(&nsp) {
-            let client = ServerSocket::new(
-                socket,
-                nsp.clone(),
-                sid.clone(),
-                on.to_owned(),
-                self.clone(),
-            );
+    /// Names of the registered namespaces, sorted.
+    pub fn namespaces(&self) -> Vec<String> {
+        let mut namespaces: Vec<String> = self.on.iter().map(|on| on.key().to_owned()).collect();
+        namespaces.sort();
+        namespaces
+    }
 
-            client.connect_callback().await;
+    /// Connected socket count and registered events of the namespace `nsp`.
+    pub fn namespace_info(&self, nsp: &str) -> Option<NamespaceInfo> {
+        let on = self.on.get(nsp)?;
+        let mut events: Vec<String> = on.iter().map(|e| e.key().to_owned().into()).collect();
+        events.sort();
 
-            poll(client.clone());
+        let sockets = self
+            .clients
+            .iter()
+            .map(|sid_map| {
+                sid_map
+                    .iter()
+                    .filter(|nsp_map| nsp_map.contains_key(nsp))
+                    .count()
+            })
+            .sum();
 
-            if handshake {
-                let _ = client.handshake(json!({ "sid": sid.clone() })).await;
-            }
+        Some(NamespaceInfo {
+            name: nsp.to_owned(),
+            sockets,
+            events,
+        })
+    }
 
-            let sid_map = self.clients.entry(esid).or_default();
+    fn connected(&self, sid: &Sid, nsp: &str) -> bool {
+        let connected = || {
+            let esid = SidGenerator::decode(sid)?;
+            let sid_map = self.clients.get(&esid)?;
+            let nsp_map = sid_map.get(sid)?;
+            Some(nsp_map.contains_key(nsp))
+        };
+        connected().unwrap_or(false)
+    }
 
-            let mut nsp_map = sid_map.entry(sid).or_default();
-            nsp_map.insert(nsp, client);
-        } else {
-            warn!("unkown nsp {} from client", nsp);
-        }
+    pub(crate) async fn client(&self, sid: &Sid, nsp: &str) -> Option<ServerSocket> {
+        let esid = &SidGenerator::decode(sid)?;
+        self.clients.get(esid)?.get(sid)?.get(nsp).cloned()
     }
 
-    async fn drop_client(self: &Arc<Self>, esid: &EngineSid) {
-        self.engine_server.close_socket(esid).await;
+    /// Joins `sid` to `rooms` in a single pass over the rooms of `nsp`. Nothing
+    /// is joined if any of the rooms is full.
+    pub(crate) async fn join<T: Into<String>>(
+        self: &Arc<Self>,
+        nsp: &str,
+        rooms: Vec<T>,
+        sid: Sid,
+    ) -> Result<Vec<RoomChange>> {
+        self.join_rooms(nsp, rooms.into_iter().map(Into::into).collect(), sid)
+    }
 
-        if self.clients.remove(esid).is_some() {
-            //TODO: disconnect
+    fn join_rooms(&self, nsp: &str, rooms: Vec<Room>, sid: Sid) -> Result<Vec<RoomChange>> {
+        let mut nsp_rooms = self.rooms.entry(nsp.to_owned()).or_default();
+        if let Some(&max) = self.room_capacities.get(nsp) {
+            let full = rooms.iter().find(|room| match nsp_rooms.get(*room) {
+                Some(room_sids) => room_sids.len() >= max && !room_sids.contains(&sid),
+                None => max == 0,
+            });
+            if let Some(room) = full {
+                return Err(Error::RoomFull(room.clone(), max));
+            }
         }
 
-        // FIXME: performance will be low if too many nsp and rooms
-        self.rooms.iter_mut().for_each(|mut nsp_clients| {
-            for room_clients in nsp_clients.values_mut() {
-                room_clients.retain(|sid| SidGenerator::decode(sid).as_ref() != Some(esid))
-            }
-        });
+        Ok(rooms
+            .into_iter()
+            .map(|room| {
+                let room_sids = nsp_rooms.entry(room.clone()).or_default();
+                let changed = room_sids.insert(sid.clone());
+                RoomChange {
+                    room,
+                    changed,
+                    size: room_sids.len(),
+                }
+            })
+            .collect())
     }
-}
 
-#[derive(Default)]
-pub(crate) struct SidGenerator {
-    seq: AtomicUsize,
-}
+    /// Binds `sid` to `user` in `nsp`, unbinding it from the user it was bound
+    /// to. It joins the rooms the user had when they were exported, if any.
+    pub(crate) fn bind_user(&self, nsp: &str, user: String, sid: Sid) {
+        let rooms = self.imported_rooms(nsp, &user);
+        {
+            let mut nsp_users = self.users.entry(nsp.to_owned()).or_default();
+            for user_sids in nsp_users.values_mut() {
+                user_sids.remove(&sid);
+            }
+            nsp_users.retain(|_, user_sids| !user_sids.is_empty());
+            nsp_users.entry(user).or_default().insert(sid.clone());
+        }
+        if !rooms.is_empty() {
+            if let Err(e) = self.join_rooms(nsp, rooms, sid.clone()) {
+                warn!("imported rooms of {} not joined: {}", sid, e);
+            }
+        }
+    }
 
-impl SidGenerator {
-    pub fn generate(&self, engine_sid: &EngineSid) -> Sid {
-        let seq = self.seq.fetch_add(1, Ordering::SeqCst);
-        Arc::new(base64::encode(format!("{}-{}", engine_sid, seq)))
+    /// The rooms of `user` in `nsp` of the snapshot imported last, unless
+    /// it expired.
+    fn imported_rooms(&self, nsp: &str, user: &str) -> Vec<Room> {
+        let mut imported = self.imported_rooms.lock();
+        match imported.as_ref() {
+            Some((deadline, _)) if *deadline <= Instant::now() => {
+                *imported = None;
+                Vec::new()
+            }
+            Some((_, users)) => users
+                .get(nsp)
+                .and_then(|nsp_users| nsp_users.get(user).cloned())
+                .unwrap_or_default(),
+            None => Vec::new(),
+        }
     }
 
-    pub fn decode(sid: &Sid) -> Option<EngineSid> {
-        let sid_vec = base64::decode(sid.as_bytes()).ok()?;
-        let esid_sid = std::str::from_utf8(&sid_vec).ok()?;
-        let tokens: Vec<&str> = esid_sid.split('-').collect();
-        Some(Arc::new(tokens[0].to_owned()))
+    /// Takes the users in the rooms of every namespace, e.g. to be saved before
+    /// a controlled restart and imported afterwards.
+    pub fn export_rooms(&self) -> RoomsSnapshot {
+        let mut snapshot = RoomsSnapshot::default();
+        for nsp_rooms in self.rooms.iter() {
+            let nsp_users = match self.users.get(nsp_rooms.key()) {
+                Some(nsp_users) => nsp_users,
+                None => continue,
+            };
+            for (room, room_sids) in nsp_rooms.value() {
+                let users: BTreeSet<User> = nsp_users
+                    .iter()
+                    .filter(|(_, user_sids)| !user_sids.is_disjoint(room_sids))
+                    .map(|(user, _)| user.clone())
+                    .collect();
+                if !users.is_empty() {
+                    snapshot
+                        .namespaces
+                        .entry(nsp_rooms.key().clone())
+                        .or_default()
+                        .insert(room.clone(), users);
+                }
+            }
+        }
+        snapshot
     }
-}
 
-fn poll(socket: ServerSocket) {
-    tokio::runtime::Handle::current().spawn(async move {
+    /// Rejoins users to the rooms of `snapshot` as they reconnect: each socket
+    /// bound to a user within `window` joins the rooms the user was in. An
+    /// import replaces the previous one.
+    pub fn import_rooms(&self, snapshot: RoomsSnapshot, window: Duration) {
+        let mut users = UserRooms::new();
+        for (nsp, rooms) in snapshot.namespaces {
+            let nsp_users = users.entry(nsp).or_default();
+            for (room, room_users) in rooms {
+                for user in room_users {
+                    nsp_users.entry(user).or_default().push(room.clone());
+                }
+            }
+        }
+        *self.imported_rooms.lock() = Some((Instant::now() + window, users));
+    }
+
+    /// The sockets of `nsp` bound to `user`.
+    pub(crate) fn user_sids(&self, nsp: &str, user: &str) -> HashSet<Sid> {
+        self.users
+            .get(nsp)
+            .and_then(|nsp_users| nsp_users.get(user).cloned())
+            .unwrap_or_default()
+    }
+
+    /// Removes `sid` from `rooms` in a single pass over the rooms of `nsp`.
+    pub(crate) async fn leave(
+        self: &Arc<Self>,
+        nsp: &str,
+        rooms: Vec<&str>,
+        sid: &Sid,
+    ) -> Vec<RoomChange> {
+        let mut nsp_rooms = self.rooms.get_mut(nsp);
+        rooms
+            .into_iter()
+            .map(|room| {
+                let room_sids = nsp_rooms
+                    .as_mut()
+                    .and_then(|nsp_rooms| nsp_rooms.get_mut(room));
+                let (changed, size) = match room_sids {
+                    Some(room_sids) => (room_sids.remove(sid), room_sids.len()),
+                    None => (false, 0),
+                };
+                RoomChange {
+                    room: room.to_owned(),
+                    changed,
+                    size,
+                }
+            })
+            .collect()
+    }
+
+    /// Removes `sid` from every room of `nsp` it is in.
+    pub(crate) async fn leave_all(self: &Arc<Self>, nsp: &str, sid: &Sid) -> Vec<RoomChange> {
+        let mut changes = Vec::new();
+        if let Some(mut nsp_rooms) = self.rooms.get_mut(nsp) {
+            for (room, room_sids) in nsp_rooms.iter_mut() {
+                if room_sids.remove(sid) {
+                    changes.push(RoomChange {
+                        room: room.clone(),
+                        changed: true,
+                        size: room_sids.len(),
+                    });
+                }
+            }
+        }
+        changes
+    }
+
+    async fn create_client(self: &Arc<Self>, esid: EngineSid) {
+        if let Some(engine_socket) = self.engine_server.socket(&esid).await {
+            let socket = RawSocket::server_end(
+                engine_socket,
+                self.cipher.clone(),
+                self.compression_threshold
+                    .map(|threshold| Arc::new(Compression::new(threshold, self.max_payload))),
+            );
+
+            let poll_task = poll(self.clone(), socket, esid.clone());
+            self.poll_tasks.insert(esid, poll_task);
+        }
+    }
+
+    /// The socket of `nsp` on the connection `esid`.
+    fn namespace_client(&self, esid: &EngineSid, nsp: &str) -> Option<ServerSocket> {
+        let sid_map = self.clients.get(esid)?;
+        let client = sid_map.iter().find_map(|nsp_map| nsp_map.get(nsp).cloned());
+        client
+    }
+
+    /// The sockets of every namespace on the connection `esid`.
+    fn connection_clients(&self, esid: &EngineSid) -> Vec<ServerSocket> {
+        match self.clients.get(esid) {
+            Some(sid_map) => sid_map
+                .iter()
+                .flat_map(|nsp_map| nsp_map.values().cloned().collect::<Vec<_>>())
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Hands `packet` to the socket of its namespace, connecting the namespace
+    /// first for a CONNECT packet.
+    async fn route(self: &Arc<Self>, socket: &RawSocket, esid: &EngineSid, packet: Packet) {
+        match self.namespace_client(esid, &packet.nsp) {
+            Some(client) => {
+                if let Some((kind, reason)) = self.exceeded_limit(&client, &packet) {
+                    let error = ProtocolError {
+                        kind,
+                        esid: esid.clone(),
+                        nsp: Some(packet.nsp.clone()),
+                        raw: Bytes::from(&packet),
+                        reason,
+                    };
+                    self.protocol_error(error, vec![client]).await;
+                    return;
+                }
+                if let Some(event) = packet_event(&packet).filter(|e| !client.has_handler(e)) {
+                    let error = ProtocolError {
+                        kind: ProtocolErrorKind::UnknownEvent,
+                        esid: esid.clone(),
+                        nsp: Some(packet.nsp.clone()),
+                        raw: Bytes::from(&packet),
+                        reason: format!("no handler of {}", String::from(event)),
+                    };
+                    self.protocol_error(error, vec![client.clone()]).await;
+                }
+                let _ = client.handle_socketio_packet(&packet).await;
+                if packet.ptype == PacketType::Disconnect {
+                    // the connection stays open for the other namespaces
+                    self.remove_client(esid, &client).await;
+                }
+            }
+            None if packet.ptype == PacketType::Connect => {
+                self.connect_namespace(socket, esid, &packet).await
+            }
+            None => {
+                let error = ProtocolError {
+                    kind: ProtocolErrorKind::UnknownNamespace,
+                    esid: esid.clone(),
+                    nsp: Some(packet.nsp.clone()),
+                    raw: Bytes::from(&packet),
+                    reason: format!("{:?} for unconnected namespace", packet.ptype),
+                };
+                self.protocol_error(error, Vec::new()).await;
+            }
+        }
+    }
+
+    /// Why an event exceeds the limits of its namespace, see
+    /// `ServerBuilder::max_payload` and `ServerBuilder::rate_limit`.
+    fn exceeded_limit(
+        &self,
+        client: &ServerSocket,
+        packet: &Packet,
+    ) -> Option<(ProtocolErrorKind, String)> {
+        if !matches!(packet.ptype, PacketType::Event | PacketType::BinaryEvent) {
+            return None;
+        }
+        if let Some(&max) = self.max_payloads.get(&packet.nsp) {
+            let attachments = packet.attachments.iter().flatten().map(Bytes::len);
+            let size = Bytes::from(packet).len() + attachments.sum::<usize>();
+            if size > max {
+                let reason = format!("event of {} bytes, at most {} allowed", size, max);
+                return Some((ProtocolErrorKind::PayloadTooLarge, reason));
+            }
+        }
+        match &client.rate_limiter {
+            Some(limiter) if !limiter.admit() => Some((
+                ProtocolErrorKind::RateLimited,
+                format!("rate limit of {} exceeded", packet.nsp),
+            )),
+            _ => None,
+        }
+    }
+
+    /// Reports a packet the server could not handle to the hook of
+    /// `ServerBuilder::on_protocol_error`, and to the `Error` handlers of
+    /// `clients` with the diagnostics as payload.
+    async fn protocol_error(&self, error: ProtocolError, clients: Vec<ServerSocket>) {
+        warn!(
+            "{} from {}: {}",
+            error.kind.as_str(),
+            error.esid,
+            error.reason
+        );
+        let payload = error.to_json();
+        if let Some(hook) = &self.protocol_error_hook {
+            hook(error);
+        }
+        for client in clients {
+            client
+                .callback(&Event::Error, Some(payload.clone().into()), None)
+                .await;
+        }
+    }
+
+    async fn connect_namespace(
+        self: &Arc<Self>,
+        socket: &RawSocket,
+        esid: &EngineSid,
+        packet: &Packet,
+    ) {
+        let sid = self.sid_generator.generate(esid);
+        socket.accept_compression(self.engine_server.handshake_query(esid).as_ref());
+        let nsp = packet.nsp.clone();
+        let data = SocketData::default();
+
+        #[cfg(feature = "auth-jwt")]
+        if let Some(auth) = self.jwt_auth.get(&nsp) {
+            let headers = self.engine_server.handshake_headers(esid);
+            match auth.authenticate(packet.data.as_ref(), headers.as_ref()) {
+                Ok(claims) => {
+                    data.insert(auth.key().to_owned(), claims);
+                }
+                Err(message) => {
+                    trace!("refused {} to {}: {}", sid, nsp, message);
+                    let _ = socket
+                        .connect_error(&nsp, json!({ "message": message }))
+                        .await;
+                    return;
+                }
+            }
+        }
+
+        if let Some(chain) = self.middlewares.get(&nsp) {
+            let handshake = Handshake {
+                nsp: nsp.clone(),
+                auth: packet.data.clone(),
+                headers: self
+                    .engine_server
+                    .handshake_headers(esid)
+                    .unwrap_or_default(),
+                address: self.engine_server.remote_addr(esid),
+            };
+            let timeout = self
+                .namespace_timeouts
+                .get(&nsp)
+                .copied()
+                .unwrap_or(MIDDLEWARE_TIMEOUT);
+            if let Err(message) = run_chain(chain.clone(), handshake, timeout).await {
+                trace!("refused {} to {}: {}", sid, nsp, message);
+                let _ = socket
+                    .connect_error(&nsp, json!({ "message": message }))
+                    .await;
+                return;
+            }
+        }
+
+        // the tokens come in their own headers, apart from the auth
+        let headers = self.engine_server.handshake_headers(esid);
+        let token = |key: &str| Some(headers.as_ref()?.get(key)?.to_str()?.to_owned());
+        if let Some(token) = token(MIGRATION_TOKEN) {
+            let key = SessionKey::Migration(token);
+            self.restore(&nsp, &sid, key, &data).await;
+        }
+        if let (Some(token), Some(_)) = (token(RESUME_TOKEN), self.resume) {
+            let key = SessionKey::Resume(token);
+            self.restore(&nsp, &sid, key, &data).await;
+        }
+
+        self.insert_clients(socket.clone(), packet, esid.clone(), sid, data)
+            .await;
+    }
+
+    /// Removes the socket of a namespace the client left, with its rooms and user.
+    async fn remove_client(self: &Arc<Self>, esid: &EngineSid, client: &ServerSocket) {
+        let (sid, nsp) = (client.sid(), client.namespace());
+        let removed = match self.clients.get(esid) {
+            Some(sid_map) => sid_map.remove(&sid).is_some(),
+            None => false,
+        };
+        if removed {
+            self.release_namespace(&nsp);
+        }
+        self.resume_tokens.remove(&sid);
+        self.leave_all(&nsp, &sid).await;
+        if let Some(mut nsp_users) = self.users.get_mut(&nsp) {
+            for user_sids in nsp_users.values_mut() {
+                user_sids.remove(&sid);
+            }
+            nsp_users.retain(|_, user_sids| !user_sids.is_empty());
+        }
+    }
+
+    /// Restores the rooms, data and user a socket had on the server it was
+    /// migrated from, see `migrate`, or before it lost its connection, see
+    /// `ServerBuilder::resume_sessions`.
+    async fn restore(self: &Arc<Self>, nsp: &str, sid: &Sid, key: SessionKey, data: &SocketData) {
+        let kind = match key {
+            SessionKey::Resume(_) => "resumption",
+            _ => "migration",
+        };
+        let state = match self.session_store.get(&key).await {
+            Ok(Some(state)) => state,
+            Ok(None) => {
+                warn!("{} of {} unknown or expired", kind, sid);
+                return;
+            }
+            Err(e) => {
+                warn!("{} of {} not restored: {}", kind, sid, e);
+                return;
+            }
+        };
+        // tokens are single use
+        let _ = self.session_store.delete(&key).await;
+        if state["nsp"] != nsp {
+            warn!("{} of {} is for namespace {}", kind, sid, state["nsp"]);
+            return;
+        }
+
+        if let Some(values) = state["data"].as_object() {
+            for (key, value) in values {
+                data.insert(key.clone(), value.clone());
+            }
+        }
+        let rooms: Vec<&str> = state["rooms"]
+            .as_array()
+            .map(|rooms| rooms.iter().filter_map(Value::as_str).collect())
+            .unwrap_or_default();
+        if let Err(e) = self.join(nsp, rooms, sid.clone()).await {
+            warn!("{} of {} not restored to every room: {}", kind, sid, e);
+        }
+        if let Some(user) = state["user"].as_str() {
+            self.bind_user(nsp, user.to_owned(), sid.clone());
+        }
+    }
+
+    async fn insert_clients(
+        self: &Arc<Self>,
+        socket: RawSocket,
+        packet: &Packet,
+        esid: EngineSid,
+        sid: Sid,
+        data: SocketData,
+    ) {
+        let nsp = packet.nsp.clone();
+        if let Some(on) = self.namespace_on(&nsp) {
+            let client = ServerSocket::new(
+                socket,
+                nsp.clone(),
+                sid.clone(),
+                on.to_owned(),
+                self.clone(),
+                data,
+            );
+
+            let mut handshake = json!({ "sid": sid.clone() });
+            if let Some((_, rotation)) = self.resume {
+                handshake["pid"] = json!(self.issue_resume_token(&sid));
+                self.rotate_resume_token(client.clone(), rotation);
+            }
+            // answers the CONNECT ahead of whatever the Connect handlers emit
+            let _ = client.handshake(handshake).await;
+            client.connect_callback(packet).await;
+
+            if !merge_client(&self.clients, esid, sid.clone(), nsp.clone(), client) {
+                warn!("namespace {} of {} connected concurrently", nsp, sid);
+                self.release_namespace(&nsp);
+            }
+        } else {
+            let error = ProtocolError {
+                kind: ProtocolErrorKind::UnknownNamespace,
+                esid,
+                nsp: Some(nsp),
+                raw: Bytes::from(packet),
+                reason: "namespace not served".to_owned(),
+            };
+            self.protocol_error(error, Vec::new()).await;
+        }
+    }
+
+    fn issue_resume_token(&self, sid: &Sid) -> String {
+        let token = random_token();
+        self.resume_tokens.insert(sid.clone(), token.clone());
+        token
+    }
+
+    /// Sends `client` a new resume token every `rotation` while it is connected.
+    fn rotate_resume_token(self: &Arc<Self>, client: ServerSocket, rotation: Duration) {
+        let server = self.clone();
+        self.engine_server.spawn(async move {
+            let sid = client.sid();
+            loop {
+                tokio::time::sleep(rotation).await;
+                if !client.is_connected() || !server.resume_tokens.contains_key(&sid) {
+                    break;
+                }
+                let token = server.issue_resume_token(&sid);
+                let _ = client
+                    .emit_reserved(Event::Resume, json!({ "pid": token }).into())
+                    .await;
+            }
+        });
+    }
+
+    /// Keeps the state of `client` under its resume token if it lost its
+    /// connection, for the client to resume it.
+    async fn suspend(&self, client: &ServerSocket, reason: DisconnectReason) {
+        let sid = client.sid();
+        let token = match self.resume_tokens.remove(&sid) {
+            Some((_, token)) => token,
+            None => return,
+        };
+        let lost = matches!(
+            reason,
+            DisconnectReason::PingTimeout
+                | DisconnectReason::TransportClose
+                | DisconnectReason::TransportError
+        );
+        let ttl = match self.resume {
+            Some((ttl, _)) if lost => ttl,
+            _ => return,
+        };
+        let state = self.session_state(&client.namespace(), &sid, client);
+        let key = SessionKey::Resume(token);
+        if let Err(e) = self.session_store.set(&key, state, Some(ttl)).await {
+            warn!("session of {} not kept for resumption: {}", sid, e);
+        }
+    }
+
+    /// The handlers of `nsp`, set up from the dynamic handlers matching it when
+    /// a client first connects to it. A socket connecting to a dynamic namespace
+    /// is counted until `release_namespace`.
+    fn namespace_on(&self, nsp: &str) -> Option<Arc<On>> {
+        // the count is locked, so the namespace isn't evicted meanwhile
+        let mut count = match self.dynamic_sockets.entry(nsp.to_owned()) {
+            dashmap::mapref::entry::Entry::Occupied(count) => count.into_ref(),
+            dashmap::mapref::entry::Entry::Vacant(vacant) => {
+                if let Some(on) = self.on.get(nsp) {
+                    return Some(on.clone());
+                }
+                let on = On::new();
+                for handler in self.dynamic.iter().filter(|h| h.matcher.matches(nsp)) {
+                    on.insert(handler.event.clone(), (handler.callback)());
+                }
+                if on.is_empty() {
+                    return None;
+                }
+                self.on.insert(nsp.to_owned(), Arc::new(on));
+                vacant.insert(0)
+            }
+        };
+        *count += 1;
+        self.on.get(nsp).map(|on| on.clone())
+    }
+
+    /// Counts off a socket which left `nsp`. A dynamic namespace is removed
+    /// with its last socket, so namespaces chosen by clients don't pile up.
+    fn release_namespace(&self, nsp: &str) {
+        if let dashmap::mapref::entry::Entry::Occupied(mut count) =
+            self.dynamic_sockets.entry(nsp.to_owned())
+        {
+            *count.get_mut() -= 1;
+            if *count.get() == 0 {
+                self.on.remove(nsp);
+                count.remove();
+            }
+        }
+    }
+
+    /// The state of type `S` registered with `ServerBuilder::state`.
+    pub(crate) fn state<S: Send + Sync + 'static>(&self) -> Option<Arc<S>> {
+        let state = self.states.get(&TypeId::of::<S>())?.clone();
+        state.downcast().ok()
+    }
+
+    pub(crate) fn dispatcher(&self) -> Option<Arc<Dispatcher>> {
+        match self.dispatch_mode {
+            DispatchMode::Pool(_) => self.dispatcher.clone(),
+            mode => mode.dispatcher(self.dispatch_queue, self.engine_server.runtime()),
+        }
+    }
+
+    fn slow_consumer(&self, esid: &EngineSid, stats: QueueStats) {
+        for client in self.connection_clients(esid) {
+            self.engine_server
+                .spawn(async move { client.slow_consumer_callback(stats).await });
+        }
+    }
+
+    /// Drops the connection `esid`, firing the `Close` and `Disconnect`
+    /// handlers of its sockets still connected with `reason`.
+    async fn drop_client(self: &Arc<Self>, esid: &EngineSid, reason: DisconnectReason) {
+        self.engine_server.close_socket(esid).await;
+
+        if let Some((_, sid_map)) = self.clients.remove(esid) {
+            let clients: Vec<ServerSocket> = sid_map
+                .iter()
+                .flat_map(|nsp_map| nsp_map.values().cloned().collect::<Vec<_>>())
+                .collect();
+            // sockets which did not disconnect themselves lost their connection
+            for client in clients.iter().filter(|client| client.is_connected()) {
+                self.suspend(client, reason).await;
+                client.lost(reason).await;
+            }
+            for client in &clients {
+                self.resume_tokens.remove(&client.sid());
+                self.release_namespace(&client.namespace());
+            }
+        }
+
+        // FIXME: performance will be low if too many nsp and rooms
+        self.rooms.iter_mut().for_each(|mut nsp_clients| {
+            for room_clients in nsp_clients.values_mut() {
+                room_clients.retain(|sid| SidGenerator::decode(sid).as_ref() != Some(esid))
+            }
+        });
+        self.users.iter_mut().for_each(|mut nsp_users| {
+            for user_sids in nsp_users.values_mut() {
+                user_sids.retain(|sid| SidGenerator::decode(sid).as_ref() != Some(esid))
+            }
+            nsp_users.retain(|_, user_sids| !user_sids.is_empty());
+        });
+
+        // last, called from a handler run by the poll task this cancels the caller
+        if let Some((_, poll_task)) = self.poll_tasks.remove(esid) {
+            poll_task.abort();
+            let _ = poll_task.await;
+        }
+    }
+}
+
+/// An opaque token handed to a client, which it presents to get its session
+/// back.
+fn random_token() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect()
+}
+
+#[derive(Default)]
+pub(crate) struct SidGenerator {
+    seq: AtomicUsize,
+}
+
+impl SidGenerator {
+    pub fn generate(&self, engine_sid: &EngineSid) -> Sid {
+        let seq = self.seq.fetch_add(1, Ordering::SeqCst);
+        Arc::new(base64::encode(format!("{}-{}", engine_sid, seq)))
+    }
+
+    pub fn decode(sid: &Sid) -> Option<EngineSid> {
+        let sid_vec = base64::decode(sid.as_bytes()).ok()?;
+        let esid_sid = std::str::from_utf8(&sid_vec).ok()?;
+        let (esid, _) = esid_sid.rsplit_once('-')?;
+        Some(Arc::new(esid.to_owned()))
+    }
+}
+
+/// Polls the connection `esid`, shared by the sockets of its namespaces, and
+/// hands every packet to the socket of its namespace. The connection is dropped
+/// if no namespace is connected within `CONNECT_TIMEOUT`.
+fn poll(server: Arc<Server>, socket: RawSocket, esid: EngineSid) -> JoinHandle<()> {
+    let engine_server = server.engine_server.clone();
+    engine_server.spawn(async move {
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(CONNECT_TIMEOUT);
         loop {
+            let next = match server.clients.contains_key(&esid) {
+                true => socket.poll_received().await,
+                false => match tokio::time::timeout_at(deadline, socket.poll_received()).await {
+                    Ok(next) => next,
+                    Err(_) => {
+                        warn!("connect timeout, {:?} dropped", esid);
+                        // dropping the client aborts this task
+                        let server_clone = server.clone();
+                        server.engine_server.spawn(async move {
+                            server_clone
+                                .drop_client(&esid, DisconnectReason::ServerDisconnect)
+                                .await
+                        });
+                        break;
+                    }
+                },
+            };
             // tries to restart a poll cycle whenever a 'normal' error occurs,
             // it just logs on network errors, in case the poll cycle returned
             // `Result::Ok`, the server receives a close frame so it's safe to
             // terminate
-            let next = socket.poll_packet().await;
             match next {
-                Some(e @ Err(Error::IncompleteResponseFromEngineIo(_))) => {
-                    trace!("Network error occured: {:?}", e.err());
+                Some(Ok(Received::Packet(packet))) => server.route(&socket, &esid, packet).await,
+                Some(Ok(received)) => {
+                    let event = match received {
+                        Received::Ping => Event::Ping,
+                        _ => Event::Pong,
+                    };
+                    for client in server.connection_clients(&esid) {
+                        client.callback(&event, None, None).await;
+                    }
+                }
+                Some(Err(Error::ProtocolViolation(reason, raw))) => {
+                    // the packet stream can't be resynchronized after garbage
+                    let clients = server.connection_clients(&esid);
+                    let error = ProtocolError {
+                        kind: ProtocolErrorKind::Undecodable,
+                        esid: esid.clone(),
+                        nsp: None,
+                        raw,
+                        reason,
+                    };
+                    server.protocol_error(error, clients.clone()).await;
+                    for client in clients {
+                        client
+                            .close("protocol violation", DisconnectReason::ParseError)
+                            .await;
+                    }
+                    break;
+                }
+                Some(Err(e)) => {
+                    trace!("Network error occured: {:?}", e);
+                    let reason = json!(e.to_string());
+                    for client in server.connection_clients(&esid) {
+                        client
+                            .callback(&Event::Error, Some(reason.clone().into()), None)
+                            .await;
+                    }
                 }
                 None => break,
-                _ => {}
             }
         }
-    });
+    })
+}
+
+/// The custom event of an EVENT or BINARY_EVENT packet.
+fn packet_event(packet: &Packet) -> Option<Event> {
+    if !matches!(packet.ptype, PacketType::Event | PacketType::BinaryEvent) {
+        return None;
+    }
+    let name = match &packet.data {
+        Some(Value::String(name)) => name.as_str(),
+        Some(Value::Array(array)) => array.first()?.as_str()?,
+        _ => return None,
+    };
+    match Event::from(name) {
+        event @ Event::Custom(_) => Some(event),
+        _ => None,
+    }
+}
+
+/// Adds `client` to the namespaces of `sid`, keeping the other namespaces of the
+/// sid. A connected namespace is never replaced, returns whether `client` was added.
+fn merge_client<T>(
+    clients: &Clients<T>,
+    esid: EngineSid,
+    sid: Sid,
+    nsp: NameSpace,
+    client: T,
+) -> bool {
+    let sid_map = clients.entry(esid).or_default();
+    let mut nsp_map = sid_map.entry(sid).or_default();
+    match nsp_map.entry(nsp) {
+        Entry::Occupied(_) => false,
+        Entry::Vacant(entry) => {
+            entry.insert(client);
+            true
+        }
+    }
 }
 
 #[cfg(test)]
 mod test {
     use std::{
+        collections::{BTreeMap, BTreeSet, HashSet},
         sync::{
-            atomic::{AtomicBool, Ordering},
+            atomic::{AtomicBool, AtomicUsize, Ordering},
             Arc,
         },
-        time::Duration,
+        time::{Duration, Instant},
     };
 
+    use crate::server::{Handshake, Next};
     use crate::{
-        client::ClientBuilder, client::Socket, server::client::Client as ServerClient,
-        test::rust_socket_io_server, AckId, Event, Payload, ServerBuilder,
+        client::ClientBuilder, client::Socket, error::Result,
+        server::client::Client as ServerClient, test::rust_socket_io_server, AckContext, AckError,
+        AckId, AckPolicy, AckSender, Data, DisconnectReason, DispatchMode, Error, Event,
+        HandlerOverflow, IdGenerator, Json, MemoryStore, NamespaceMatcher, Payload, PayloadCipher,
+        ProtocolErrorKind, RawPayload, ServerBuilder, TransportKind, TransportType,
     };
+    use engineio_rs::{Packet as EnginePacket, PacketType as EnginePacketType, ServerOption};
 
-    use super::SidGenerator;
-    use futures_util::FutureExt;
-    use serde_json::json;
+    use super::{merge_client, Clients, RoomChange, RoomsSnapshot, Sample, Sid, SidGenerator};
+    use bytes::Bytes;
+    use futures_util::{future::BoxFuture, FutureExt, StreamExt};
+    use serde_json::{json, Value};
     use tracing::info;
 
     #[test]
@@ -367,6 +1603,51 @@ mod test {
         let sid = generator.generate(&engine_sid);
 
         assert_eq!(SidGenerator::decode(&sid), Some(engine_sid));
+
+        // engine sids of a custom generator may contain the separator
+        let engine_sid = Arc::new("engine-sid".to_owned());
+        let sid = generator.generate(&engine_sid);
+        assert_eq!(SidGenerator::decode(&sid), Some(engine_sid));
+
+        // sids sent by clients are arbitrary
+        for sid in ["", "not base64!", "bm9zZXE="] {
+            assert_eq!(SidGenerator::decode(&Arc::new(sid.to_owned())), None);
+        }
+    }
+
+    #[test]
+    fn test_merge_client() {
+        let clients: Clients<&str> = Default::default();
+        let esid = Arc::new("esid".to_owned());
+        let sid = Arc::new("sid".to_owned());
+
+        assert!(merge_client(
+            &clients,
+            esid.clone(),
+            sid.clone(),
+            "/".to_owned(),
+            "root"
+        ));
+        assert!(merge_client(
+            &clients,
+            esid.clone(),
+            sid.clone(),
+            "/admin".to_owned(),
+            "admin"
+        ));
+        assert!(!merge_client(
+            &clients,
+            esid.clone(),
+            sid.clone(),
+            "/".to_owned(),
+            "other"
+        ));
+
+        let sid_map = clients.get(&esid).unwrap();
+        let nsp_map = sid_map.get(&sid).unwrap();
+        assert_eq!(nsp_map.len(), 2);
+        assert_eq!(nsp_map.get("/"), Some(&"root"));
+        assert_eq!(nsp_map.get("/admin"), Some(&"admin"));
     }
 
     #[tokio::test]
@@ -374,10 +1655,52 @@ mod test {
         // tracing_subscriber::fmt()
         //     .with_env_filter("engineio=trace,socketio=trace")
         //     .init();
-        setup();
+        let ack_contexts = setup();
         test_emit().await;
         test_client_ask_ack().await;
         test_server_ask_ack().await;
+        // observed by the ack callback of the broadcast, where a failed assert
+        // would only be reported to the error handler
+        let contexts = ack_contexts.lock().unwrap().clone();
+        assert_eq!(contexts.len(), 1);
+        assert_eq!(contexts[0].event, Event::from("server_ask_ack"));
+        assert_eq!(contexts[0].rooms, vec!["room 2".to_owned()]);
+        test_wildcard().await;
+    }
+
+    async fn test_wildcard() {
+        let is_recv = Arc::new(AtomicBool::default());
+        let is_recv_clone = Arc::clone(&is_recv);
+
+        let callback = move |payload: Option<Payload>, socket: Socket, _: Option<AckId>| {
+            let is_recv = is_recv_clone.clone();
+            async move {
+                let is_expected = socket.event() == Some(&Event::from("wild:reply"))
+                    && payload == Some(json!("wild:card").into());
+                is_recv.store(is_expected, Ordering::SeqCst);
+            }
+            .boxed()
+        };
+
+        let url = rust_socket_io_server();
+        let socket = ClientBuilder::new(url)
+            .namespace("/admin")
+            .on("wild:*", callback)
+            .on(Event::Connect, move |_payload, socket, _| {
+                async move {
+                    socket.emit("wild:card", json!("")).await.expect("success");
+                }
+                .boxed()
+            })
+            .connect()
+            .await;
+
+        assert!(socket.is_ok());
+
+        // wait recv data
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        assert!(is_recv.load(Ordering::SeqCst));
     }
 
     async fn test_emit() {
@@ -455,6 +1778,9 @@ mod test {
         tokio::time::sleep(Duration::from_millis(100)).await;
 
         assert!(is_client_ack.load(Ordering::SeqCst));
+        // the answered ack is no longer held
+        let usage = socket.expect("success").memory_usage().await;
+        assert_eq!(usage.acks, 0);
     }
 
     async fn test_server_ask_ack() {
@@ -511,13 +1837,16 @@ mod test {
         assert!(is_server_recv_ack.load(Ordering::SeqCst));
     }
 
-    fn setup() {
+    fn setup() -> Arc<std::sync::Mutex<Vec<AckContext>>> {
         let echo_callback =
             move |_payload: Option<Payload>, socket: ServerClient, _need_ack: Option<AckId>| {
                 async move {
                     info!("server echo callback");
-                    socket.join(vec!["room 1"]).await;
-                    socket.emit_to(vec!["room 1"], "echo", json!("")).await;
+                    let _ = socket.join(vec!["room 1"]).await;
+                    socket
+                        .emit_to(vec!["room 1"], "echo", json!(""))
+                        .await
+                        .expect("success");
                     socket.leave(vec!["room 1"]).await;
                     info!("server echo callback done");
                 }
@@ -537,8 +1866,14 @@ mod test {
                 .boxed()
             };
 
+        let ack_contexts = Arc::new(std::sync::Mutex::new(vec![]));
+        let ack_contexts_clone = ack_contexts.clone();
         let server_recv_ack =
             move |_payload: Option<Payload>, socket: ServerClient, _need_ack: Option<AckId>| {
+                ack_contexts_clone
+                    .lock()
+                    .unwrap()
+                    .extend(socket.ack_context().cloned());
                 async move {
                     socket
                         .emit("server_recv_ack", json!(""))
@@ -549,8 +1884,9 @@ mod test {
             };
 
         let trigger_ack = move |_message: Option<Payload>, socket: ServerClient, _| {
+            let server_recv_ack = server_recv_ack.clone();
             async move {
-                socket.join(vec!["room 2"]).await;
+                let _ = socket.join(vec!["room 2"]).await;
                 socket
                     .emit_to_with_ack(
                         vec!["room 2"],
@@ -559,19 +1895,2487 @@ mod test {
                         Duration::from_millis(400),
                         server_recv_ack,
                     )
-                    .await;
+                    .await
+                    .expect("success");
                 socket.leave(vec!["room 2"]).await;
             }
             .boxed()
         };
 
+        let wildcard = move |_payload: Option<Payload>, socket: ServerClient, _| {
+            async move {
+                let event = socket.event().cloned().map(String::from);
+                socket
+                    .emit("wild:reply", json!(event))
+                    .await
+                    .expect("success");
+            }
+            .boxed()
+        };
+
         let url = rust_socket_io_server();
         let server = ServerBuilder::new(url.port().unwrap())
+            .on("/admin", "wild:*", wildcard)
             .on("/admin", "echo", echo_callback)
             .on("/admin", "client_ack", client_ack)
             .on("/admin", "trigger_server_ack", trigger_ack)
             .build();
 
         tokio::spawn(async move { server.serve().await });
+        ack_contexts
+    }
+
+    struct XorCipher(u8);
+
+    impl PayloadCipher for XorCipher {
+        fn encrypt(&self, data: Bytes) -> Result<Bytes> {
+            Ok(data.iter().map(|b| b ^ self.0).collect())
+        }
+
+        fn decrypt(&self, data: Bytes) -> Result<Bytes> {
+            self.encrypt(data)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cipher() {
+        let echo = |payload: Option<Payload>, socket: ServerClient, _: Option<AckId>| {
+            async move {
+                if let Some(Payload::Multi(payload)) = payload {
+                    socket
+                        .emit("echo", Payload::Multi(payload))
+                        .await
+                        .expect("success");
+                }
+            }
+            .boxed()
+        };
+        let server = ServerBuilder::new(4210)
+            .cipher(Arc::new(XorCipher(0x5a)))
+            .on("/", "echo", echo)
+            .build();
+        tokio::spawn(async move { server.serve().await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let is_recv = Arc::new(AtomicBool::default());
+        let is_recv_clone = Arc::clone(&is_recv);
+        let socket = ClientBuilder::new("http://localhost:4210")
+            .cipher(Arc::new(XorCipher(0x5a)))
+            .on("echo", move |payload, _, _| {
+                let is_recv = is_recv_clone.clone();
+                async move {
+                    let expected = Payload::Multi(vec![
+                        json!("secret").into(),
+                        Bytes::from_static(&[1, 2, 3]).into(),
+                    ]);
+                    is_recv.store(payload == Some(expected), Ordering::SeqCst);
+                }
+                .boxed()
+            })
+            .on(Event::Connect, move |_, socket, _| {
+                async move {
+                    let payload = Payload::Multi(vec![
+                        json!("secret").into(),
+                        Bytes::from_static(&[1, 2, 3]).into(),
+                    ]);
+                    socket.emit("echo", payload).await.expect("success");
+                }
+                .boxed()
+            })
+            .connect()
+            .await;
+
+        assert!(socket.is_ok());
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(is_recv.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_compression() {
+        let echo = |payload: Option<Payload>, socket: ServerClient, _: Option<AckId>| {
+            async move {
+                if let Some(payload) = payload {
+                    socket.emit("echo", payload).await.expect("success");
+                }
+            }
+            .boxed()
+        };
+        let server = ServerBuilder::new(4211)
+            .compression(64)
+            .on("/", "echo", echo)
+            .build();
+        tokio::spawn(async move { server.serve().await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let data = json!({ "text": "a".repeat(1024) });
+        let is_recv = Arc::new(AtomicBool::default());
+        let is_recv_clone = Arc::clone(&is_recv);
+        let expected = data.clone();
+        let socket = ClientBuilder::new("http://localhost:4211")
+            .compression(64)
+            .on("echo", move |payload, _, _| {
+                let is_recv = is_recv_clone.clone();
+                let expected = expected.clone();
+                async move {
+                    is_recv.store(payload == Some(Payload::Json(expected)), Ordering::SeqCst);
+                }
+                .boxed()
+            })
+            .on(Event::Connect, move |_, socket, _| {
+                let data = data.clone();
+                async move {
+                    socket.emit("echo", data).await.expect("success");
+                }
+                .boxed()
+            })
+            .connect()
+            .await;
+
+        assert!(socket.is_ok());
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(is_recv.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_concurrency_limit() {
+        let calls = Arc::new(AtomicUsize::default());
+        let calls_clone = Arc::clone(&calls);
+        let slow = move |_: Option<Payload>, _: ServerClient, _: Option<AckId>| {
+            let calls = calls_clone.clone();
+            async move {
+                calls.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(300)).await;
+            }
+            .boxed()
+        };
+        let server = ServerBuilder::new(4212)
+            .dispatch_mode(DispatchMode::Parallel)
+            .concurrency_limit("/", "slow", 1, HandlerOverflow::Drop)
+            .on("/", "slow", slow)
+            .build();
+        tokio::spawn(async move { server.serve().await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let socket = ClientBuilder::new("http://localhost:4212")
+            .on(Event::Connect, move |_, socket, _| {
+                async move {
+                    for _ in 0..3 {
+                        socket.emit("slow", json!("")).await.expect("success");
+                    }
+                }
+                .boxed()
+            })
+            .connect()
+            .await;
+
+        assert!(socket.is_ok());
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_handler_timeout() {
+        let is_done = Arc::new(AtomicBool::default());
+        let is_done_clone = Arc::clone(&is_done);
+        let stuck = move |_: Option<Payload>, _: ServerClient, _: Option<AckId>| {
+            let is_done = is_done_clone.clone();
+            async move {
+                tokio::time::sleep(Duration::from_secs(10)).await;
+                is_done.store(true, Ordering::SeqCst);
+            }
+            .boxed()
+        };
+        let is_error = Arc::new(AtomicBool::default());
+        let is_error_clone = Arc::clone(&is_error);
+        let error = move |payload: Option<Payload>, _: ServerClient, _: Option<AckId>| {
+            let is_error = is_error_clone.clone();
+            async move {
+                if let Some(Payload::Json(message)) = payload {
+                    let message = message.as_str().unwrap_or_default().to_owned();
+                    is_error.store(message.contains("stuck"), Ordering::SeqCst);
+                }
+            }
+            .boxed()
+        };
+        let server = ServerBuilder::new(4213)
+            .handler_timeout("/", "stuck", Duration::from_millis(100))
+            .on("/", "stuck", stuck)
+            .on("/", Event::Error, error)
+            .build();
+        tokio::spawn(async move { server.serve().await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let socket = ClientBuilder::new("http://localhost:4213")
+            .on(Event::Connect, move |_, socket, _| {
+                async move {
+                    socket.emit("stuck", json!("")).await.expect("success");
+                }
+                .boxed()
+            })
+            .connect()
+            .await;
+
+        assert!(socket.is_ok());
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(is_error.load(Ordering::SeqCst));
+        assert!(!is_done.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_handler_panic() {
+        let boom = |_: Option<Payload>,
+                    _: ServerClient,
+                    _: Option<AckId>|
+         -> BoxFuture<'static, ()> { panic!("boom") };
+        let is_error = Arc::new(AtomicBool::default());
+        let is_error_clone = Arc::clone(&is_error);
+        let error = move |payload: Option<Payload>, _: ServerClient, _: Option<AckId>| {
+            let is_error = is_error_clone.clone();
+            async move {
+                if let Some(Payload::Json(message)) = payload {
+                    let message = message.as_str().unwrap_or_default().to_owned();
+                    is_error.store(message.ends_with("panicked: boom"), Ordering::SeqCst);
+                }
+            }
+            .boxed()
+        };
+        let is_recv = Arc::new(AtomicBool::default());
+        let is_recv_clone = Arc::clone(&is_recv);
+        let after = move |_: Option<Payload>, _: ServerClient, _: Option<AckId>| {
+            let is_recv = is_recv_clone.clone();
+            async move { is_recv.store(true, Ordering::SeqCst) }.boxed()
+        };
+        let server = ServerBuilder::new(4214)
+            .on("/", "boom", boom)
+            .on("/", "after", after)
+            .on("/", Event::Error, error)
+            .build();
+        tokio::spawn(async move { server.serve().await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let socket = ClientBuilder::new("http://localhost:4214")
+            .on(Event::Connect, move |_, socket, _| {
+                async move {
+                    socket.emit("boom", json!("")).await.expect("success");
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    socket.emit("after", json!("")).await.expect("success");
+                }
+                .boxed()
+            })
+            .connect()
+            .await;
+
+        assert!(socket.is_ok());
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(is_error.load(Ordering::SeqCst));
+        assert!(is_recv.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_connect() {
+        let server_connects = Arc::new(AtomicUsize::default());
+        let server_connects_clone = Arc::clone(&server_connects);
+        let on_connect = move |_: Option<Payload>, _: ServerClient, _: Option<AckId>| {
+            let server_connects = server_connects_clone.clone();
+            async move {
+                server_connects.fetch_add(1, Ordering::SeqCst);
+            }
+            .boxed()
+        };
+        let server = ServerBuilder::new(4215)
+            .on("/", Event::Connect, on_connect)
+            .build();
+        tokio::spawn(async move { server.serve().await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let handshakes = Arc::new(AtomicUsize::default());
+        let handshakes_clone = Arc::clone(&handshakes);
+        let socket = ClientBuilder::new("http://localhost:4215")
+            .on(Event::Connect, move |_, socket, _| {
+                let handshakes = handshakes_clone.clone();
+                async move {
+                    // connect the namespace a second time, as after a flaky upgrade
+                    if handshakes.fetch_add(1, Ordering::SeqCst) == 0 {
+                        socket.send_connect(None).await.expect("success");
+                    }
+                }
+                .boxed()
+            })
+            .connect()
+            .await;
+
+        assert!(socket.is_ok());
+        tokio::time::sleep(Duration::from_millis(300)).await;
+
+        assert_eq!(server_connects.load(Ordering::SeqCst), 1);
+        assert_eq!(handshakes.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_ack_guard() {
+        let guarded = |_: Option<Payload>, socket: ServerClient, need_ack: Option<AckId>| {
+            async move {
+                if let Some(ack_id) = need_ack {
+                    let guard = socket.ack_guard(ack_id);
+                    guard.ack_with(&("ok", 1)).await.expect("success");
+                }
+            }
+            .boxed()
+        };
+        let dropped = |_: Option<Payload>, socket: ServerClient, need_ack: Option<AckId>| {
+            async move {
+                if let Some(ack_id) = need_ack {
+                    let _guard = socket.ack_guard(ack_id).fallback(json!("no response"));
+                }
+            }
+            .boxed()
+        };
+        let server = ServerBuilder::new(4216)
+            .on("/", "guarded", guarded)
+            .on("/", "dropped", dropped)
+            .build();
+        tokio::spawn(async move { server.serve().await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let acks = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let acks_clone = Arc::clone(&acks);
+        let socket = ClientBuilder::new("http://localhost:4216")
+            .on(Event::Connect, move |_, socket, _| {
+                let acks = acks_clone.clone();
+                async move {
+                    for event in ["guarded", "dropped"] {
+                        let acks = acks.clone();
+                        let callback = move |payload: Option<Payload>, _: Socket, _| {
+                            acks.lock().unwrap().push(payload);
+                            async {}.boxed()
+                        };
+                        socket
+                            .emit_with_ack(event, json!(""), Duration::from_secs(1), callback)
+                            .await
+                            .expect("success");
+                    }
+                }
+                .boxed()
+            })
+            .connect()
+            .await;
+
+        assert!(socket.is_ok());
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let acks = acks.lock().unwrap();
+        assert_eq!(acks.len(), 2);
+        assert!(acks.contains(&Some(json!(["ok", 1]).into())));
+        assert!(acks.contains(&Some(json!("no response").into())));
+    }
+
+    #[tokio::test]
+    async fn test_on_dynamic() {
+        let received = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let received_clone = received.clone();
+        let on_ev = move |_: Option<Payload>, socket: ServerClient, _: Option<AckId>| {
+            received_clone.lock().unwrap().push(socket.namespace());
+            async {}.boxed()
+        };
+        let tenants = regex::Regex::new(r"^/tenant-\d+$").unwrap();
+        let server = ServerBuilder::new(4249)
+            .on_dynamic(tenants, "ev", on_ev.clone())
+            .on_dynamic(
+                NamespaceMatcher::new(|nsp| nsp.starts_with("/org/")),
+                "ev",
+                on_ev,
+            )
+            .build();
+        let server_clone = server.clone();
+        tokio::spawn(async move { server_clone.serve().await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(server.namespaces().is_empty());
+
+        let mut sockets = Vec::new();
+        for nsp in ["/tenant-7", "/org/acme"] {
+            let socket = ClientBuilder::new("http://localhost:4249")
+                .namespace(nsp)
+                .connect()
+                .await
+                .expect("success");
+            socket.emit("ev", json!(null)).await.expect("success");
+            sockets.push(socket);
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let mut namespaces = received.lock().unwrap().clone();
+        namespaces.sort();
+        assert_eq!(
+            namespaces,
+            vec!["/org/acme".to_owned(), "/tenant-7".to_owned()]
+        );
+        assert_eq!(server.namespace_info("/tenant-7").unwrap().sockets, 1);
+        assert!(server.namespace_info("/tenant-x").is_none());
+
+        // the namespaces go away with their last socket
+        for socket in sockets {
+            socket.disconnect().await.expect("success");
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(server.namespaces().is_empty());
+        assert!(server.dynamic_sockets.is_empty());
+
+        // and are set up again on the next connect
+        let _socket = ClientBuilder::new("http://localhost:4249")
+            .namespace("/tenant-7")
+            .connect()
+            .await
+            .expect("success");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(server.namespaces(), vec!["/tenant-7".to_owned()]);
+    }
+
+    #[tokio::test]
+    async fn test_handle_extractors() {
+        #[derive(serde::Deserialize)]
+        struct Login {
+            name: String,
+        }
+
+        let server = ServerBuilder::new(4250)
+            .state(AtomicUsize::new(0))
+            .handle(
+                "/",
+                "login",
+                |Json(login): Json<Login>, ack: AckSender, Data(count): Data<AtomicUsize>| async move {
+                    let logins = count.fetch_add(1, Ordering::SeqCst) + 1;
+                    let _ = ack.send(json!({ "name": login.name, "logins": logins })).await;
+                },
+            )
+            .build();
+        let server_clone = server.clone();
+        tokio::spawn(async move { server_clone.serve().await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let socket = ClientBuilder::new("http://localhost:4250")
+            .connect()
+            .await
+            .expect("success");
+        let timeout = Duration::from_secs(2);
+        let reply = socket
+            .request_with_policy("login", json!({ "name": "ann" }), timeout, AckPolicy::Fail)
+            .await
+            .expect("success");
+        assert_eq!(reply, Payload::Json(json!({ "name": "ann", "logins": 1 })));
+
+        // the arguments can't be extracted, the handler is not run
+        let reply = socket
+            .request_with_policy(
+                "login",
+                Bytes::from_static(b"ann"),
+                timeout,
+                AckPolicy::Fail,
+            )
+            .await
+            .expect("success");
+        assert!(matches!(reply, Payload::Json(value) if value["error"].is_string()));
+        let reply = socket
+            .request_with_policy("login", json!({ "nick": "ann" }), timeout, AckPolicy::Fail)
+            .await
+            .expect("success");
+        assert!(matches!(reply, Payload::Json(value) if value["error"].is_string()));
+    }
+
+    #[tokio::test]
+    async fn test_ack_sender_once() {
+        let second_requested = Arc::new(AtomicBool::new(true));
+        let second_requested_clone = second_requested.clone();
+        let server = ServerBuilder::new(4252)
+            .handle("/", "ev", move |first: AckSender, second: AckSender| {
+                let second_requested = second_requested_clone.clone();
+                async move {
+                    assert!(first.requested());
+                    let _ = first.send(Bytes::from_static(b"first")).await;
+                    second_requested.store(second.requested(), Ordering::SeqCst);
+                    let _ = second.send(json!("second")).await;
+                }
+            })
+            .build();
+        let server_clone = server.clone();
+        tokio::spawn(async move { server_clone.serve().await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let socket = ClientBuilder::new("http://localhost:4252")
+            .connect()
+            .await
+            .expect("success");
+        let reply = socket
+            .request_with_policy("ev", json!(null), Duration::from_secs(2), AckPolicy::Fail)
+            .await
+            .expect("success");
+        assert_eq!(reply, Payload::Binary(Bytes::from_static(b"first")));
+        assert!(!second_requested.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_emit_all() {
+        let noop = |_: Option<Payload>, _: ServerClient, _: Option<AckId>| async {}.boxed();
+        let server = ServerBuilder::new(4217)
+            .on("/", Event::Connect, noop)
+            .on("/admin", Event::Connect, noop)
+            .build();
+        let server_clone = server.clone();
+        tokio::spawn(async move { server_clone.serve().await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let notices = Arc::new(AtomicUsize::default());
+        let mut sockets = Vec::new();
+        for nsp in ["/", "/admin"] {
+            let notices = notices.clone();
+            let socket = ClientBuilder::new("http://localhost:4217")
+                .namespace(nsp)
+                .on("notice", move |_, _, _| {
+                    let notices = notices.clone();
+                    async move {
+                        notices.fetch_add(1, Ordering::SeqCst);
+                    }
+                    .boxed()
+                })
+                .connect()
+                .await;
+            assert!(socket.is_ok());
+            sockets.push(socket);
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        assert_eq!(
+            server.namespaces(),
+            vec!["/".to_owned(), "/admin".to_owned()]
+        );
+        let info = server.namespace_info("/admin").expect("registered");
+        assert_eq!(info.sockets, 1);
+        assert_eq!(info.events, vec!["open".to_owned()]);
+        assert!(server.namespace_info("/missing").is_none());
+
+        server
+            .emit_all("notice", json!("restarting in 5 minutes"))
+            .await
+            .expect("success");
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(notices.load(Ordering::SeqCst), 2);
+
+        // the socket of "/admin" is in no room
+        server
+            .broadcast("/admin", "notice", json!("admins only"))
+            .await
+            .expect("success");
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(notices.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_multi_address_failover() {
+        let noop = |_: Option<Payload>, _: ServerClient, _: Option<AckId>| async {}.boxed();
+        let server = ServerBuilder::new(4218)
+            .on("/", Event::Connect, noop)
+            .build();
+        tokio::spawn(async move { server.serve().await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let socket =
+            ClientBuilder::new_multi(vec!["http://localhost:4299", "http://localhost:4218"])
+                .reconnect(false)
+                .connect()
+                .await;
+        assert!(socket.is_ok());
+
+        let socket = ClientBuilder::new_multi(vec!["http://localhost:4299"])
+            .reconnect(false)
+            .connect()
+            .await;
+        assert!(socket.is_err());
+    }
+
+    #[cfg(feature = "auth-jwt")]
+    #[tokio::test]
+    async fn test_jwt_auth() {
+        use jsonwebtoken::{encode, EncodingKey, Header};
+
+        let subjects = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let subjects_clone = Arc::clone(&subjects);
+        let on_connect = move |_: Option<Payload>, socket: ServerClient, _: Option<AckId>| {
+            let subjects = subjects_clone.clone();
+            async move {
+                let claims = socket.data().get("claims").map(|c| c["sub"].clone());
+                subjects.lock().unwrap().push(claims);
+            }
+            .boxed()
+        };
+        let server = ServerBuilder::new(4220)
+            .jwt_auth("/", crate::JwtAuth::hs256(b"secret"))
+            .on("/", Event::Connect, on_connect)
+            .build();
+        tokio::spawn(async move { server.serve().await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let claims = json!({"sub": "alice", "exp": u32::MAX});
+        let token = encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(b"secret"),
+        )
+        .unwrap();
+        let socket = ClientBuilder::new("http://localhost:4220")
+            .opening_header("Authorization", format!("Bearer {}", token))
+            .connect()
+            .await;
+        assert!(socket.is_ok());
+
+        // refused without a token, the connect handler never runs
+        let _ = ClientBuilder::new("http://localhost:4220").connect().await;
+        tokio::time::sleep(Duration::from_millis(300)).await;
+
+        assert_eq!(*subjects.lock().unwrap(), vec![Some(json!("alice"))]);
+    }
+
+    #[tokio::test]
+    async fn test_use_middleware() {
+        let connected = Arc::new(AtomicUsize::new(0));
+        let connected_clone = connected.clone();
+        let on_connect = move |_: Option<Payload>, _: ServerClient, _: Option<AckId>| {
+            connected_clone.fetch_add(1, Ordering::SeqCst);
+            async {}.boxed()
+        };
+        let server = ServerBuilder::new(4251)
+            .use_middleware("/admin", |handshake: Handshake, next: Next| {
+                async move {
+                    assert!(handshake.address.is_some());
+                    match handshake.headers.get("x-role").and_then(|v| v.to_str()) {
+                        Some("admin") => next.run(handshake).await,
+                        _ => Err("admins only".to_owned()),
+                    }
+                }
+                .boxed()
+            })
+            .use_middleware("/admin", |handshake: Handshake, next: Next| {
+                async move { next.run(handshake).await }.boxed()
+            })
+            .on("/admin", Event::Connect, on_connect)
+            .build();
+        tokio::spawn(async move { server.serve().await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let socket = ClientBuilder::new("http://localhost:4251")
+            .namespace("/admin")
+            .opening_header("x-role", "admin")
+            .connect()
+            .await;
+        assert!(socket.is_ok());
+
+        // refused by the first middleware, the connect handler never runs
+        let _ = ClientBuilder::new("http://localhost:4251")
+            .namespace("/admin")
+            .opening_header("x-role", "guest")
+            .connect()
+            .await;
+        tokio::time::sleep(Duration::from_millis(300)).await;
+
+        assert_eq!(connected.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_connect_auth() {
+        let received = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let received_clone = received.clone();
+        let on_connect = move |payload: Option<Payload>, _: ServerClient, _: Option<AckId>| {
+            received_clone.lock().unwrap().push(payload);
+            async {}.boxed()
+        };
+        let server = ServerBuilder::new(4253)
+            .use_middleware("/", |handshake: Handshake, next: Next| {
+                async move {
+                    match handshake.auth.as_ref().map(|auth| &auth["token"]) {
+                        Some(token) if token == "abc" => next.run(handshake).await,
+                        _ => Err("invalid token".to_owned()),
+                    }
+                }
+                .boxed()
+            })
+            .on("/", Event::Connect, on_connect)
+            .build();
+        tokio::spawn(async move { server.serve().await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let socket = ClientBuilder::new("http://localhost:4253")
+            .auth(json!({ "token": "abc" }))
+            .connect()
+            .await;
+        assert!(socket.is_ok());
+        let _ = ClientBuilder::new("http://localhost:4253")
+            .auth(json!({ "token": "xyz" }))
+            .connect()
+            .await;
+        tokio::time::sleep(Duration::from_millis(300)).await;
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 1);
+        assert!(matches!(&received[0], Some(Payload::Json(auth)) if auth["token"] == "abc"));
+    }
+
+    #[tokio::test]
+    async fn test_sids_to_emit_once() {
+        // never served, only the room bookkeeping is used
+        let server = ServerBuilder::new(4221).build();
+        let alice = Arc::new("alice".to_owned());
+        let bob = Arc::new("bob".to_owned());
+        server
+            .join("/", vec!["a", "b"], alice.clone())
+            .await
+            .unwrap();
+        server.join("/", vec!["b"], bob.clone()).await.unwrap();
+
+        let sids = server.sids_to_emit("/", vec!["a", "b", "alice"]).await;
+        assert_eq!(sids, HashSet::from([alice.clone(), bob.clone()]));
+
+        // a sid is reachable in a namespace without rooms
+        let sids = server.sids_to_emit("/admin", vec!["alice", "alice"]).await;
+        assert_eq!(sids, HashSet::from([alice]));
+    }
+
+    #[tokio::test]
+    async fn test_room_capacity() -> Result<()> {
+        // never served, only the room bookkeeping is used
+        let server = ServerBuilder::new(4221).room_capacity("/", 1).build();
+        let alice = Arc::new("alice".to_owned());
+        let bob = Arc::new("bob".to_owned());
+
+        let changes = server.join("/", vec!["a"], alice.clone()).await?;
+        assert_eq!(
+            changes,
+            vec![RoomChange {
+                room: "a".to_owned(),
+                changed: true,
+                size: 1,
+            }]
+        );
+        // joining again is no change, and does not count against the capacity
+        let changes = server.join("/", vec!["a"], alice.clone()).await?;
+        assert!(!changes[0].changed);
+
+        let result = server.join("/", vec!["b", "a"], bob.clone()).await;
+        assert!(matches!(result, Err(Error::RoomFull(room, 1)) if room == "a"));
+        // nothing is joined if any room is full
+        assert!(!server.rooms.get("/").unwrap().contains_key("b"));
+
+        let changes = server.leave("/", vec!["a", "c"], &alice).await;
+        assert!(changes[0].changed && changes[0].size == 0);
+        assert!(!changes[1].changed);
+        assert!(server.join("/", vec!["a", "b"], bob.clone()).await?[0].changed);
+
+        let mut changes = server.leave_all("/", &bob).await;
+        changes.sort_by(|a, b| a.room.cmp(&b.room));
+        let rooms: Vec<&str> = changes.iter().map(|c| c.room.as_str()).collect();
+        assert_eq!(rooms, vec!["a", "b"]);
+        assert!(server.leave_all("/", &bob).await.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sample() {
+        let sids: HashSet<Sid> = (0..10).map(|i| Arc::new(i.to_string())).collect();
+
+        let picked = Sample::Count(3).pick(sids.clone());
+        assert_eq!(picked.len(), 3);
+        assert!(picked.is_subset(&sids));
+        assert_eq!(Sample::Count(20).pick(sids.clone()), sids);
+
+        assert_eq!(Sample::Fraction(0.25).pick(sids.clone()).len(), 3);
+        assert_eq!(Sample::Fraction(1.5).pick(sids.clone()), sids);
+        assert!(Sample::Fraction(0.0).pick(sids).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_bind_user() {
+        // never served, only the user bookkeeping is used
+        let server = ServerBuilder::new(4221).build();
+        let tab = Arc::new("tab".to_owned());
+        let phone = Arc::new("phone".to_owned());
+        server.bind_user("/", "alice".to_owned(), tab.clone());
+        server.bind_user("/", "alice".to_owned(), phone.clone());
+        assert_eq!(
+            server.user_sids("/", "alice"),
+            HashSet::from([tab.clone(), phone.clone()])
+        );
+        assert!(server.user_sids("/admin", "alice").is_empty());
+
+        // a socket belongs to one user
+        server.bind_user("/", "bob".to_owned(), phone.clone());
+        assert_eq!(server.user_sids("/", "alice"), HashSet::from([tab]));
+        assert_eq!(server.user_sids("/", "bob"), HashSet::from([phone]));
+    }
+
+    #[tokio::test]
+    async fn test_export_rooms() -> Result<()> {
+        // never served, only the room bookkeeping is used
+        let server = ServerBuilder::new(4221).build();
+        let tab = Arc::new("tab".to_owned());
+        let guest = Arc::new("guest".to_owned());
+        server.bind_user("/", "alice".to_owned(), tab.clone());
+        server.join("/", vec!["lobby", "dev"], tab).await?;
+        server.join("/", vec!["lobby"], guest).await?;
+
+        let snapshot = server.export_rooms();
+        let alice = BTreeSet::from(["alice".to_owned()]);
+        let expected = BTreeMap::from([
+            ("dev".to_owned(), alice.clone()),
+            ("lobby".to_owned(), alice),
+        ]);
+        assert_eq!(snapshot.namespaces.get("/"), Some(&expected));
+        let json = serde_json::to_value(&snapshot)?;
+        assert_eq!(serde_json::from_value::<RoomsSnapshot>(json)?, snapshot);
+
+        // after the restart the sockets of alice rejoin as they bind
+        let server = ServerBuilder::new(4221).build();
+        server.import_rooms(snapshot.clone(), Duration::from_secs(60));
+        let phone = Arc::new("phone".to_owned());
+        server.bind_user("/", "alice".to_owned(), phone.clone());
+        assert_eq!(server.export_rooms(), snapshot);
+        let lobby = server.rooms.get("/").unwrap().get("lobby").cloned();
+        assert_eq!(lobby, Some(HashSet::from([phone])));
+
+        // not once the window is over
+        server.import_rooms(snapshot, Duration::ZERO);
+        let late = Arc::new("late".to_owned());
+        server.bind_user("/", "bob".to_owned(), late.clone());
+        server.bind_user("/", "alice".to_owned(), late);
+        assert_eq!(
+            server.rooms.get("/").unwrap().get("lobby").unwrap().len(),
+            1
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_user() {
+        let reasons = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let reasons_clone = reasons.clone();
+        let on_connect = |_: Option<Payload>, socket: ServerClient, _: Option<AckId>| {
+            async move {
+                let _ = socket.join(vec!["lobby"]).await;
+                socket.bind_user("alice");
+            }
+            .boxed()
+        };
+        let on_close = move |reason: Option<Payload>, _: ServerClient, _: Option<AckId>| {
+            reasons_clone.lock().unwrap().push(reason);
+            async {}.boxed()
+        };
+        let server = ServerBuilder::new(4229)
+            .on("/", Event::Connect, on_connect)
+            .on("/", Event::Close, on_close)
+            .build();
+        let server_clone = server.clone();
+        tokio::spawn(async move { server_clone.serve().await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let mut sockets = Vec::new();
+        for _ in 0..2 {
+            let socket = ClientBuilder::new("http://localhost:4229")
+                .reconnect(false)
+                .connect()
+                .await;
+            sockets.push(socket.expect("success"));
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        assert_eq!(server.disconnect_user("/", "alice", "banned").await, 2);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        assert_eq!(
+            *reasons.lock().unwrap(),
+            vec![Some(Payload::from(json!("banned"))); 2]
+        );
+        assert!(server.clients.is_empty());
+        assert!(server.user_sids("/", "alice").is_empty());
+        assert!(server.rooms.get("/").unwrap()["lobby"].is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_sid() {
+        let reasons = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let reasons_clone = reasons.clone();
+        let on_connect = |_: Option<Payload>, socket: ServerClient, _: Option<AckId>| {
+            async move {
+                let _ = socket.join(vec!["lobby"]).await;
+            }
+            .boxed()
+        };
+        let on_kick = |_: Option<Payload>, socket: ServerClient, _: Option<AckId>| {
+            async move {
+                let _ = socket.disconnect().await;
+            }
+            .boxed()
+        };
+        let on_close = move |reason: Option<Payload>, _: ServerClient, _: Option<AckId>| {
+            reasons_clone.lock().unwrap().push(reason);
+            async {}.boxed()
+        };
+        let server = ServerBuilder::new(4255)
+            .on("/", Event::Connect, on_connect)
+            .on("/", "kick", on_kick)
+            .on("/", Event::Close, on_close)
+            .build();
+        let server_clone = server.clone();
+        tokio::spawn(async move { server_clone.serve().await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let mut sockets = Vec::new();
+        for _ in 0..3 {
+            let socket = ClientBuilder::new("http://localhost:4255")
+                .reconnect(false)
+                .connect()
+                .await;
+            sockets.push(socket.expect("success"));
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // kicked by a handler of its own connection
+        sockets[0].emit("kick", json!(null)).await.expect("success");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(server.clients.len(), 2);
+
+        let sid = server.rooms.get("/").unwrap()["lobby"]
+            .iter()
+            .next()
+            .cloned()
+            .unwrap();
+        assert!(server.disconnect_sid("/", &sid).await);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!server.disconnect_sid("/", &sid).await);
+
+        let reason = Some(Payload::from(json!("server namespace disconnect")));
+        assert_eq!(*reasons.lock().unwrap(), vec![reason; 2]);
+        assert_eq!(server.clients.len(), 1);
+        assert_eq!(server.rooms.get("/").unwrap()["lobby"].len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_reason() {
+        let reason = |payload: &Option<Payload>| {
+            DisconnectReason::try_from(payload.as_ref().unwrap()).unwrap()
+        };
+        let on_server = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let on_server_clone = on_server.clone();
+        let server = ServerBuilder::new(4256)
+            .on(
+                "/",
+                Event::Disconnect,
+                move |payload, _: ServerClient, _| {
+                    on_server_clone.lock().unwrap().push(reason(&payload));
+                    async {}.boxed()
+                },
+            )
+            .build();
+        let server_clone = server.clone();
+        tokio::spawn(async move { server_clone.serve().await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let on_client = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut sockets = Vec::new();
+        for _ in 0..3 {
+            let on_client = on_client.clone();
+            let socket = ClientBuilder::new("http://localhost:4256")
+                .reconnect(false)
+                .on(Event::Disconnect, move |payload, _, _| {
+                    on_client.lock().unwrap().push(reason(&payload));
+                    async {}.boxed()
+                })
+                .connect()
+                .await;
+            sockets.push(socket.expect("success"));
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        sockets[0].disconnect().await.expect("success");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let sid = server
+            .clients
+            .iter()
+            .flat_map(|sid_map| sid_map.iter().map(|e| e.key().clone()).collect::<Vec<_>>())
+            .next()
+            .unwrap();
+        server.disconnect_sid("/", &sid).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        server.close().await;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        assert_eq!(
+            *on_server.lock().unwrap(),
+            vec![
+                DisconnectReason::ClientDisconnect,
+                DisconnectReason::ServerDisconnect,
+                DisconnectReason::ServerShutdown,
+            ]
+        );
+        assert_eq!(
+            *on_client.lock().unwrap(),
+            vec![
+                DisconnectReason::ClientDisconnect,
+                DisconnectReason::ServerDisconnect,
+                DisconnectReason::TransportClose,
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sockets_in_room() {
+        let on_connect = |_: Option<Payload>, socket: ServerClient, _: Option<AckId>| {
+            async move {
+                let _ = socket.join(vec!["lobby"]).await;
+            }
+            .boxed()
+        };
+        let server = ServerBuilder::new(4239)
+            .on("/", Event::Connect, on_connect)
+            .build();
+        let server_clone = server.clone();
+        tokio::spawn(async move { server_clone.serve().await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let mut sockets = Vec::new();
+        for _ in 0..3 {
+            let socket = ClientBuilder::new("http://localhost:4239")
+                .reconnect(false)
+                .connect()
+                .await;
+            sockets.push(socket.expect("success"));
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let lobby: Vec<ServerClient> = server.sockets_in_room("/", "lobby").collect().await;
+        let sids: HashSet<_> = lobby.iter().map(|socket| socket.sid()).collect();
+        assert_eq!(sids.len(), 3);
+        assert_eq!(server.sockets_in_room("/", "empty").count().await, 0);
+        assert_eq!(server.sockets_in_room("/admin", "lobby").count().await, 0);
+
+        // a socket gone after the stream was created is skipped
+        let stream = server.sockets_in_room("/", "lobby");
+        sockets.pop().unwrap().disconnect().await.expect("success");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(stream.count().await, 2);
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_operator() {
+        let on_connect = |_: Option<Payload>, socket: ServerClient, _: Option<AckId>| {
+            async move {
+                let _ = socket.join(vec!["lobby"]).await;
+            }
+            .boxed()
+        };
+        let on_mute = |_: Option<Payload>, socket: ServerClient, _: Option<AckId>| {
+            async move {
+                let _ = socket.join(vec!["muted"]).await;
+            }
+            .boxed()
+        };
+        let on_shout = |_: Option<Payload>, socket: ServerClient, _: Option<AckId>| {
+            async move {
+                let r = socket
+                    .to("lobby")
+                    .except("muted")
+                    .emit("msg", json!("hi"))
+                    .await;
+                assert!(r.is_ok());
+            }
+            .boxed()
+        };
+        let server = ServerBuilder::new(4246)
+            .on("/", Event::Connect, on_connect)
+            .on("/", "mute", on_mute)
+            .on("/", "shout", on_shout)
+            .build();
+        let server_clone = server.clone();
+        tokio::spawn(async move { server_clone.serve().await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let mut received = Vec::new();
+        let mut sockets = Vec::new();
+        for _ in 0..3 {
+            let count = Arc::new(AtomicUsize::new(0));
+            let count_clone = count.clone();
+            let socket = ClientBuilder::new("http://localhost:4246")
+                .reconnect(false)
+                .on("msg", move |_, _, _| {
+                    count_clone.fetch_add(1, Ordering::SeqCst);
+                    async {}.boxed()
+                })
+                .connect()
+                .await;
+            received.push(count);
+            sockets.push(socket.expect("success"));
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let counts = || {
+            received
+                .iter()
+                .map(|count| count.load(Ordering::SeqCst))
+                .collect::<Vec<_>>()
+        };
+
+        sockets[2].emit("mute", json!(null)).await.expect("success");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        // neither the sender nor the muted socket
+        sockets[0]
+            .emit("shout", json!(null))
+            .await
+            .expect("success");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(counts(), vec![0, 1, 0]);
+
+        server
+            .of("/")
+            .emit("msg", json!("all"))
+            .await
+            .expect("success");
+        server
+            .of("/")
+            .to("muted")
+            .emit("msg", json!("muted"))
+            .await
+            .expect("success");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(counts(), vec![1, 2, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_audit() {
+        // never served, only the room bookkeeping is used
+        let server = ServerBuilder::new(4221).build();
+        let alice = Arc::new("alice".to_owned());
+        server
+            .join("/", vec!["a", "b"], alice.clone())
+            .await
+            .unwrap();
+
+        let repaired = server.audit().await;
+        assert_eq!(repaired.stale_room_sids, 2);
+        assert_eq!(repaired.orphan_clients, 0);
+        assert!(server.rooms.get("/").unwrap().is_empty());
+
+        // a client whose engine.io socket is gone, e.g. after a missed close
+        let ghost = Arc::new("ghost".to_owned());
+        server.clients.insert(ghost.clone(), Default::default());
+        let repaired = server.audit().await;
+        assert_eq!(repaired.orphan_clients, 1);
+        assert_eq!(repaired.stale_room_sids, 0);
+        assert!(!server.clients.contains_key(&ghost));
+
+        let stats = server.audit_stats();
+        assert_eq!(stats.runs, 2);
+        assert_eq!(stats.stale_room_sids, 2);
+        assert_eq!(stats.orphan_clients, 1);
+    }
+
+    #[tokio::test]
+    async fn test_request() {
+        let answers = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let answers_clone = Arc::clone(&answers);
+        let on_connect = move |_: Option<Payload>, socket: ServerClient, _: Option<AckId>| {
+            let answers = answers_clone.clone();
+            async move {
+                tokio::spawn(async move {
+                    let timeout = Duration::from_secs(1);
+                    let answer = socket.request("version", json!(""), timeout).await;
+                    answers.lock().unwrap().push(answer.ok());
+                    let unanswered = socket.request("ignored", json!(""), timeout).await;
+                    answers.lock().unwrap().push(unanswered.ok());
+                });
+            }
+            .boxed()
+        };
+        let server = ServerBuilder::new(4222)
+            .on("/", Event::Connect, on_connect)
+            .build();
+        tokio::spawn(async move { server.serve().await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let socket = ClientBuilder::new("http://localhost:4222")
+            .on("version", |_, socket: Socket, need_ack| {
+                async move {
+                    if let Some(id) = need_ack {
+                        socket.ack(id, json!("1.0.0")).await.expect("success");
+                    }
+                }
+                .boxed()
+            })
+            .connect()
+            .await;
+        assert!(socket.is_ok());
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+
+        let answers = answers.lock().unwrap();
+        assert_eq!(*answers, vec![Some(json!("1.0.0").into()), None]);
+    }
+
+    #[tokio::test]
+    async fn test_health_probe() {
+        let answers = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let answers_clone = Arc::clone(&answers);
+        let on_connect = move |_: Option<Payload>, socket: ServerClient, _: Option<AckId>| {
+            let answers = answers_clone.clone();
+            async move {
+                tokio::spawn(async move {
+                    let answer = socket
+                        .request("health", json!(""), Duration::from_secs(1))
+                        .await;
+                    answers.lock().unwrap().push(answer.ok());
+                });
+            }
+            .boxed()
+        };
+        let server = ServerBuilder::new(4223)
+            .on("/", Event::Connect, on_connect)
+            .build();
+        tokio::spawn(async move { server.serve().await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let probe = crate::HealthProbe::new("health")
+            .version("2.1.0")
+            .platform("gateway");
+        let socket = ClientBuilder::new("http://localhost:4223")
+            .health_probe(probe)
+            .connect()
+            .await;
+        assert!(socket.is_ok());
+        tokio::time::sleep(Duration::from_millis(300)).await;
+
+        let answer = json!({"version": "2.1.0", "platform": "gateway", "rtt_ms": null});
+        assert_eq!(*answers.lock().unwrap(), vec![Some(answer.into())]);
+    }
+
+    #[tokio::test]
+    async fn test_runtime() {
+        // a current-thread runtime only runs its tasks on the thread driving it
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let handle = runtime.handle().clone();
+        std::thread::Builder::new()
+            .name("socketio-runtime".to_owned())
+            .spawn(move || {
+                runtime.block_on(async { tokio::time::sleep(Duration::from_secs(3)).await })
+            })
+            .unwrap();
+
+        let threads = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let threads_clone = Arc::clone(&threads);
+        let on_connect = move |_: Option<Payload>, _: ServerClient, _: Option<AckId>| {
+            let threads = threads_clone.clone();
+            async move {
+                let thread = std::thread::current().name().map(str::to_owned);
+                threads.lock().unwrap().push(thread);
+            }
+            .boxed()
+        };
+        let server = ServerBuilder::new(4224)
+            .runtime(handle)
+            .on("/", Event::Connect, on_connect)
+            .build();
+        tokio::spawn(async move { server.serve().await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let socket = ClientBuilder::new("http://localhost:4224").connect().await;
+        assert!(socket.is_ok());
+        tokio::time::sleep(Duration::from_millis(300)).await;
+
+        let threads = threads.lock().unwrap();
+        assert_eq!(*threads, vec![Some("socketio-runtime".to_owned())]);
+    }
+
+    #[tokio::test]
+    async fn test_close_poll_tasks() {
+        let noop = |_: Option<Payload>, _: ServerClient, _: Option<AckId>| async {}.boxed();
+        let server = ServerBuilder::new(4225)
+            .on("/", Event::Connect, noop)
+            .build();
+        let server_clone = server.clone();
+        tokio::spawn(async move { server_clone.serve().await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let mut sockets = Vec::new();
+        for _ in 0..2 {
+            let socket = ClientBuilder::new("http://localhost:4225").connect().await;
+            assert!(socket.is_ok());
+            sockets.push(socket);
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(server.poll_tasks.len(), 2);
+
+        let socket = sockets.pop().unwrap().unwrap();
+        socket.disconnect().await.expect("success");
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(server.poll_tasks.len(), 1);
+
+        server.close().await;
+        assert!(server.poll_tasks.is_empty());
+        assert!(server.clients.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_client_join() {
+        let noop = |_: Option<Payload>, _: ServerClient, _: Option<AckId>| async {}.boxed();
+        let server = ServerBuilder::new(4226)
+            .on("/", Event::Connect, noop)
+            .build();
+        let server_clone = server.clone();
+        tokio::spawn(async move { server_clone.serve().await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let record = |event: &'static str| {
+            let events = events.clone();
+            move |payload: Option<Payload>, _: Socket, _: Option<AckId>| {
+                events.lock().unwrap().push((event, payload));
+                async {}.boxed()
+            }
+        };
+        let socket = ClientBuilder::new("http://localhost:4226")
+            .reconnect(false)
+            .on(Event::Error, record("error"))
+            .on(Event::Close, record("close"))
+            .connect()
+            .await
+            .expect("success");
+        let closing = ClientBuilder::new("http://localhost:4226")
+            .connect()
+            .await
+            .expect("success");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        closing.disconnect().await.expect("success");
+        let joined = tokio::time::timeout(Duration::from_secs(1), closing.join()).await;
+        assert!(joined.is_ok());
+
+        server.close().await;
+        let joined = tokio::time::timeout(Duration::from_secs(1), socket.join()).await;
+        assert!(joined.is_ok());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // the error of the failed transport is reported before
+        let events = events.lock().unwrap();
+        assert_eq!(
+            events[events.len() - 2..],
+            [
+                ("error", Some(json!("connection lost").into())),
+                ("close", None)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_coalesce() {
+        let sent = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sent_clone = sent.clone();
+        let on_connect = move |_: Option<Payload>, socket: ServerClient, _: Option<AckId>| {
+            let sent = sent_clone.clone();
+            async move {
+                let emits = (0..50).map(|i| socket.emit("cursor", json!(i)));
+                let results = futures_util::future::join_all(emits).await;
+                for (i, result) in results.into_iter().enumerate() {
+                    match result {
+                        Ok(()) => sent.lock().unwrap().push(Some(json!(i).into())),
+                        Err(Error::SupersededEmit()) => {}
+                        Err(e) => panic!("unexpected error {e}"),
+                    }
+                }
+            }
+            .boxed()
+        };
+        let server = ServerBuilder::new(4228)
+            .coalesce("/", "cursor")
+            .on("/", Event::Connect, on_connect)
+            .build();
+        tokio::spawn(async move { server.serve().await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let cursors = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let cursors_clone = cursors.clone();
+        let _socket = ClientBuilder::new("http://localhost:4228")
+            .on("cursor", move |payload: Option<Payload>, _: Socket, _| {
+                cursors_clone.lock().unwrap().push(payload);
+                async {}.boxed()
+            })
+            .connect()
+            .await
+            .expect("success");
+        tokio::time::sleep(Duration::from_millis(300)).await;
+
+        // emits may be collapsed, but those reported sent arrive in order and
+        // the latest one always does
+        let cursors = cursors.lock().unwrap().clone();
+        assert_eq!(cursors, *sent.lock().unwrap());
+        assert_eq!(cursors.last(), Some(&Some(json!(49).into())));
+    }
+
+    #[tokio::test]
+    async fn test_resubscribe() {
+        let channels = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let channels_clone = channels.clone();
+        let on_subscribe = move |channel: Option<Payload>, _: ServerClient, _: Option<AckId>| {
+            channels_clone.lock().unwrap().push(channel);
+            async {}.boxed()
+        };
+        let noop = |_: Option<Payload>, _: ServerClient, _: Option<AckId>| async {}.boxed();
+        let server = ServerBuilder::new(4230)
+            .on("/", Event::Connect, noop)
+            .on("/", "subscribe", on_subscribe)
+            .build();
+        let server_clone = server.clone();
+        tokio::spawn(async move { server_clone.serve().await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let socket = ClientBuilder::new("http://localhost:4230")
+            .reconnect_delay(Duration::from_millis(10), Duration::from_millis(50))
+            .subscribe("subscribe", json!("news"))
+            .connect()
+            .await
+            .expect("success");
+        socket
+            .subscribe("subscribe", json!("sports"))
+            .await
+            .expect("success");
+        socket
+            .subscribe("subscribe", json!("weather"))
+            .await
+            .expect("success");
+        assert!(socket.unsubscribe("subscribe", json!("weather")).await);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        server.close().await;
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        let channel = |name: &str| Some(Payload::from(json!(name)));
+        assert_eq!(
+            *channels.lock().unwrap(),
+            vec![
+                channel("news"),
+                channel("sports"),
+                channel("weather"),
+                channel("news"),
+                channel("sports")
+            ]
+        );
+
+        // `2["subscribe","news"]` and `2["subscribe","sports"]` are kept
+        assert_eq!(socket.memory_usage().await.recovery, 44);
+        assert!(socket.unsubscribe("subscribe", json!("news")).await);
+        assert!(socket.unsubscribe("subscribe", json!("sports")).await);
+        assert_eq!(socket.memory_usage().await.recovery, 0);
+    }
+
+    #[tokio::test]
+    async fn test_lifecycle_events() {
+        let pongs = Arc::new(AtomicUsize::default());
+        let pongs_clone = pongs.clone();
+        let closed = Arc::new(AtomicUsize::default());
+        let closed_clone = closed.clone();
+        let noop = |_: Option<Payload>, _: ServerClient, _: Option<AckId>| async {}.boxed();
+        let server = ServerBuilder::new(4231)
+            .server_option(ServerOption {
+                ping_interval: 50,
+                ping_timeout: 200,
+                max_payload: 102400,
+            })
+            .on("/", Event::Connect, noop)
+            .on("/", Event::Pong, move |_, _: ServerClient, _| {
+                pongs_clone.fetch_add(1, Ordering::SeqCst);
+                async {}.boxed()
+            })
+            .on("/", Event::Close, move |_, _: ServerClient, _| {
+                closed_clone.fetch_add(1, Ordering::SeqCst);
+                async {}.boxed()
+            })
+            .build();
+        let server_clone = server.clone();
+        tokio::spawn(async move { server_clone.serve().await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let pings = Arc::new(AtomicUsize::default());
+        let pings_clone = pings.clone();
+        let attempts = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let attempts_clone = attempts.clone();
+        let _socket = ClientBuilder::new("http://localhost:4231")
+            .reconnect_delay(Duration::from_millis(10), Duration::from_millis(50))
+            .on(Event::Ping, move |_, _: Socket, _| {
+                pings_clone.fetch_add(1, Ordering::SeqCst);
+                async {}.boxed()
+            })
+            .on(Event::Reconnecting, move |attempt, _: Socket, _| {
+                attempts_clone.lock().unwrap().push(attempt);
+                async {}.boxed()
+            })
+            .connect()
+            .await
+            .expect("success");
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(pings.load(Ordering::SeqCst) > 0);
+        assert!(pongs.load(Ordering::SeqCst) > 0);
+
+        server.close().await;
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(closed.load(Ordering::SeqCst), 1);
+        let attempts = attempts.lock().unwrap();
+        assert_eq!(attempts.first(), Some(&Some(Payload::from(json!(1)))));
+    }
+
+    #[tokio::test]
+    async fn test_ack_policy() {
+        let received = Arc::new(AtomicUsize::default());
+        let received_clone = received.clone();
+        let ids = Arc::new(std::sync::Mutex::new(vec![]));
+        let ids_clone = ids.clone();
+        // only the event emitted again after the reconnect is acked
+        let on_resend = move |_: Option<Payload>, socket: ServerClient, id: Option<AckId>| {
+            let received = received_clone.clone();
+            ids_clone.lock().unwrap().push(id);
+            async move {
+                if received.fetch_add(1, Ordering::SeqCst) > 0 {
+                    let _ = socket.ack(id.unwrap(), json!("again")).await;
+                }
+            }
+            .boxed()
+        };
+        let noop = |_: Option<Payload>, _: ServerClient, _: Option<AckId>| async {}.boxed();
+        let server = ServerBuilder::new(4227)
+            .on("/", Event::Connect, noop)
+            .on("/", "resend", on_resend)
+            .on("/", "fail", noop)
+            .build();
+        let server_clone = server.clone();
+        tokio::spawn(async move { server_clone.serve().await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let socket = ClientBuilder::new("http://localhost:4227")
+            .reconnect_delay(Duration::from_millis(10), Duration::from_millis(50))
+            .connect()
+            .await
+            .expect("success");
+        let timeout = Duration::from_secs(3);
+        let (resend, fail) = (socket.clone(), socket.clone());
+        let fail = tokio::spawn(async move {
+            fail.request_with_policy("fail", json!(""), timeout, AckPolicy::Fail)
+                .await
+        });
+        // takes the second ack id
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let resend = tokio::spawn(async move {
+            resend
+                .request_with_policy("resend", json!(""), timeout, AckPolicy::Resend)
+                .await
+        });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        server.close().await;
+        let fail = fail.await.unwrap();
+        assert!(matches!(
+            fail,
+            Err(Error::FailedAck(AckError::Disconnected))
+        ));
+        let resend = resend.await.unwrap();
+        assert_eq!(resend.ok(), Some(json!("again").into()));
+        assert_eq!(received.load(Ordering::SeqCst), 2);
+        // the new socket gave the resent event an id of its own
+        assert_eq!(*ids.lock().unwrap(), vec![Some(1), Some(0)]);
+    }
+
+    #[tokio::test]
+    async fn test_migrate() {
+        let on_connect = |_: Option<Payload>, socket: ServerClient, _: Option<AckId>| {
+            async move {
+                let _ = socket.join(vec!["lobby"]).await;
+                socket.bind_user("alice");
+                socket.data().insert("name".to_owned(), json!("Alice"));
+            }
+            .boxed()
+        };
+        let restored = Arc::new(std::sync::Mutex::new(None));
+        let restored_clone = restored.clone();
+        let on_migrated = move |_: Option<Payload>, socket: ServerClient, _: Option<AckId>| {
+            let name = socket.data().get("name").map(|name| name.value().clone());
+            *restored_clone.lock().unwrap() = name;
+            async {}.boxed()
+        };
+        // the instances share a store as they would share Redis
+        let store = Arc::new(MemoryStore::default());
+        let old = ServerBuilder::new(4232)
+            .session_store(store.clone())
+            .on("/", Event::Connect, on_connect)
+            .build();
+        let new = ServerBuilder::new(4233)
+            .session_store(store)
+            .on("/", Event::Connect, on_migrated)
+            .build();
+        for server in [old.clone(), new.clone()] {
+            tokio::spawn(async move { server.serve().await });
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let migrated = Arc::new(AtomicBool::new(false));
+        let migrated_clone = migrated.clone();
+        let socket = ClientBuilder::new("http://localhost:4232")
+            .reconnect(false)
+            .on(Event::Migrate, move |_, _, _| {
+                migrated_clone.store(true, Ordering::SeqCst);
+                async {}.boxed()
+            })
+            .connect()
+            .await
+            .expect("success");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        // only the server itself sends control events
+        let forged = json!({ "url": "http://localhost:4233", "token": "forged" });
+        assert!(matches!(
+            socket.emit("migrate", forged).await,
+            Err(Error::InvalidReservedEvent(_))
+        ));
+
+        let sid = old.user_sids("/", "alice").into_iter().next().unwrap();
+        let moved = old.migrate("/", &sid, "http://localhost:4233").await;
+        assert!(matches!(moved, Ok(true)));
+        tokio::time::sleep(Duration::from_millis(300)).await;
+
+        assert!(migrated.load(Ordering::SeqCst));
+        assert!(old.clients.is_empty());
+        assert_eq!(*restored.lock().unwrap(), Some(json!("Alice")));
+        let sids = new.user_sids("/", "alice");
+        assert_eq!(sids.len(), 1);
+        assert_eq!(new.rooms.get("/").unwrap()["lobby"], sids);
+        assert!(matches!(
+            old.migrate("/", &sid, "http://localhost:4233").await,
+            Ok(false)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_announce_maintenance() {
+        let (connect_tx, mut connects) = tokio::sync::mpsc::unbounded_channel();
+        let server = ServerBuilder::new(4245)
+            .on("/", Event::Connect, move |_, _: ServerClient, _| {
+                let _ = connect_tx.send(Instant::now());
+                async {}.boxed()
+            })
+            .build();
+        let server_clone = server.clone();
+        tokio::spawn(async move { server_clone.serve().await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let (announced_tx, mut announced) = tokio::sync::mpsc::unbounded_channel();
+        let _socket = ClientBuilder::new("http://localhost:4245")
+            .reconnect_delay(Duration::from_millis(10), Duration::from_millis(10))
+            .on(Event::Maintenance, move |payload, _, _| {
+                let _ = announced_tx.send(payload);
+                async {}.boxed()
+            })
+            .connect()
+            .await
+            .expect("success");
+        let wait = Duration::from_secs(2);
+        tokio::time::timeout(wait, connects.recv())
+            .await
+            .expect("connected");
+
+        server
+            .announce_maintenance(Duration::from_millis(200))
+            .await
+            .expect("success");
+        let payload = tokio::time::timeout(wait, announced.recv())
+            .await
+            .expect("announced");
+        assert_eq!(payload, Some(Some(json!({ "delay": 200 }).into())));
+
+        let closed_at = Instant::now();
+        server.close().await;
+        // not before the suggested delay, at the latest after twice of it
+        let reconnected_at = tokio::time::timeout(wait, connects.recv())
+            .await
+            .expect("reconnected")
+            .expect("success");
+        let elapsed = reconnected_at - closed_at;
+        assert!(elapsed >= Duration::from_millis(200), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(800), "{:?}", elapsed);
+    }
+
+    #[tokio::test]
+    async fn test_set_accepting() {
+        let noop = |_: Option<Payload>, _: ServerClient, _: Option<AckId>| async {}.boxed();
+        let server = ServerBuilder::new(4235)
+            .on("/", Event::Connect, noop)
+            .build();
+        let server_clone = server.clone();
+        tokio::spawn(async move { server_clone.serve().await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let connect = || {
+            ClientBuilder::new("http://localhost:4235")
+                .reconnect(false)
+                .connect()
+        };
+        let socket = connect().await.expect("success");
+        // the namespace CONNECT is handled after the handshake returned
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        server.set_accepting(false);
+        assert!(!server.is_accepting());
+        assert!(connect().await.is_err());
+        // the connected socket is still served
+        assert!(socket.emit("message", json!("still here")).await.is_ok());
+        assert_eq!(server.clients.len(), 1);
+
+        server.set_accepting(true);
+        assert!(connect().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_client_metrics() {
+        let on_connect = |_: Option<Payload>, socket: ServerClient, _: Option<AckId>| {
+            async move {
+                let _ = socket.emit("hello", json!("client")).await;
+            }
+            .boxed()
+        };
+        let on_echo = |data: Option<Payload>, socket: ServerClient, id: Option<AckId>| {
+            async move {
+                let _ = socket.ack(id.unwrap(), data.unwrap()).await;
+            }
+            .boxed()
+        };
+        let server = ServerBuilder::new(4236)
+            .on("/", Event::Connect, on_connect)
+            .on("/", "echo", on_echo)
+            .build();
+        let server_clone = server.clone();
+        tokio::spawn(async move { server_clone.serve().await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let socket = ClientBuilder::new("http://localhost:4236")
+            .reconnect(false)
+            .connect()
+            .await
+            .expect("success");
+        let answer = socket
+            .request("echo", json!(1), Duration::from_secs(1))
+            .await;
+        assert!(answer.is_ok());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let metrics = socket.metrics().await;
+        assert_eq!(metrics.emits_sent, 1);
+        // the greeting, acks are not counted as events
+        assert_eq!(metrics.events_received, 1);
+        assert_eq!(metrics.acks_outstanding, 0);
+        assert_eq!(metrics.reconnects, 0);
+        assert!(metrics.transport.is_some());
+        assert!(metrics.connected_for.is_some());
+        assert!(metrics.rtt.is_some());
+
+        socket.disconnect().await.expect("success");
+        let metrics = socket.metrics().await;
+        assert_eq!(metrics.transport, None);
+        assert_eq!(metrics.connected_for, None);
+    }
+
+    #[tokio::test]
+    async fn test_websocket_headers() {
+        let noop = |_: Option<Payload>, _: ServerClient, _: Option<AckId>| async {}.boxed();
+        let server = ServerBuilder::new(4237)
+            .on("/", Event::Connect, noop)
+            .build();
+        let server_clone = server.clone();
+        tokio::spawn(async move { server_clone.serve().await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let _socket = ClientBuilder::new("http://localhost:4237")
+            .transport_type(TransportType::Websocket)
+            .opening_header("x-tenant", "acme")
+            .websocket_header("x-gateway", "edge-1")
+            .websocket_protocols(["chat", "superchat"])
+            .reconnect(false)
+            .connect()
+            .await
+            .expect("success");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let esid = server.clients.iter().next().expect("client").key().clone();
+        let headers = server
+            .engine_server
+            .handshake_headers(&esid)
+            .expect("headers");
+        let header = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|v| v.to_str())
+                .map(str::to_owned)
+        };
+        assert_eq!(header("x-tenant").as_deref(), Some("acme"));
+        assert_eq!(header("x-gateway").as_deref(), Some("edge-1"));
+        assert_eq!(
+            header("sec-websocket-protocol").as_deref(),
+            Some("chat, superchat")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_protocol_violation() -> Result<()> {
+        let reason = Arc::new(std::sync::Mutex::new(None));
+        let reason_clone = reason.clone();
+        let server = ServerBuilder::new(4241)
+            .on("/", Event::Close, move |payload, _: ServerClient, _| {
+                *reason_clone.lock().unwrap() = payload;
+                async {}.boxed()
+            })
+            .build();
+        let server_clone = server.clone();
+        tokio::spawn(async move { server_clone.serve().await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // a raw engine.io client, the socket.io client can't send garbage
+        let url = url::Url::parse("http://localhost:4241/socket.io/")?;
+        let socket = engineio_rs::SocketBuilder::new(url).build().await?;
+        socket.connect().await?;
+        socket
+            .emit(EnginePacket::new(EnginePacketType::Message, "0"))
+            .await?;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(server.clients.len(), 1);
+
+        socket
+            .emit(EnginePacket::new(EnginePacketType::Message, "2[\"ev\""))
+            .await?;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        assert_eq!(
+            *reason.lock().unwrap(),
+            Some(Payload::from(json!("protocol violation")))
+        );
+        assert!(server.clients.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_forged_reserved_event() -> Result<()> {
+        let disconnects = Arc::new(AtomicUsize::new(0));
+        let disconnects_clone = disconnects.clone();
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let server = ServerBuilder::new(4267)
+            .on("/", Event::Disconnect, move |_, _: ServerClient, _| {
+                disconnects_clone.fetch_add(1, Ordering::SeqCst);
+                async {}.boxed()
+            })
+            .on("/", "ev", move |payload, _: ServerClient, _| {
+                let _ = tx.send(payload);
+                async {}.boxed()
+            })
+            .build();
+        let server_clone = server.clone();
+        tokio::spawn(async move { server_clone.serve().await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // a raw engine.io client, the socket.io client can't emit reserved events
+        let url = url::Url::parse("http://localhost:4267/socket.io/")?;
+        let socket = engineio_rs::SocketBuilder::new(url).build().await?;
+        socket.connect().await?;
+        for packet in [
+            "0",
+            "2[\"disconnect\",\"io server disconnect\"]",
+            "2[\"ev\",1]",
+        ] {
+            socket
+                .emit(EnginePacket::new(EnginePacketType::Message, packet))
+                .await?;
+        }
+        let payload = tokio::time::timeout(Duration::from_secs(2), rx.recv()).await;
+        assert_eq!(payload.unwrap(), Some(Some(json!(1).into())));
+
+        // the forged event fired nothing, the socket is still connected
+        assert_eq!(disconnects.load(Ordering::SeqCst), 0);
+        assert_eq!(server.clients.len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_on_protocol_error() -> Result<()> {
+        let hooked = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let hooked_clone = hooked.clone();
+        let errors = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let errors_clone = errors.clone();
+        let noop = |_: Option<Payload>, _: ServerClient, _: Option<AckId>| async {}.boxed();
+        let server = ServerBuilder::new(4254)
+            .on("/", "ev", noop)
+            .on("/", Event::Error, move |payload, _: ServerClient, _| {
+                errors_clone.lock().unwrap().push(payload);
+                async {}.boxed()
+            })
+            .on_protocol_error(move |error| hooked_clone.lock().unwrap().push(error))
+            .build();
+        let server_clone = server.clone();
+        tokio::spawn(async move { server_clone.serve().await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let url = url::Url::parse("http://localhost:4254/socket.io/")?;
+        let socket = engineio_rs::SocketBuilder::new(url).build().await?;
+        socket.connect().await?;
+        for packet in [
+            "0",
+            "2[\"ev\",1]",
+            "2[\"v2:ev\",1]",
+            "2/admin,[\"ev\",1]",
+            "2[\"ev\"",
+        ] {
+            socket
+                .emit(EnginePacket::new(EnginePacketType::Message, packet))
+                .await?;
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        let hooked = hooked.lock().unwrap();
+        let kinds: Vec<_> = hooked.iter().map(|error| error.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                ProtocolErrorKind::UnknownEvent,
+                ProtocolErrorKind::UnknownNamespace,
+                ProtocolErrorKind::Undecodable,
+            ]
+        );
+        assert_eq!(hooked[1].nsp.as_deref(), Some("/admin"));
+        assert_eq!(hooked[2].raw, Bytes::from_static(b"2[\"ev\""));
+
+        // the namespace hears of its own errors only
+        let errors = errors.lock().unwrap();
+        assert_eq!(errors.len(), 2);
+        assert!(matches!(&errors[0], Some(Payload::Json(e)) if e["kind"] == "unknown_event"));
+        assert!(matches!(&errors[1], Some(Payload::Json(e)) if e["kind"] == "undecodable"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_multiple_namespaces() -> Result<()> {
+        let received = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let record = |received: Arc<std::sync::Mutex<Vec<String>>>| {
+            move |_: Option<Payload>, socket: ServerClient, _: Option<AckId>| {
+                received.lock().unwrap().push(socket.namespace());
+                async {}.boxed()
+            }
+        };
+        let server = ServerBuilder::new(4248)
+            .on("/", "ev", record(received.clone()))
+            .on("/admin", "ev", record(received.clone()))
+            .build();
+        let server_clone = server.clone();
+        tokio::spawn(async move { server_clone.serve().await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // a raw engine.io client, the socket.io client connects one namespace
+        let url = url::Url::parse("http://localhost:4248/socket.io/")?;
+        let socket = engineio_rs::SocketBuilder::new(url).build().await?;
+        socket.connect().await?;
+        for packet in ["0", "0/admin,", "2[\"ev\",1]", "2/admin,[\"ev\",1]"] {
+            socket
+                .emit(EnginePacket::new(EnginePacketType::Message, packet))
+                .await?;
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        assert_eq!(server.clients.len(), 1);
+        assert_eq!(server.clients.iter().next().unwrap().len(), 2);
+        let mut namespaces = received.lock().unwrap().clone();
+        namespaces.sort();
+        assert_eq!(namespaces, vec!["/".to_owned(), "/admin".to_owned()]);
+
+        // leaving a namespace keeps the connection of the others
+        socket
+            .emit(EnginePacket::new(EnginePacketType::Message, "1/admin,"))
+            .await?;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(server.namespace_info("/admin").unwrap().sockets, 0);
+        assert_eq!(server.namespace_info("/").unwrap().sockets, 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_memory_cap() {
+        let sent = Arc::new(AtomicUsize::default());
+        let sent_clone = sent.clone();
+        let exceeded = Arc::new(AtomicBool::new(false));
+        let exceeded_clone = exceeded.clone();
+        let reason = Arc::new(std::sync::Mutex::new(None));
+        let reason_clone = reason.clone();
+        let server = ServerBuilder::new(4242)
+            .memory_cap(4096)
+            .on("/", Event::Connect, move |_, socket: ServerClient, _| {
+                let sent = sent_clone.clone();
+                let exceeded = exceeded_clone.clone();
+                async move {
+                    // the client never answers, so the acks pile up
+                    let noop =
+                        |_: Option<Payload>, _: ServerClient, _: Option<AckId>| async {}.boxed();
+                    loop {
+                        let timeout = Duration::from_secs(10);
+                        match socket.emit_with_ack("ev", json!(""), timeout, noop).await {
+                            Ok(()) => sent.fetch_add(1, Ordering::SeqCst),
+                            Err(e) => {
+                                let cap = matches!(e, Error::ExceededMemoryCap(n) if n > 4096);
+                                exceeded.store(cap, Ordering::SeqCst);
+                                break;
+                            }
+                        };
+                    }
+                }
+                .boxed()
+            })
+            .on("/", Event::Close, move |payload, _: ServerClient, _| {
+                *reason_clone.lock().unwrap() = payload;
+                async {}.boxed()
+            })
+            .build();
+        let server_clone = server.clone();
+        tokio::spawn(async move { server_clone.serve().await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let _socket = ClientBuilder::new("http://localhost:4242")
+            .reconnect(false)
+            .connect()
+            .await
+            .expect("success");
+        tokio::time::sleep(Duration::from_millis(300)).await;
+
+        assert!(sent.load(Ordering::SeqCst) > 0);
+        assert!(exceeded.load(Ordering::SeqCst));
+        assert_eq!(
+            *reason.lock().unwrap(),
+            Some(Payload::from(json!("memory cap exceeded")))
+        );
+        assert!(server.clients.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_client_max_payload() {
+        let received = Arc::new(AtomicUsize::new(0));
+        let received_clone = received.clone();
+        let server = ServerBuilder::new(4247)
+            .on("/", "msg", move |_, _: ServerClient, _| {
+                received_clone.fetch_add(1, Ordering::SeqCst);
+                async {}.boxed()
+            })
+            .build();
+        let server_clone = server.clone();
+        tokio::spawn(async move { server_clone.serve().await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let socket = ClientBuilder::new("http://localhost:4247")
+            .max_payload(64)
+            .connect()
+            .await
+            .expect("success");
+        assert!(matches!(
+            socket.emit("msg", json!("x".repeat(64))).await,
+            Err(Error::PayloadTooLarge(_, 64))
+        ));
+        let blob = Bytes::from(vec![0; 64]);
+        assert!(matches!(
+            socket.emit_with_binary("msg", json!({}), vec![blob]).await,
+            Err(Error::PayloadTooLarge(_, 64))
+        ));
+        // the connection is still usable
+        socket.emit("msg", json!("x")).await.expect("success");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(received.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_emit_with_binary() {
+        let received = Arc::new(std::sync::Mutex::new(None));
+        let received_clone = received.clone();
+        let server = ServerBuilder::new(4243)
+            .on("/", "upload", move |payload, _: ServerClient, _| {
+                *received_clone.lock().unwrap() = payload;
+                async {}.boxed()
+            })
+            .build();
+        let server_clone = server.clone();
+        tokio::spawn(async move { server_clone.serve().await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let socket = ClientBuilder::new("http://localhost:4243")
+            .connect()
+            .await
+            .expect("success");
+        let blobs = vec![Bytes::from_static(b"first"), Bytes::from_static(b"second")];
+        socket
+            .emit_with_binary("upload", json!({ "name": "a.bin" }), blobs.clone())
+            .await
+            .expect("success");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        assert_eq!(
+            *received.lock().unwrap(),
+            Some(Payload::Multi(vec![
+                json!({ "name": "a.bin" }).into(),
+                blobs[0].clone().into(),
+                blobs[1].clone().into(),
+            ]))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_emit_batch() {
+        let received = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let received_clone = received.clone();
+        let server = ServerBuilder::new(4244)
+            .dispatch_mode(DispatchMode::Ordered)
+            .on("/", "batch", move |payload, _: ServerClient, _| {
+                received_clone.lock().unwrap().push(payload);
+                async {}.boxed()
+            })
+            .build();
+        let server_clone = server.clone();
+        tokio::spawn(async move { server_clone.serve().await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let socket = ClientBuilder::new("http://localhost:4244")
+            .connect()
+            .await
+            .expect("success");
+        let blob = Bytes::from_static(b"blob");
+        let multi = Payload::Multi(vec![
+            RawPayload::Json(json!(3)),
+            RawPayload::Json(json!("three")),
+        ]);
+        socket
+            .emit_batch(vec![
+                ("batch".into(), json!(1).into()),
+                ("batch".into(), blob.clone().into()),
+                ("batch".into(), multi.clone()),
+            ])
+            .await
+            .expect("success");
+        socket.emit_batch(vec![]).await.expect("success");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // one call per event, in order, each with the arguments it was sent with
+        assert_eq!(
+            *received.lock().unwrap(),
+            vec![Some(json!(1).into()), Some(blob.into()), Some(multi)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sticky() {
+        let noop = |_: Option<Payload>, _: ServerClient, _: Option<AckId>| async {}.boxed();
+        let server = ServerBuilder::new(4240)
+            .on("/", Event::Connect, noop)
+            .build();
+        let server_clone = server.clone();
+        tokio::spawn(async move { server_clone.serve().await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let socket = ClientBuilder::new("http://localhost:4240")
+            .sticky(Event::Connect)
+            .reconnect(false)
+            .connect()
+            .await
+            .expect("success");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let fired = Arc::new(AtomicUsize::new(0));
+        for event in [Event::Connect, Event::from("news")] {
+            let fired = fired.clone();
+            socket
+                .on(event, move |_, _: Socket, _| {
+                    fired.fetch_add(1, Ordering::SeqCst);
+                    async {}.boxed()
+                })
+                .await;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // only the sticky connect is replayed
+        assert_eq!(fired.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_on_typed() {
+        #[derive(serde::Serialize, serde::Deserialize)]
+        struct Greeting {
+            name: String,
+        }
+
+        let errors = Arc::new(AtomicUsize::new(0));
+        let errors_clone = errors.clone();
+        let on_error = move |_: Option<Payload>, _: ServerClient, _: Option<AckId>| {
+            errors_clone.fetch_add(1, Ordering::SeqCst);
+            async {}.boxed()
+        };
+        let server = ServerBuilder::new(4258)
+            .on_typed(
+                "/",
+                "greet",
+                |greeting: Greeting, socket: ServerClient, _| {
+                    async move {
+                        let reply = Greeting {
+                            name: format!("hello {}", greeting.name),
+                        };
+                        let _ = socket.emit_ref("greeted", &reply).await;
+                    }
+                    .boxed()
+                },
+            )
+            .on_typed(
+                "/",
+                "ask",
+                |greeting: Greeting, socket: ServerClient, ack| {
+                    let ack = socket.ack_sender(ack);
+                    async move {
+                        assert!(ack.requested());
+                        let _ = ack.send(json!(format!("hi {}", greeting.name))).await;
+                    }
+                    .boxed()
+                },
+            )
+            .on("/", Event::Error, on_error)
+            .build();
+        tokio::spawn(async move { server.serve().await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let socket = ClientBuilder::new("http://localhost:4258")
+            .on_typed("greeted", move |greeting: Greeting, _, _| {
+                let _ = tx.send(greeting.name);
+                async {}.boxed()
+            })
+            .connect()
+            .await
+            .expect("success");
+
+        socket
+            .emit("greet", json!({ "nick": "ann" }))
+            .await
+            .unwrap();
+        socket
+            .emit("greet", json!({ "name": "ann" }))
+            .await
+            .unwrap();
+        let name = tokio::time::timeout(Duration::from_secs(2), rx.recv()).await;
+        assert_eq!(name.unwrap().as_deref(), Some("hello ann"));
+        // the payload which doesn't deserialize went to the Error handler
+        assert_eq!(errors.load(Ordering::SeqCst), 1);
+
+        let reply = socket
+            .request_with_policy(
+                "ask",
+                json!({ "name": "bob" }),
+                Duration::from_secs(2),
+                AckPolicy::Fail,
+            )
+            .await
+            .expect("success");
+        assert_eq!(reply, Payload::Json(json!("hi bob")));
+    }
+
+    #[tokio::test]
+    async fn test_resume_sessions() {
+        let on_join = |_: Option<Payload>, socket: ServerClient, _: Option<AckId>| {
+            async move {
+                let _ = socket.join(vec!["vip"]).await;
+            }
+            .boxed()
+        };
+        let auths = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let auths_clone = auths.clone();
+        let server = ServerBuilder::new(4259)
+            .resume_sessions(Duration::from_secs(5), Duration::from_millis(400))
+            .use_middleware("/", move |handshake: Handshake, next: Next| {
+                auths_clone.lock().unwrap().push(handshake.auth.clone());
+                next.run(handshake).boxed()
+            })
+            .on("/", "join", on_join)
+            .build();
+        let server_clone = server.clone();
+        tokio::spawn(async move { server_clone.serve().await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let socket = ClientBuilder::new("http://localhost:4259")
+            .reconnect_delay(Duration::from_millis(10), Duration::from_millis(10))
+            .auth(json!({ "resume": "mine" }))
+            .connect()
+            .await
+            .expect("success");
+        socket.emit("join", json!({})).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let vip = || {
+            server
+                .rooms
+                .get("/")
+                .and_then(|rooms| rooms.get("vip").cloned())
+                .unwrap_or_default()
+        };
+        let sid = vip().into_iter().next().expect("joined");
+        let token = server.resume_tokens.get(&sid).map(|t| t.clone());
+        assert!(token.as_deref().is_some_and(|token| token != sid.as_str()));
+        let start = Instant::now();
+        let mut rotated = token.clone();
+        while rotated == token && start.elapsed() < Duration::from_secs(2) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            rotated = server.resume_tokens.get(&sid).map(|t| t.clone());
+        }
+        assert!(rotated.is_some() && rotated != token);
+        // the rotated token reaches the client well before the next rotation
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // the connection drops, the client resumes its session
+        let esid = SidGenerator::decode(&sid).unwrap();
+        server.engine_server.close_socket(&esid).await;
+        let start = Instant::now();
+        let mut resumed = vip();
+        while (resumed.is_empty() || resumed.contains(&sid))
+            && start.elapsed() < Duration::from_secs(3)
+        {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            resumed = vip();
+        }
+        assert_eq!(resumed.len(), 1);
+        assert!(!resumed.contains(&sid));
+        assert!(!server.resume_tokens.contains_key(&sid));
+        // the token is sent apart, the auth is left as the user set it
+        let auth = Some(json!({ "resume": "mine" }));
+        assert_eq!(*auths.lock().unwrap(), vec![auth.clone(), auth]);
+    }
+
+    #[tokio::test]
+    async fn test_websocket_only() {
+        let server = ServerBuilder::new(4262)
+            .transports([TransportKind::Websocket])
+            .build();
+        tokio::spawn(async move { server.serve().await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let connect = |transport_type| {
+            ClientBuilder::new("http://localhost:4262")
+                .transport_type(transport_type)
+                .reconnect(false)
+                .connect()
+        };
+        // the client skips the polling handshake the server refuses
+        let socket = connect(TransportType::Websocket).await.expect("success");
+        assert!(socket.emit("hello", json!({})).await.is_ok());
+        assert!(connect(TransportType::Polling).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_id_generator() {
+        #[derive(Default)]
+        struct SimIds(AtomicUsize);
+
+        impl IdGenerator for SimIds {
+            fn generate(&self) -> Sid {
+                Arc::new(format!("sim-{}", self.0.fetch_add(1, Ordering::SeqCst)))
+            }
+        }
+
+        let noop = |_: Option<Payload>, _: ServerClient, _: Option<AckId>| async {}.boxed();
+        let server = ServerBuilder::new(4263)
+            .id_generator(SimIds::default())
+            .on("/", Event::Connect, noop)
+            .build();
+        let server_clone = server.clone();
+        tokio::spawn(async move { server_clone.serve().await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let socket = ClientBuilder::new("http://localhost:4263")
+            .reconnect(false)
+            .connect()
+            .await
+            .expect("success");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(server.clients.contains_key(&Arc::new("sim-0".to_owned())));
+        let _ = socket.disconnect().await;
+    }
+
+    #[tokio::test]
+    async fn test_namespace_limits() {
+        let handled = Arc::new(AtomicUsize::default());
+        let handled_clone = handled.clone();
+        let count = move |_: Option<Payload>, _: ServerClient, _: Option<AckId>| {
+            handled_clone.fetch_add(1, Ordering::SeqCst);
+            async {}.boxed()
+        };
+        let is_done = Arc::new(AtomicBool::default());
+        let is_done_clone = is_done.clone();
+        let stuck = move |_: Option<Payload>, _: ServerClient, _: Option<AckId>| {
+            let is_done = is_done_clone.clone();
+            async move {
+                tokio::time::sleep(Duration::from_secs(10)).await;
+                is_done.store(true, Ordering::SeqCst);
+            }
+            .boxed()
+        };
+        let is_timeout = Arc::new(AtomicBool::default());
+        let is_timeout_clone = is_timeout.clone();
+        let error = move |payload: Option<Payload>, _: ServerClient, _: Option<AckId>| {
+            if let Some(Payload::Json(Value::String(message))) = payload {
+                if message.contains("stuck") {
+                    is_timeout_clone.store(true, Ordering::SeqCst);
+                }
+            }
+            async {}.boxed()
+        };
+        let kinds = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let kinds_clone = kinds.clone();
+        let server = ServerBuilder::new(4264)
+            .max_payload("/firehose", 64)
+            .rate_limit("/firehose", 3, Duration::from_secs(60))
+            .namespace_timeout("/firehose", Duration::from_millis(100))
+            .on("/firehose", "ev", count.clone())
+            .on("/firehose", "stuck", stuck)
+            .on("/firehose", Event::Error, error)
+            .on("/", "ev", count)
+            .on_protocol_error(move |error| kinds_clone.lock().unwrap().push(error.kind))
+            .build();
+        tokio::spawn(async move { server.serve().await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let firehose = ClientBuilder::new("http://localhost:4264")
+            .namespace("/firehose")
+            .connect()
+            .await
+            .expect("success");
+        let big = json!("x".repeat(100));
+        firehose.emit("ev", big).await.expect("success");
+        firehose.emit("stuck", json!(1)).await.expect("success");
+        for _ in 0..3 {
+            firehose.emit("ev", json!(1)).await.expect("success");
+        }
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(handled.load(Ordering::SeqCst), 2);
+        assert!(is_timeout.load(Ordering::SeqCst));
+        assert!(!is_done.load(Ordering::SeqCst));
+        assert_eq!(
+            *kinds.lock().unwrap(),
+            vec![
+                ProtocolErrorKind::PayloadTooLarge,
+                ProtocolErrorKind::RateLimited
+            ]
+        );
+
+        // the other namespaces keep the global limits
+        let root = ClientBuilder::new("http://localhost:4264")
+            .connect()
+            .await
+            .expect("success");
+        for _ in 0..5 {
+            root.emit("ev", json!("x".repeat(100)))
+                .await
+                .expect("success");
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(handled.load(Ordering::SeqCst), 7);
     }
 }
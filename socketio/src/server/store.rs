@@ -0,0 +1,179 @@
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use dashmap::DashMap;
+use serde_json::Value;
+
+use crate::error::Result;
+
+/// What a session entry belongs to.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum SessionKey {
+    Sid(String),
+    User(String),
+    /// A socket handed over to another server, see `Server::migrate`.
+    Migration(String),
+    /// A socket which lost its connection, see `ServerBuilder::resume_sessions`.
+    Resume(String),
+}
+
+impl SessionKey {
+    pub(crate) fn to_key(&self) -> String {
+        match self {
+            Self::Sid(sid) => format!("sid:{}", sid),
+            Self::User(user) => format!("user:{}", user),
+            Self::Migration(token) => format!("migration:{}", token),
+            Self::Resume(token) => format!("resume:{}", token),
+        }
+    }
+}
+
+/// Storage for state that outlives a single connection, shared by the server
+/// features which persist sessions.
+#[async_trait]
+pub trait SessionStore: Send + Sync {
+    async fn get(&self, key: &SessionKey) -> Result<Option<Value>>;
+
+    /// Stores `value` under `key`, dropping it after `ttl` if given.
+    async fn set(&self, key: &SessionKey, value: Value, ttl: Option<Duration>) -> Result<()>;
+
+    async fn delete(&self, key: &SessionKey) -> Result<()>;
+}
+
+/// Keeps sessions in the server process, they are lost on restart. Expired
+/// entries are dropped when read.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    entries: DashMap<String, (Value, Option<Instant>)>,
+}
+
+#[async_trait]
+impl SessionStore for MemoryStore {
+    async fn get(&self, key: &SessionKey) -> Result<Option<Value>> {
+        let key = key.to_key();
+        let now = Instant::now();
+        self.entries
+            .remove_if(&key, |_, (_, expires)| expires.is_some_and(|e| e <= now));
+        Ok(self.entries.get(&key).map(|entry| entry.0.clone()))
+    }
+
+    async fn set(&self, key: &SessionKey, value: Value, ttl: Option<Duration>) -> Result<()> {
+        let expires = ttl.map(|ttl| Instant::now() + ttl);
+        self.entries.insert(key.to_key(), (value, expires));
+        Ok(())
+    }
+
+    async fn delete(&self, key: &SessionKey) -> Result<()> {
+        self.entries.remove(&key.to_key());
+        Ok(())
+    }
+}
+
+#[cfg(feature = "session-redis")]
+pub use self::redis_store::RedisStore;
+
+#[cfg(feature = "session-redis")]
+mod redis_store {
+    use std::time::Duration;
+
+    use async_trait::async_trait;
+    use redis::{aio::ConnectionManager, AsyncCommands};
+    use serde_json::Value;
+
+    use super::{SessionKey, SessionStore};
+    use crate::{error::Result, Error};
+
+    /// Keeps sessions in Redis as JSON strings, so they survive restarts and are
+    /// shared between server instances.
+    #[derive(Clone)]
+    pub struct RedisStore {
+        conn: ConnectionManager,
+        prefix: String,
+    }
+
+    impl RedisStore {
+        pub async fn new(client: redis::Client) -> Result<Self> {
+            let conn = ConnectionManager::new(client).await.map_err(store_error)?;
+            Ok(Self {
+                conn,
+                prefix: "socketio:session:".to_owned(),
+            })
+        }
+
+        /// Prefix of all keys written, `socketio:session:` by default.
+        pub fn prefix<T: Into<String>>(mut self, prefix: T) -> Self {
+            self.prefix = prefix.into();
+            self
+        }
+
+        fn key(&self, key: &SessionKey) -> String {
+            format!("{}{}", self.prefix, key.to_key())
+        }
+    }
+
+    fn store_error(e: redis::RedisError) -> Error {
+        Error::IncompleteSessionStore(e.to_string())
+    }
+
+    #[async_trait]
+    impl SessionStore for RedisStore {
+        async fn get(&self, key: &SessionKey) -> Result<Option<Value>> {
+            let mut conn = self.conn.clone();
+            let value: Option<String> = conn.get(self.key(key)).await.map_err(store_error)?;
+            match value {
+                Some(value) => Ok(Some(serde_json::from_str(&value)?)),
+                None => Ok(None),
+            }
+        }
+
+        async fn set(&self, key: &SessionKey, value: Value, ttl: Option<Duration>) -> Result<()> {
+            let mut conn = self.conn.clone();
+            let value = serde_json::to_string(&value)?;
+            match ttl {
+                Some(ttl) => conn
+                    .pset_ex::<_, _, ()>(self.key(key), value, ttl.as_millis() as u64)
+                    .await
+                    .map_err(store_error),
+                None => conn
+                    .set::<_, _, ()>(self.key(key), value)
+                    .await
+                    .map_err(store_error),
+            }
+        }
+
+        async fn delete(&self, key: &SessionKey) -> Result<()> {
+            let mut conn = self.conn.clone();
+            conn.del::<_, ()>(self.key(key)).await.map_err(store_error)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_memory_store() -> Result<()> {
+        let store = MemoryStore::default();
+        let sid = SessionKey::Sid("abc".to_owned());
+        let user = SessionKey::User("abc".to_owned());
+
+        store.set(&sid, json!({"rooms": ["a"]}), None).await?;
+        assert_eq!(store.get(&sid).await?, Some(json!({"rooms": ["a"]})));
+        // sids and user ids do not collide
+        assert_eq!(store.get(&user).await?, None);
+
+        store.delete(&sid).await?;
+        assert_eq!(store.get(&sid).await?, None);
+
+        store
+            .set(&user, json!(1), Some(Duration::from_millis(50)))
+            .await?;
+        assert_eq!(store.get(&user).await?, Some(json!(1)));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(store.get(&user).await?, None);
+        Ok(())
+    }
+}
@@ -1,34 +1,48 @@
 use std::{
+    any::Any,
+    collections::{hash_map::Entry, HashMap, HashSet},
     fmt::Debug,
     ops::DerefMut,
+    panic::AssertUnwindSafe,
     pin::Pin,
     sync::{
-        atomic::{AtomicBool, Ordering},
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
         Arc,
     },
     time::Duration,
 };
 
 use crate::{
-    ack::Ack,
-    callback::Callback,
+    ack::{Ack, AckContext, AckError, AckGuard, AckPolicy},
+    callback::{Callback, HandlerConfig, HandlerOverflow, PanicPolicy},
+    cipher::PayloadCipher,
+    compression::{self, Compression},
+    dispatcher::{spawn, DispatchLimit, Dispatcher},
     error::Result,
-    packet::{AckIdGenerator, Packet, PacketType},
-    payload::RawPayload,
-    AckId, Error, Event, Payload,
+    memory::{Encoder, MemoryUsage},
+    packet::{self, AckIdGenerator, Packet, PacketType},
+    payload::{Nested, RawPayload},
+    AckId, DisconnectReason, Error, Event, Payload,
 };
 
+#[cfg(feature = "client")]
+use crate::client::metrics::Counters;
+
 use async_stream::try_stream;
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
 use dashmap::DashMap;
 use engineio_rs::{
     Packet as EnginePacket, PacketType as EnginePacketType, Socket as EngineSocket, StreamGenerator,
 };
-use futures_util::{future::BoxFuture, Stream, StreamExt};
+use futures_util::{
+    future::{select, BoxFuture, Either},
+    FutureExt, Stream, StreamExt,
+};
 use serde::Serialize;
 use serde_json::{json, Value};
 use tokio::{
-    sync::{Mutex, RwLock},
+    runtime::Handle,
+    sync::{oneshot, Mutex, RwLock, Semaphore},
     time::Instant,
 };
 use tracing::error;
@@ -46,16 +60,126 @@ pub struct Socket<C> {
     socket: RawSocket,
     on: Arc<DashMap<Event, Callback<C>>>,
     outstanding_acks: Arc<RwLock<Vec<Ack<C>>>>,
+    // the summed `size` of `outstanding_acks`
+    ack_bytes: Arc<AtomicUsize>,
+    // payloads kept to restore the connection, see `MemoryUsage::recovery`
+    recovery_bytes: Arc<AtomicUsize>,
     is_connected: Arc<AtomicBool>,
+    // whether the `Disconnect` handlers fired since the last connect
+    disconnected: Arc<AtomicBool>,
     callback_client_fn: Arc<dyn Fn(Self) -> C + Send + Sync>,
     ack_id_gen: Arc<AckIdGenerator>,
+    // round trip of the last answered ack in milliseconds, `u64::MAX` before any
+    last_rtt: Arc<AtomicU64>,
+    // the event being dispatched, set on the socket passed to a callback
+    event: Option<Event>,
+    // the broadcast answered, set on the socket passed to an ack callback
+    ack_context: Option<Arc<AckContext>>,
+    handlers: Arc<HashMap<Event, HandlerState>>,
+    panic_policy: PanicPolicy,
+    dispatcher: Option<Arc<Dispatcher>>,
+    // bounds the handler invocations of the namespace
+    dispatch_limit: Option<Arc<DispatchLimit>>,
+    // the timeout of the handlers without one of their own
+    namespace_timeout: Option<Duration>,
+    // bytes the socket may hold before it is disconnected, see `memory_usage`
+    memory_cap: Option<usize>,
+    // where callbacks are spawned, the current runtime if `None`
+    runtime: Option<Handle>,
+    // identifies the socket to keep its events on one dispatcher worker
+    id: usize,
+    // events whose emits are collapsed to the latest payload while queued
+    coalesce: Arc<HashSet<Event>>,
+    // the latest payload of a coalesced event waiting for its emit in flight
+    coalesced: Arc<parking_lot::Mutex<Coalesced>>,
+    // events whose latest payload is kept for handlers registered later
+    sticky: Arc<HashSet<Event>>,
+    sticky_payloads: Arc<DashMap<Event, Option<Payload>>>,
+    // whether the peer may send the control events, only a server may
+    control_events: bool,
+    // the CONNECT data sent to the client, repeated on a duplicate CONNECT
+    #[cfg(feature = "server")]
+    handshake: Arc<RwLock<Option<Value>>>,
+}
+
+static SOCKET_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Header of the token a migrated client presents when it connects, kept
+/// apart from the auth of its `Connect` packet.
+pub(crate) const MIGRATION_TOKEN: &str = "x-socketio-migration";
+/// Header of the token a client resuming its session presents when it
+/// connects.
+pub(crate) const RESUME_TOKEN: &str = "x-socketio-resume";
+
+/// The latest payload of each coalesced event with an emit in flight, and
+/// where to report the outcome of sending it.
+type Coalesced = HashMap<Event, Option<(Payload, oneshot::Sender<Result<()>>)>>;
+
+/// The emit in flight of a coalesced event. Hands out the payloads waiting
+/// behind it, and clears the event if it is cancelled, so later emits of it
+/// do not wait on an emit which never finishes.
+struct InFlight<'a> {
+    coalesced: &'a parking_lot::Mutex<Coalesced>,
+    event: &'a Event,
+    done: bool,
+}
+
+impl InFlight<'_> {
+    fn next(&mut self) -> Option<(Payload, oneshot::Sender<Result<()>>)> {
+        let mut coalesced = self.coalesced.lock();
+        let next = coalesced.get_mut(self.event).and_then(Option::take);
+        if next.is_none() {
+            coalesced.remove(self.event);
+            self.done = true;
+        }
+        next
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.coalesced.lock().remove(self.event);
+        }
+    }
+}
+
+struct HandlerState {
+    limit: Option<(Arc<Semaphore>, HandlerOverflow)>,
+    timeout: Option<Duration>,
 }
 
 #[derive(Clone)]
 pub(crate) struct RawSocket {
     engine_client: Arc<EngineSocket>,
-    generator: Arc<Mutex<StreamGenerator<Packet, Error>>>,
+    generator: Arc<Mutex<StreamGenerator<Received, Error>>>,
+    cipher: Option<Arc<dyn PayloadCipher>>,
+    compression: Option<Arc<Compression>>,
+    encoder: Option<Arc<Encoder>>,
     is_server: bool,
+    #[cfg(feature = "client")]
+    counters: Option<Arc<Counters>>,
+    #[cfg(feature = "client")]
+    max_payload: Option<usize>,
+}
+
+/// What the socket.io layer receives from engine.io.
+#[derive(Debug)]
+pub(crate) enum Received {
+    Packet(Packet),
+    /// A heartbeat of the server, answered by engine.io.
+    Ping,
+    /// The answer of the client to a heartbeat.
+    Pong,
+}
+
+/// How an ack awaited by `send_with_ack` expires and is settled.
+struct AckOptions {
+    timeout: Duration,
+    policy: AckPolicy,
+    // told why the ack failed, see `AckPolicy::Fail`
+    failed: Option<oneshot::Sender<AckError>>,
+    context: Option<Arc<AckContext>>,
 }
 
 #[derive(Serialize)]
@@ -71,6 +195,66 @@ impl BinaryPlaceHolder {
             num,
         }
     }
+
+    /// Returns the attachment index if `value` is a placeholder, validated the
+    /// same way as the reference parser does.
+    fn index(value: &Value, attachment_count: usize) -> Result<Option<usize>> {
+        if value.get("_placeholder") != Some(&Value::Bool(true)) {
+            return Ok(None);
+        }
+        match value.get("num").and_then(Value::as_u64) {
+            Some(num) if (num as usize) < attachment_count => Ok(Some(num as usize)),
+            _ => Err(Error::InvalidPacket()),
+        }
+    }
+
+    /// Replaces the placeholders nested in `value` by their attachments, `None`
+    /// if there are none.
+    fn replace(value: &Value, attachments: &[Bytes]) -> Result<Option<Nested>> {
+        if let Some(num) = Self::index(value, attachments.len())? {
+            return Ok(Some(Nested::Binary(attachments[num].clone())));
+        }
+        let nested = match value {
+            Value::Array(values) => {
+                let replaced = values
+                    .iter()
+                    .map(|value| Self::replace(value, attachments))
+                    .collect::<Result<Vec<_>>>()?;
+                if replaced.iter().all(Option::is_none) {
+                    return Ok(None);
+                }
+                Nested::Array(
+                    values
+                        .iter()
+                        .zip(replaced)
+                        .map(|(value, nested)| {
+                            nested.unwrap_or_else(|| Nested::Json(value.clone()))
+                        })
+                        .collect(),
+                )
+            }
+            Value::Object(map) => {
+                let replaced = map
+                    .values()
+                    .map(|value| Self::replace(value, attachments))
+                    .collect::<Result<Vec<_>>>()?;
+                if replaced.iter().all(Option::is_none) {
+                    return Ok(None);
+                }
+                Nested::Object(
+                    map.iter()
+                        .zip(replaced)
+                        .map(|((key, value), nested)| {
+                            let nested = nested.unwrap_or_else(|| Nested::Json(value.clone()));
+                            (key.clone(), nested)
+                        })
+                        .collect(),
+                )
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(nested))
+    }
 }
 
 impl<C: Clone + Send + 'static> Socket<C> {
@@ -83,27 +267,137 @@ impl<C: Clone + Send + 'static> Socket<C> {
         namespace: T,
         on: Arc<DashMap<Event, Callback<C>>>,
         callback_client_fn: Arc<dyn Fn(Self) -> C + Send + Sync>,
+        handlers: &HashMap<Event, HandlerConfig>,
     ) -> Self {
+        let handlers = handlers
+            .iter()
+            .map(|(event, config)| {
+                let state = HandlerState {
+                    limit: config
+                        .limit
+                        .map(|(max, overflow)| (Arc::new(Semaphore::new(max)), overflow)),
+                    timeout: config.timeout,
+                };
+                (event.to_owned(), state)
+            })
+            .collect();
         Socket {
             socket,
             nsp: namespace.into(),
             on,
             outstanding_acks: Arc::new(RwLock::new(Vec::new())),
+            ack_bytes: Default::default(),
+            recovery_bytes: Default::default(),
             is_connected: Arc::new(AtomicBool::new(true)),
+            disconnected: Default::default(),
             callback_client_fn,
             ack_id_gen: Default::default(),
+            last_rtt: Arc::new(AtomicU64::new(u64::MAX)),
+            event: None,
+            ack_context: None,
+            handlers: Arc::new(handlers),
+            panic_policy: Default::default(),
+            dispatcher: None,
+            dispatch_limit: None,
+            namespace_timeout: None,
+            memory_cap: None,
+            runtime: None,
+            id: SOCKET_ID.fetch_add(1, Ordering::Relaxed),
+            coalesce: Default::default(),
+            coalesced: Default::default(),
+            sticky: Default::default(),
+            sticky_payloads: Default::default(),
+            control_events: false,
+            #[cfg(feature = "server")]
+            handshake: Default::default(),
         }
     }
 
+    pub(crate) fn with_dispatcher(mut self, dispatcher: Option<Arc<Dispatcher>>) -> Self {
+        self.dispatcher = dispatcher;
+        self
+    }
+
+    pub(crate) fn is_connected(&self) -> bool {
+        self.is_connected.load(Ordering::Acquire)
+    }
+
+    #[cfg(feature = "server")]
+    pub(crate) fn with_dispatch_limit(mut self, limit: Option<Arc<DispatchLimit>>) -> Self {
+        self.dispatch_limit = limit;
+        self
+    }
+
+    #[cfg(feature = "server")]
+    pub(crate) fn with_namespace_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.namespace_timeout = timeout;
+        self
+    }
+
+    #[cfg(feature = "server")]
+    pub(crate) fn with_memory_cap(mut self, memory_cap: Option<usize>) -> Self {
+        self.memory_cap = memory_cap;
+        self
+    }
+
+    #[cfg(feature = "server")]
+    pub(crate) fn with_runtime(mut self, runtime: Option<Handle>) -> Self {
+        self.runtime = runtime;
+        self
+    }
+
+    pub(crate) fn with_coalesce(mut self, coalesce: HashSet<Event>) -> Self {
+        self.coalesce = Arc::new(coalesce);
+        self
+    }
+
+    /// Keeps the latest payload of the `sticky` events in `payloads`.
+    #[cfg(feature = "client")]
+    /// Shares the count of the payloads kept to restore the connection, e.g.
+    /// the subscriptions of a client.
+    #[cfg(feature = "client")]
+    pub(crate) fn with_recovery_bytes(mut self, recovery_bytes: Arc<AtomicUsize>) -> Self {
+        self.recovery_bytes = recovery_bytes;
+        self
+    }
+
+    pub(crate) fn with_sticky(
+        mut self,
+        sticky: HashSet<Event>,
+        payloads: Arc<DashMap<Event, Option<Payload>>>,
+    ) -> Self {
+        self.sticky = Arc::new(sticky);
+        self.sticky_payloads = payloads;
+        self
+    }
+
+    /// Accepts the control events the server sends, like `Event::Migrate`,
+    /// from the peer.
+    #[cfg(feature = "client")]
+    pub(crate) fn with_control_events(mut self) -> Self {
+        self.control_events = true;
+        self
+    }
+
+    pub(crate) fn with_panic_policy(mut self, panic_policy: PanicPolicy) -> Self {
+        self.panic_policy = panic_policy;
+        self
+    }
+
     /// Connects the client to a server. Afterwards the `emit_*` methods can be
     /// called to interact with the server.
     #[cfg(feature = "client")]
-    pub(crate) async fn connect(&self) -> Result<()> {
+    pub(crate) async fn connect(&self, auth: Option<Value>) -> Result<()> {
         // Connect the underlying socket
         self.socket.connect().await?;
+        self.send_connect(auth).await
+    }
 
-        // construct the opening packet
-        let open_packet = Packet::new(PacketType::Connect, self.nsp.clone(), None, None, 0, None);
+    /// Sends the opening packet of the namespace, with `auth` as its data.
+    #[cfg(feature = "client")]
+    pub(crate) async fn send_connect(&self, auth: Option<Value>) -> Result<()> {
+        self.socket.negotiate_compression();
+        let open_packet = Packet::new(PacketType::Connect, self.nsp.clone(), auth, None, 0, None);
 
         self.socket.send(open_packet).await?;
 
@@ -111,10 +405,11 @@ impl<C: Clone + Send + 'static> Socket<C> {
     }
 
     /// Sends a message to the server using the underlying `engine.io` protocol.
-    /// This message takes an event, which could either be one of the common
-    /// events like "message" or "error" or a custom event like "foo". But be
-    /// careful, the data string needs to be valid JSON. It's recommended to use
-    /// a library like `serde_json` to serialize the data properly.
+    /// This message takes an event, which could either be "message" or a custom
+    /// event like "foo". Events the library sends or fires itself, like "error"
+    /// or "migrate", fail with `Error::InvalidReservedEvent`. But be careful,
+    /// the data string needs to be valid JSON. It's recommended to use a library
+    /// like `serde_json` to serialize the data properly.
     ///
     /// # Example
     /// ```no_run
@@ -151,7 +446,163 @@ impl<C: Clone + Send + 'static> Socket<C> {
         if !self.is_connected.load(Ordering::Acquire) {
             return Err(Error::IllegalActionBeforeOpen());
         }
-        self.socket.emit(&self.nsp, event.into(), data.into()).await
+        let event = event.into();
+        event.check_emit()?;
+        if self.coalesce.contains(&event) {
+            self.emit_coalesced(event, data.into()).await?;
+        } else {
+            self.socket.emit(&self.nsp, event, data.into()).await?;
+        }
+        self.check_memory().await
+    }
+
+    /// Emits `json` followed by `attachments` as one event, like
+    /// `socket.emit(event, meta, blob1, blob2)` in JS: the peer's handler gets
+    /// them as separate arguments. Fails with `Error::InvalidAttachmentCount`
+    /// for more than 255 attachments.
+    pub async fn emit_with_binary<E, J>(
+        &self,
+        event: E,
+        json: J,
+        attachments: Vec<Bytes>,
+    ) -> Result<()>
+    where
+        E: Into<Event>,
+        J: Into<Value>,
+    {
+        let mut payloads = vec![RawPayload::Json(json.into())];
+        payloads.extend(attachments.into_iter().map(RawPayload::Binary));
+        self.emit(event, Payload::Multi(payloads)).await
+    }
+
+    /// Emits `data` without converting it to a [`Payload`] first, so it is
+    /// serialized once, right into the outgoing packet. Saves allocations for
+    /// events emitted at a high rate, e.g. on every tick of a game loop. `data`
+    /// is always sent as JSON, binary data needs [`Socket::emit`].
+    pub async fn emit_ref<E, T>(&self, event: E, data: &T) -> Result<()>
+    where
+        E: Into<Event>,
+        T: Serialize + ?Sized,
+    {
+        if !self.is_connected.load(Ordering::Acquire) {
+            return Err(Error::IllegalActionBeforeOpen());
+        }
+        let event = event.into();
+        event.check_emit()?;
+        if self.coalesce.contains(&event) {
+            let data = serde_json::to_value(data)?;
+            self.emit_coalesced(event, Payload::Json(data)).await?;
+        } else {
+            self.socket.emit_ref(&self.nsp, &event, data).await?;
+        }
+        self.check_memory().await
+    }
+
+    /// Emits `events` in order in one write of the transport: one request
+    /// over polling, frames flushed together over websocket. Events are sent
+    /// as given, even those set to be coalesced.
+    pub async fn emit_batch(&self, events: Vec<(Event, Payload)>) -> Result<()> {
+        if !self.is_connected.load(Ordering::Acquire) {
+            return Err(Error::IllegalActionBeforeOpen());
+        }
+        let packets = events
+            .into_iter()
+            .map(|(event, data)| {
+                event.check_emit()?;
+                RawSocket::build_packet_for_payload(data, Some(event), &self.nsp, None, false)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        self.socket.send_batch(packets).await?;
+        self.check_memory().await
+    }
+
+    /// Emits `event` even if it is reserved, for the control events the server
+    /// sends itself, like `Event::Migrate`.
+    #[cfg(feature = "server")]
+    pub(crate) async fn emit_reserved(&self, event: Event, data: Payload) -> Result<()> {
+        if !self.is_connected.load(Ordering::Acquire) {
+            return Err(Error::IllegalActionBeforeOpen());
+        }
+        self.socket.emit(&self.nsp, event, data).await
+    }
+
+    /// The approximate memory held for this socket: the packets buffered for a
+    /// polling client, the emits awaiting their ack and the payloads kept to
+    /// restore the connection. Payloads handed to handlers and the socket
+    /// itself are not counted.
+    pub async fn memory_usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            queued: self.socket.queued_bytes().await,
+            acks: self.ack_bytes.load(Ordering::Relaxed),
+            recovery: self.recovery_bytes.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Disconnects the socket once it holds more than its memory cap.
+    async fn check_memory(&self) -> Result<()> {
+        let cap = match self.memory_cap {
+            Some(cap) => cap,
+            None => return Ok(()),
+        };
+        let usage = self.memory_usage().await.total();
+        if usage <= cap {
+            return Ok(());
+        }
+        warn!(
+            "socket of {} holds {} bytes, above its cap of {}",
+            self.nsp, usage, cap
+        );
+        self.callback(
+            &Event::Close,
+            Some(json!("memory cap exceeded").into()),
+            None,
+        )
+        .await;
+        let reason = match self.socket.is_server {
+            true => DisconnectReason::ServerDisconnect,
+            false => DisconnectReason::ClientDisconnect,
+        };
+        self.disconnect_callback(reason).await;
+        let _ = self.disconnect().await;
+        Err(Error::ExceededMemoryCap(usage))
+    }
+
+    /// Emits `data` unless an emit of `event` is in flight, in which case `data`
+    /// replaces the payload waiting for it. The emit in flight sends the latest
+    /// waiting payload once done, so only the newest state reaches the peer.
+    /// Resolves once `data` was sent, or fails with `Error::SupersededEmit` if
+    /// a newer payload replaced it.
+    async fn emit_coalesced(&self, event: Event, data: Payload) -> Result<()> {
+        let waiting = match self.coalesced.lock().entry(event.clone()) {
+            Entry::Occupied(mut entry) => {
+                trace!("coalesced emit of {:?}", event);
+                let (tx, rx) = oneshot::channel();
+                if let Some((_, replaced)) = entry.get_mut().replace((data, tx)) {
+                    let _ = replaced.send(Err(Error::SupersededEmit()));
+                }
+                Err(rx)
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(None);
+                Ok(data)
+            }
+        };
+        let data = match waiting {
+            Ok(data) => data,
+            // the emit in flight was cancelled before sending `data`
+            Err(rx) => return rx.await.unwrap_or(Err(Error::SupersededEmit())),
+        };
+
+        let mut in_flight = InFlight {
+            coalesced: &self.coalesced,
+            event: &event,
+            done: false,
+        };
+        let result = self.socket.emit(&self.nsp, event.clone(), data).await;
+        while let Some((data, owner)) = in_flight.next() {
+            let _ = owner.send(self.socket.emit(&self.nsp, event.clone(), data).await);
+        }
+        result
     }
 
     #[inline]
@@ -165,15 +616,45 @@ impl<C: Clone + Send + 'static> Socket<C> {
         self.socket.ack(&self.nsp, id, data.into()).await
     }
 
+    /// Acks with `value` serialized to JSON.
+    pub async fn ack_with<T: Serialize>(&self, id: AckId, value: &T) -> Result<()> {
+        self.ack(id, serde_json::to_value(value)?).await
+    }
+
+    /// Wraps an ack id received by a handler in a guard which reports a missing
+    /// response when dropped.
+    pub fn ack_guard(&self, id: AckId) -> AckGuard {
+        AckGuard::new(
+            self.socket.clone(),
+            self.nsp.clone(),
+            self.is_connected.clone(),
+            id,
+        )
+    }
+
     #[cfg(feature = "server")]
     #[inline]
     pub(crate) async fn handshake(&self, data: Value) -> Result<()> {
         if !self.is_connected.load(Ordering::Acquire) {
             return Err(Error::IllegalActionBeforeOpen());
         }
+        *self.handshake.write().await = Some(data.clone());
         self.socket.handshake(&self.nsp, data).await
     }
 
+    /// Answers a CONNECT for the already connected namespace, which clients may
+    /// re-send after a flaky upgrade, with the original handshake instead of
+    /// connecting again.
+    #[cfg(feature = "server")]
+    async fn handle_duplicate_connect(&self) -> Result<()> {
+        let data = self.handshake.read().await.clone();
+        trace!("duplicate connect, handshake {:?}", data);
+        match data {
+            Some(data) => self.socket.handshake(&self.nsp, data).await,
+            None => Ok(()),
+        }
+    }
+
     /// Disconnects this client from the server by sending a `socket.io` closing
     /// packet.
     /// # Example
@@ -282,42 +763,450 @@ impl<C: Clone + Send + 'static> Socket<C> {
         E: Into<Event>,
         D: Into<Payload>,
     {
+        self.emit_with_ack_policy(event, data, timeout, AckPolicy::default(), callback)
+            .await
+    }
+
+    /// Like `emit_with_ack`, `policy` decides what happens to the ack if the
+    /// client reconnects before it arrived.
+    pub async fn emit_with_ack_policy<F, E, D>(
+        &self,
+        event: E,
+        data: D,
+        timeout: Duration,
+        policy: AckPolicy,
+        callback: F,
+    ) -> Result<()>
+    where
+        F: for<'a> std::ops::FnMut(Option<Payload>, C, Option<AckId>) -> BoxFuture<'static, ()>
+            + 'static
+            + Send
+            + Sync,
+        E: Into<Event>,
+        D: Into<Payload>,
+    {
+        let callback = Callback::new(callback);
+        let options = AckOptions {
+            timeout,
+            policy,
+            failed: None,
+            context: None,
+        };
+        self.send_with_ack(event.into(), data.into(), callback, options)
+            .await
+    }
+
+    /// Like `emit_with_ack`, the socket passed to `callback` reports `context`.
+    #[cfg(feature = "server")]
+    pub(crate) async fn emit_with_ack_context<F>(
+        &self,
+        data: Payload,
+        timeout: Duration,
+        context: Arc<AckContext>,
+        callback: F,
+    ) -> Result<()>
+    where
+        F: for<'a> std::ops::FnMut(Option<Payload>, C, Option<AckId>) -> BoxFuture<'static, ()>
+            + 'static
+            + Send
+            + Sync,
+    {
+        let event = context.event.clone();
+        let callback = Callback::new(callback);
+        let options = AckOptions {
+            timeout,
+            policy: AckPolicy::default(),
+            failed: None,
+            context: Some(context),
+        };
+        self.send_with_ack(event, data, callback, options).await
+    }
+
+    async fn send_with_ack(
+        &self,
+        event: Event,
+        data: Payload,
+        callback: Callback<C>,
+        options: AckOptions,
+    ) -> Result<()> {
+        let AckOptions {
+            timeout,
+            policy,
+            failed,
+            context,
+        } = options;
         if !self.is_connected.load(Ordering::Acquire) {
             return Err(Error::IllegalActionBeforeOpen());
         }
-        let id = self.ack_id_gen.generate();
-        let packet = RawSocket::build_packet_for_payload(
-            data.into(),
-            Some(event.into()),
-            &self.nsp,
-            Some(id),
-            false,
-        )?;
+        event.check_emit()?;
+        let mut acks = self.outstanding_acks.write().await;
+        // acks past their timeout are never called, their ids are free again
+        acks.retain(|ack| {
+            let live = ack.time_started.elapsed() < ack.timeout;
+            if !live {
+                self.ack_bytes.fetch_sub(ack.size, Ordering::Relaxed);
+            }
+            live
+        });
+        let id = self.next_ack_id(&acks);
+        let packet =
+            RawSocket::build_packet_for_payload(data, Some(event), &self.nsp, Some(id), false)?;
+        let resend = (policy == AckPolicy::Resend).then(|| packet.clone());
+        let size = std::mem::size_of::<Ack<C>>()
+            + resend
+                .as_ref()
+                .map_or(0, |packet| Bytes::from(packet).len());
 
         let ack = Ack {
             id,
             time_started: Instant::now(),
             timeout,
-            callback: Callback::new(callback),
+            callback,
+            policy,
+            packet: resend,
+            failed,
+            context,
+            size,
         };
 
         // add the ack to the tuple of outstanding acks
-        self.outstanding_acks.write().await.push(ack);
+        self.ack_bytes.fetch_add(size, Ordering::Relaxed);
+        acks.push(ack);
+        drop(acks);
 
         trace!("socket emit_with_ack {:?}", packet);
-        self.socket.send(packet).await
+        self.socket.send(packet).await?;
+        self.check_memory().await
     }
 
-    async fn callback(&self, event: &Event, payload: Option<Payload>, need_ack: Option<AckId>) {
-        let self_clone = self.clone();
+    /// Emits `event` and waits for the peer to ack it, returning the ack data or
+    /// `Error::TimedOutAck` after `timeout`. An ack without data is returned as
+    /// JSON `null`.
+    pub async fn request<E, D>(&self, event: E, data: D, timeout: Duration) -> Result<Payload>
+    where
+        E: Into<Event>,
+        D: Into<Payload>,
+    {
+        self.request_with_policy(event, data, timeout, AckPolicy::default())
+            .await
+    }
+
+    /// Like `request`, an ack failed through `policy` returns `Error::FailedAck`.
+    pub async fn request_with_policy<E, D>(
+        &self,
+        event: E,
+        data: D,
+        timeout: Duration,
+        policy: AckPolicy,
+    ) -> Result<Payload>
+    where
+        E: Into<Event>,
+        D: Into<Payload>,
+    {
+        let (tx, rx) = oneshot::channel();
+        let tx = Arc::new(std::sync::Mutex::new(Some(tx)));
+        let callback = move |payload: Option<Payload>, _: C, _: Option<AckId>| {
+            let tx = tx.lock().ok().and_then(|mut tx| tx.take());
+            async move {
+                if let Some(tx) = tx {
+                    let _ = tx.send(payload.unwrap_or(Payload::Json(Value::Null)));
+                }
+            }
+            .boxed()
+        };
+        let (failed_tx, failed) = oneshot::channel();
+        let callback = Callback::new(callback);
+        let options = AckOptions {
+            timeout,
+            policy,
+            failed: Some(failed_tx),
+            context: None,
+        };
+        self.send_with_ack(event.into(), data.into(), callback, options)
+            .await?;
+
+        // the ack expired or the socket was dropped if neither sender is used
+        let timed_out = |_| Error::TimedOutAck(timeout);
+        let answer = async {
+            match select(failed, rx).await {
+                Either::Left((Ok(err), _)) => Err(Error::FailedAck(err)),
+                Either::Left((Err(_), rx)) => rx.await.map_err(timed_out),
+                Either::Right((payload, _)) => payload.map_err(timed_out),
+            }
+        };
+        match tokio::time::timeout(timeout, answer).await {
+            Ok(answer) => answer,
+            Err(_) => Err(Error::TimedOutAck(timeout)),
+        }
+    }
+
+    /// Removes the acks still waiting for a response when the connection is
+    /// lost. Those with `AckPolicy::Fail` fail right away, the others are
+    /// returned to be resent.
+    #[cfg(feature = "client")]
+    pub(crate) async fn take_acks(&self) -> Vec<Ack<C>> {
+        let acks = {
+            let mut acks = self.outstanding_acks.write().await;
+            self.ack_bytes.store(0, Ordering::Relaxed);
+            std::mem::take(&mut *acks)
+        };
+        let mut resend = Vec::new();
+        for ack in acks {
+            if ack.time_started.elapsed() >= ack.timeout {
+                continue;
+            }
+            match ack.policy {
+                AckPolicy::Fail => self.fail_ack(ack, AckError::Disconnected),
+                AckPolicy::Resend => resend.push(ack),
+            }
+        }
+        resend
+    }
+
+    #[cfg(feature = "client")]
+    pub(crate) fn fail_ack(&self, mut ack: Ack<C>, err: AckError) {
+        match ack.failed.take() {
+            Some(failed) => {
+                let _ = failed.send(err);
+            }
+            None => self.callback_error(format!("ack {} failed: {}", ack.id, err)),
+        }
+    }
+
+    /// The number of emits waiting for their ack.
+    #[cfg(feature = "client")]
+    pub(crate) async fn outstanding_ack_count(&self) -> usize {
+        self.outstanding_acks.read().await.len()
+    }
+
+    /// The transport of the connection, `None` while disconnected.
+    #[cfg(feature = "client")]
+    pub(crate) fn transport(&self) -> Option<&'static str> {
+        self.socket.transport()
+    }
+
+    /// A fresh ack id, after a wraparound ids still waiting for a response are
+    /// skipped.
+    fn next_ack_id(&self, acks: &[Ack<C>]) -> AckId {
+        loop {
+            let id = self.ack_id_gen.generate();
+            if acks.iter().all(|ack| ack.id != id) {
+                return id;
+            }
+        }
+    }
+
+    /// Emits the events of `acks` again and waits for their acks on this socket.
+    /// The ids were given out by the previous socket, so each gets a new one.
+    #[cfg(feature = "client")]
+    pub(crate) async fn resend_acks(&self, acks: Vec<Ack<C>>) {
+        for mut ack in acks {
+            let mut outstanding = self.outstanding_acks.write().await;
+            ack.id = self.next_ack_id(&outstanding);
+            if let Some(packet) = ack.packet.as_mut() {
+                packet.id = Some(ack.id);
+            }
+            let packet = ack.packet.clone();
+            self.ack_bytes.fetch_add(ack.size, Ordering::Relaxed);
+            outstanding.push(ack);
+            drop(outstanding);
+            if let Some(packet) = packet {
+                trace!("socket resend {:?}", packet);
+                if let Err(e) = self.socket.send(packet).await {
+                    warn!("resend of ack failed: {}", e);
+                }
+            }
+        }
+    }
+
+    /// How long the peer took to answer the last acked emit.
+    pub fn rtt(&self) -> Option<Duration> {
+        match self.last_rtt.load(Ordering::Relaxed) {
+            u64::MAX => None,
+            millis => Some(Duration::from_millis(millis)),
+        }
+    }
+
+    /// The concrete event which triggered the running callback, useful for
+    /// handlers registered on wildcard patterns like `chat:*`.
+    pub fn event(&self) -> Option<&Event> {
+        self.event.as_ref()
+    }
+
+    /// The broadcast answered by the running ack callback, for acks of
+    /// `Server::emit_to_with_ack`.
+    pub fn ack_context(&self) -> Option<&AckContext> {
+        self.ack_context.as_deref()
+    }
+
+    /// Returns the key of the handler for `event`. If none is registered for the
+    /// event itself, the longest matching wildcard pattern is used.
+    fn handler_key(&self, event: &Event) -> Option<Event> {
+        if self.on.contains_key(event) {
+            return Some(event.to_owned());
+        }
+        self.on
+            .iter()
+            .map(|entry| entry.key().to_owned())
+            .filter(|key| key.is_pattern() && key.matches(event))
+            .max_by_key(|key| String::from(key.to_owned()).len())
+    }
+
+    /// Fires the `Disconnect` handlers with `reason`, once per connection.
+    pub(crate) async fn disconnect_callback(&self, reason: DisconnectReason) {
+        if self.disconnected.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        self.callback(&Event::Disconnect, Some(reason.into()), None)
+            .await;
+    }
+
+    /// Whether a handler, or a pattern handler, is registered for `event`.
+    #[cfg(feature = "server")]
+    pub(crate) fn has_handler(&self, event: &Event) -> bool {
+        self.handler_key(event).is_some()
+    }
+
+    pub(crate) async fn callback(
+        &self,
+        event: &Event,
+        payload: Option<Payload>,
+        need_ack: Option<AckId>,
+    ) {
+        if self.sticky.contains(event) {
+            self.sticky_payloads
+                .insert(event.to_owned(), payload.clone());
+        }
+        // only application events are limited, lifecycle handlers always run
+        let admission = match &self.dispatch_limit {
+            Some(limit) if matches!(event, Event::Message | Event::Custom(_)) => {
+                match limit.admit().await {
+                    Some(admission) => Some(admission),
+                    None => {
+                        warn!(
+                            "dispatch limit of {} reached, dropped {:?}",
+                            self.nsp, event
+                        );
+                        return;
+                    }
+                }
+            }
+            _ => None,
+        };
+        let mut self_clone = self.clone();
         let event = event.to_owned();
-        tokio::spawn(async move {
-            if let Some(mut callback) = self_clone.on.get_mut(&event) {
-                let c = (self_clone.callback_client_fn)((self_clone).clone());
-                trace!("do callback {:?}", event);
-                callback(payload, c, need_ack).await;
-                trace!("done callback {:?}", event);
+        self_clone.event = Some(event.clone());
+        let job = async move {
+            if admission
+                .as_ref()
+                .is_some_and(|admission| !admission.start())
+            {
+                return;
             }
+            let key = match self_clone.handler_key(&event) {
+                Some(key) => key,
+                None => return,
+            };
+            let state = self_clone.handlers.get(&key);
+            let _permit = match state.and_then(|state| state.limit.as_ref()) {
+                Some((semaphore, HandlerOverflow::Queue)) => {
+                    semaphore.clone().acquire_owned().await.ok()
+                }
+                Some((semaphore, HandlerOverflow::Drop)) => {
+                    match semaphore.clone().try_acquire_owned() {
+                        Ok(permit) => Some(permit),
+                        Err(_) => {
+                            warn!("handler of {:?} busy, dropped {:?}", key, event);
+                            return;
+                        }
+                    }
+                }
+                None => None,
+            };
+
+            // release the handler before awaiting, so invocations may overlap
+            let future = match self_clone.on.get_mut(&key) {
+                Some(mut callback) => {
+                    let c = (self_clone.callback_client_fn)((self_clone).clone());
+                    let invoke = AssertUnwindSafe(|| callback(payload, c, need_ack));
+                    match std::panic::catch_unwind(invoke) {
+                        Ok(future) => AssertUnwindSafe(future).catch_unwind(),
+                        Err(panic) => {
+                            drop(callback);
+                            self_clone
+                                .handle_panic(&String::from(event.clone()), panic)
+                                .await;
+                            return;
+                        }
+                    }
+                }
+                None => return,
+            };
+            trace!("do callback {:?}", event);
+            let timeout = state.and_then(|state| state.timeout);
+            let result = match timeout.or(self_clone.namespace_timeout) {
+                Some(timeout) => {
+                    // the handler future is dropped, cancelling it, on timeout
+                    let result = tokio::time::timeout(timeout, future).await;
+                    if result.is_err() {
+                        warn!("handler of {:?} timed out after {:?}", event, timeout);
+                        let message = format!(
+                            "handler of {} timed out after {:?}",
+                            String::from(event.clone()),
+                            timeout
+                        );
+                        self_clone.callback_error(message);
+                    }
+                    result.unwrap_or(Ok(()))
+                }
+                None => future.await,
+            };
+            if let Err(panic) = result {
+                self_clone
+                    .handle_panic(&String::from(event.clone()), panic)
+                    .await;
+            }
+            trace!("done callback {:?}", event);
+        };
+
+        match &self.dispatcher {
+            Some(dispatcher) => dispatcher.dispatch(self.id, job.boxed()).await,
+            None => {
+                spawn(self.runtime.as_ref(), job);
+            }
+        }
+    }
+
+    /// Reports a panicked handler through the `Error` handler and applies the
+    /// panic policy.
+    async fn handle_panic(&self, handler: &str, panic: Box<dyn Any + Send>) {
+        let reason = panic
+            .downcast_ref::<&str>()
+            .map(|s| s.to_string())
+            .or_else(|| panic.downcast_ref::<String>().cloned())
+            .unwrap_or_default();
+        let message = format!("handler of {} panicked: {}", handler, reason);
+        error!("{}", message);
+        self.callback_error(message);
+
+        if self.panic_policy == PanicPolicy::Disconnect {
+            let _ = self.disconnect().await;
+        }
+    }
+
+    /// Fires the `Error` handler with `message`.
+    pub(crate) fn callback_error(&self, message: String) {
+        let mut self_clone = self.clone();
+        self_clone.event = Some(Event::Error);
+        spawn(self.runtime.as_ref(), async move {
+            let future = match self_clone.on.get_mut(&Event::Error) {
+                Some(mut callback) => {
+                    let c = (self_clone.callback_client_fn)(self_clone.clone());
+                    callback(Some(json!(message).into()), c, None)
+                }
+                None => return,
+            };
+            future.await;
         });
     }
 
@@ -331,8 +1220,11 @@ impl<C: Clone + Send + 'static> Socket<C> {
                 if ack.id == id {
                     to_be_removed.push(index);
 
-                    if ack.time_started.elapsed() < ack.timeout {
+                    let elapsed = ack.time_started.elapsed();
+                    if elapsed < ack.timeout {
                         trace!("ack packet {:?}", packet);
+                        self.last_rtt
+                            .store(elapsed.as_millis() as u64, Ordering::Relaxed);
                         let payload = if is_binary {
                             Self::decode_binary_payload(&packet.data, &packet.attachments, false)
                         } else {
@@ -341,24 +1233,45 @@ impl<C: Clone + Send + 'static> Socket<C> {
 
                         trace!("decode ack payload {:?}", payload);
 
-                        ack.callback.deref_mut()(
-                            payload,
-                            (self.callback_client_fn)(self.clone()),
-                            None,
-                        )
-                        .await;
+                        let mut socket = self.clone();
+                        socket.ack_context = ack.context.clone();
+                        let c = (self.callback_client_fn)(socket);
+                        let invoke =
+                            AssertUnwindSafe(|| ack.callback.deref_mut()(payload, c, None));
+                        let result = match std::panic::catch_unwind(invoke) {
+                            Ok(future) => AssertUnwindSafe(future).catch_unwind().await,
+                            Err(panic) => Err(panic),
+                        };
+                        if let Err(panic) = result {
+                            self.handle_panic(&format!("ack {}", id), panic).await;
+                        }
                     } else {
                         trace!("Received an Ack that is now timed out (elapsed time was longer than specified duration)");
                     }
                 }
             }
-            for index in to_be_removed {
-                self.outstanding_acks.write().await.remove(index);
+            // back to front, so the remaining indices stay valid
+            let mut acks = self.outstanding_acks.write().await;
+            for index in to_be_removed.into_iter().rev() {
+                let ack = acks.remove(index);
+                self.ack_bytes.fetch_sub(ack.size, Ordering::Relaxed);
             }
         }
         Ok(())
     }
 
+    /// Whether `event` may come from the peer. The reserved events are fired by
+    /// the library itself, so one like `Event::Disconnect` can't be forged,
+    /// except the control events a server sends its clients.
+    fn accepts(&self, event: &Event) -> bool {
+        let control = matches!(event, Event::Migrate | Event::Maintenance | Event::Resume);
+        if event.is_reserved() && !(control && self.control_events) {
+            warn!("dropped reserved event {:?} sent by the peer", event);
+            return false;
+        }
+        true
+    }
+
     /// Handles a binary event.
     #[inline]
     async fn handle_binary_event(&self, packet: &Packet) -> Result<()> {
@@ -372,13 +1285,16 @@ impl<C: Clone + Send + 'static> Socket<C> {
             _ => Event::Message,
         };
 
+        if !self.accepts(&event) {
+            return Ok(());
+        }
         let payload = Self::decode_binary_payload(&packet.data, &packet.attachments, true);
         self.callback(&event, payload, packet.id).await;
 
         Ok(())
     }
 
-    fn decode_binary_payload(
+    pub(crate) fn decode_binary_payload(
         data: &Option<Value>,
         attachments: &Option<Vec<Bytes>>,
         skip_event: bool,
@@ -405,35 +1321,24 @@ impl<C: Clone + Send + 'static> Socket<C> {
             vec,
             attachments
         );
+        let atts = attachments.as_deref().unwrap_or_default();
         let mut vec_payload = vec![];
         for (index, value) in vec.iter().enumerate() {
             if skip_event && index == 0 {
                 continue;
             }
             trace!("do_decode_binary_payload {:?}", value);
-            if value.get("_placeholder").is_some() {
-                let index = value
-                    .get("num")
-                    .ok_or(Error::InvalidPacket())?
-                    .as_u64()
-                    .ok_or(Error::InvalidPacket())? as usize;
-                if let Some(atts) = attachments {
-                    let b = atts.get(index).ok_or(Error::InvalidPacket())?.to_owned();
-                    vec_payload.push(RawPayload::Binary(b));
-                } else {
-                    return Err(Error::InvalidPacket());
-                }
-            } else {
-                vec_payload.push(RawPayload::Json(value.to_owned()))
-            }
+            vec_payload.push(match BinaryPlaceHolder::replace(value, atts)? {
+                Some(Nested::Binary(bin)) => RawPayload::Binary(bin),
+                Some(nested) => RawPayload::Nested(nested),
+                None => RawPayload::Json(value.to_owned()),
+            });
         }
 
-        if vec_payload.len() == 1 {
-            // SAFETY: len checked before
-            let payload = vec_payload.pop().unwrap();
-            return Ok(payload.into());
+        match <[RawPayload; 1]>::try_from(vec_payload) {
+            Ok([payload]) => Ok(payload.into()),
+            Err(vec_payload) => Ok(Payload::Multi(vec_payload)),
         }
-        Ok(Payload::Multi(vec_payload))
     }
 
     /// A method for handling the Event Client Packets.
@@ -455,6 +1360,9 @@ impl<C: Clone + Send + 'static> Socket<C> {
                 Event::Message
             };
 
+            if !self.accepts(&event) {
+                return Ok(());
+            }
             let payload = Self::decode_event_payload(packet, true);
             self.callback(&event, payload, packet.id).await;
         } else {
@@ -464,17 +1372,15 @@ impl<C: Clone + Send + 'static> Socket<C> {
         Ok(())
     }
 
-    fn decode_event_payload(packet: &Packet, skip_event: bool) -> Option<Payload> {
+    pub(crate) fn decode_event_payload(packet: &Packet, skip_event: bool) -> Option<Payload> {
         match packet.data {
             Some(serde_json::Value::Array(ref contents)) if contents.is_empty() => None,
             Some(serde_json::Value::Array(ref contents)) if contents.len() == 1 => {
-                // SAFETY: len checked before
-                Some(Payload::Json(contents.get(0).unwrap().clone()))
+                contents.first().cloned().map(Payload::Json)
             }
             Some(serde_json::Value::Array(ref contents)) if contents.len() == 2 => {
                 if skip_event {
-                    // SAFETY: len checked before
-                    Some(Payload::Json(contents.get(1).unwrap().clone()))
+                    contents.get(1).cloned().map(Payload::Json)
                 } else {
                     Some(Payload::Multi(
                         contents.iter().cloned().map(RawPayload::from).collect(),
@@ -502,6 +1408,7 @@ impl<C: Clone + Send + 'static> Socket<C> {
 
     pub(crate) async fn handle_connect(&self, packet: Option<&Packet>) -> Result<()> {
         self.is_connected.store(true, Ordering::Release);
+        self.disconnected.store(false, Ordering::Release);
         trace!("callback connect {:?}", packet);
         let payload = packet.map(|p| p.data.clone().into());
 
@@ -513,7 +1420,7 @@ impl<C: Clone + Send + 'static> Socket<C> {
     /// This method is later registered as the callback for the `on_data` event of the
     /// engineio client.
     #[inline]
-    async fn handle_socketio_packet(&self, packet: &Packet) -> Result<()> {
+    pub(crate) async fn handle_socketio_packet(&self, packet: &Packet) -> Result<()> {
         trace!("handle_socketio_packet {:?}", packet);
         if packet.nsp == self.nsp {
             match packet.ptype {
@@ -541,10 +1448,19 @@ impl<C: Clone + Send + 'static> Socket<C> {
                             .await;
                     }
                 }
+                #[cfg(feature = "server")]
+                PacketType::Connect if self.socket.is_server => {
+                    self.handle_duplicate_connect().await?
+                }
                 PacketType::Connect => self.handle_connect(Some(packet)).await?,
                 PacketType::Disconnect => {
                     self.is_connected.store(false, Ordering::Release);
                     self.callback(&Event::Close, None, None).await;
+                    let reason = match self.socket.is_server {
+                        true => DisconnectReason::ClientDisconnect,
+                        false => DisconnectReason::ServerDisconnect,
+                    };
+                    self.disconnect_callback(reason).await;
                 }
                 PacketType::ConnectError => {
                     self.is_connected.store(false, Ordering::Release);
@@ -566,7 +1482,19 @@ impl<C: Clone + Send + 'static> Socket<C> {
     pub(crate) async fn poll_packet(&self) -> Option<Result<Packet>> {
         loop {
             // poll for the next payload
-            let next = self.socket.poll_packet().await;
+            let next = match self.socket.poll_received().await {
+                Some(Ok(Received::Ping)) => {
+                    self.callback(&Event::Ping, None, None).await;
+                    continue;
+                }
+                Some(Ok(Received::Pong)) => {
+                    self.callback(&Event::Pong, None, None).await;
+                    continue;
+                }
+                Some(Ok(Received::Packet(packet))) => Some(Ok(packet)),
+                Some(Err(err)) => Some(Err(err)),
+                None => None,
+            };
             match next {
                 None => {
                     // end the stream if the underlying one is closed
@@ -593,24 +1521,82 @@ impl<C: Clone + Send + 'static> Socket<C> {
 impl RawSocket {
     /// Creates an instance of `Socket`.
     #[cfg(feature = "client")]
-    pub(super) fn client_end(engine_client: EngineSocket) -> Self {
+    pub(super) fn client_end(
+        engine_client: EngineSocket,
+        cipher: Option<Arc<dyn PayloadCipher>>,
+        compression: Option<Arc<Compression>>,
+    ) -> Self {
         RawSocket {
             engine_client: Arc::new(engine_client.clone()),
             generator: Arc::new(Mutex::new(StreamGenerator::new(Self::stream(
                 engine_client,
+                cipher.clone(),
+                compression.clone(),
             )))),
+            cipher,
+            compression,
+            encoder: None,
             is_server: false,
+            counters: None,
+            max_payload: None,
+        }
+    }
+
+    /// Encodes outgoing packets into a pre-allocated buffer within `limits`.
+    #[cfg(feature = "client")]
+    pub(crate) fn with_memory_limits(mut self, limits: Option<crate::MemoryLimits>) -> Self {
+        self.encoder = limits.map(|limits| Arc::new(Encoder::new(limits)));
+        self
+    }
+
+    /// Counts the events sent and received in `counters`.
+    #[cfg(feature = "client")]
+    pub(crate) fn with_counters(mut self, counters: Arc<Counters>) -> Self {
+        self.counters = Some(counters);
+        self
+    }
+
+    /// Refuses to send packets of more than `max_payload` bytes.
+    #[cfg(feature = "client")]
+    pub(crate) fn with_max_payload(mut self, max_payload: Option<usize>) -> Self {
+        self.max_payload = max_payload;
+        self
+    }
+
+    /// The transport of the connection, `None` while disconnected.
+    #[cfg(feature = "client")]
+    pub(crate) fn transport(&self) -> Option<&'static str> {
+        match (
+            self.is_engineio_connected(),
+            self.engine_client.is_websocket(),
+        ) {
+            (false, _) => None,
+            (true, true) => Some("websocket"),
+            (true, false) => Some("polling"),
         }
     }
 
     #[cfg(feature = "server")]
-    pub(super) fn server_end(engine_client: EngineSocket) -> Self {
+    pub(super) fn server_end(
+        engine_client: EngineSocket,
+        cipher: Option<Arc<dyn PayloadCipher>>,
+        compression: Option<Arc<Compression>>,
+    ) -> Self {
         RawSocket {
             engine_client: Arc::new(engine_client.clone()),
             generator: Arc::new(Mutex::new(StreamGenerator::new(Self::stream(
                 engine_client,
+                cipher.clone(),
+                compression.clone(),
             )))),
+            cipher,
+            compression,
+            encoder: None,
             is_server: true,
+            #[cfg(feature = "client")]
+            counters: None,
+            #[cfg(feature = "client")]
+            max_payload: None,
         }
     }
 
@@ -625,6 +1611,26 @@ impl RawSocket {
         Ok(())
     }
 
+    /// Enables compression if the server advertised it in the engine.io handshake.
+    /// The client announced it in the query of its handshake request.
+    #[cfg(feature = "client")]
+    pub(crate) fn negotiate_compression(&self) {
+        if let Some(compression) = &self.compression {
+            let extra = self.engine_client.handshake_extra();
+            compression.negotiate(extra.get(compression::CAPABILITY).and_then(Value::as_str));
+        }
+    }
+
+    /// Enables compression if the client announced it in the query of its
+    /// engine.io handshake request.
+    #[cfg(feature = "server")]
+    pub(crate) fn accept_compression(&self, query: Option<&HashMap<String, String>>) {
+        if let Some(compression) = &self.compression {
+            let capability = query.and_then(|q| q.get(compression::CAPABILITY));
+            compression.negotiate(capability.map(String::as_str));
+        }
+    }
+
     /// Disconnects from the server by sending a socket.io `Disconnect` packet. This results
     /// in the underlying engine.io transport to get closed as well.
     pub async fn disconnect(&self) -> Result<()> {
@@ -635,6 +1641,16 @@ impl RawSocket {
         Ok(())
     }
 
+    /// The size of the packets buffered for a polling client.
+    async fn queued_bytes(&self) -> usize {
+        #[cfg(feature = "server")]
+        if self.is_server {
+            let stats = self.engine_client.queue_stats().await;
+            return stats.map_or(0, |stats| stats.bytes);
+        }
+        0
+    }
+
     /// Sends a `socket.io` packet to the server using the `engine.io` client.
     pub async fn send(&self, packet: Packet) -> Result<()> {
         if !self.is_engineio_connected() {
@@ -643,30 +1659,120 @@ impl RawSocket {
         }
 
         // the packet, encoded as an engine.io message packet
-        let engine_packet = EnginePacket::new(EnginePacketType::Message, Bytes::from(&packet));
+        let data = match &self.encoder {
+            Some(encoder) => encoder.encode(&packet)?,
+            None => Bytes::from(&packet),
+        };
+        self.send_encoded(packet.ptype, data, packet.attachments)
+            .await
+    }
 
-        match packet.attachments {
-            None => {
-                self.engine_client.emit(engine_packet).await?;
-            }
-            Some(attachments) if attachments.is_empty() => {
-                self.engine_client.emit(engine_packet).await?;
-            }
-            Some(attachments) => {
-                let mut packets = vec![engine_packet];
+    /// Sends `packets` with their attachments in one batch of the `engine.io`
+    /// client.
+    pub async fn send_batch(&self, packets: Vec<Packet>) -> Result<()> {
+        if !self.is_engineio_connected() {
+            trace!("socket emit before open {:?}", packets);
+            return Err(Error::IllegalActionBeforeOpen());
+        }
+
+        let mut batch = Vec::with_capacity(packets.len());
+        let mut ptypes = Vec::with_capacity(packets.len());
+        for packet in packets {
+            let data = match &self.encoder {
+                Some(encoder) => encoder.encode(&packet)?,
+                None => Bytes::from(&packet),
+            };
+            batch.extend(self.engine_packets(data, packet.attachments)?);
+            ptypes.push(packet.ptype);
+        }
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        self.engine_client.emit_multi(batch).await?;
+        for ptype in ptypes {
+            self.count_emitted(ptype);
+        }
+        Ok(())
+    }
+
+    /// Sends a packet of type `ptype` encoded as `data`, followed by its binary
+    /// attachments.
+    async fn send_encoded(
+        &self,
+        ptype: PacketType,
+        data: Bytes,
+        attachments: Option<Vec<Bytes>>,
+    ) -> Result<()> {
+        let mut packets = self.engine_packets(data, attachments)?;
+        match packets.len() {
+            1 => self.engine_client.emit(packets.remove(0)).await?,
+            // atomic send attachments
+            _ => self.engine_client.emit_multi(packets).await?,
+        }
+        self.count_emitted(ptype);
 
-                for attachment in attachments {
-                    let engine_packet =
-                        EnginePacket::new(EnginePacketType::MessageBinary, attachment);
-                    packets.push(engine_packet);
+        Ok(())
+    }
+
+    /// The engine.io packets of a packet encoded as `data` and its attachments.
+    fn engine_packets(
+        &self,
+        data: Bytes,
+        attachments: Option<Vec<Bytes>>,
+    ) -> Result<Vec<EnginePacket>> {
+        let engine_packet = match &self.compression {
+            Some(compression) => match compression.compress(&data)? {
+                // compressed packets are binary and told apart by the gzip header
+                Some(compressed) => {
+                    self.engine_packet(EnginePacketType::MessageBinary, compressed)?
                 }
+                None => self.engine_packet(EnginePacketType::Message, data)?,
+            },
+            None => self.engine_packet(EnginePacketType::Message, data)?,
+        };
 
-                // atomic send attachments
-                self.engine_client.emit_multi(packets).await?;
+        let mut packets = vec![engine_packet];
+        for attachment in attachments.into_iter().flatten() {
+            packets.push(self.engine_packet(EnginePacketType::MessageBinary, attachment)?);
+        }
+
+        #[cfg(feature = "client")]
+        if let Some(max_payload) = self.max_payload {
+            let size = packets.iter().map(|packet| packet.data.len()).sum();
+            if size > max_payload {
+                return Err(Error::PayloadTooLarge(size, max_payload));
             }
         }
+        Ok(packets)
+    }
 
-        Ok(())
+    // only clients count the packets by type
+    #[cfg_attr(not(feature = "client"), allow(unused_variables))]
+    fn count_emitted(&self, ptype: PacketType) {
+        #[cfg(feature = "client")]
+        if let Some(counters) = &self.counters {
+            if matches!(
+                ptype,
+                PacketType::Event
+                    | PacketType::BinaryEvent
+                    | PacketType::Ack
+                    | PacketType::BinaryAck
+            ) {
+                counters.emitted();
+            }
+        }
+    }
+
+    /// Wraps encoded data into an engine.io packet, encrypting it if a cipher is set.
+    fn engine_packet(&self, ptype: EnginePacketType, data: Bytes) -> Result<EnginePacket> {
+        match &self.cipher {
+            Some(cipher) => Ok(EnginePacket::new(
+                EnginePacketType::MessageBinary,
+                cipher.encrypt(data)?,
+            )),
+            None => Ok(EnginePacket::new(ptype, data)),
+        }
     }
 
     pub async fn ack(&self, nsp: &str, id: usize, data: Payload) -> Result<()> {
@@ -684,6 +1790,30 @@ impl RawSocket {
         self.send(packet).await
     }
 
+    /// Emits `data` serialized straight into the encoded packet.
+    pub async fn emit_ref<T: Serialize + ?Sized>(
+        &self,
+        nsp: &str,
+        event: &Event,
+        data: &T,
+    ) -> Result<()> {
+        if !self.is_engineio_connected() {
+            trace!("socket emit before open {:?}", event);
+            return Err(Error::IllegalActionBeforeOpen());
+        }
+
+        let encode = |buffer: &mut BytesMut| packet::encode_event_into(buffer, nsp, event, data);
+        let data = match &self.encoder {
+            Some(encoder) => encoder.encode_with(encode)?,
+            None => {
+                let mut buffer = BytesMut::new();
+                encode(&mut buffer)?;
+                buffer.freeze()
+            }
+        };
+        self.send_encoded(PacketType::Event, data, None).await
+    }
+
     #[cfg(feature = "server")]
     pub(crate) async fn handshake(&self, nsp: &str, data: Value) -> Result<()> {
         let packet = Packet::new(
@@ -697,6 +1827,19 @@ impl RawSocket {
         self.send(packet).await
     }
 
+    /// Refuses the connection to the namespace `nsp` with a CONNECT_ERROR packet.
+    pub(crate) async fn connect_error(&self, nsp: &str, data: Value) -> Result<()> {
+        let packet = Packet::new(
+            PacketType::ConnectError,
+            nsp.to_owned(),
+            Some(data),
+            None,
+            0,
+            None,
+        );
+        self.send(packet).await
+    }
+
     /// Returns a packet for a payload, could be used for bot binary and non binary
     /// events and acks. Convenance method.
     #[inline]
@@ -707,6 +1850,7 @@ impl RawSocket {
         id: Option<usize>,
         is_ack: bool,
     ) -> Result<Packet> {
+        payload.check()?;
         let (data, attachments) = Self::encode_data(event, payload);
 
         let packet_type = match attachments.is_empty() {
@@ -750,6 +1894,9 @@ impl RawSocket {
                     match payload {
                         RawPayload::Binary(bin) => Self::process_binary(data, bin, attachments),
                         RawPayload::Json(value) => data.push(value),
+                        RawPayload::Nested(nested) => {
+                            data.push(Self::process_nested(nested, attachments))
+                        }
                     }
                 }
             }
@@ -762,18 +1909,57 @@ impl RawSocket {
         attachments.push(bin_data);
     }
 
-    pub(crate) async fn poll_packet(&self) -> Option<Result<Packet>> {
+    /// Replaces the binaries inside `nested` by placeholders in place.
+    fn process_nested(nested: Nested, attachments: &mut Vec<Bytes>) -> Value {
+        match nested {
+            Nested::Json(value) => value,
+            Nested::Binary(bin_data) => {
+                let place_holder = json!(BinaryPlaceHolder::new(attachments.len()));
+                attachments.push(bin_data);
+                place_holder
+            }
+            Nested::Array(values) => Value::Array(
+                values
+                    .into_iter()
+                    .map(|value| Self::process_nested(value, attachments))
+                    .collect(),
+            ),
+            Nested::Object(map) => Value::Object(
+                map.into_iter()
+                    .map(|(key, value)| (key, Self::process_nested(value, attachments)))
+                    .collect(),
+            ),
+        }
+    }
+
+    pub(crate) async fn poll_received(&self) -> Option<Result<Received>> {
         let mut generator = self.generator.lock().await;
-        generator.next().await
+        let received = generator.next().await;
+        #[cfg(feature = "client")]
+        if let (Some(counters), Some(Ok(Received::Packet(packet)))) = (&self.counters, &received) {
+            if matches!(packet.ptype, PacketType::Event | PacketType::BinaryEvent) {
+                counters.received();
+            }
+        }
+        received
     }
 
-    fn stream(client: EngineSocket) -> Pin<Box<impl Stream<Item = Result<Packet>> + Send>> {
+    fn stream(
+        client: EngineSocket,
+        cipher: Option<Arc<dyn PayloadCipher>>,
+        compression: Option<Arc<Compression>>,
+    ) -> Pin<Box<impl Stream<Item = Result<Received>> + Send>> {
         Box::pin(try_stream! {
             for await received_data in client.clone() {
                 let packet = received_data?;
-                if packet.ptype == EnginePacketType::Message || packet.ptype == EnginePacketType::MessageBinary {
-                    let packet = Self::handle_engineio_packet(packet, client.clone()).await?;
-                    yield packet;
+                match packet.ptype {
+                    EnginePacketType::Message | EnginePacketType::MessageBinary => {
+                        let packet = Self::handle_engineio_packet(packet, client.clone(), &cipher, &compression).await?;
+                        yield Received::Packet(packet);
+                    }
+                    EnginePacketType::Ping => yield Received::Ping,
+                    EnginePacketType::Pong => yield Received::Pong,
+                    _ => {}
                 }
             }
         })
@@ -783,8 +1969,22 @@ impl RawSocket {
     async fn handle_engineio_packet(
         packet: EnginePacket,
         mut client: EngineSocket,
+        cipher: &Option<Arc<dyn PayloadCipher>>,
+        compression: &Option<Arc<Compression>>,
     ) -> Result<Packet> {
-        let mut packet = Packet::try_from(&packet.data)?;
+        let decrypt = |data: Bytes| match cipher {
+            Some(cipher) => cipher.decrypt(data),
+            None => Ok(data),
+        };
+        // whatever the peer sent can't be decoded, the connection is closed
+        let raw = packet.data.clone();
+        let violation = |err: Error| Error::ProtocolViolation(err.to_string(), raw.clone());
+        let data = decrypt(packet.data)?;
+        let data = match compression {
+            Some(compression) => compression.decompress(data).map_err(violation)?,
+            None => data,
+        };
+        let mut packet = Packet::try_from(&data).map_err(violation)?;
 
         // Only handle attachments if there are any
         if packet.attachment_count > 0 {
@@ -792,16 +1992,18 @@ impl RawSocket {
             let mut attachments = Vec::new();
             while attachments_left > 0 {
                 // TODO: This is not nice! Find a different way to peek the next element while mapping the stream
-                let next = client.next().await.unwrap();
+                let next = client.next().await.ok_or(Error::StoppedEngineIoSocket)?;
                 match next {
                     Err(err) => return Err(err.into()),
                     Ok(packet) => match packet.ptype {
                         EnginePacketType::MessageBinary | EnginePacketType::Message => {
-                            attachments.push(packet.data);
+                            attachments.push(decrypt(packet.data)?);
                             attachments_left -= 1;
                         }
                         _ => {
-                            return Err(Error::InvalidAttachmentPacketType(packet.ptype.into()));
+                            return Err(violation(Error::InvalidAttachmentPacketType(
+                                packet.ptype.into(),
+                            )));
                         }
                     },
                 }
@@ -822,7 +2024,161 @@ impl Debug for RawSocket {
         f.debug_struct("Socket")
             .field("engine_client", &self.engine_client)
             .field("is_server", &self.is_server)
+            .field("encrypted", &self.cipher.is_some())
             .field("connected", &self.is_engineio_connected())
             .finish()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use proptest::prelude::*;
+    use std::collections::BTreeMap;
+
+    type TestSocket = Socket<()>;
+
+    fn raw_payload() -> impl Strategy<Value = RawPayload> {
+        prop_oneof![
+            any::<i64>().prop_map(|n| RawPayload::Json(json!(n))),
+            ".*".prop_map(|s| RawPayload::Json(json!(s))),
+            prop::collection::vec(any::<i32>(), 0..4).prop_map(|v| RawPayload::Json(json!(v))),
+            prop::collection::vec(any::<u8>(), 0..16).prop_map(RawPayload::from),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn test_binary_payload_roundtrip(payloads in prop::collection::vec(raw_payload(), 2..6)) {
+            let packet = RawSocket::build_packet_for_payload(
+                Payload::Multi(payloads.clone()),
+                Some(Event::from("ev")),
+                "/",
+                None,
+                false,
+            )
+            .unwrap();
+
+            let mut decoded = Packet::try_from(&Bytes::from(&packet)).unwrap();
+            decoded.attachments = packet.attachments;
+
+            let payload = TestSocket::decode_binary_payload(&decoded.data, &decoded.attachments, true);
+            prop_assert_eq!(payload, Some(Payload::Multi(payloads)));
+        }
+
+        #[test]
+        fn test_decode_arbitrary(
+            data in prop_oneof![
+                prop::collection::vec(any::<u8>(), 0..64),
+                "[0-6]([0-9]-)?(/[a-z]*,)?[0-9]*\\[.*".prop_map(String::into_bytes),
+            ],
+            attachments in prop::collection::vec(prop::collection::vec(any::<u8>(), 0..4), 0..3),
+        ) {
+            // malformed packets are errors, never panics
+            if let Ok(packet) = Packet::try_from(&Bytes::from(data)) {
+                let attachments = Some(attachments.into_iter().map(Bytes::from).collect());
+                TestSocket::decode_binary_payload(&packet.data, &attachments, true);
+                TestSocket::decode_event_payload(&packet, true);
+            }
+        }
+    }
+
+    #[test]
+    fn test_attachment_count() {
+        let payload = |count| {
+            Payload::Multi(
+                (0..count)
+                    .map(|_| RawPayload::Binary(Bytes::from_static(b"a")))
+                    .collect(),
+            )
+        };
+        let build = |count| {
+            RawSocket::build_packet_for_payload(payload(count), Some("ev".into()), "/", None, false)
+        };
+
+        assert_eq!(build(255).unwrap().attachment_count, 255);
+        assert!(matches!(
+            build(256),
+            Err(Error::InvalidAttachmentCount(256))
+        ));
+    }
+
+    #[test]
+    fn test_encode_placeholder() {
+        let packet = RawSocket::build_packet_for_payload(
+            Payload::Multi(vec![json!(1).into(), Bytes::from_static(b"a").into()]),
+            Some(Event::from("ev")),
+            "/",
+            None,
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(
+            Bytes::from(&packet),
+            "51-[\"ev\",1,{\"_placeholder\":true,\"num\":0}]"
+        );
+    }
+
+    #[test]
+    fn test_decode_nested_placeholder() -> Result<()> {
+        // as sent by the JS client for `emit("ev", { file, meta: [1, buffer] }, buffer)`
+        let bytes = Bytes::from_static(
+            b"53-[\"ev\",{\"file\":{\"_placeholder\":true,\"num\":0},\"meta\":[1,{\"_placeholder\":true,\"num\":2}]},{\"_placeholder\":true,\"num\":1}]",
+        );
+        let packet = Packet::try_from(&bytes)?;
+        let attachments = Some(vec![
+            Bytes::from_static(b"0"),
+            Bytes::from_static(b"1"),
+            Bytes::from_static(b"2"),
+        ]);
+
+        let payload = TestSocket::decode_binary_payload(&packet.data, &attachments, true);
+        let object = Nested::Object(BTreeMap::from([
+            ("file".to_owned(), Nested::Binary(Bytes::from_static(b"0"))),
+            (
+                "meta".to_owned(),
+                Nested::Array(vec![
+                    Nested::Json(json!(1)),
+                    Nested::Binary(Bytes::from_static(b"2")),
+                ]),
+            ),
+        ]));
+        let expected = Payload::Multi(vec![
+            RawPayload::Nested(object),
+            RawPayload::Binary(Bytes::from_static(b"1")),
+        ]);
+        assert_eq!(payload.as_ref(), Some(&expected));
+
+        // the encoder puts the placeholders back in place, numbered in order
+        let (encoded, encoded_attachments) = RawSocket::encode_data(Some("ev".into()), expected);
+        assert_eq!(
+            encoded,
+            json!([
+                "ev",
+                {
+                    "file": { "_placeholder": true, "num": 0 },
+                    "meta": [1, { "_placeholder": true, "num": 1 }],
+                },
+                { "_placeholder": true, "num": 2 },
+            ])
+        );
+        assert_eq!(encoded_attachments, vec!["0", "2", "1"]);
+
+        // placeholders must be `true` like in the reference parser
+        let data = Some(json!(["ev", { "_placeholder": false, "num": 0 }]));
+        let payload = TestSocket::decode_binary_payload(&data, &attachments, true);
+        assert_eq!(
+            payload,
+            Some(Payload::Json(json!({ "_placeholder": false, "num": 0 })))
+        );
+
+        // out of range attachments are rejected
+        let data = Some(json!(["ev", { "a": { "_placeholder": true, "num": 3 } }]));
+        assert_eq!(
+            TestSocket::decode_binary_payload(&data, &attachments, true),
+            None
+        );
+        Ok(())
+    }
+}